@@ -0,0 +1,139 @@
+//! The Android host tools, fastboot, adb and edl, for scenario steps that
+//! reflash and reboot a device. fastboot and adb are pointed at the device
+//! with `-s` when its serial is configured, EDL mode is recognised by the USB
+//! device the board enumerates as.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::config::AndroidConfig;
+use crate::state::usb_present;
+use anyhow::Result;
+use serde::Deserialize;
+use strum_macros::Display;
+
+/// How often to check whether the device has shown up to a tool
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Tool {
+    Fastboot,
+    Adb,
+    Edl,
+}
+
+/// What to reboot into
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum RebootTarget {
+    System,
+    Bootloader,
+    Recovery,
+    Edl,
+}
+
+/// Run a tool with `args` and return what it printed, stdout then stderr
+/// (fastboot prints most things to stderr). Its output is logged to `target`.
+pub async fn run(tool: Tool, config: &AndroidConfig, args: &[String], target: &str) -> Result<String> {
+    let mut cmd = tokio::process::Command::new(tool.to_string());
+    if tool != Tool::Edl {
+        if let Some(serial) = &config.serial {
+            cmd.arg("-s").arg(serial);
+        }
+    }
+    cmd.args(args).stdin(Stdio::null()).kill_on_drop(true);
+    debug!("Running {} {}", tool, args.join(" "));
+    let output = cmd
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {}: {}", tool, e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().chain(stderr.lines()) {
+        info!(target: target, "{}: {}", tool, line);
+    }
+    if !output.status.success() {
+        bail!("{} {} failed ({}): {}", tool, args.join(" "), output.status, stderr.trim());
+    }
+    Ok(format!("{}{}", stdout, stderr))
+}
+
+/// Whether the output of `fastboot devices` or `adb devices` lists the device
+/// (any device if there's no serial) in one of `states`
+fn listed(output: &str, serial: Option<&str>, states: &[&str]) -> bool {
+    output.lines().any(|line| match line.split_once('\t') {
+        Some((s, state)) => (serial.is_none() || serial == Some(s)) && states.contains(&state.trim()),
+        None => false,
+    })
+}
+
+/// Whether the device can be reached with a tool right now, adb only counts
+/// once it's authorised
+pub async fn visible(tool: Tool, config: &AndroidConfig) -> bool {
+    let states: &[&str] = match tool {
+        Tool::Edl => return usb_present(&config.edl_usb),
+        Tool::Fastboot => &["fastboot"],
+        Tool::Adb => &["device", "recovery"],
+    };
+    let output = tokio::process::Command::new(tool.to_string())
+        .arg("devices")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await;
+    match output {
+        Ok(output) => listed(&String::from_utf8_lossy(&output.stdout), config.serial.as_deref(), states),
+        Err(e) => {
+            debug!("Failed to run {} devices: {}", tool, e);
+            false
+        }
+    }
+}
+
+/// Wait until the device can be reached with a tool, callers put a limit on it
+pub async fn wait_for(tool: Tool, config: &AndroidConfig) {
+    while !visible(tool, config).await {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Reboot with fastboot if the device is in the bootloader, otherwise adb
+pub async fn reboot(config: &AndroidConfig, to: RebootTarget, target: &str) -> Result<()> {
+    let tool = match visible(Tool::Fastboot, config).await {
+        true => Tool::Fastboot,
+        false => Tool::Adb,
+    };
+    let args: &[&str] = match to {
+        RebootTarget::System => &["reboot"],
+        RebootTarget::Bootloader => &["reboot", "bootloader"],
+        RebootTarget::Recovery => &["reboot", "recovery"],
+        RebootTarget::Edl if tool == Tool::Fastboot => &["oem", "edl"],
+        RebootTarget::Edl => &["reboot", "edl"],
+    };
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    run(tool, config, &args, target).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_devices() {
+        let fastboot = "1a2b3c4d\tfastboot\nZY22\tfastboot\n";
+        assert!(listed(fastboot, Some("ZY22"), &["fastboot"]));
+        assert!(listed(fastboot, None, &["fastboot"]));
+        assert!(!listed(fastboot, Some("1a2b"), &["fastboot"]));
+        assert!(!listed("", None, &["fastboot"]));
+
+        let adb = "List of devices attached\n1a2b3c4d\tunauthorized\nZY22\tdevice\n\n";
+        assert!(listed(adb, Some("ZY22"), &["device", "recovery"]));
+        assert!(!listed(adb, Some("1a2b3c4d"), &["device", "recovery"]));
+        // The header isn't a device called List
+        assert!(!listed("List of devices attached\n", Some("List"), &["of"]));
+    }
+}
@@ -0,0 +1,458 @@
+//! Per-run artifact directories. Each operation on a device gets a timestamped
+//! directory collecting everything about that run, with a stable layout so
+//! other tools can consume it:
+//!
+//! ```text
+//! <dir>/<codename>/<YYYYmmdd-HHMMSS>-<kind>/
+//!     metadata.json   see [Metadata]
+//!     console.log     <timestamp>\t<connection>\t<line>
+//!     states.log      <timestamp>\t<state>
+//!     thermal.log     <timestamp>\t<state>\t<zone>\t<celsius>
+//!     crashes/
+//!         <YYYYmmdd-HHMMSS>-<state>/<name>.log
+//!     screenshots/
+//!     transfers/
+//! ```
+//!
+//! Timestamps are RFC 3339 in local time with millisecond precision.
+//!
+//! Once a run has finished its logs are gzipped (`console.log.gz`) if
+//! compression is enabled and it's uploaded if that's configured, see
+//! [crate::upload]. Old runs are deleted according to the retention settings
+//! whenever a new run starts.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::config::{ArtifactsConfig, Device, UploadConfig};
+use crate::crash::CrashReport;
+use crate::upload;
+use crate::{ConnectionEvent, ConnectionEventData, DeviceEvent, RunningDevice};
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+pub const METADATA: &str = "metadata.json";
+pub const CONSOLE_LOG: &str = "console.log";
+pub const STATES_LOG: &str = "states.log";
+pub const THERMAL_LOG: &str = "thermal.log";
+pub const CRASHES: &str = "crashes";
+pub const SCREENSHOTS: &str = "screenshots";
+pub const TRANSFERS: &str = "transfers";
+
+fn timestamp() -> String {
+    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+}
+
+fn console_line(ev: ConnectionEventData, index: &mut ConsoleIndex) -> Option<String> {
+    match ev.event {
+        ConnectionEvent::NewLine(line) => {
+            let ts = timestamp();
+            index.lines += 1;
+            index.first.get_or_insert_with(|| ts.clone());
+            index.last = Some(ts.clone());
+            if !index.connections.contains(&ev.device) {
+                index.connections.push(ev.device.clone());
+            }
+            Some(format!("{}\t{}\t{}\n", ts, ev.device, line))
+        }
+        _ => None,
+    }
+}
+
+/// Store the logs collected after a crash in a directory of their own, failed
+/// commands leave `<name>.error` with the reason instead
+async fn write_crash(crashes: &Path, report: &CrashReport) -> Result<PathBuf> {
+    let name = format!("{}-{}", chrono::Local::now().format("%Y%m%d-%H%M%S"), report.state);
+    // Crashes can come in quick succession
+    let mut dir = crashes.join(&name);
+    let mut n = 1;
+    while dir.exists() {
+        dir = crashes.join(format!("{}.{}", name, n));
+        n += 1;
+    }
+    tokio::fs::create_dir(&dir).await?;
+    for output in report.outputs.iter() {
+        let (file, contents) = match &output.output {
+            Ok(log) => (format!("{}.log", output.name), log),
+            Err(e) => (format!("{}.error", output.name), e),
+        };
+        tokio::fs::write(dir.join(file), contents).await?;
+    }
+    Ok(dir)
+}
+
+/// Summary of the console log of a run, so searches can skip runs without
+/// reading (and decompressing) their logs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConsoleIndex {
+    pub lines: usize,
+    /// Timestamps of the first and last lines
+    pub first: Option<String>,
+    pub last: Option<String>,
+    pub connections: Vec<String>,
+}
+
+/// Describes a run so its results are reproducible and attributable, written
+/// when the run starts and updated when it finishes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub codename: String,
+    /// SHA-256 of the device config
+    pub config_hash: String,
+    pub fbug_version: String,
+    pub kind: String,
+    pub start: String,
+    pub end: Option<String>,
+    /// Triggers run, in order
+    pub triggers: Vec<String>,
+    pub final_state: Option<String>,
+    /// Why the run failed, if it did
+    pub error: Option<String>,
+    /// Set when the run finishes
+    #[serde(default)]
+    pub console: Option<ConsoleIndex>,
+    /// Where the run was uploaded to
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// The artifact directory for a single run
+pub struct RunDir {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    compress: bool,
+    upload: Option<UploadConfig>,
+    recorder: Option<(oneshot::Sender<()>, JoinHandle<Result<ConsoleIndex>>)>,
+}
+
+/// Read a log of a run, whether or not it has been compressed. Returns None
+/// if the run doesn't have it.
+pub fn read_log(run: &Path, name: &str) -> Result<Option<String>> {
+    let path = run.join(name);
+    match std::fs::read_to_string(&path) {
+        Ok(log) => return Ok(Some(log)),
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => bail!("Failed to read {}: {}", path.display(), e),
+        Err(_) => {}
+    }
+    let gz = run.join(format!("{}.gz", name));
+    let file = match std::fs::File::open(&gz) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => bail!("Failed to read {}: {}", gz.display(), e),
+    };
+    let mut log = String::new();
+    GzDecoder::new(file)
+        .read_to_string(&mut log)
+        .map_err(|e| anyhow!("Failed to decompress {}: {}", gz.display(), e))?;
+    Ok(Some(log))
+}
+
+pub fn read_metadata(run: &Path) -> Result<Metadata> {
+    let json = std::fs::read_to_string(run.join(METADATA))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// The run directories of a device, oldest first
+pub fn runs(config: &ArtifactsConfig, codename: &str) -> Result<Vec<PathBuf>> {
+    let dir = config.dir().join(codename);
+    let mut runs = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => bail!("Failed to read {}: {}", dir.display(), e),
+    };
+    // Named by start time so this is chronological
+    runs.sort();
+    Ok(runs)
+}
+
+fn dir_size(path: &Path) -> u64 {
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|e| match e.metadata() {
+                    Ok(m) if m.is_dir() => dir_size(&e.path()),
+                    Ok(m) => m.len(),
+                    Err(_) => 0,
+                })
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Delete the finished runs of a device that are older than the maximum age,
+/// then the oldest ones until its runs fit in the maximum size
+pub fn prune(config: &ArtifactsConfig, codename: &str) -> Result<()> {
+    if config.max_age_days.is_none() && config.max_size_mb.is_none() {
+        return Ok(());
+    }
+    let runs: Vec<(PathBuf, u64)> = runs(config, codename)?
+        .into_iter()
+        .map(|r| {
+            let size = dir_size(&r);
+            (r, size)
+        })
+        .collect();
+    let mut total: u64 = runs.iter().map(|(_, size)| size).sum();
+    let max_size = config.max_size_mb.map(|mb| mb * 1024 * 1024);
+    let oldest = config
+        .max_age_days
+        .map(|days| chrono::Local::now() - chrono::Duration::days(days as i64));
+    for (run, size) in runs {
+        // Runs still in progress haven't got an end time
+        let Ok(Metadata { end: Some(_), start, .. }) = read_metadata(&run) else {
+            continue;
+        };
+        let expired = match (oldest, chrono::DateTime::parse_from_rfc3339(&start)) {
+            (Some(oldest), Ok(start)) => start < oldest,
+            _ => false,
+        };
+        let too_big = max_size.is_some_and(|max| total > max);
+        if !expired && !too_big {
+            continue;
+        }
+        debug!("Deleting run {}", run.display());
+        std::fs::remove_dir_all(&run).map_err(|e| anyhow!("Failed to delete {}: {}", run.display(), e))?;
+        total = total.saturating_sub(size);
+    }
+    Ok(())
+}
+
+/// Replace a file with a gzipped copy
+fn compress(path: &Path) -> Result<()> {
+    let gz = path.with_file_name(format!("{}.gz", path.file_name().unwrap_or_default().to_string_lossy()));
+    let mut input = std::fs::File::open(path)?;
+    let mut encoder = GzEncoder::new(std::fs::File::create(&gz)?, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+impl RunDir {
+    /// Create a new run directory for a device, `kind` describes the operation
+    /// (e.g. `trigger-boot`). Returns None if artifacts are disabled.
+    pub fn create(config: &ArtifactsConfig, device: &Device, kind: &str) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let codename = &device.codename;
+        if let Err(e) = prune(config, codename) {
+            warn!("Failed to delete old runs of {}: {}", codename, e);
+        }
+        let parent = config.dir().join(codename);
+        std::fs::create_dir_all(&parent)
+            .map_err(|e| anyhow!("Failed to create {}: {}", parent.display(), e))?;
+        let name = format!("{}-{}", chrono::Local::now().format("%Y%m%d-%H%M%S"), kind);
+        // Two runs can start in the same second
+        let mut path = parent.join(&name);
+        let mut n = 1;
+        while let Err(e) = std::fs::create_dir(&path) {
+            if e.kind() != std::io::ErrorKind::AlreadyExists {
+                bail!("Failed to create {}: {}", path.display(), e);
+            }
+            path = parent.join(format!("{}.{}", name, n));
+            n += 1;
+        }
+        for dir in [CRASHES, SCREENSHOTS, TRANSFERS] {
+            std::fs::create_dir(path.join(dir))?;
+        }
+        debug!("Run artifacts for {} in {}", codename, path.display());
+        let run_dir = Self {
+            path,
+            metadata: Metadata {
+                codename: codename.clone(),
+                config_hash: device.config_hash.clone(),
+                fbug_version: env!("CARGO_PKG_VERSION").to_string(),
+                kind: kind.to_string(),
+                start: timestamp(),
+                end: None,
+                triggers: vec![],
+                final_state: None,
+                error: None,
+                console: None,
+                url: None,
+            },
+            compress: config.compress,
+            upload: config.upload.clone(),
+            recorder: None,
+        };
+        run_dir.write_metadata()?;
+        Ok(Some(run_dir))
+    }
+
+    fn write_metadata(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.metadata)?;
+        std::fs::write(self.join(METADATA), json + "\n")?;
+        Ok(())
+    }
+
+    /// Note that a trigger was run as part of this run
+    pub fn add_trigger(&mut self, name: &str) {
+        self.metadata.triggers.push(name.to_string());
+    }
+
+    /// A subdirectory (or file) of the run directory
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Start recording the console output and state changes of a device
+    pub async fn record(&mut self, dev: &RunningDevice) -> Result<()> {
+        let mut console_rx = dev.subscribe().await?;
+        let mut thermal_rx = dev.subscribe_thermal().await?;
+        let mut state_rx = dev.watch_state();
+        let mut events_rx = dev.events();
+        let crashes = self.join(CRASHES);
+        let mut console = File::create(self.join(CONSOLE_LOG)).await?;
+        let mut states = File::create(self.join(STATES_LOG)).await?;
+        // Only created once there's a sample
+        let mut thermal: Option<File> = None;
+        let thermal_path = self.join(THERMAL_LOG);
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+
+        let task = tokio::spawn(async move {
+            let mut index = ConsoleIndex::default();
+            let initial = state_rx.borrow_and_update().clone();
+            if let Some(state) = initial {
+                states.write_all(format!("{}\t{}\n", timestamp(), state).as_bytes()).await?;
+            }
+            loop {
+                tokio::select! {
+                    ev = console_rx.recv() => match ev {
+                        Ok(ev) => if let Some(line) = console_line(ev, &mut index) {
+                            console.write_all(line.as_bytes()).await?;
+                        },
+                        Err(RecvError::Lagged(n)) => warn!("Run log dropped {} lines", n),
+                        Err(RecvError::Closed) => break,
+                    },
+                    readings = thermal_rx.recv() => match readings {
+                        Ok(readings) => {
+                            let file = match &mut thermal {
+                                Some(file) => file,
+                                None => thermal.insert(File::create(&thermal_path).await?),
+                            };
+                            let ts = timestamp();
+                            let state = state_rx.borrow().clone().unwrap_or_else(|| "unknown".to_string());
+                            for r in readings {
+                                file.write_all(format!("{}\t{}\t{}\t{:.1}\n", ts, state, r.zone, r.celsius).as_bytes()).await?;
+                            }
+                        }
+                        Err(RecvError::Lagged(n)) => warn!("Run log dropped {} thermal samples", n),
+                        Err(RecvError::Closed) => break,
+                    },
+                    ev = events_rx.recv() => match ev {
+                        Ok(DeviceEvent::CrashReport(report)) => match write_crash(&crashes, &report).await {
+                            Ok(dir) => info!("Crash logs in {}", dir.display()),
+                            Err(e) => warn!("Failed to store crash logs: {}", e),
+                        },
+                        Ok(_) => {}
+                        // Lines are events too, only crash reports matter here
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    },
+                    res = state_rx.changed() => {
+                        if res.is_err() {
+                            break;
+                        }
+                        let state = state_rx.borrow_and_update().clone();
+                        let state = state.as_deref().unwrap_or("unknown");
+                        states.write_all(format!("{}\t{}\n", timestamp(), state).as_bytes()).await?;
+                    }
+                    _ = &mut stop_rx => break,
+                }
+            }
+            // Catch up on whatever was already received
+            loop {
+                match console_rx.try_recv() {
+                    Ok(ev) => {
+                        if let Some(line) = console_line(ev, &mut index) {
+                            console.write_all(line.as_bytes()).await?;
+                        }
+                    }
+                    Err(TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+            console.flush().await?;
+            states.flush().await?;
+            if let Some(mut thermal) = thermal {
+                thermal.flush().await?;
+            }
+            Ok(index)
+        });
+        self.recorder = Some((stop_tx, task));
+        Ok(())
+    }
+
+    /// Stop recording, flush the logs and complete the metadata, then
+    /// compress the logs if enabled
+    pub async fn finish(&mut self, final_state: Option<String>, error: Option<String>) {
+        if let Some((stop, task)) = self.recorder.take() {
+            let _ = stop.send(());
+            match task.await {
+                Ok(Err(e)) => warn!("Failed to write run logs to {}: {}", self.path.display(), e),
+                Err(e) => warn!("Run recorder failed: {}", e),
+                Ok(Ok(index)) => self.metadata.console = Some(index),
+            }
+        }
+        self.metadata.end = Some(timestamp());
+        self.metadata.final_state = final_state;
+        self.metadata.error = error;
+        if let Err(e) = self.write_metadata() {
+            warn!("Failed to write run metadata to {}: {}", self.path.display(), e);
+        }
+        if self.compress {
+            let logs = [self.join(CONSOLE_LOG), self.join(STATES_LOG), self.join(THERMAL_LOG)];
+            let res = tokio::task::spawn_blocking(move || {
+                logs.iter()
+                    .filter(|log| log.exists())
+                    .try_for_each(|log| compress(log))
+            })
+            .await;
+            if let Ok(Err(e)) = res {
+                warn!("Failed to compress run logs in {}: {}", self.path.display(), e);
+            }
+        }
+        if let Some(config) = self.upload.clone() {
+            self.upload(&config).await;
+        }
+    }
+
+    /// Upload the finished run, its URL is in the uploaded metadata too
+    async fn upload(&mut self, config: &UploadConfig) {
+        let url = match upload::url(config, &self.path) {
+            Ok(url) => url,
+            Err(e) => {
+                warn!("Not uploading the run: {}", e);
+                return;
+            }
+        };
+        self.metadata.url = Some(url.clone());
+        if let Err(e) = self.write_metadata() {
+            warn!("Failed to write run metadata to {}: {}", self.path.display(), e);
+        }
+        match upload::upload(config, &self.path).await {
+            Ok(()) => info!("Uploaded {} to {}", self.path.display(), url),
+            Err(e) => {
+                warn!("Failed to upload {}: {}", self.path.display(), e);
+                self.metadata.url = None;
+                if let Err(e) = self.write_metadata() {
+                    warn!("Failed to write run metadata to {}: {}", self.path.display(), e);
+                }
+            }
+        }
+    }
+}
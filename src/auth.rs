@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::{ClientConfig, Permission, TlsConfig, TokenConfig};
+use anyhow::Result;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        bail!("{}: no certificates found", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("{}: no private key found", path.display())
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
+
+/// Build the TLS acceptor for the agent
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let server = match &config.client_ca {
+        Some(ca) => builder
+            .with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(load_roots(ca)?)))
+            .with_single_cert(certs, key)?,
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+    };
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+/// Build a TLS connector for clients, returns None if TLS isn't configured
+pub fn connector(config: &ClientConfig) -> Result<Option<TlsConnector>> {
+    let ca = match &config.ca {
+        Some(ca) => ca,
+        None => return Ok(None),
+    };
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_roots(ca)?);
+    let client = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("Both a client cert and key are needed for mTLS"),
+    };
+    Ok(Some(TlsConnector::from(Arc::new(client))))
+}
+
+pub fn server_name(host: &str) -> Result<ServerName> {
+    ServerName::try_from(host).map_err(|e| anyhow!("Invalid server name {}: {}", host, e))
+}
+
+/// Compare without bailing out early so the time taken doesn't leak how much
+/// of a token matched
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Look up the permissions granted by a token
+pub fn authenticate<'a>(tokens: &'a [TokenConfig], token: &str) -> Option<&'a TokenConfig> {
+    tokens.iter().find(|t| token_eq(&t.token, token))
+}
+
+/// The permissions a connection has before authenticating
+pub fn default_permissions(tokens: &[TokenConfig]) -> Vec<Permission> {
+    if tokens.is_empty() {
+        vec![Permission::Read, Permission::Write, Permission::Control]
+    } else {
+        vec![]
+    }
+}
@@ -0,0 +1,184 @@
+//! Accounting for how much each connection prints, so a console that can't
+//! keep up is noticed. A UART at 3 Mbaud that's saturated silently drops
+//! characters, and a line longer than
+//! [MAX_LINE_LENGTH](crate::latency::MAX_LINE_LENGTH) is split (see
+//! [Event::Overflow](crate::Event::Overflow)). Both are warned about, and the
+//! counters are published with the device.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::time::Instant;
+
+use crate::config::ConnectionInfo;
+use serde::{Deserialize, Serialize};
+
+/// A serial connection is saturated once it carries this much of what its
+/// baud rate allows
+const SATURATION: f64 = 0.9;
+
+/// What a connection has printed since the device started
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Throughput {
+    pub bytes: u64,
+    pub lines: u64,
+    /// Lines that were too long and had to be split
+    pub overflows: u64,
+    /// Over the last second
+    pub bytes_per_sec: f64,
+    pub lines_per_sec: f64,
+    pub peak_bytes_per_sec: f64,
+    /// Whether a serial connection is close to what its baud rate allows
+    pub saturated: bool,
+}
+
+impl Display for Throughput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} lines, {} bytes, peak {:.0} bytes/s",
+            self.lines, self.bytes, self.peak_bytes_per_sec
+        )?;
+        if self.overflows > 0 {
+            write!(f, ", {} overlong lines split", self.overflows)?;
+        }
+        if self.saturated {
+            write!(f, ", saturated")?;
+        }
+        Ok(())
+    }
+}
+
+/// The throughput of each connection, by label
+pub type BandwidthMap = BTreeMap<String, Throughput>;
+
+struct Counter {
+    throughput: Throughput,
+    /// Bytes per second the line can carry, for serial connections
+    capacity: Option<f64>,
+    /// Counts at the last tick
+    bytes: u64,
+    lines: u64,
+}
+
+impl Counter {
+    fn new(capacity: Option<f64>) -> Self {
+        Self {
+            throughput: Throughput::default(),
+            capacity,
+            bytes: 0,
+            lines: 0,
+        }
+    }
+}
+
+/// Bytes per second a UART carries at `baud`, 8N1 takes 10 bits per byte
+fn capacity(baud: u32) -> f64 {
+    baud as f64 / 10.0
+}
+
+/// The counters of a device's connections, driven by the device loop
+pub struct Bandwidth {
+    counters: BTreeMap<String, Counter>,
+    /// The serial connection baud rate properties apply to by default
+    serial: Option<String>,
+    last: Instant,
+}
+
+impl Bandwidth {
+    pub fn new(connections: &[ConnectionInfo]) -> Self {
+        let counters = connections
+            .iter()
+            .filter_map(|c| match c {
+                ConnectionInfo::Serial(s) => Some((s.label.clone(), Counter::new(Some(capacity(s.baud))))),
+                // They don't print anything
+                ConnectionInfo::Ssh(_) | ConnectionInfo::Usb(_) => None,
+                c => Some((c.label().to_string(), Counter::new(None))),
+            })
+            .collect();
+        let serial = connections.iter().find_map(|c| match c {
+            ConnectionInfo::Serial(s) => Some(s.label.clone()),
+            _ => None,
+        });
+        Self {
+            counters,
+            serial,
+            last: Instant::now(),
+        }
+    }
+
+    /// A connection was opened, connections added while the device is
+    /// running are counted from then on
+    pub fn up(&mut self, connection: &str) {
+        self.counters
+            .entry(connection.to_string())
+            .or_insert_with(|| Counter::new(None));
+    }
+
+    /// A line was received, its line ending counts too. Lines that fbug made
+    /// up (like `CONNECTIONS` and `THERMAL`) aren't from a connection.
+    pub fn line(&mut self, connection: &str, line: &str) {
+        if let Some(counter) = self.counters.get_mut(connection) {
+            counter.throughput.bytes += line.len() as u64 + 1;
+            counter.throughput.lines += 1;
+        }
+    }
+
+    /// A line was longer than [crate::latency::MAX_LINE_LENGTH] and was split
+    pub fn overflow(&mut self, connection: &str) {
+        if let Some(counter) = self.counters.get_mut(connection) {
+            counter.throughput.overflows += 1;
+        }
+    }
+
+    /// The baud rate of a serial connection (the first one if `None`) was
+    /// changed by a property
+    pub fn baud(&mut self, connection: Option<&str>, baud: u32) {
+        let Some(counter) = connection
+            .or(self.serial.as_deref())
+            .and_then(|s| self.counters.get_mut(s))
+        else {
+            return;
+        };
+        counter.capacity = Some(capacity(baud));
+    }
+
+    /// Work out the rates since the last tick, warning about connections that
+    /// became saturated. Returns the counters to publish.
+    pub fn tick(&mut self, codename: &str, now: Instant) -> BandwidthMap {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        if elapsed <= 0.0 {
+            return self.counters();
+        }
+        for (connection, counter) in self.counters.iter_mut() {
+            let throughput = &mut counter.throughput;
+            throughput.bytes_per_sec = (throughput.bytes - counter.bytes) as f64 / elapsed;
+            throughput.lines_per_sec = (throughput.lines - counter.lines) as f64 / elapsed;
+            throughput.peak_bytes_per_sec = throughput.peak_bytes_per_sec.max(throughput.bytes_per_sec);
+            counter.bytes = throughput.bytes;
+            counter.lines = throughput.lines;
+            let Some(capacity) = counter.capacity else {
+                continue;
+            };
+            let saturated = throughput.bytes_per_sec >= capacity * SATURATION;
+            if saturated && !throughput.saturated {
+                warn!(
+                    "{}: {} is saturated at {:.0} of {:.0} bytes/s, characters may be lost",
+                    codename, connection, throughput.bytes_per_sec, capacity
+                );
+            } else if !saturated && throughput.saturated {
+                info!("{}: {} is no longer saturated", codename, connection);
+            }
+            throughput.saturated = saturated;
+        }
+        self.counters()
+    }
+
+    pub fn counters(&self) -> BandwidthMap {
+        self.counters
+            .iter()
+            .map(|(connection, counter)| (connection.clone(), counter.throughput.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,194 @@
+//! Console throughput and latency benchmark. Lines are sent to a connection
+//! that echoes them back (a loopback adapter or an echo target) and timed on
+//! their way through the read loop, codec and event pipeline.
+//!
+//! Each line is `FBUG-BENCH <seq> <sent_ns> <payload>`, where `sent_ns` is
+//! relative to the start of the benchmark and the payload is derived from
+//! `seq` so corrupted lines can be told apart from dropped ones.
+
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::{ConnectionEvent, ConnectionInput, RunningDevice};
+use anyhow::Result;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+
+const MARKER: &str = "FBUG-BENCH";
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Number of lines to send
+    pub count: usize,
+    /// Length of each line in bytes, excluding the newline
+    pub size: usize,
+    /// Lines per second to send at, 0 for as fast as possible
+    pub rate: u32,
+    /// How long to wait for stragglers after the last line is sent
+    pub drain: Duration,
+    pub connection: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    pub sent_lines: usize,
+    pub sent_bytes: usize,
+    pub received_lines: usize,
+    pub received_bytes: usize,
+    pub corrupted_lines: usize,
+    /// Lines the event pipeline dropped because we didn't keep up
+    pub lagged_lines: u64,
+    /// From the first line sent to the last one received
+    pub duration: Duration,
+    /// Sorted end-to-end latencies
+    pub latencies: Vec<Duration>,
+}
+
+fn line(seq: usize, sent: Duration, size: usize) -> String {
+    let mut line = format!("{} {} {} ", MARKER, seq, sent.as_nanos());
+    let pad = size.saturating_sub(line.len());
+    line.extend((0..pad).map(|i| (b'a' + ((seq + i) % 26) as u8) as char));
+    line
+}
+
+/// Parse and verify an echoed line, returns the sequence number and when it
+/// was sent, or None if it was corrupted
+fn parse(received: &str, size: usize) -> Option<(usize, Duration)> {
+    let mut parts = received.splitn(4, ' ');
+    let (_, seq, sent) = (parts.next()?, parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+    let sent = Duration::from_nanos(sent);
+    (line(seq, sent, size) == received).then_some((seq, sent))
+}
+
+impl BenchReport {
+    fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let i = ((self.latencies.len() - 1) as f64 * p).round() as usize;
+        self.latencies[i]
+    }
+
+    pub fn dropped_lines(&self) -> usize {
+        self.sent_lines
+            .saturating_sub(self.received_lines + self.corrupted_lines)
+    }
+
+    pub fn dropped_bytes(&self) -> usize {
+        self.sent_bytes.saturating_sub(self.received_bytes)
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.duration.as_secs_f64().max(f64::EPSILON);
+        writeln!(f, "sent:       {} lines, {} bytes", self.sent_lines, self.sent_bytes)?;
+        writeln!(
+            f,
+            "received:   {} lines, {} bytes in {:.3}s",
+            self.received_lines, self.received_bytes, secs
+        )?;
+        writeln!(
+            f,
+            "dropped:    {} lines, {} bytes ({} corrupted, {} lagged)",
+            self.dropped_lines() + self.corrupted_lines,
+            self.dropped_bytes(),
+            self.corrupted_lines,
+            self.lagged_lines
+        )?;
+        writeln!(
+            f,
+            "throughput: {:.1} lines/s, {:.1} KiB/s",
+            self.received_lines as f64 / secs,
+            self.received_bytes as f64 / secs / 1024.0
+        )?;
+        write!(
+            f,
+            "latency:    min {:?} p50 {:?} p95 {:?} p99 {:?} max {:?}",
+            self.percentile(0.0),
+            self.percentile(0.5),
+            self.percentile(0.95),
+            self.percentile(0.99),
+            self.percentile(1.0)
+        )
+    }
+}
+
+/// Run the benchmark against a device whose console echoes what it's sent
+pub async fn bench(dev: &RunningDevice, opts: &BenchOptions) -> Result<BenchReport> {
+    let mut rx = dev.subscribe().await?;
+    let (done_tx, mut done_rx) = oneshot::channel::<()>();
+    let size = opts.size;
+    let start = Instant::now();
+
+    let sender = async move {
+        let interval = (opts.rate > 0).then(|| Duration::from_secs(1) / opts.rate);
+        let mut sent_bytes = 0;
+        for seq in 0..opts.count {
+            if let Some(interval) = interval {
+                tokio::time::sleep_until((start + interval * seq as u32).into()).await;
+            }
+            let data = line(seq, start.elapsed(), size);
+            sent_bytes += data.len() + 1;
+            dev.send(ConnectionInput {
+                connection: opts.connection.clone(),
+                data: data.into(),
+            })
+            .await?;
+        }
+        let _ = done_tx.send(());
+        Ok::<usize, anyhow::Error>(sent_bytes)
+    };
+
+    let receiver = async {
+        let mut report = BenchReport::default();
+        let mut seen = vec![false; opts.count];
+        let mut last = start;
+        // Set once everything has been sent, stragglers are waited for until then
+        let mut deadline: Option<Instant> = None;
+        while report.received_lines + report.corrupted_lines < opts.count {
+            let ev = tokio::select! {
+                ev = rx.recv() => ev,
+                _ = &mut done_rx, if deadline.is_none() => {
+                    deadline = Some(Instant::now() + opts.drain);
+                    continue;
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or(start).into()), if deadline.is_some() => break,
+            };
+            let ev = match ev {
+                Ok(ev) => ev,
+                Err(RecvError::Lagged(n)) => {
+                    report.lagged_lines += n;
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let ConnectionEvent::NewLine(received) = ev.event else {
+                continue;
+            };
+            // Anything else on the console isn't ours
+            if !received.starts_with(MARKER) {
+                continue;
+            }
+            let now = Instant::now();
+            match parse(&received, size) {
+                Some((seq, sent)) if seq < opts.count && !seen[seq] => {
+                    seen[seq] = true;
+                    report.received_lines += 1;
+                    report.received_bytes += received.len() + 1;
+                    report.latencies.push(now.duration_since(start).saturating_sub(sent));
+                    last = now;
+                }
+                _ => report.corrupted_lines += 1,
+            }
+        }
+        report.duration = last.duration_since(start);
+        report
+    };
+
+    let (sent_bytes, mut report) = tokio::join!(sender, receiver);
+    report.sent_lines = opts.count;
+    report.sent_bytes = sent_bytes?;
+    report.latencies.sort();
+    Ok(report)
+}
@@ -0,0 +1,167 @@
+//! Bringing up SSH connections whose host isn't known until the device is
+//! running, like a board that gets its address by DHCP and prints it on the
+//! console. The host can reference [variables](crate::vars) captured from the
+//! console (`${ip}`), and the connection is only tried while the device is in
+//! one of its `states`. Once the host is known it's tried every `retry` ms
+//! until it answers, then the connection is up: its commands are run there and
+//! `connection <label> up` is emitted from the `CONNECTIONS` source like for
+//! any other connection.
+
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use crate::config::SshConnection;
+use crate::connections::ssh_command;
+use crate::state::StateMachine;
+use crate::vars::{self, Vars};
+use crate::Event;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+/// Whether a host expanded from console output is safe to pass to ssh, the
+/// device under test could otherwise print an ssh option like
+/// `-oProxyCommand=...` and have it run on this host
+fn valid_host(host: &str) -> bool {
+    !host.is_empty() && !host.starts_with('-') && !host.contains(char::is_whitespace)
+}
+
+struct Bootstrap {
+    info: SshConnection,
+    /// Where the host is published once it answers
+    host_tx: watch::Sender<Option<String>>,
+    /// The host that should be connected to, if it's known and the device is
+    /// in one of the states
+    target: Option<String>,
+    /// When to next check whether the target answers, None once it has (or
+    /// while a check is running)
+    next: Option<Instant>,
+    /// The last host that was refused, so it's only warned about once
+    rejected: Option<String>,
+}
+
+impl Bootstrap {
+    fn is_up(&self) -> bool {
+        self.host_tx.borrow().is_some()
+    }
+}
+
+/// The bootstrapped SSH connections of a device, driven by the device loop
+pub struct Bootstraps {
+    bootstraps: Vec<Bootstrap>,
+    variables: Vars,
+    tx: UnboundedSender<Event>,
+}
+
+impl Bootstraps {
+    pub fn new(
+        bootstraps: Vec<(SshConnection, watch::Sender<Option<String>>)>,
+        variables: Vars,
+        tx: UnboundedSender<Event>,
+    ) -> Self {
+        Self {
+            bootstraps: bootstraps
+                .into_iter()
+                .map(|(info, host_tx)| Bootstrap {
+                    info,
+                    host_tx,
+                    target: None,
+                    next: None,
+                    rejected: None,
+                })
+                .collect(),
+            variables,
+            tx,
+        }
+    }
+
+    /// Whether any check is due
+    pub fn busy(&self) -> bool {
+        self.bootstraps.iter().any(|b| b.next.is_some())
+    }
+
+    /// The state or the variables captured from the console changed, work
+    /// out which host each connection should be on. Connections whose host
+    /// changed (or that shouldn't be up any more) go down.
+    pub fn update(&mut self, sm: &StateMachine, now: Instant) {
+        let mut vars = self.variables.clone();
+        vars.extend(sm.context().clone());
+        for bootstrap in self.bootstraps.iter_mut() {
+            let wanted = bootstrap.info.states.is_empty() || bootstrap.info.states.iter().any(|s| sm.in_state(s));
+            // Unset variables just mean it isn't known yet
+            let target = wanted
+                .then(|| vars::expand(&bootstrap.info.host, &vars).ok())
+                .flatten()
+                .filter(|host| {
+                    if valid_host(host) {
+                        return true;
+                    }
+                    if bootstrap.rejected.as_ref() != Some(host) {
+                        warn!("{}: refusing to connect to host {:?}", bootstrap.info.label, host);
+                        bootstrap.rejected = Some(host.clone());
+                    }
+                    false
+                });
+            if target == bootstrap.target {
+                continue;
+            }
+            if bootstrap.is_up() {
+                bootstrap.host_tx.send_replace(None);
+                let _ = self.tx.send(Event::ConnectionDown(bootstrap.info.label.clone()));
+            }
+            if let Some(host) = &target {
+                debug!("Trying to reach {} at {}", bootstrap.info.label, host);
+            }
+            bootstrap.next = target.as_ref().map(|_| now);
+            bootstrap.target = target;
+        }
+    }
+
+    /// Start the checks that are due, each reports back as [Event::Reachable]
+    pub fn poll(&mut self, now: Instant) {
+        for bootstrap in self.bootstraps.iter_mut() {
+            let (Some(host), Some(next)) = (&bootstrap.target, bootstrap.next) else {
+                continue;
+            };
+            if now < next {
+                continue;
+            }
+            bootstrap.next = None;
+            let mut cmd = tokio::process::Command::from(ssh_command(&bootstrap.info, host, "true"));
+            cmd.stdout(Stdio::null()).stderr(Stdio::null()).kill_on_drop(true);
+            let connection = bootstrap.info.label.clone();
+            let host = host.clone();
+            let tx = self.tx.clone();
+            tokio::spawn(async move {
+                let ok = match cmd.status().await {
+                    Ok(status) => status.success(),
+                    Err(e) => {
+                        warn!("{}: failed to run ssh: {}", connection, e);
+                        false
+                    }
+                };
+                let _ = tx.send(Event::Reachable { connection, host, ok });
+            });
+        }
+    }
+
+    /// A check finished, the connection is up if it answered. Otherwise it's
+    /// tried again after a while.
+    pub fn result(&mut self, connection: &str, host: &str, ok: bool, now: Instant) {
+        let Some(bootstrap) = self
+            .bootstraps
+            .iter_mut()
+            .find(|b| b.info.label == connection && b.target.as_deref() == Some(host))
+        else {
+            // The host changed while it was being checked
+            return;
+        };
+        if !ok {
+            trace!("{} isn't reachable at {} yet", connection, host);
+            bootstrap.next = Some(now + Duration::from_millis(bootstrap.info.retry as u64));
+            return;
+        }
+        info!("Reached {} at {}", connection, host);
+        bootstrap.host_tx.send_replace(Some(host.to_string()));
+        let _ = self.tx.send(Event::ConnectionUp(connection.to_string()));
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::Display;
+
+use crate::config::{Control, Device, TransitionAction, TransitionCondition};
+use crate::state::{parse_usb_match, EdgeData, StateMachine, USB_ADD, USB_REMOVE};
+use anyhow::Result;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Resolve the states an edge is valid from, an empty list means any state
+/// other than the target.
+fn edge_from<'a>(sm: &'a StateMachine, edge: &'a EdgeData) -> Vec<&'a str> {
+    if edge.from.is_empty() {
+        sm.states()
+            .iter()
+            .map(|s| s.name.as_str())
+            .filter(|s| *s != edge.to)
+            .collect()
+    } else {
+        edge.from.iter().map(|s| s.as_str()).collect()
+    }
+}
+
+/// Two actions conflict if they have the same priority and could match the
+/// same line. Only identical values and substring matches can be detected.
+fn conflicts(x: &TransitionAction, y: &TransitionAction) -> bool {
+    if x.source != y.source || x.event != y.event || x.priority != y.priority {
+        return false;
+    }
+    if x.value == y.value {
+        return true;
+    }
+    let is_regex = |a: &TransitionAction| a.value.starts_with("^");
+    !is_regex(x) && !is_regex(y) && (x.value.contains(&y.value) || y.value.contains(&x.value))
+}
+
+/// Statically analyse the state graph of a device, this doesn't touch any
+/// hardware so it can be run before connections are opened.
+pub fn analyse(sm: &StateMachine, controls: &[Control]) -> Vec<Diagnostic> {
+    let mut diags = vec![];
+    let edges = sm.transitions();
+    let is_parent = |name: &str| {
+        sm.states()
+            .iter()
+            .any(|s| s.parent.as_deref() == Some(name))
+    };
+
+    for state in sm.states() {
+        // Parent states are usually just used to group their children
+        if is_parent(&state.name) {
+            continue;
+        }
+        if !edges.iter().any(|e| e.to == state.name) {
+            diags.push(Diagnostic::warning(format!(
+                "State {} is unreachable, no transitions lead to it",
+                state.name
+            )));
+        }
+        if !edges.iter().any(|e| edge_from(sm, e).contains(&state.name.as_str())) {
+            diags.push(Diagnostic::warning(format!(
+                "State {} has no outgoing transitions",
+                state.name
+            )));
+        }
+    }
+
+    for (i, a) in edges.iter().enumerate() {
+        for (j, x) in a.actions.iter().enumerate() {
+            if a.actions[..j].contains(x) {
+                diags.push(Diagnostic::warning(format!(
+                    "Transition to {} has duplicate action {:?}",
+                    a.to, x.value
+                )));
+            }
+        }
+        let a_from = edge_from(sm, a);
+        for b in edges[i + 1..].iter() {
+            let overlap: Vec<&str> = edge_from(sm, b)
+                .into_iter()
+                .filter(|s| a_from.contains(s))
+                .collect();
+            if overlap.is_empty() {
+                continue;
+            }
+            for x in a.actions.iter() {
+                for y in b.actions.iter().filter(|y| conflicts(x, y)) {
+                    diags.push(Diagnostic::warning(format!(
+                        "Transitions to {} ({:?}) and {} ({:?}) can match the same line from states {}, give one of them a higher priority",
+                        a.to,
+                        x.value,
+                        b.to,
+                        y.value,
+                        overlap.join(", ")
+                    )));
+                }
+            }
+        }
+    }
+
+    for edge in edges {
+        for cond in edge.conditions.iter() {
+            if let TransitionCondition::Usb(id) = cond {
+                if parse_usb_match(id).is_none() {
+                    diags.push(Diagnostic::error(format!(
+                        "Transition to {} has invalid USB match {:?}, expected vid:pid in hex or udev properties",
+                        edge.to, id
+                    )));
+                }
+            }
+        }
+        for action in edge.actions.iter().filter(|a| a.is_usb()) {
+            if parse_usb_match(&action.value).is_none() {
+                diags.push(Diagnostic::error(format!(
+                    "Transition to {} has invalid USB match {:?}, expected vid:pid in hex or udev properties",
+                    edge.to, action.value
+                )));
+            }
+            if ![USB_ADD, USB_REMOVE].contains(&action.event.as_str()) {
+                diags.push(Diagnostic::error(format!(
+                    "Transition to {} has USB action with event {:?}, expected {} or {}",
+                    edge.to, action.event, USB_ADD, USB_REMOVE
+                )));
+            }
+        }
+        let from = edge_from(sm, edge);
+        for trigger in edge.triggers.iter() {
+            if let Some(f) = trigger.from.iter().find(|f| !from.contains(&f.as_str())) {
+                diags.push(Diagnostic::error(format!(
+                    "Trigger {} is valid from {} but its transition to {} isn't",
+                    trigger.name, f, edge.to
+                )));
+            }
+            for step in trigger.sequence.iter() {
+                if step.control == "send" && step.value.is_none() {
+                    diags.push(Diagnostic::error(format!(
+                        "Trigger {} has a send step without a value",
+                        trigger.name
+                    )));
+                }
+                if !["wait", "send"].contains(&step.control.as_str())
+                    && !controls.iter().any(|c| c.name == step.control)
+                {
+                    diags.push(Diagnostic::error(format!(
+                        "Trigger {} references unknown control {}",
+                        trigger.name, step.control
+                    )));
+                }
+            }
+        }
+    }
+
+    diags
+}
+
+/// Build the state machine for a device and analyse it
+pub fn check_device(device: &Device) -> Result<Vec<Diagnostic>> {
+    let sm = StateMachine::new(device.states.clone(), device.transitions.clone())?;
+    Ok(analyse(&sm, &device.controls))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(value: &str, priority: i32) -> TransitionAction {
+        TransitionAction {
+            source: "UART".to_string(),
+            event: "input".to_string(),
+            value: value.to_string(),
+            priority,
+        }
+    }
+
+    #[test]
+    fn identical_and_substring_values_conflict() {
+        assert!(conflicts(&action("Booting Linux", 0), &action("Booting Linux", 0)));
+        assert!(conflicts(&action("Booting", 0), &action("Booting Linux", 0)));
+        assert!(conflicts(&action("Booting Linux", 0), &action("Linux", 0)));
+        assert!(conflicts(&action("^Booting.*", 0), &action("^Booting.*", 0)));
+    }
+
+    #[test]
+    fn distinct_actions_dont_conflict() {
+        assert!(!conflicts(&action("Booting", 0), &action("login:", 0)));
+        // The priorities decide which wins
+        assert!(!conflicts(&action("Booting", 1), &action("Booting", 0)));
+        // Regexes can only be compared when they're identical
+        assert!(!conflicts(&action("^Boot", 0), &action("^Booting", 0)));
+        assert!(!conflicts(&action("^Boot", 0), &action("Booting", 0)));
+        let mut other = action("Booting", 0);
+        other.source = "MODEM".to_string();
+        assert!(!conflicts(&action("Booting", 0), &other));
+    }
+}
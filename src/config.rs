@@ -1,12 +1,22 @@
 use serde::{Deserialize, Serialize};
-use std::{fmt, path::PathBuf};
+use sha2::{Digest, Sha256};
+use log::LevelFilter;
+use std::{collections::BTreeMap, fmt, path::PathBuf};
 use strum_macros::Display;
 use crate::state::{State, Transition};
+use crate::vars::Vars;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
 pub struct Device {
     pub name: String,
     pub codename: String,
+    /// Groups this device belongs to, used to select devices for fleet commands
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Free form tags, conventionally `key:value` (e.g. `soc:sdm845`)
+    #[serde(default)]
+    pub tags: Vec<String>,
     pub description: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
@@ -15,6 +25,94 @@ pub struct Device {
     pub controls: Vec<Control>,
     pub states: Vec<State>,
     pub transitions: Vec<Transition>,
+    pub lava: Option<LavaConfig>,
+    pub power: Option<PowerConfig>,
+    /// How fastboot, adb and edl find the device, see [crate::android]
+    pub android: Option<AndroidConfig>,
+    pub thermal: Option<ThermalConfig>,
+    pub crash: Option<CrashConfig>,
+    pub idle: Option<IdleConfig>,
+    /// Working out which state the device is in at startup, see
+    /// [crate::identify]
+    pub identify: Option<IdentifyConfig>,
+    /// Prompts to answer automatically, see [crate::responder]
+    #[serde(default)]
+    pub responders: Vec<ResponderConfig>,
+    /// Default values for variables used in trigger sequences
+    #[serde(default)]
+    pub variables: Vars,
+    #[serde(default)]
+    pub log: LogConfig,
+    /// SHA-256 of the config file the device was loaded from
+    #[serde(skip)]
+    pub config_hash: String,
+    /// Where to record how each line was matched, see [crate::trace]
+    #[serde(skip)]
+    pub trace_matches: Option<PathBuf>,
+}
+
+/// Log levels for the console output and errors of a device
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct LogConfig {
+    /// Level for all connections of the device
+    pub level: Option<LevelFilter>,
+    /// Levels for individual connections by label, these take precedence
+    #[serde(default)]
+    pub connections: BTreeMap<String, LevelFilter>,
+    #[serde(default)]
+    pub timestamps: TimestampConfig,
+}
+
+/// How console lines are timestamped, see [crate::timestamps]
+#[derive(Debug, Default, Display, PartialEq, Eq, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum TimestampMode {
+    #[default]
+    Off,
+    /// Time since the reference point
+    Elapsed,
+    /// Time since the previous line
+    Delta,
+    Both,
+}
+
+impl TimestampMode {
+    /// The next mode, for toggling through them at runtime
+    pub fn next(self) -> Self {
+        match self {
+            TimestampMode::Off => TimestampMode::Elapsed,
+            TimestampMode::Elapsed => TimestampMode::Delta,
+            TimestampMode::Delta => TimestampMode::Both,
+            TimestampMode::Both => TimestampMode::Off,
+        }
+    }
+}
+
+impl std::str::FromStr for TimestampMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "off" => TimestampMode::Off,
+            "elapsed" => TimestampMode::Elapsed,
+            "delta" => TimestampMode::Delta,
+            "both" => TimestampMode::Both,
+            _ => bail!("Invalid timestamp mode {:?}, expected off, elapsed, delta or both", s),
+        })
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct TimestampConfig {
+    #[serde(default)]
+    pub mode: TimestampMode,
+    /// Elapsed time is counted from entering this state, e.g. the first boot
+    /// stage for the time since power on. By default it's counted from the
+    /// last transition.
+    pub since: Option<String>,
 }
 
 // Connections
@@ -25,6 +123,58 @@ pub enum ConnectionInfo {
     Serial(SerialConfig),
     Usb(UsbConnection),
     Ssh(SshConnection),
+    Qemu(QemuConfig),
+    Process(ProcessConfig),
+    File(FileConfig),
+    Container(ContainerConfig),
+    Can(CanConfig),
+    Bluetooth(BluetoothConfig),
+    Capture(CaptureConfig),
+    Psu(PsuConfig),
+}
+
+impl ConnectionInfo {
+    pub fn label(&self) -> &str {
+        match self {
+            ConnectionInfo::Serial(s) => &s.label,
+            ConnectionInfo::Usb(u) => &u.label,
+            ConnectionInfo::Ssh(s) => &s.label,
+            ConnectionInfo::Qemu(q) => &q.label,
+            ConnectionInfo::Process(p) => &p.label,
+            ConnectionInfo::File(f) => &f.label,
+            ConnectionInfo::Container(c) => &c.label,
+            ConnectionInfo::Can(c) => &c.label,
+            ConnectionInfo::Bluetooth(b) => &b.label,
+            ConnectionInfo::Capture(c) => &c.label,
+            ConnectionInfo::Psu(p) => &p.label,
+        }
+    }
+
+    /// Whether the connection comes and goes while the device is running,
+    /// rather than being required to open
+    pub fn hotplug(&self) -> bool {
+        match self {
+            ConnectionInfo::Serial(s) => s.hotplug,
+            _ => false,
+        }
+    }
+
+    /// Logging in to a getty, for the types that support it
+    pub fn login(&self) -> Option<&LoginConfig> {
+        match self {
+            ConnectionInfo::Serial(s) => s.login.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The keepalive probe, for the types that support one
+    pub fn probe(&self) -> Option<&ProbeConfig> {
+        match self {
+            ConnectionInfo::Serial(s) => s.probe.as_ref(),
+            ConnectionInfo::Ssh(s) => s.probe.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 fn _default_baud() -> u32 {
@@ -50,6 +200,57 @@ pub struct SerialConfig {
     pub baud: u32,
     #[serde(default = "_default_lines")]
     pub lines: bool,
+    /// Keepalive probes, see [crate::health]
+    pub probe: Option<ProbeConfig>,
+    /// The port comes and goes, e.g. a USB gadget serial port that only
+    /// appears once the device has booted
+    #[serde(default)]
+    pub hotplug: bool,
+    /// Log in to the getty on this port, see [crate::login]
+    pub login: Option<LoginConfig>,
+    #[serde(default)]
+    pub send: SendConfig,
+}
+
+/// The line ending sent after each line of input
+#[derive(Debug, Default, Display, PartialEq, Eq, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Cr,
+    Crlf,
+}
+
+impl LineEnding {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::Cr => b"\r",
+            LineEnding::Crlf => b"\r\n",
+        }
+    }
+}
+
+/// How input is written to a console, for bootloaders that drop characters
+/// when they arrive too fast
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct SendConfig {
+    #[serde(default)]
+    pub line_ending: LineEnding,
+    /// Milliseconds to wait between characters, or between chunks if
+    /// `chunk_size` is set
+    #[serde(default)]
+    pub char_delay: u32,
+    /// Bytes to write at a time, everything at once if 0 (and there's no delay)
+    #[serde(default)]
+    pub chunk_size: usize,
+    /// Drop the console's echo of each line sent, so it isn't logged or
+    /// matched against transitions
+    #[serde(default)]
+    pub suppress_echo: bool,
 }
 
 fn _default_usb_label() -> String {
@@ -67,12 +268,366 @@ fn _default_ssh_label() -> String {
     "SSH".to_string()
 }
 
+fn _default_ssh_port() -> u16 {
+    22
+}
+
+fn _default_ssh_alive_interval() -> u32 {
+    1
+}
+
+fn _default_ssh_alive_count_max() -> u32 {
+    8
+}
+
+fn _default_ssh_retry() -> u32 {
+    2000
+}
+
 #[derive(Debug, PartialEq, Deserialize, Clone)]
 pub struct SshConnection {
     #[serde(default = "_default_ssh_label")]
     pub label: String,
+    /// Can reference [variables](crate::vars) like `${ip}`, captured from the
+    /// console, see [crate::bootstrap]
     pub host: String,
+    #[serde(default = "_default_ssh_port")]
+    pub port: u16,
+    /// Defaults to whatever ssh would use, e.g. from ~/.ssh/config
+    pub user: Option<String>,
+    /// Private key to authenticate with
+    pub identity: Option<PathBuf>,
+    /// Seconds between alive checks
+    #[serde(default = "_default_ssh_alive_interval")]
+    pub alive_interval: u32,
+    /// Missed alive checks before the connection is considered dead
+    #[serde(default = "_default_ssh_alive_count_max")]
+    pub alive_count_max: u32,
+    /// Keepalive probes, see [crate::health]
+    pub probe: Option<ProbeConfig>,
+    /// Only connect once the device is in one of these states, e.g. once it
+    /// has brought up its network
+    #[serde(default)]
+    pub states: Vec<String>,
+    /// Time in ms between attempts to reach a bootstrapped host
+    #[serde(default = "_default_ssh_retry")]
+    pub retry: u32,
+}
+
+impl SshConnection {
+    /// Whether the connection is only brought up while the device is running,
+    /// rather than the host being known and reachable up front
+    pub fn bootstrapped(&self) -> bool {
+        !self.states.is_empty() || self.host.contains('$')
+    }
+}
+
+fn _default_probe_interval() -> u32 {
+    10000
+}
+
+fn _default_probe_timeout() -> u32 {
+    3000
+}
+
+fn _default_probe_failures() -> u32 {
+    3
+}
+
+/// A keepalive probe for a connection. Console connections are only probed
+/// once they've been quiet for `interval`, since output shows they're alive.
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ProbeConfig {
+    /// Time in ms between probes
+    #[serde(default = "_default_probe_interval")]
+    pub interval: u32,
+    /// Time in ms to wait for a response
+    #[serde(default = "_default_probe_timeout")]
+    pub timeout: u32,
+    /// A line to send to a console to provoke a response, by default an empty
+    /// one which makes a shell print its prompt again
+    #[serde(default)]
+    pub send: String,
+    /// A regex the response must match, e.g. the shell prompt. Any output
+    /// will do if not set.
+    pub expect: Option<String>,
+    /// Consecutive failed probes before the connection is considered hung,
+    /// it's degraded before then
+    #[serde(default = "_default_probe_failures")]
+    pub failures: u32,
+    /// Stop pinging the systemd watchdog while the connection is hung, so the
+    /// agent is restarted and reopens it
+    #[serde(default)]
+    pub watchdog: bool,
+}
+
+fn _default_login_delay() -> u32 {
+    1000
+}
+
+fn _default_login_timeout() -> u32 {
+    5000
+}
+
+fn _default_login_attempts() -> u32 {
+    3
+}
+
+fn _default_true() -> bool {
+    true
+}
+
+/// Logging in to a console running a getty. Prompts aren't terminated lines,
+/// so the username and password are sent blind and the login is checked by
+/// running a command.
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct LoginConfig {
+    /// Defaults to the device's username
+    pub username: Option<String>,
+    /// Defaults to the device's password, if neither is set none is sent
+    pub password: Option<String>,
+    /// Only log in while in these states (or their children), any state if
+    /// empty
+    #[serde(default)]
+    pub states: Vec<String>,
+    /// Time in ms between sending the username and password
+    #[serde(default = "_default_login_delay")]
+    pub delay: u32,
+    /// Time in ms to wait for the shell to answer after logging in
+    #[serde(default = "_default_login_timeout")]
+    pub timeout: u32,
+    #[serde(default = "_default_login_attempts")]
+    pub attempts: u32,
+    /// Once logged in, send input that doesn't name a connection here
+    /// instead of the first connection
+    #[serde(default = "_default_true")]
+    pub prefer: bool,
+}
+
+fn _default_qemu_label() -> String {
+    "QEMU".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct QemuConfig {
+    #[serde(default = "_default_qemu_label")]
+    pub label: String,
+    /// Command to launch QEMU, the serial and QMP arguments are appended. If
+    /// not set then fbug attaches to an already running instance.
+    pub command: Option<Vec<String>>,
+    /// Unix socket for the serial chardev
+    pub serial: PathBuf,
+    /// Unix socket for QMP
+    pub qmp: PathBuf,
+}
+
+fn _default_process_label() -> String {
+    "PROCESS".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ProcessConfig {
+    #[serde(default = "_default_process_label")]
+    pub label: String,
+    /// The command to run on a PTY, as a list of arguments
+    pub command: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub send: SendConfig,
+}
+
+fn _default_file_label() -> String {
+    "FILE".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct FileConfig {
+    #[serde(default = "_default_file_label")]
+    pub label: String,
+    /// The file or FIFO to follow
+    pub path: PathBuf,
+    /// Read the existing contents of the file rather than starting at the end
+    #[serde(default)]
+    pub from_start: bool,
+}
+
+fn _default_container_label() -> String {
+    "CONTAINER".to_string()
+}
+
+fn _default_container_runtime() -> String {
+    "docker".to_string()
+}
+
+fn _default_container_shell() -> String {
+    "sh".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ContainerConfig {
+    #[serde(default = "_default_container_label")]
+    pub label: String,
+    /// Name or ID of an existing container
+    pub name: String,
+    /// The container runtime CLI, e.g. docker or podman
+    #[serde(default = "_default_container_runtime")]
+    pub runtime: String,
+    /// Shell used to run data sent to the container
+    #[serde(default = "_default_container_shell")]
+    pub shell: String,
+}
+
+fn _default_can_label() -> String {
+    "CAN".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct CanConfig {
+    #[serde(default = "_default_can_label")]
+    pub label: String,
+    /// The SocketCAN interface, e.g. can0
+    pub interface: String,
+}
+
+fn _default_bluetooth_label() -> String {
+    "BT".to_string()
+}
+
+fn _default_rfcomm_channel() -> u8 {
+    1
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct BluetoothConfig {
+    #[serde(default = "_default_bluetooth_label")]
+    pub label: String,
+    /// MAC address of the DUT
+    pub address: String,
+    /// The RFCOMM channel of the serial port profile
+    #[serde(default = "_default_rfcomm_channel")]
+    pub channel: u8,
+}
+
+fn _default_capture_label() -> String {
+    "CAPTURE".to_string()
+}
+
+fn _default_capture_interval() -> u32 {
+    1000
+}
+
+/// Frames grabbed from a video capture card (e.g. on the DUT's HDMI output),
+/// for telling states apart when the console goes quiet
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct CaptureConfig {
+    #[serde(default = "_default_capture_label")]
+    pub label: String,
+    /// The V4L2 device of the capture card
+    pub device: Option<PathBuf>,
+    /// Command which writes a single frame to stdout as a binary PGM, instead
+    /// of grabbing one from `device` with ffmpeg
+    pub command: Option<Vec<String>>,
+    /// Time in ms between frames
+    #[serde(default = "_default_capture_interval")]
+    pub interval: u32,
+    #[serde(default)]
+    pub screens: Vec<ScreenConfig>,
+}
+
+fn _default_screen_threshold() -> f32 {
+    0.95
+}
+
+/// Something recognisable on screen, reported as the line `screen <name>`
+/// when it appears
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ScreenConfig {
+    pub name: String,
+    /// Reference image (binary PGM) the region must look like
+    pub image: Option<PathBuf>,
+    /// The part of the frame to look at as x, y, width and height in pixels,
+    /// the whole frame by default
+    pub region: Option<[u32; 4]>,
+    /// How similar the region must be to the image, from 0 to 1
+    #[serde(default = "_default_screen_threshold")]
+    pub threshold: f32,
+    /// Text that must be recognised in the region (with tesseract)
+    pub text: Option<String>,
+}
+
+fn _default_psu_label() -> String {
+    "PSU".to_string()
+}
+
+fn _default_psu_baud() -> u32 {
+    9600
+}
+
+fn _default_scpi_port() -> u16 {
+    5025
+}
+
+fn _default_psu_interval() -> u32 {
+    1000
+}
+
+fn _default_psu_timeout() -> u32 {
+    1000
+}
+
+fn _default_overcurrent_query() -> String {
+    "CURR:PROT:TRIP?".to_string()
+}
+
+fn _default_overvoltage_query() -> String {
+    "VOLT:PROT:TRIP?".to_string()
+}
+
+/// A bench power supply or battery simulator controlled with SCPI, over a
+/// serial port or a raw TCP socket
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct PsuConfig {
+    #[serde(default = "_default_psu_label")]
+    pub label: String,
+    /// Serial port of the instrument
+    pub path: Option<PathBuf>,
+    #[serde(default = "_default_psu_baud")]
+    pub baud: u32,
+    /// Host of the instrument, instead of a serial port
+    pub host: Option<String>,
+    #[serde(default = "_default_scpi_port")]
     pub port: u16,
+    /// The output to use on instruments with several, selected with
+    /// `INST:NSEL`
+    pub channel: Option<u32>,
+    /// Milliseconds between checks of the protection state
+    #[serde(default = "_default_psu_interval")]
+    pub interval: u32,
+    /// Milliseconds to wait for the instrument to answer a query
+    #[serde(default = "_default_psu_timeout")]
+    pub timeout: u32,
+    /// Query that returns 1 once overcurrent protection has tripped, empty to
+    /// not check
+    #[serde(default = "_default_overcurrent_query")]
+    pub overcurrent: String,
+    /// Query that returns 1 once overvoltage protection has tripped, empty to
+    /// not check
+    #[serde(default = "_default_overvoltage_query")]
+    pub overvoltage: String,
+    /// Report the measured voltage and current each interval
+    #[serde(default)]
+    pub measure: bool,
 }
 
 // Controls
@@ -91,6 +646,8 @@ pub struct Control {
 pub enum ControlType {
     Button(ButtonControl),
     Command(CommandControl),
+    Psu(PsuControl),
+    Sysrq(SysrqControl),
 }
 
 #[derive(Debug, Display, PartialEq, Deserialize, Clone)]
@@ -107,11 +664,84 @@ pub struct ButtonControl {
     pub action: String,
 }
 
+fn _default_command_timeout() -> u32 {
+    10000
+}
+
+/// Shell commands to turn a control on and off, run on the host fbug is
+/// running on or, if the control's connection is an SSH connection, over it
 #[derive(Debug, PartialEq, Deserialize, Clone)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub struct CommandControl {
     pub command_on: String,
     pub command_off: String,
+    /// Milliseconds to wait for a command before killing it and failing
+    #[serde(default = "_default_command_timeout")]
+    pub timeout: u32,
+}
+
+/// Output settings of a power supply, anything not set is left as it is
+#[derive(Debug, PartialEq, Deserialize, Clone, Default)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct PsuSettings {
+    /// Volts
+    pub voltage: Option<f32>,
+    /// Current limit in amps
+    pub current: Option<f32>,
+    /// Whether the output is enabled
+    pub output: Option<bool>,
+}
+
+/// Settings to apply to a power supply when the control is turned on and off,
+/// e.g. a simulated battery level
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct PsuControl {
+    pub on: PsuSettings,
+    pub off: PsuSettings,
+}
+
+/// How a magic SysRq key is sent
+#[derive(Debug, Default, Display, PartialEq, Eq, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum SysrqMethod {
+    /// A serial break followed by the key
+    #[default]
+    Break,
+    /// Written to /proc/sysrq-trigger from a shell
+    Shell,
+}
+
+/// Sends a magic SysRq key to the kernel when pressed, e.g. to sync or crash a
+/// hung device
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct SysrqControl {
+    /// A name like `crash` or the key itself
+    pub key: String,
+    #[serde(default)]
+    pub method: SysrqMethod,
+}
+
+impl SysrqControl {
+    pub fn key(&self) -> anyhow::Result<u8> {
+        Ok(match self.key.as_str() {
+            "sync" => b's',
+            "crash" => b'c',
+            "reboot" => b'b',
+            "poweroff" => b'o',
+            "remount-ro" => b'u',
+            "show-tasks" => b't',
+            "show-blocked" => b'w',
+            "show-regs" => b'p',
+            "show-memory" => b'm',
+            key => match key.as_bytes() {
+                [c] if c.is_ascii_lowercase() || c.is_ascii_digit() => *c,
+                _ => bail!("Unknown sysrq key {:?}", key),
+            },
+        })
+    }
 }
 
 // States
@@ -122,23 +752,78 @@ pub enum GlobalProperties {
     Baud(u32),
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Clone, Copy)]
+/// What to do when a property can't be applied
+#[derive(Debug, Display, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Clone, Copy)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[strum(serialize_all = "kebab-case")]
+pub enum PropertyFailure {
+    /// Log an error and carry on in the new state
+    #[default]
+    Alert,
+    /// Try again up to [Property::retries] times, then alert
+    Retry,
+    /// Go back to the previous state, the hardware is still set up for it
+    Revert,
+}
+
+fn _default_property_retries() -> u32 {
+    3
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
 pub struct Property {
     #[serde(flatten)]
     pub name: GlobalProperties,
+    /// The serial connection it applies to, the first one if not set
+    pub connection: Option<String>,
+    #[serde(default)]
+    pub on_failure: PropertyFailure,
+    #[serde(default = "_default_property_retries")]
+    pub retries: u32,
 }
 
 // Transitions
 
 #[derive(Debug, PartialEq, Deserialize, Clone)]
 pub struct TransitionAction {
-    /// Connection label
+    /// Connection label, or `USB` for USB devices being enumerated or removed
     pub source: String,
     pub event: String,
     pub value: String,
+    /// When a line matches multiple actions the one with the highest priority wins
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Debug, Display, PartialEq, Eq, Deserialize, Clone, Copy)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+#[strum(serialize_all = "kebab-case")]
+pub enum GpioLevel {
+    High,
+    Low,
+}
+
+/// One part of a composite transition, all of a transition's conditions have
+/// to be met within its window for it to occur
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub enum TransitionCondition {
+    /// A console line, matched the same way as an action
+    Line(TransitionAction),
+    /// A USB device with this `vid:pid` (in hex), or these udev properties,
+    /// is enumerated
+    Usb(String),
+    /// A GPIO (by its sysfs number) is at this level
+    Gpio { pin: u32, level: GpioLevel },
+}
+
+fn _default_hold_timeout() -> u32 {
+    30000
 }
 
 #[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
 pub struct TransitionTrigger {
     #[serde(skip)]
     pub to: String,
@@ -149,23 +834,282 @@ pub struct TransitionTrigger {
     #[serde(default)]
     pub sequence: Vec<TransitionTriggerSequence>,
     pub timeout: Option<u32>,
+    /// How long (in ms) held controls stay held waiting for the target state
+    #[serde(default = "_default_hold_timeout")]
+    pub hold_timeout: u32,
+    /// What has to be true for the trigger to run at all
+    #[serde(default)]
+    pub requires: TriggerRequirements,
+    /// States (or their parents) the trigger refuses to run from unless it's
+    /// forced, e.g. flashing while Linux is up
+    #[serde(default)]
+    pub interlock: Vec<String>,
 }
 
-#[derive(Debug, Display, PartialEq, Deserialize, Clone)]
+/// Preconditions of a trigger, it's refused if any of them don't hold
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct TriggerRequirements {
+    /// The device isn't reserved by someone else
+    #[serde(default)]
+    pub unreserved: bool,
+    /// The device has a power control
+    #[serde(default)]
+    pub power: bool,
+    /// Connections that have to be healthy, they need a probe
+    #[serde(default)]
+    pub healthy: Vec<String>,
+}
+
+#[derive(Debug, Display, Default, PartialEq, Deserialize, Clone)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub enum ControlAction {
+    #[default]
     #[serde(alias = "on")]
     Press,
-    #[serde(alias = "on")]
+    #[serde(alias = "off")]
     Release,
     Hold,
 }
 
 #[derive(Debug, PartialEq, Deserialize, Clone)]
 pub struct TransitionTriggerSequence {
+    /// The control to use, or "wait" or "send"
     pub control: String,
+    #[serde(default)]
     pub action: ControlAction,
     pub duration: Option<u32>,
+    /// For "send" steps, the input to send, `${name}` is replaced by variables
+    pub value: Option<String>,
+    /// For "send" steps, the connection to send to (default: the first)
+    pub connection: Option<String>,
+}
+
+// Thermal
+
+fn _default_thermal_interval() -> u32 {
+    5000
+}
+
+fn _default_hysteresis() -> f32 {
+    2.0
+}
+
+/// Temperature sampling while the device is in certain states, see
+/// [crate::thermal]
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ThermalConfig {
+    /// States (or parents of states) to sample in, e.g. those with a shell
+    pub states: Vec<String>,
+    /// An SSH connection to run the command on, otherwise it's run locally
+    pub connection: Option<String>,
+    /// Command that prints `<zone> <temperature>` lines, in degrees or
+    /// millidegrees Celsius. Reads the thermal zones from sysfs by default.
+    pub command: Option<String>,
+    /// Milliseconds between samples
+    #[serde(default = "_default_thermal_interval")]
+    pub interval: u32,
+    /// Only keep these zones, all by default
+    #[serde(default)]
+    pub zones: Vec<String>,
+    /// Degrees a zone has to cool below a threshold before it's cleared
+    #[serde(default = "_default_hysteresis")]
+    pub hysteresis: f32,
+    #[serde(default)]
+    pub thresholds: Vec<ThermalThreshold>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ThermalThreshold {
+    pub name: String,
+    /// Degrees Celsius
+    pub celsius: f32,
+    /// The zone to watch, the hottest one by default
+    pub zone: Option<String>,
+    /// Trigger to run when the threshold is exceeded, e.g. to power off
+    pub trigger: Option<String>,
+}
+
+// Crash collection
+
+fn _default_crash_commands() -> Vec<CrashCommand> {
+    [
+        ("dmesg", "dmesg"),
+        ("journal", "journalctl -b -1 --no-pager"),
+        ("last_kmsg", "cat /proc/last_kmsg"),
+    ]
+    .into_iter()
+    .map(|(name, command)| CrashCommand {
+        name: name.to_string(),
+        command: command.to_string(),
+    })
+    .collect()
+}
+
+fn _default_crash_timeout() -> u32 {
+    30000
+}
+
+/// Collecting logs once the device has a shell again after a crash, see
+/// [crate::crash]
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct CrashConfig {
+    /// States (or parents of states) that mean the device crashed
+    pub states: Vec<String>,
+    /// States (or parents of states) to collect in, those with a shell
+    pub shell: Vec<String>,
+    /// An SSH connection to run the commands on, otherwise they're run locally
+    pub connection: Option<String>,
+    /// Commands whose output is collected, by default the kernel log, the
+    /// journal of the previous boot and `/proc/last_kmsg`
+    #[serde(default = "_default_crash_commands")]
+    pub commands: Vec<CrashCommand>,
+    /// Milliseconds each command is given to finish
+    #[serde(default = "_default_crash_timeout")]
+    pub timeout: u32,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+pub struct CrashCommand {
+    /// Names the file the output is stored in
+    pub name: String,
+    pub command: String,
+}
+
+fn _default_idle_after() -> u32 {
+    600000
+}
+
+/// Quieting down the device while nobody is using it, see [crate::idle]
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct IdleConfig {
+    /// Time in ms the device has to be left alone in its resting state
+    #[serde(default = "_default_idle_after")]
+    pub after: u32,
+    /// Connections to close while idle
+    #[serde(default)]
+    pub close: Vec<String>,
+    /// Only lines matching this regex wake the device, any line does if unset
+    pub wake: Option<String>,
+}
+
+// Identifying the initial state
+
+fn _default_identify_timeout() -> u32 {
+    3000
+}
+
+/// Probing the device for its state at startup, see [crate::identify]
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct IdentifyConfig {
+    /// The line to send to make the device print something, an empty line
+    /// makes a shell or bootloader print its prompt again
+    #[serde(default)]
+    pub send: String,
+    /// The connection to send to and watch, the first one if not set
+    pub connection: Option<String>,
+    /// Time in ms to wait for output that identifies a state
+    #[serde(default = "_default_identify_timeout")]
+    pub timeout: u32,
+    /// The state the device is in if nothing is printed at all, e.g. off
+    pub silent: Option<String>,
+}
+
+// Responders
+
+fn _default_responder_limit() -> u32 {
+    3
+}
+
+fn _default_responder_period() -> u32 {
+    60000
+}
+
+/// A prompt that's answered automatically, see [crate::responder]
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ResponderConfig {
+    /// For logs, the prompt if not set
+    pub name: Option<String>,
+    /// A regex matched against console lines and incomplete lines
+    pub prompt: String,
+    /// The answer, it may use variables
+    pub send: String,
+    /// Send the answer without a line ending, e.g. for `Press any key`
+    #[serde(default)]
+    pub raw: bool,
+    /// Only answer prompts on this connection, any connection if not set. The
+    /// answer goes to the connection the prompt came from.
+    pub connection: Option<String>,
+    /// Only answer while in these states (or their children), any state if
+    /// empty
+    #[serde(default)]
+    pub states: Vec<String>,
+    /// Answer at most `limit` times in `period` ms
+    #[serde(default = "_default_responder_limit")]
+    pub limit: u32,
+    #[serde(default = "_default_responder_period")]
+    pub period: u32,
+}
+
+// LAVA
+
+/// Triggers to run for LAVA's power commands
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct LavaConfig {
+    pub power_on: Option<String>,
+    pub power_off: Option<String>,
+    pub hard_reset: Option<String>,
+}
+
+// Power
+
+fn _default_cycle_delay() -> u32 {
+    2000
+}
+
+/// The control that powers the device, for `fbug power`
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct PowerConfig {
+    pub control: String,
+    /// The state the device reaches after being powered on
+    pub on_state: Option<String>,
+    /// The state the device reaches after being powered off
+    pub off_state: Option<String>,
+    /// Milliseconds to leave the device off for when power cycling
+    #[serde(default = "_default_cycle_delay")]
+    pub cycle_delay: u32,
+}
+
+fn _default_edl_usb() -> String {
+    "05c6:9008".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct AndroidConfig {
+    /// The serial fastboot and adb know the device by, passed with `-s`
+    pub serial: Option<String>,
+    /// The USB device the board enumerates as in EDL mode, a `vid:pid` or
+    /// udev properties like the `usb` transition action takes
+    #[serde(default = "_default_edl_usb")]
+    pub edl_usb: String,
+}
+
+impl Default for AndroidConfig {
+    fn default() -> Self {
+        Self {
+            serial: None,
+            edl_usb: _default_edl_usb(),
+        }
+    }
 }
 
 fn validate_config(config: &Device) -> anyhow::Result<()> {
@@ -180,19 +1124,66 @@ fn validate_config(config: &Device) -> anyhow::Result<()> {
         return Err(anyhow!("Duplicate transition names found"));
     }
     for control in config.controls.iter() {
-        if config.connections.iter().map(|c| {
-            match c {
-                ConnectionInfo::Serial(s) => &s.label,
-                ConnectionInfo::Usb(u) => &u.label,
-                ConnectionInfo::Ssh(s) => &s.label,
-            }
-        }).find(|conn| *conn == &control.connection).is_none() {
+        if !config.connections.iter().any(|c| c.label() == control.connection) {
             return Err(anyhow!(
                 "Control {} references non-existent connection {}",
                 control.name,
                 control.connection
             ));
         }
+        if let ControlType::Sysrq(sysrq) = &control.control_type {
+            sysrq.key().map_err(|e| anyhow!("Control {}: {}", control.name, e))?;
+        }
+    }
+    for (i, info) in config.connections.iter().enumerate() {
+        if config.connections[..i].iter().any(|c| c.label() == info.label()) {
+            bail!("Duplicate connection label {}, give each connection its own", info.label());
+        }
+        if info.login().is_some_and(|l| l.username.is_none() && config.username.is_none()) {
+            bail!("Connection {} logs in but there's no username", info.label());
+        }
+    }
+    for responder in config.responders.iter() {
+        if let Some(connection) = &responder.connection {
+            if !config.connections.iter().any(|c| c.label() == connection) {
+                bail!("Responder for {:?} answers on {}, which doesn't exist", responder.prompt, connection);
+            }
+        }
+    }
+    for prop in config.states.iter().flat_map(|s| s.properties.iter()) {
+        let Some(connection) = &prop.connection else {
+            continue;
+        };
+        if !config
+            .connections
+            .iter()
+            .any(|c| matches!(c, ConnectionInfo::Serial(s) if &s.label == connection))
+        {
+            bail!("Property {} applies to {}, which isn't a serial connection", prop.name, connection);
+        }
+    }
+    for trigger in config.transitions.iter().flat_map(|t| t.triggers.iter()) {
+        for connection in trigger.requires.healthy.iter() {
+            if !config.connections.iter().any(|c| c.label() == connection && c.probe().is_some()) {
+                bail!("Trigger {} needs {} to be healthy, but it isn't a probed connection", trigger.name, connection);
+            }
+        }
+        if trigger.requires.power && config.power.is_none() {
+            bail!("Trigger {} needs power control, but there's no power config", trigger.name);
+        }
+        if let Some(state) = trigger.interlock.iter().find(|s| !config.states.iter().any(|st| &st.name == *s)) {
+            bail!("Trigger {} is interlocked in state {}, which doesn't exist", trigger.name, state);
+        }
+    }
+    if let Some(crash) = &config.crash {
+        if crash.commands.iter().any(|c| c.name.is_empty() || c.name.contains('/')) {
+            bail!("Crash command names must be non-empty and can't contain /");
+        }
+    }
+    if let Some(power) = &config.power {
+        if !config.controls.iter().any(|c| c.name == power.control) {
+            bail!("Power control {} doesn't exist", power.control);
+        }
     }
     Ok(())
 }
@@ -200,6 +1191,10 @@ fn validate_config(config: &Device) -> anyhow::Result<()> {
 pub fn load_config(path: &PathBuf) -> anyhow::Result<Device> {
     let config = std::fs::read_to_string(path)?;
     let mut device: Device = serde_yaml::from_str(&config)?;
+    device.config_hash = Sha256::digest(config.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
     device.transitions.iter_mut().for_each(|trans| {
         trans.triggers.iter_mut().for_each(|trigger| {
             if trigger.from.is_empty() {
@@ -212,3 +1207,329 @@ pub fn load_config(path: &PathBuf) -> anyhow::Result<Device> {
     log::trace!("{:#?}", device);
     Ok(device)
 }
+
+
+/// Load device configs from a list of files or directories, directories are
+/// searched (non-recursively) for yaml files.
+pub fn load_configs(paths: &[PathBuf]) -> anyhow::Result<Vec<Device>> {
+    let mut devices: Vec<Device> = vec![];
+    for path in paths {
+        let files = if path.is_dir() {
+            let mut files = std::fs::read_dir(path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            files.retain(|f| {
+                matches!(f.extension().and_then(|e| e.to_str()), Some("yaml") | Some("yml"))
+            });
+            files.sort();
+            files
+        } else {
+            vec![path.clone()]
+        };
+        for file in files {
+            let device = load_config(&file).map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+            if devices.iter().any(|d| d.codename == device.codename) {
+                bail!("{}: duplicate device codename {}", file.display(), device.codename);
+            }
+            devices.push(device);
+        }
+    }
+    Ok(devices)
+}
+
+// Host config
+
+/// Config for the host fbug is running on, shared by all devices
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct HostConfig {
+    #[serde(default)]
+    pub agent: AgentConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub labgrid: LabgridConfig,
+    #[serde(default)]
+    pub artifacts: ArtifactsConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub display: DisplayConfig,
+    #[serde(default)]
+    pub reservations: ReservationsConfig,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct DaemonConfig {
+    /// The Unix socket the daemon serves on and other commands attach to.
+    /// Defaults to $XDG_RUNTIME_DIR/fbug/daemon.sock
+    pub socket: Option<PathBuf>,
+}
+
+impl DaemonConfig {
+    pub fn socket(&self) -> PathBuf {
+        if let Some(socket) = &self.socket {
+            return socket.clone();
+        }
+        let dir = std::env::var("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| std::env::temp_dir());
+        dir.join("fbug").join("daemon.sock")
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ReservationsConfig {
+    /// Where reservations are kept, it's shared by all users of the host.
+    /// Defaults to /var/lib/fbug/reservations
+    pub dir: Option<PathBuf>,
+}
+
+/// How the log lines of several devices running in one process are told
+/// apart
+#[derive(Debug, Default, Display, PartialEq, Eq, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum LogLayout {
+    /// Lines are prefixed with the device and connection they're from
+    #[default]
+    Plain,
+    /// Like plain, with each device's prefix in a color of its own
+    Color,
+    /// The first two devices side by side in colored columns, everything
+    /// else across both
+    Split,
+}
+
+impl std::str::FromStr for LogLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "plain" => LogLayout::Plain,
+            "color" => LogLayout::Color,
+            "split" => LogLayout::Split,
+            _ => bail!("Invalid log layout {:?}, expected plain, color or split", s),
+        })
+    }
+}
+
+fn _default_column_width() -> usize {
+    80
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct DisplayConfig {
+    #[serde(default)]
+    pub layout: LogLayout,
+    /// Width of each column of the split layout, longer lines are wrapped
+    #[serde(default = "_default_column_width")]
+    pub column_width: usize,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            layout: LogLayout::default(),
+            column_width: _default_column_width(),
+        }
+    }
+}
+
+fn _default_labgrid_serial_port() -> u16 {
+    20000
+}
+
+fn _default_labgrid_http_port() -> u16 {
+    20080
+}
+
+fn _default_labgrid_listen() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct LabgridConfig {
+    /// The address labgrid clients should use to reach this host
+    pub host: Option<String>,
+    /// The address the consoles and the power endpoint listen on
+    #[serde(default = "_default_labgrid_listen")]
+    pub listen: String,
+    /// The agent token labgrid's power requests authenticate with, it's
+    /// added to the URLs in the exporter config
+    pub token: Option<String>,
+    /// Consoles are exported on consecutive ports starting from this one
+    #[serde(default = "_default_labgrid_serial_port")]
+    pub serial_base_port: u16,
+    /// Port for the REST power control endpoint
+    #[serde(default = "_default_labgrid_http_port")]
+    pub http_port: u16,
+}
+
+impl Default for LabgridConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            listen: _default_labgrid_listen(),
+            token: None,
+            serial_base_port: _default_labgrid_serial_port(),
+            http_port: _default_labgrid_http_port(),
+        }
+    }
+}
+
+fn _default_artifacts_enabled() -> bool {
+    true
+}
+
+/// Where per-run artifact directories are written
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ArtifactsConfig {
+    #[serde(default = "_default_artifacts_enabled")]
+    pub enabled: bool,
+    /// Defaults to $XDG_DATA_HOME/fbug/runs
+    pub dir: Option<PathBuf>,
+    /// Gzip the logs of runs once they've finished
+    #[serde(default = "_default_artifacts_compress")]
+    pub compress: bool,
+    /// Delete runs older than this many days
+    pub max_age_days: Option<u32>,
+    /// Delete the oldest runs of a device once its runs take up more than this
+    pub max_size_mb: Option<u64>,
+    /// Upload runs once they've finished, see [crate::upload]
+    pub upload: Option<UploadConfig>,
+}
+
+fn _default_artifacts_compress() -> bool {
+    true
+}
+
+impl Default for ArtifactsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: _default_artifacts_enabled(),
+            dir: None,
+            compress: _default_artifacts_compress(),
+            max_age_days: None,
+            max_size_mb: None,
+            upload: None,
+        }
+    }
+}
+
+impl ArtifactsConfig {
+    pub fn dir(&self) -> PathBuf {
+        if let Some(dir) = &self.dir {
+            return dir.clone();
+        }
+        let data = std::env::var("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| std::env::var("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+            .unwrap_or_else(|_| std::env::temp_dir());
+        data.join("fbug").join("runs")
+    }
+}
+
+#[derive(Debug, Display, PartialEq, Eq, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum UploadKind {
+    /// Copied with the aws cli
+    S3,
+    /// Each file is PUT, creating collections as needed
+    Webdav,
+    /// A tarball of the run is PUT
+    Http,
+}
+
+fn _default_upload_timeout() -> u32 {
+    300000
+}
+
+/// Where finished runs are uploaded to
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct UploadConfig {
+    #[serde(rename = "type")]
+    pub kind: UploadKind,
+    /// `s3://<bucket>/<prefix>` for s3, otherwise the base URL runs are
+    /// uploaded under
+    pub url: String,
+    /// The base URL runs can be fetched from, if it isn't `url` (e.g. the
+    /// website endpoint of a bucket)
+    pub public_url: Option<String>,
+    /// `<user>:<password>` for webdav and http
+    pub user: Option<String>,
+    /// Extra headers for webdav and http, like `Authorization: Bearer ...`
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// Time in ms the upload may take
+    #[serde(default = "_default_upload_timeout")]
+    pub timeout: u32,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Require clients to present a certificate signed by this CA
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Display, PartialEq, Eq, Deserialize, Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// List devices, watch state and console output
+    Read,
+    /// Send input to consoles
+    Write,
+    /// Run triggers and other controls
+    Control,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct TokenConfig {
+    /// A friendly name for logs
+    pub name: String,
+    pub token: String,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct AgentConfig {
+    pub tls: Option<TlsConfig>,
+    /// If any tokens are configured clients must authenticate with one
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct ClientConfig {
+    /// Connect to agents over TLS, verifying them against this CA
+    pub ca: Option<PathBuf>,
+    /// Client certificate and key for agents that require mTLS
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub token: Option<String>,
+    /// Only watch: agents refuse input and controls from this client
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Load the host config, it's optional so a missing file gives the defaults
+pub fn load_host_config(path: &PathBuf) -> anyhow::Result<HostConfig> {
+    match std::fs::read_to_string(path) {
+        Ok(config) => Ok(serde_yaml::from_str(&config)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HostConfig::default()),
+        Err(e) => Err(anyhow!("{}: {}", path.display(), e)),
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{config::BluetoothConfig, ConnectionEventData, Event};
+use crate::latency::{RawBytes, TimedLinesCodec};
+use anyhow::Result;
+use bluer::rfcomm::{SocketAddr, Stream};
+use bluer::Address;
+use futures::SinkExt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type Connecting = Pin<Box<dyn Future<Output = std::io::Result<Stream>> + Send>>;
+
+/// A Bluetooth serial console (RFCOMM). The DUT usually isn't reachable until
+/// it has booted so connecting is retried with a backoff, and the connection
+/// is re-established whenever it drops. Like a hotplugged serial port it's
+/// announced as down and up again when that happens.
+pub struct Bluetooth {
+    tx: UnboundedSender<Event>,
+    lines: Option<Framed<Stream, TimedLinesCodec>>,
+    info: BluetoothConfig,
+    address: Address,
+    backoff: Duration,
+    /// When to try connecting again
+    retry_at: Instant,
+    /// The attempt in progress. Reading is interrupted whenever there's
+    /// something to send, so the attempt and the backoff are kept here to
+    /// carry on where they left off.
+    connecting: Option<Connecting>,
+}
+
+impl Bluetooth {
+    fn connect(&self) -> Connecting {
+        trace!("Connecting to {} channel {}", self.address, self.info.channel);
+        Box::pin(Stream::connect(SocketAddr::new(self.address, self.info.channel)))
+    }
+
+    fn connected(&mut self, stream: Stream) {
+        info!("{} connected", self.info.label);
+        self.lines = Some(Framed::with_capacity(stream, TimedLinesCodec::new(), 1024));
+        self.backoff = MIN_BACKOFF;
+    }
+
+    /// Try to connect again once the backoff has passed
+    async fn reconnect(&mut self) {
+        if self.connecting.is_none() {
+            tokio::time::sleep_until(self.retry_at).await;
+            self.connecting = Some(self.connect());
+        }
+        let Some(connecting) = self.connecting.as_mut() else {
+            return;
+        };
+        let res = connecting.await;
+        self.connecting = None;
+        match res {
+            Ok(stream) => {
+                self.connected(stream);
+                let _ = self.tx.send(Event::ConnectionUp(self.info.label.clone()));
+            }
+            Err(e) => {
+                trace!("Failed to connect to {}: {}, retrying in {:?}", self.address, e, self.backoff);
+                self.retry_at = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        self.lines = None;
+        self.retry_at = Instant::now();
+        let _ = self.tx.send(Event::ConnectionDown(self.info.label.clone()));
+    }
+}
+
+impl Connection for Bluetooth {
+    type Info = BluetoothConfig;
+    type Action = ();
+
+    async fn new(tx: UnboundedSender<Event>, info: &BluetoothConfig) -> Result<Self, ConnectionError> {
+        let address: Address = info.address.parse().map_err(|_| {
+            error!("Invalid Bluetooth address {}", info.address);
+            ConnectionError::NoSuchDevice
+        })?;
+        let mut bt = Self {
+            tx,
+            lines: None,
+            info: info.clone(),
+            address,
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            connecting: None,
+        };
+        match bt.connect().await {
+            Ok(stream) => bt.connected(stream),
+            Err(e) => warn!("Failed to connect to {}: {}, will keep trying", address, e),
+        }
+        Ok(bt)
+    }
+
+    async fn action(&self, _action: Self::Action) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send(&mut self, buf: &str) -> Result<()> {
+        let lines = self
+            .lines
+            .as_mut()
+            .ok_or_else(|| anyhow!("{} isn't connected", self.info.label))?;
+        lines
+            .send(buf)
+            .await
+            .map_err(|e| anyhow!("Failed to write to {}: {}", self.info.label, e))
+    }
+
+    async fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        let lines = self
+            .lines
+            .as_mut()
+            .ok_or_else(|| anyhow!("{} isn't connected", self.info.label))?;
+        lines
+            .send(RawBytes(buf))
+            .await
+            .map_err(|e| anyhow!("Failed to write to {}: {}", self.info.label, e))
+    }
+
+    async fn read(&mut self) {
+        let Some(lines) = self.lines.as_mut() else {
+            self.reconnect().await;
+            return;
+        };
+        match lines.try_next().await {
+            Ok(Some((line, timing))) => {
+                if lines.codec_mut().take_overflow() {
+                    let _ = self.tx.send(Event::Overflow(self.info.label.clone()));
+                }
+                let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+                    device: self.info.label.clone(),
+                    event: ConnectionEvent::NewLine(line),
+                    timing,
+                }));
+            }
+            Ok(None) => {
+                warn!("{} disconnected", self.info.label);
+                self.disconnected();
+            }
+            Err(e) => {
+                warn!("{} disconnected: {}", self.info.label, e);
+                self.disconnected();
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
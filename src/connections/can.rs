@@ -0,0 +1,144 @@
+use crate::{config::CanConfig, ConnectionEventData, Event};
+use crate::latency::Timing;
+use anyhow::Result;
+use socketcan::tokio::CanSocket;
+use socketcan::{CanFrame, EmbeddedFrame, ExtendedId, Id, Socket, StandardId};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_stream::StreamExt;
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+/// A SocketCAN interface. Frames are turned into lines in the same
+/// `<id>#<data>` format that `cansend` and `candump -L` use so they can be
+/// matched by transition actions like any other console output.
+pub struct Can {
+    tx: UnboundedSender<Event>,
+    socket: CanSocket,
+    info: CanConfig,
+}
+
+/// Sends frames for controls from outside the poll loop, a new socket is
+/// opened for each frame
+#[derive(Clone)]
+pub struct CanControl {
+    interface: String,
+}
+
+impl CanControl {
+    pub fn send(&self, frame: &CanFrame) -> Result<()> {
+        trace!("{}: sending {}", self.interface, format_frame(frame));
+        let socket = socketcan::CanSocket::open(&self.interface)
+            .map_err(|e| anyhow!("Failed to open CAN interface {}: {}", self.interface, e))?;
+        socket
+            .write_frame(frame)
+            .map_err(|e| anyhow!("Failed to send CAN frame: {}", e))
+    }
+}
+
+/// Format a frame as `123#DEADBEEF`, remote frames are `123#R`
+pub fn format_frame(frame: &CanFrame) -> String {
+    let id = match frame.id() {
+        Id::Standard(id) => format!("{:03X}", id.as_raw()),
+        Id::Extended(id) => format!("{:08X}", id.as_raw()),
+    };
+    if frame.is_remote_frame() {
+        return format!("{}#R", id);
+    }
+    let data: String = frame.data().iter().map(|b| format!("{:02X}", b)).collect();
+    format!("{}#{}", id, data)
+}
+
+/// Parse a frame in `cansend` format, ids with more than 3 digits are extended
+pub fn parse_frame(s: &str) -> Result<CanFrame> {
+    let (id, data) = s
+        .trim()
+        .split_once('#')
+        .ok_or_else(|| anyhow!("Invalid CAN frame {}, expected <id>#<data>", s))?;
+    let raw = u32::from_str_radix(id, 16).map_err(|_| anyhow!("Invalid CAN id {}", id))?;
+    let id: Id = if id.len() <= 3 {
+        StandardId::new(raw as u16)
+            .ok_or_else(|| anyhow!("Invalid standard CAN id {:X}", raw))?
+            .into()
+    } else {
+        ExtendedId::new(raw)
+            .ok_or_else(|| anyhow!("Invalid extended CAN id {:X}", raw))?
+            .into()
+    };
+    if data.starts_with('R') {
+        let dlc = data[1..].parse().unwrap_or(0);
+        return CanFrame::new_remote(id, dlc).ok_or_else(|| anyhow!("Invalid remote frame {}", s));
+    }
+    let data = data.replace('.', "");
+    if data.len() % 2 != 0 {
+        bail!("Invalid CAN data {}", data);
+    }
+    let bytes = (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow!("Invalid CAN data {}", data))?;
+    CanFrame::new(id, &bytes).ok_or_else(|| anyhow!("CAN frame too long: {}", s))
+}
+
+impl Can {
+    pub fn ctrl(&self) -> CanControl {
+        CanControl {
+            interface: self.info.interface.clone(),
+        }
+    }
+}
+
+impl Connection for Can {
+    type Info = CanConfig;
+    type Action = CanFrame;
+
+    async fn new(tx: UnboundedSender<Event>, info: &CanConfig) -> Result<Self, ConnectionError> {
+        trace!("Opening CAN interface {}", info.interface);
+        let socket = CanSocket::open(&info.interface).map_err(|e| {
+            error!("Failed to open CAN interface {}: {}", info.interface, e);
+            ConnectionError::NoSuchDevice
+        })?;
+        Ok(Self {
+            tx,
+            socket,
+            info: info.clone(),
+        })
+    }
+
+    async fn action(&self, frame: Self::Action) -> Result<()> {
+        trace!("{}: sending {}", self.info.interface, format_frame(&frame));
+        self.socket
+            .write_frame(frame)
+            .await
+            .map_err(|e| anyhow!("Failed to send CAN frame: {}", e))
+    }
+
+    /// Data sent to a CAN connection is a frame in `cansend` format
+    async fn send(&mut self, buf: &str) -> Result<()> {
+        let frame = parse_frame(buf)?;
+        self.action(frame).await
+    }
+
+    async fn read(&mut self) {
+        match self.socket.next().await {
+            Some(Ok(frame)) => {
+                let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+                    device: self.info.label.clone(),
+                    event: ConnectionEvent::NewLine(format_frame(&frame)),
+                    timing: Timing::now(),
+                }));
+            }
+            Some(Err(e)) => {
+                // The interface probably went down, don't spin
+                warn!("{}: {}", self.info.interface, e);
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            None => tokio::time::sleep(Duration::from_millis(500)).await,
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::{CaptureConfig, ScreenConfig};
+use crate::latency::Timing;
+use crate::{ConnectionEventData, Event};
+use anyhow::Result;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+/// A greyscale video frame
+#[derive(Clone, Debug)]
+pub struct Frame {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// Parse a binary PGM (P5) with 8 bit samples
+    pub fn parse_pgm(data: &[u8]) -> Result<Self> {
+        let mut fields = vec![];
+        let mut i = 0;
+        while fields.len() < 4 {
+            // Fields are separated by whitespace and comments run to the end of the line
+            while i < data.len() && (data[i].is_ascii_whitespace() || data[i] == b'#') {
+                if data[i] == b'#' {
+                    while i < data.len() && data[i] != b'\n' {
+                        i += 1;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+            let start = i;
+            while i < data.len() && !data[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if start == i {
+                bail!("Truncated PGM header");
+            }
+            fields.push(std::str::from_utf8(&data[start..i])?);
+        }
+        // A single whitespace character separates the header from the pixels
+        i += 1;
+        if fields[0] != "P5" {
+            bail!("Not a binary PGM ({})", fields[0]);
+        }
+        let width: u32 = fields[1].parse()?;
+        let height: u32 = fields[2].parse()?;
+        if fields[3].parse::<u32>()? > 255 {
+            bail!("16 bit PGMs aren't supported");
+        }
+        let len = width as usize * height as usize;
+        let pixels = data
+            .get(i..i + len)
+            .ok_or_else(|| anyhow!("Truncated PGM, expected {}x{} pixels", width, height))?
+            .to_vec();
+        Ok(Self { width, height, pixels })
+    }
+
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let mut pgm = format!("P5\n{} {}\n255\n", self.width, self.height).into_bytes();
+        pgm.extend_from_slice(&self.pixels);
+        pgm
+    }
+
+    /// The part of the frame in `region` (x, y, width, height)
+    pub fn crop(&self, region: Option<[u32; 4]>) -> Result<Frame> {
+        let Some([x, y, width, height]) = region else {
+            return Ok(self.clone());
+        };
+        if x + width > self.width || y + height > self.height {
+            bail!("Region {:?} is outside the {}x{} frame", region.unwrap(), self.width, self.height);
+        }
+        let mut pixels = Vec::with_capacity(width as usize * height as usize);
+        for row in y..y + height {
+            let start = (row * self.width + x) as usize;
+            pixels.extend_from_slice(&self.pixels[start..start + width as usize]);
+        }
+        Ok(Frame { width, height, pixels })
+    }
+
+    /// How similar two frames of the same size are, from 0 (inverted) to 1
+    /// (identical)
+    pub fn similarity(&self, other: &Frame) -> Option<f32> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return None;
+        }
+        if self.pixels.is_empty() {
+            return Some(1.0);
+        }
+        let diff: u64 = self
+            .pixels
+            .iter()
+            .zip(other.pixels.iter())
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum();
+        Some(1.0 - diff as f32 / (self.pixels.len() as f32 * 255.0))
+    }
+}
+
+struct Screen {
+    config: ScreenConfig,
+    reference: Option<Frame>,
+    /// Whether it matched the last frame, it's only reported when it appears
+    visible: bool,
+}
+
+/// A video capture card. Frames are grabbed periodically and compared with
+/// the configured screens, each time one appears the line `screen <name>` is
+/// emitted so transitions can match it like console output.
+pub struct Capture {
+    tx: UnboundedSender<Event>,
+    info: CaptureConfig,
+    screens: Vec<Screen>,
+    interval: Interval,
+    /// Whether the last grab failed, so errors aren't repeated every frame
+    failing: bool,
+}
+
+impl Capture {
+    fn command(&self) -> Result<Command> {
+        match (&self.info.command, &self.info.device) {
+            (Some(command), _) => {
+                let (program, args) = command
+                    .split_first()
+                    .ok_or_else(|| anyhow!("{}: empty capture command", self.info.label))?;
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                Ok(cmd)
+            }
+            (None, Some(device)) => {
+                let mut cmd = Command::new("ffmpeg");
+                cmd.args(["-loglevel", "error", "-f", "v4l2", "-i"])
+                    .arg(device)
+                    .args(["-frames:v", "1", "-pix_fmt", "gray", "-f", "image2pipe", "-vcodec", "pgm", "-"]);
+                Ok(cmd)
+            }
+            (None, None) => bail!("{}: no capture device or command", self.info.label),
+        }
+    }
+
+    async fn grab(&self) -> Result<Frame> {
+        let output = self
+            .command()?
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output()
+            .await
+            .map_err(|e| anyhow!("{}: failed to run capture command: {}", self.info.label, e))?;
+        if !output.status.success() {
+            bail!(
+                "{}: capture failed ({}): {}",
+                self.info.label,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Frame::parse_pgm(&output.stdout).map_err(|e| anyhow!("{}: {}", self.info.label, e))
+    }
+
+    /// Whether tesseract finds `text` in the frame
+    async fn recognise(frame: &Frame, text: &str) -> Result<bool> {
+        let mut child = Command::new("tesseract")
+            .args(["stdin", "stdout"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to run tesseract: {}", e))?;
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(&frame.to_pgm()).await?;
+        drop(stdin);
+        let output = child.wait_with_output().await?;
+        let recognised = String::from_utf8_lossy(&output.stdout);
+        trace!("Recognised {:?}", recognised);
+        // OCR breaks lines wherever it likes
+        let normalise = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+        Ok(normalise(&recognised).contains(&normalise(text)))
+    }
+
+    async fn matches(screen: &Screen, frame: &Frame) -> Result<bool> {
+        let region = frame.crop(screen.config.region)?;
+        if let Some(reference) = &screen.reference {
+            let similarity = region.similarity(reference).ok_or_else(|| {
+                anyhow!(
+                    "Screen {}: the reference image is {}x{} but the region is {}x{}",
+                    screen.config.name,
+                    reference.width,
+                    reference.height,
+                    region.width,
+                    region.height
+                )
+            })?;
+            trace!("Screen {}: similarity {:.3}", screen.config.name, similarity);
+            if similarity < screen.config.threshold {
+                return Ok(false);
+            }
+        }
+        match &screen.config.text {
+            Some(text) => Self::recognise(&region, text).await,
+            None => Ok(true),
+        }
+    }
+}
+
+impl Connection for Capture {
+    type Info = CaptureConfig;
+    type Action = ();
+
+    async fn new(tx: UnboundedSender<Event>, info: &CaptureConfig) -> Result<Self, ConnectionError> {
+        if info.device.is_none() && info.command.is_none() {
+            error!("{} needs a device or command to capture frames with", info.label);
+            return Err(ConnectionError::NoSuchDevice);
+        }
+        let screens = info
+            .screens
+            .iter()
+            .map(|config| {
+                let reference = match &config.image {
+                    Some(path) => Some(
+                        std::fs::read(path)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|data| Frame::parse_pgm(&data))
+                            .map_err(|e| {
+                                error!("Screen {}: failed to load {}: {}", config.name, path.display(), e);
+                                ConnectionError::OpenFailed
+                            })?,
+                    ),
+                    None => None,
+                };
+                Ok(Screen {
+                    config: config.clone(),
+                    reference,
+                    visible: false,
+                })
+            })
+            .collect::<Result<Vec<_>, ConnectionError>>()?;
+        let mut interval = interval(Duration::from_millis(info.interval as u64));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            tx,
+            info: info.clone(),
+            screens,
+            interval,
+            failing: false,
+        })
+    }
+
+    async fn action(&self, _action: Self::Action) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send(&mut self, _buf: &str) -> Result<()> {
+        bail!("Can't send to {}, it's a capture card", self.info.label)
+    }
+
+    async fn read(&mut self) {
+        self.interval.tick().await;
+        let frame = match self.grab().await {
+            Ok(frame) => frame,
+            Err(e) => {
+                if !self.failing {
+                    let _ = self.tx.send(Event::Error {
+                        connection: self.info.label.clone(),
+                        message: e.to_string(),
+                    });
+                }
+                self.failing = true;
+                return;
+            }
+        };
+        self.failing = false;
+        for screen in self.screens.iter_mut() {
+            let visible = Self::matches(screen, &frame).await.unwrap_or_else(|e| {
+                warn!("{}", e);
+                false
+            });
+            if visible && !screen.visible {
+                debug!("{}: screen {} appeared", self.info.label, screen.config.name);
+                let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+                    device: self.info.label.clone(),
+                    event: ConnectionEvent::NewLine(format!("screen {}", screen.config.name)),
+                    timing: Timing::now(),
+                }));
+            }
+            screen.visible = visible;
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
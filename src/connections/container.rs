@@ -0,0 +1,196 @@
+use crate::{config::ContainerConfig, ConnectionEventData, Event};
+use crate::latency::Timing;
+use anyhow::Result;
+use std::process::Stdio;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+/// A Docker or Podman container treated as a device. Its logs are the
+/// console, data sent to it is run with `exec` and start/stop are used for
+/// power controls.
+pub struct Container {
+    tx: UnboundedSender<Event>,
+    info: ContainerConfig,
+    ctrl: ContainerControl,
+    /// The `logs --follow` process, it exits whenever the container stops
+    logs: Option<Child>,
+    /// Where to pick the logs up from when they're reattached
+    since: SystemTime,
+}
+
+#[derive(Clone, Debug)]
+pub enum ContainerAction {
+    Start,
+    Stop,
+    Restart,
+    Pause(bool),
+}
+
+/// Runs container lifecycle commands through the container runtime CLI
+#[derive(Clone)]
+pub struct ContainerControl {
+    runtime: String,
+    name: String,
+}
+
+impl ContainerControl {
+    pub fn action(&self, action: ContainerAction) -> Result<()> {
+        let command = match action {
+            ContainerAction::Start => "start",
+            ContainerAction::Stop => "stop",
+            ContainerAction::Restart => "restart",
+            ContainerAction::Pause(true) => "pause",
+            ContainerAction::Pause(false) => "unpause",
+        };
+        trace!("{} {} {}", self.runtime, command, self.name);
+        let output = std::process::Command::new(&self.runtime)
+            .arg(command)
+            .arg(&self.name)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| anyhow!("Failed to run {}: {}", self.runtime, e))?;
+        if !output.status.success() {
+            bail!(
+                "{} {} {} failed: {}",
+                self.runtime,
+                command,
+                self.name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Forward each line of output as an event until the stream ends
+fn forward_lines<R: AsyncRead + Unpin + Send + 'static>(tx: UnboundedSender<Event>, label: String, stream: R) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stream).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let _ = tx.send(Event::ConnectionEvent(ConnectionEventData {
+                device: label.clone(),
+                event: ConnectionEvent::NewLine(line),
+                timing: Timing::now(),
+            }));
+        }
+    });
+}
+
+impl Container {
+    /// Spawn a command and forward its stdout and stderr as console output
+    fn spawn_forwarded(&self, cmd: &mut Command) -> Result<Child> {
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to run {}: {}", self.info.runtime, e))?;
+        if let Some(stdout) = child.stdout.take() {
+            forward_lines(self.tx.clone(), self.info.label.clone(), stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward_lines(self.tx.clone(), self.info.label.clone(), stderr);
+        }
+        Ok(child)
+    }
+
+    fn attach_logs(&self) -> Result<Child> {
+        let since = self.since.duration_since(UNIX_EPOCH)?.as_secs_f64();
+        self.spawn_forwarded(
+            Command::new(&self.info.runtime)
+                .arg("logs")
+                .arg("--follow")
+                .arg("--since")
+                .arg(format!("{:.3}", since))
+                .arg(&self.info.name),
+        )
+    }
+
+    pub fn ctrl(&self) -> ContainerControl {
+        self.ctrl.clone()
+    }
+}
+
+impl Connection for Container {
+    type Info = ContainerConfig;
+    type Action = ContainerAction;
+
+    async fn new(tx: UnboundedSender<Event>, info: &ContainerConfig) -> Result<Self, ConnectionError> {
+        let status = Command::new(&info.runtime)
+            .args(["inspect", "--format", "{{.Id}}", &info.name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await
+            .map_err(|e| {
+                error!("Failed to run {}: {}", info.runtime, e);
+                ConnectionError::OpenFailed
+            })?;
+        if !status.success() {
+            error!("No such container {}", info.name);
+            return Err(ConnectionError::NoSuchDevice);
+        }
+        Ok(Self {
+            tx,
+            info: info.clone(),
+            ctrl: ContainerControl {
+                runtime: info.runtime.clone(),
+                name: info.name.clone(),
+            },
+            logs: None,
+            since: SystemTime::now(),
+        })
+    }
+
+    async fn action(&self, action: Self::Action) -> Result<()> {
+        self.ctrl.action(action)
+    }
+
+    async fn send(&mut self, buf: &str) -> Result<()> {
+        debug!("{} exec: {}", self.info.name, buf);
+        // Not waited on, the output turns up as console lines like a shell's would
+        let mut child = self.spawn_forwarded(
+            Command::new(&self.info.runtime)
+                .arg("exec")
+                .arg(&self.info.name)
+                .arg(&self.info.shell)
+                .arg("-c")
+                .arg(buf),
+        )?;
+        tokio::spawn(async move {
+            let _ = child.wait().await;
+        });
+        Ok(())
+    }
+
+    async fn read(&mut self) {
+        match &mut self.logs {
+            Some(child) => {
+                // Child::wait is cancel safe so this can sit in the poll loop
+                if let Ok(status) = child.wait().await {
+                    trace!("{} logs exited with {}", self.info.name, status);
+                }
+                self.logs = None;
+                self.since = SystemTime::now();
+                // Most likely the container stopped, don't hammer the runtime
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            None => match self.attach_logs() {
+                Ok(child) => self.logs = Some(child),
+                Err(e) => {
+                    warn!("{}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            },
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{config::FileConfig, ConnectionEventData, Event};
+use crate::latency::Timing;
+use anyhow::Result;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader, SeekFrom};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+/// How often to check a file for new data once we've caught up
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Follows a file (like `tail -F`) or a FIFO, each line written to it is an
+/// event. Files that get truncated or replaced (log rotation) are reopened.
+pub struct FileTail {
+    tx: UnboundedSender<Event>,
+    reader: BufReader<File>,
+    info: FileConfig,
+    /// Partial line, kept across reads in case the writer hasn't finished it
+    buf: Vec<u8>,
+    /// Bytes read from the current file, used to spot truncation
+    pos: u64,
+    inode: u64,
+    fifo: bool,
+}
+
+impl FileTail {
+    async fn open(path: &Path, from_start: bool) -> Result<(File, u64, u64, bool)> {
+        let meta = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| anyhow!("Failed to stat {:?}: {}", path, e))?;
+        let fifo = meta.file_type().is_fifo();
+        // Opening a FIFO read-only blocks until there's a writer and gives EOF
+        // every time a writer goes away, holding it open for writing avoids both
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(fifo)
+            .open(path)
+            .await
+            .map_err(|e| anyhow!("Failed to open {:?}: {}", path, e))?;
+        let pos = if fifo || from_start {
+            0
+        } else {
+            file.seek(SeekFrom::End(0)).await?
+        };
+        Ok((file, pos, meta.ino(), fifo))
+    }
+
+    /// Reopen the file from the start if it was truncated or replaced
+    async fn check_rotated(&mut self) -> Result<()> {
+        if self.fifo {
+            return Ok(());
+        }
+        let meta = match tokio::fs::metadata(&self.info.path).await {
+            Ok(meta) => meta,
+            // Probably mid-rotation, try again next time
+            Err(_) => return Ok(()),
+        };
+        if meta.ino() == self.inode && meta.len() >= self.pos {
+            return Ok(());
+        }
+        debug!("{:?} was truncated or replaced, reopening", self.info.path);
+        let (file, pos, inode, fifo) = Self::open(&self.info.path, true).await?;
+        self.reader = BufReader::new(file);
+        self.buf.clear();
+        self.pos = pos;
+        self.inode = inode;
+        self.fifo = fifo;
+        Ok(())
+    }
+}
+
+impl Connection for FileTail {
+    type Info = FileConfig;
+    type Action = ();
+
+    async fn new(tx: UnboundedSender<Event>, info: &FileConfig) -> Result<Self, ConnectionError> {
+        let (file, pos, inode, fifo) = Self::open(&info.path, info.from_start).await.map_err(|e| {
+            error!("{}", e);
+            ConnectionError::OpenFailed
+        })?;
+        Ok(Self {
+            tx,
+            reader: BufReader::new(file),
+            info: info.clone(),
+            buf: vec![],
+            pos,
+            inode,
+            fifo,
+        })
+    }
+
+    async fn action(&self, _action: Self::Action) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send(&mut self, _buf: &str) -> Result<()> {
+        bail!("{} is read only", self.info.label)
+    }
+
+    async fn read(&mut self) {
+        // read_until is cancel safe as long as the buffer is kept around
+        match self.reader.read_until(b'\n', &mut self.buf).await {
+            Ok(n) if n > 0 && self.buf.ends_with(b"\n") => {
+                self.pos += n as u64;
+                let line = String::from_utf8_lossy(&self.buf)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string();
+                self.buf.clear();
+                let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+                    device: self.info.label.clone(),
+                    event: ConnectionEvent::NewLine(line),
+                    timing: Timing::now(),
+                }));
+            }
+            // Caught up, possibly with half a line
+            Ok(n) => {
+                self.pos += n as u64;
+                if let Err(e) = self.check_rotated().await {
+                    warn!("{}", e);
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Err(e) => {
+                warn!("Failed to read {:?}: {}", self.info.path, e);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
@@ -1,17 +1,85 @@
-use crate::config::{ConnectionInfo, GlobalProperties, Property};
+use crate::config::{ConnectionInfo, GlobalProperties, Property, PropertyFailure, SendConfig, SshConnection};
 use crate::Event;
 use anyhow::Result;
+#[cfg(target_os = "linux")]
+use bluetooth::Bluetooth;
+#[cfg(target_os = "linux")]
+use can::Can;
+use capture::Capture;
+use container::Container;
+#[cfg(unix)]
+use file::FileTail;
+#[cfg(unix)]
+use process::Process;
+#[cfg(unix)]
+use qemu::Qemu;
+use psu::Psu;
 use serial::Serial;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::io::ErrorKind;
+use std::time::{Duration, Instant};
 use std::vec;
 use thiserror::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::broadcast::Receiver;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
 
+#[cfg(target_os = "linux")]
+mod bluetooth;
+#[cfg(target_os = "linux")]
+mod can;
+mod capture;
+mod container;
+#[cfg(unix)]
+mod file;
+#[cfg(unix)]
+mod process;
+#[cfg(unix)]
+mod qemu;
+mod psu;
 mod serial;
+mod ssh;
 
-pub use serial::SerialAction;
+#[cfg(target_os = "linux")]
+pub use can::{parse_frame, CanControl};
+pub use container::{ContainerAction, ContainerControl};
+#[cfg(unix)]
+pub use qemu::{QemuAction, QmpControl};
+pub use psu::ScpiControl;
+pub use serial::{SerialAction, SerialControl};
+pub use ssh::{ssh_command, SshControl};
+
+/// How long to wait before trying to apply a property again
+const PROPERTY_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// How often to try opening hotplugged connections that haven't appeared yet
+const HOTPLUG_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the echo of a sent line is waited for
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long an incomplete line sits in the buffer before it's reported as a
+/// [ConnectionEvent::Prompt]
+pub const PROMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// What woke the poll loop up
+enum Woke {
+    /// Input to send, and where to report whether it was sent
+    Input(ConnectionInput, Option<oneshot::Sender<Result<()>>>),
+    Change(ConnectionChange),
+    Rescan,
+}
+
+/// The open serial connections by label, properties like the baud rate apply
+/// to the one they name or else the first one
+fn serials(connections: &[Connectable]) -> Vec<(String, SerialControl)> {
+    connections
+        .iter()
+        .filter_map(|c| match c {
+            Connectable::Serial(s) => Some((s.name().to_string(), s.ctrl())),
+            _ => None,
+        })
+        .collect()
+}
 
 #[derive(Error, Debug)]
 pub enum ConnectionError {
@@ -27,6 +95,114 @@ pub enum ConnectionError {
 pub enum ConnectionEvent {
     NewLine(String),
     Bytes(Vec<u8>),
+    /// An incomplete line that has been waiting for its line ending for
+    /// [PROMPT_DELAY], like `Press any key to continue`. It's reported again
+    /// as a [ConnectionEvent::NewLine] if it's ever completed.
+    Prompt(String),
+}
+
+/// Data to send to a connection, if no connection is named the preferred one
+/// (see [ConnectionChange::Prefer]) or else the first is used.
+#[derive(Clone, Debug)]
+pub struct ConnectionInput {
+    pub connection: Option<String>,
+    pub data: InputData,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputData {
+    /// Sent followed by a line ending
+    Line(String),
+    /// Sent as is, e.g. a control character
+    Raw(Vec<u8>),
+}
+
+impl From<String> for InputData {
+    fn from(line: String) -> Self {
+        InputData::Line(line)
+    }
+}
+
+/// Parse a control character like `c`, `^C` or `[` (escape) for `fbug send`
+pub fn parse_ctrl(s: &str) -> Result<u8> {
+    let c = s.strip_prefix('^').unwrap_or(s);
+    match c.as_bytes() {
+        [b'?'] => Ok(0x7f),
+        [c] if c.to_ascii_uppercase() >= b'@' && c.to_ascii_uppercase() <= b'_' => Ok(c.to_ascii_uppercase() ^ 0x40),
+        _ => bail!("Invalid control character {:?}, expected e.g. c or ^C", s),
+    }
+}
+
+/// Parse hex bytes like `1b5b41` or `1b 5b 41`
+pub fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.len() % 2 != 0 {
+        bail!("Odd number of hex digits in {:?}", s);
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            // from_str_radix would take a sign too
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex byte {:?} in {:?}", String::from_utf8_lossy(pair), s))
+        })
+        .collect()
+}
+
+/// Write input as the connection's [SendConfig] says, `chunk_size` bytes (or
+/// one character if only `char_delay` is set) at a time with the delay
+/// between them
+async fn write_paced<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8], config: &SendConfig) -> std::io::Result<()> {
+    let size = match (config.chunk_size, config.char_delay) {
+        (0, 0) => data.len().max(1),
+        (0, _) => 1,
+        (size, _) => size,
+    };
+    let delay = Duration::from_millis(config.char_delay as u64);
+    for (i, chunk) in data.chunks(size).enumerate() {
+        if i > 0 && !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        writer.write_all(chunk).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Lines sent to a console that echoes its input, so the echoes can be
+/// dropped, see [SendConfig::suppress_echo]
+#[derive(Default)]
+struct Echoes {
+    sent: VecDeque<(String, Instant)>,
+}
+
+impl Echoes {
+    fn sent(&mut self, line: &str) {
+        // An empty line's echo can't be told apart from a prompt
+        let line = line.trim();
+        if !line.is_empty() {
+            self.sent.push_back((line.to_string(), Instant::now()));
+        }
+    }
+
+    /// Whether a received line is the echo of one that was sent. The echo
+    /// usually follows a prompt that didn't end the line, like `# uname -a`.
+    fn is_echo(&mut self, line: &str) -> bool {
+        let now = Instant::now();
+        self.sent.retain(|(_, at)| now.duration_since(*at) < ECHO_TIMEOUT);
+        let line = line.trim_end();
+        match self.sent.iter().position(|(sent, _)| line.ends_with(sent.as_str())) {
+            Some(i) => {
+                self.sent.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 pub trait Connection: Sized {
@@ -36,6 +212,10 @@ pub trait Connection: Sized {
     async fn new(tx: UnboundedSender<Event>, info: &Self::Info) -> Result<Self, ConnectionError>;
     async fn action(&self, action: Self::Action) -> Result<()>;
     async fn send(&mut self, buf: &str) -> Result<()>;
+    /// Send bytes without a line ending, for connections that are a byte stream
+    async fn send_raw(&mut self, _buf: &[u8]) -> Result<()> {
+        bail!("{} doesn't accept raw input", self.name())
+    }
     async fn read(&mut self);
 
     fn name(&self) -> &str;
@@ -46,19 +226,221 @@ pub enum ConnectionType {
     Serial,
     Ssh,
     Usb,
+    Qemu,
+    Process,
+    File,
+    Container,
+    Can,
+    Bluetooth,
+    Capture,
+    Psu,
 }
 
 pub enum Connectable {
     Serial(Serial),
     Ssh,
     Usb,
+    #[cfg(unix)]
+    Qemu(Qemu),
+    #[cfg(unix)]
+    Process(Process),
+    #[cfg(unix)]
+    File(FileTail),
+    Container(Container),
+    #[cfg(target_os = "linux")]
+    Can(Can),
+    #[cfg(target_os = "linux")]
+    Bluetooth(Bluetooth),
+    Capture(Capture),
+    Psu(Psu),
+}
+
+impl Connectable {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Connectable::Serial(s) => Some(s.name()),
+            #[cfg(unix)]
+            Connectable::Qemu(q) => Some(q.name()),
+            #[cfg(unix)]
+            Connectable::Process(p) => Some(p.name()),
+            #[cfg(unix)]
+            Connectable::File(f) => Some(f.name()),
+            Connectable::Container(c) => Some(c.name()),
+            #[cfg(target_os = "linux")]
+            Connectable::Can(c) => Some(c.name()),
+            #[cfg(target_os = "linux")]
+            Connectable::Bluetooth(b) => Some(b.name()),
+            Connectable::Capture(c) => Some(c.name()),
+            Connectable::Psu(p) => Some(p.name()),
+            Connectable::Ssh | Connectable::Usb => None,
+        }
+    }
+
+    async fn read(&mut self) {
+        match self {
+            Connectable::Serial(s) => s.read().await,
+            #[cfg(unix)]
+            Connectable::Qemu(q) => q.read().await,
+            #[cfg(unix)]
+            Connectable::Process(p) => p.read().await,
+            #[cfg(unix)]
+            Connectable::File(f) => f.read().await,
+            Connectable::Container(c) => c.read().await,
+            #[cfg(target_os = "linux")]
+            Connectable::Can(c) => c.read().await,
+            #[cfg(target_os = "linux")]
+            Connectable::Bluetooth(b) => b.read().await,
+            Connectable::Capture(c) => c.read().await,
+            Connectable::Psu(p) => p.read().await,
+            Connectable::Ssh => unimplemented!(),
+            Connectable::Usb => unimplemented!(),
+        }
+    }
+
+    async fn send(&mut self, data: &str) -> Result<()> {
+        match self {
+            Connectable::Serial(s) => s.send(data).await,
+            #[cfg(unix)]
+            Connectable::Qemu(q) => q.send(data).await,
+            #[cfg(unix)]
+            Connectable::Process(p) => p.send(data).await,
+            #[cfg(unix)]
+            Connectable::File(f) => f.send(data).await,
+            Connectable::Container(c) => c.send(data).await,
+            #[cfg(target_os = "linux")]
+            Connectable::Can(c) => c.send(data).await,
+            #[cfg(target_os = "linux")]
+            Connectable::Bluetooth(b) => b.send(data).await,
+            Connectable::Capture(c) => c.send(data).await,
+            Connectable::Psu(p) => p.send(data).await,
+            Connectable::Ssh | Connectable::Usb => bail!("Connection doesn't support sending"),
+        }
+    }
+
+    async fn send_raw(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            Connectable::Serial(s) => s.send_raw(data).await,
+            #[cfg(unix)]
+            Connectable::Qemu(q) => q.send_raw(data).await,
+            #[cfg(unix)]
+            Connectable::Process(p) => p.send_raw(data).await,
+            #[cfg(unix)]
+            Connectable::File(f) => f.send_raw(data).await,
+            Connectable::Container(c) => c.send_raw(data).await,
+            #[cfg(target_os = "linux")]
+            Connectable::Can(c) => c.send_raw(data).await,
+            #[cfg(target_os = "linux")]
+            Connectable::Bluetooth(b) => b.send_raw(data).await,
+            Connectable::Capture(c) => c.send_raw(data).await,
+            Connectable::Psu(p) => p.send_raw(data).await,
+            Connectable::Ssh | Connectable::Usb => bail!("Connection doesn't support sending"),
+        }
+    }
+}
+
+/// A handle for performing control actions on a connection from outside the
+/// poll loop
+#[derive(Clone)]
+pub enum ControlHandle {
+    Serial(SerialControl),
+    #[cfg(unix)]
+    Qmp(QmpControl),
+    Container(ContainerControl),
+    #[cfg(target_os = "linux")]
+    Can(CanControl),
+    /// Not a console, but commands can be run over it
+    Ssh(SshControl),
+    Scpi(ScpiControl),
 }
 
 pub struct Connections {
     connections: Vec<Connectable>,
     c_info: Vec<ConnectionInfo>,
+    /// Hotplugged connections that haven't appeared yet
+    pending: Vec<ConnectionInfo>,
+    ssh: Vec<SshControl>,
+    /// Where to publish the hosts of bootstrapped SSH connections once
+    /// they're up, see [crate::bootstrap]
+    bootstraps: Vec<(SshConnection, watch::Sender<Option<String>>)>,
     tx: UnboundedSender<Event>,
     prx: Receiver<Vec<Property>>,
+    input_tx: UnboundedSender<ConnectionInput>,
+    input_rx: UnboundedReceiver<ConnectionInput>,
+    sent_tx: UnboundedSender<(ConnectionInput, oneshot::Sender<Result<()>>)>,
+    sent_rx: UnboundedReceiver<(ConnectionInput, oneshot::Sender<Result<()>>)>,
+    changes_tx: UnboundedSender<ConnectionChange>,
+    changes_rx: UnboundedReceiver<ConnectionChange>,
+}
+
+/// A change to the set of connections while they're being polled
+#[derive(Debug)]
+pub enum ConnectionChange {
+    /// Open a connection, hotplugged ones are waited for if they aren't there
+    Add(ConnectionInfo),
+    /// Close a connection
+    Remove(String),
+    /// A connection went away, hotplugged ones are waited for again
+    Lost(String),
+    /// Send input that doesn't name a connection to this one rather than the
+    /// first, e.g. a faster console that has been logged in to
+    Prefer(Option<String>),
+}
+
+/// Open a connection, the types that aren't polled have nothing to open
+async fn open(tx: &UnboundedSender<Event>, info: &ConnectionInfo) -> Result<Option<Connectable>> {
+    log::trace!("Connecting to {:?}", info);
+    match info {
+        ConnectionInfo::Serial(info) => match Serial::new(tx.clone(), info).await {
+            Ok(serial) => Ok(Some(Connectable::Serial(serial))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        #[cfg(unix)]
+        ConnectionInfo::Qemu(info) => match Qemu::new(tx.clone(), info).await {
+            Ok(qemu) => Ok(Some(Connectable::Qemu(qemu))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        #[cfg(unix)]
+        ConnectionInfo::Process(info) => match Process::new(tx.clone(), info).await {
+            Ok(process) => Ok(Some(Connectable::Process(process))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        #[cfg(unix)]
+        ConnectionInfo::File(info) => match FileTail::new(tx.clone(), info).await {
+            Ok(file) => Ok(Some(Connectable::File(file))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        ConnectionInfo::Container(info) => match Container::new(tx.clone(), info).await {
+            Ok(container) => Ok(Some(Connectable::Container(container))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        #[cfg(target_os = "linux")]
+        ConnectionInfo::Can(info) => match Can::new(tx.clone(), info).await {
+            Ok(can) => Ok(Some(Connectable::Can(can))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        #[cfg(target_os = "linux")]
+        ConnectionInfo::Bluetooth(info) => match Bluetooth::new(tx.clone(), info).await {
+            Ok(bt) => Ok(Some(Connectable::Bluetooth(bt))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        ConnectionInfo::Capture(info) => match Capture::new(tx.clone(), info).await {
+            Ok(capture) => Ok(Some(Connectable::Capture(capture))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        ConnectionInfo::Psu(info) => match Psu::new(tx.clone(), info).await {
+            Ok(psu) => Ok(Some(Connectable::Psu(psu))),
+            Err(e) => Err(anyhow!("{}: {}", info.label, e)),
+        },
+        #[cfg(not(unix))]
+        ConnectionInfo::Qemu(_) | ConnectionInfo::Process(_) | ConnectionInfo::File(_) => {
+            bail!("{} connections aren't supported on this platform", info)
+        }
+        #[cfg(not(target_os = "linux"))]
+        ConnectionInfo::Can(_) | ConnectionInfo::Bluetooth(_) => {
+            bail!("{} connections aren't supported on this platform", info)
+        }
+        ConnectionInfo::Ssh(_) | ConnectionInfo::Usb(_) => Ok(None),
+    }
 }
 
 impl Connections {
@@ -68,27 +450,46 @@ impl Connections {
         c_info: &Vec<ConnectionInfo>,
     ) -> Result<Self> {
         let mut connections: Vec<Connectable> = vec![];
-        let mut c_info = c_info.clone();
-
-        for info in c_info.iter_mut() {
-            log::trace!("Connecting to {:?}", info);
-            match info {
-                ConnectionInfo::Serial(info) => match Serial::new(tx.clone(), info).await {
-                    Ok(serial) => connections.push(Connectable::Serial(serial)),
-                    Err(e) => {
-                        bail!(e);
-                    }
-                },
-                ConnectionInfo::Ssh(_) => {}
-                ConnectionInfo::Usb(_) => {}
+        let mut pending = vec![];
+        let mut ssh = vec![];
+        let mut bootstraps = vec![];
+
+        for info in c_info.iter() {
+            if let ConnectionInfo::Ssh(info) = info {
+                let (host_tx, host) = watch::channel((!info.bootstrapped()).then(|| info.host.clone()));
+                ssh.push(SshControl::new(info, host));
+                if info.bootstrapped() {
+                    bootstraps.push((info.clone(), host_tx));
+                }
+            }
+            match open(&tx, info).await {
+                Ok(Some(c)) => connections.push(c),
+                Ok(None) => {}
+                Err(e) if info.hotplug() => {
+                    log::debug!("Waiting for {} to appear: {}", info.label(), e);
+                    pending.push(info.clone());
+                }
+                Err(e) => bail!(e),
             }
         }
 
+        let (input_tx, input_rx) = unbounded_channel();
+        let (sent_tx, sent_rx) = unbounded_channel();
+        let (changes_tx, changes_rx) = unbounded_channel();
         let c = Self {
             connections,
-            c_info,
+            c_info: c_info.clone(),
+            pending,
+            ssh,
+            bootstraps,
             tx,
             prx,
+            input_tx,
+            input_rx,
+            sent_tx,
+            sent_rx,
+            changes_tx,
+            changes_rx,
         };
 
         Ok(c)
@@ -99,55 +500,286 @@ impl Connections {
             Connectable::Serial(_) => c_type == ConnectionType::Serial,
             Connectable::Ssh => c_type == ConnectionType::Ssh,
             Connectable::Usb => c_type == ConnectionType::Usb,
+            #[cfg(unix)]
+            Connectable::Qemu(_) => c_type == ConnectionType::Qemu,
+            #[cfg(unix)]
+            Connectable::Process(_) => c_type == ConnectionType::Process,
+            #[cfg(unix)]
+            Connectable::File(_) => c_type == ConnectionType::File,
+            Connectable::Container(_) => c_type == ConnectionType::Container,
+            #[cfg(target_os = "linux")]
+            Connectable::Can(_) => c_type == ConnectionType::Can,
+            #[cfg(target_os = "linux")]
+            Connectable::Bluetooth(_) => c_type == ConnectionType::Bluetooth,
+            Connectable::Capture(_) => c_type == ConnectionType::Capture,
+            Connectable::Psu(_) => c_type == ConnectionType::Psu,
         })
     }
 
+    /// A channel for sending data to the connections while they're being polled
+    pub fn input(&self) -> UnboundedSender<ConnectionInput> {
+        self.input_tx.clone()
+    }
+
+    /// Like [Connections::input], but whether the data could be sent is
+    /// reported back, e.g. to fail a command that sends to a connection that
+    /// doesn't exist
+    pub fn sender(&self) -> UnboundedSender<(ConnectionInput, oneshot::Sender<Result<()>>)> {
+        self.sent_tx.clone()
+    }
+
+    /// A channel for adding and removing connections while they're being polled
+    pub fn changes(&self) -> UnboundedSender<ConnectionChange> {
+        self.changes_tx.clone()
+    }
+
+    /// The SSH connections, for running commands over
+    pub fn ssh(&self) -> &[SshControl] {
+        &self.ssh
+    }
+
+    /// The bootstrapped SSH connections, along with where to publish their
+    /// hosts once they're up. They can only be taken once.
+    pub fn take_bootstraps(&mut self) -> Vec<(SshConnection, watch::Sender<Option<String>>)> {
+        std::mem::take(&mut self.bootstraps)
+    }
+
+    /// Control handles for all connections that have them, along with their labels
+    pub fn control_handles(&self) -> Vec<(String, ControlHandle)> {
+        let ssh = self
+            .ssh
+            .iter()
+            .map(|ssh| (ssh.name().to_string(), ControlHandle::Ssh(ssh.clone())));
+        self.connections
+            .iter()
+            .filter_map(|c| match c {
+                Connectable::Serial(s) => Some((s.name().to_string(), ControlHandle::Serial(s.ctrl()))),
+                #[cfg(unix)]
+                Connectable::Qemu(q) => Some((q.name().to_string(), ControlHandle::Qmp(q.ctrl()))),
+                Connectable::Container(c) => {
+                    Some((c.name().to_string(), ControlHandle::Container(c.ctrl())))
+                }
+                #[cfg(target_os = "linux")]
+                Connectable::Can(c) => Some((c.name().to_string(), ControlHandle::Can(c.ctrl()))),
+                Connectable::Psu(p) => Some((p.name().to_string(), ControlHandle::Scpi(p.ctrl()))),
+                _ => None,
+            })
+            .chain(ssh)
+            .collect()
+    }
+
+    /// The serial connections that are open, by label
+    pub fn serials(&self) -> Vec<(String, SerialControl)> {
+        serials(&self.connections)
+    }
+
     pub fn find(&mut self, name: &str) -> Option<&mut Connectable> {
-        self.connections.iter_mut().find(|c| match c {
-            Connectable::Serial(s) => s.name() == name,
-            Connectable::Ssh => false,
-            Connectable::Usb => false,
-        })
+        self.connections.iter_mut().find(|c| c.name() == Some(name))
     }
 
-    pub async fn poll(mut self) -> Result<()> {
-        let ctrl = if let Connectable::Serial(s) = self.get(ConnectionType::Serial).unwrap() {
-            s.ctrl()
-        } else {
-            bail!("No serial connection found");
-        };
-        let  read_thread = tokio::spawn(async move {
+    pub async fn poll(self) -> Result<()> {
+        let (serials_tx, serials_rx) = watch::channel(serials(&self.connections));
+        let mut input_rx = self.input_rx;
+        let mut sent_rx = self.sent_rx;
+        let mut changes_rx = self.changes_rx;
+        let mut connections = self.connections;
+        let mut pending = self.pending;
+        let c_info = self.c_info;
+        let events = self.tx.clone();
+        // Not spawned, so that the connections are closed when this is dropped
+        let read_thread = async move {
+            let mut rescan = tokio::time::interval(HOTPLUG_INTERVAL);
+            let mut preferred: Option<String> = None;
             loop {
-                for c in self.connections.iter_mut() {
-                    match c {
-                        Connectable::Serial(s) => s.read().await,
-                        Connectable::Ssh => unimplemented!(),
-                        Connectable::Usb => unimplemented!(),
-                    }
+                let woke = tokio::select! {
+                    input = input_rx.recv() => input.map(|input| Woke::Input(input, None)),
+                    sent = sent_rx.recv() => sent.map(|(input, reply)| Woke::Input(input, Some(reply))),
+                    change = changes_rx.recv() => change.map(Woke::Change),
+                    _ = rescan.tick(), if !pending.is_empty() => Some(Woke::Rescan),
+                    _ = async {
+                        if connections.is_empty() {
+                            std::future::pending::<()>().await;
+                        }
+                        // Each connection frames its own lines, a quiet one
+                        // mustn't hold up the others
+                        futures::future::select_all(connections.iter_mut().map(|c| Box::pin(c.read()))).await;
+                    } => None,
                 };
+                match woke {
+                    Some(Woke::Input(input, reply)) => {
+                        let target = input.connection.as_deref().or_else(|| {
+                            preferred
+                                .as_deref()
+                                .filter(|p| connections.iter().any(|c| c.name() == Some(*p)))
+                        });
+                        let conn = connections.iter_mut().find(|c| match (c.name(), target) {
+                            (Some(name), Some(target)) => name == target,
+                            (Some(_), None) => true,
+                            (None, _) => false,
+                        });
+                        let res = match (conn, &input.connection) {
+                            (Some(c), _) => match &input.data {
+                                InputData::Line(line) => c.send(line).await,
+                                InputData::Raw(bytes) => c.send_raw(bytes).await,
+                            },
+                            (None, Some(label)) => Err(anyhow!("No connection {} to send to", label)),
+                            (None, None) => Err(anyhow!("No connection to send to")),
+                        };
+                        match (reply, res) {
+                            (Some(reply), res) => {
+                                let _ = reply.send(res);
+                            }
+                            (None, Err(e)) => log::error!("{}", e),
+                            (None, Ok(())) => {}
+                        }
+                    }
+                    Some(Woke::Change(ConnectionChange::Add(info))) => {
+                        let label = info.label().to_string();
+                        let exists = connections.iter().any(|c| c.name() == Some(label.as_str()))
+                            || pending.iter().any(|p| p.label() == label);
+                        if exists {
+                            log::error!("There's already a connection {}", label);
+                            continue;
+                        }
+                        match open(&events, &info).await {
+                            Ok(Some(c)) => {
+                                connections.push(c);
+                                let _ = events.send(Event::ConnectionUp(label));
+                            }
+                            Ok(None) => {}
+                            Err(e) if info.hotplug() => {
+                                log::debug!("Waiting for {} to appear: {}", label, e);
+                                pending.push(info);
+                            }
+                            Err(e) => {
+                                let _ = events.send(Event::Error {
+                                    connection: label,
+                                    message: e.to_string(),
+                                });
+                            }
+                        }
+                    }
+                    Some(Woke::Change(ConnectionChange::Remove(label))) => {
+                        pending.retain(|p| p.label() != label);
+                        if let Some(i) = connections.iter().position(|c| c.name() == Some(label.as_str())) {
+                            connections.remove(i);
+                            let _ = events.send(Event::ConnectionDown(label));
+                        }
+                    }
+                    Some(Woke::Change(ConnectionChange::Lost(label))) => {
+                        let Some(i) = connections.iter().position(|c| c.name() == Some(label.as_str())) else {
+                            continue;
+                        };
+                        connections.remove(i);
+                        if let Some(info) = c_info.iter().find(|c| c.label() == label && c.hotplug()) {
+                            pending.push(info.clone());
+                        }
+                        let _ = events.send(Event::ConnectionDown(label));
+                    }
+                    Some(Woke::Change(ConnectionChange::Prefer(label))) => preferred = label,
+                    Some(Woke::Rescan) => {
+                        let mut waiting = vec![];
+                        for info in std::mem::take(&mut pending) {
+                            match open(&events, &info).await {
+                                Ok(Some(c)) => {
+                                    connections.push(c);
+                                    let _ = events.send(Event::ConnectionUp(info.label().to_string()));
+                                }
+                                Ok(None) => {}
+                                Err(_) => waiting.push(info),
+                            }
+                        }
+                        pending = waiting;
+                    }
+                    None => continue,
+                }
+                serials_tx.send_replace(serials(&connections));
             }
-        });
+        };
 
-        let action_thread = tokio::spawn(async move {
+        let mut prx = self.prx;
+        let tx = self.tx;
+        let apply = move |prop: &Property| {
+            let serials = serials_rx.borrow().clone();
+            let serial = match &prop.connection {
+                Some(label) => serials.iter().find(|(l, _)| l == label),
+                None => serials.first(),
+            };
+            let Some((label, ctrl)) = serial else {
+                match &prop.connection {
+                    Some(label) => bail!("Serial connection {} isn't open", label),
+                    None => bail!("No serial connection to set baud rate on"),
+                }
+            };
+            match prop.name {
+                GlobalProperties::Baud(x) => ctrl
+                    .action(SerialAction::Baud(x))
+                    .map_err(|e| anyhow!("Failed to set baud rate of {}: {}", label, e)),
+            }
+        };
+        let action_thread = async move {
             loop {
-                if let Ok(props) = self.prx.recv().await {
-                    for prop in props {
-                        match prop.name {
-                            GlobalProperties::Baud(x) => {
-                                let _ = ctrl.action(SerialAction::Baud(x)).map_err(|e| {
-                                    log::error!("Failed to set baud rate: {}", e);
-                                });
-                            }
+                let props = match prx.recv().await {
+                    Ok(props) => props,
+                    Err(RecvError::Lagged(n)) => {
+                        log::warn!("Missed {} property updates", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                for prop in props {
+                    let mut res = apply(&prop);
+                    if prop.on_failure == PropertyFailure::Retry {
+                        for attempt in 1..=prop.retries {
+                            let Err(e) = &res else {
+                                break;
+                            };
+                            log::warn!("{}, retrying ({}/{})", e, attempt, prop.retries);
+                            tokio::time::sleep(PROPERTY_RETRY_DELAY).await;
+                            res = apply(&prop);
                         }
                     }
+                    // Tell the device loop so it can act on failures
+                    let _ = tx.send(Event::Property {
+                        property: prop,
+                        result: res.map_err(|e| e.to_string()),
+                    });
                 }
             }
-        });
+        };
 
-        let (r1, r2) = tokio::join!(read_thread, action_thread);
-        r1?;
-        r2?;
+        tokio::join!(read_thread, action_thread);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ctrl() {
+        assert_eq!(parse_ctrl("c").unwrap(), 0x03);
+        assert_eq!(parse_ctrl("^C").unwrap(), 0x03);
+        assert_eq!(parse_ctrl("^c").unwrap(), 0x03);
+        assert_eq!(parse_ctrl("[").unwrap(), 0x1b);
+        assert_eq!(parse_ctrl("@").unwrap(), 0x00);
+        assert_eq!(parse_ctrl("?").unwrap(), 0x7f);
+        assert!(parse_ctrl("").is_err());
+        assert!(parse_ctrl("^").is_err());
+        assert!(parse_ctrl("cc").is_err());
+        assert!(parse_ctrl("1").is_err());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex("1b5b41").unwrap(), [0x1b, 0x5b, 0x41]);
+        assert_eq!(parse_hex("1b 5b 41").unwrap(), [0x1b, 0x5b, 0x41]);
+        assert_eq!(parse_hex("FF").unwrap(), [0xff]);
+        assert!(parse_hex("").unwrap().is_empty());
+        assert!(parse_hex("1b5").is_err());
+        assert!(parse_hex("zz").is_err());
+        assert!(parse_hex("+1").is_err());
+    }
+}
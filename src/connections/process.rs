@@ -0,0 +1,142 @@
+use crate::{config::ProcessConfig, ConnectionEventData, Event};
+use crate::latency::TimedLinesCodec;
+use anyhow::Result;
+use nix::pty::openpty;
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use std::fs::File as StdFile;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+
+use super::{write_paced, Connection, ConnectionError, ConnectionEvent, Echoes};
+
+/// An arbitrary command running on a PTY, its output is the console and
+/// `send()` writes to its input.
+pub struct Process {
+    tx: UnboundedSender<Event>,
+    lines: FramedRead<File, TimedLinesCodec>,
+    // A separate handle for writing so writes don't wait on a pending read
+    input: File,
+    info: ProcessConfig,
+    child: Child,
+    exited: bool,
+    echoes: Echoes,
+}
+
+impl Process {
+    fn spawn(info: &ProcessConfig) -> Result<(File, File, Child)> {
+        let (program, args) = info
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty command for {}", info.label))?;
+        let pty = openpty(None, None)?;
+        // Safety: openpty just gave us these fds and nothing else owns them
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(pty.master), OwnedFd::from_raw_fd(pty.slave)) };
+
+        // Don't echo our input back at us
+        let mut termios = tcgetattr(slave.as_raw_fd())?;
+        termios.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(slave.as_raw_fd(), SetArg::TCSANOW, &termios)?;
+
+        let mut cmd = Command::new(program);
+        cmd.args(args)
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .kill_on_drop(true);
+        if let Some(cwd) = &info.cwd {
+            cmd.current_dir(cwd);
+        }
+        // Give the process its own session so it behaves like it's on a terminal
+        unsafe {
+            cmd.pre_exec(|| {
+                nix::unistd::setsid()
+                    .map(|_| ())
+                    .map_err(|e| std::io::Error::from_raw_os_error(e as i32))
+            });
+        }
+        debug!("Spawning {} {:?}", program, args);
+        let child = cmd
+            .spawn()
+            .map_err(|e| anyhow!("Failed to spawn {}: {}", program, e))?;
+        let input = File::from_std(StdFile::from(master.try_clone()?));
+        Ok((File::from_std(StdFile::from(master)), input, child))
+    }
+}
+
+impl Connection for Process {
+    type Info = ProcessConfig;
+    type Action = ();
+
+    async fn new(tx: UnboundedSender<Event>, info: &ProcessConfig) -> Result<Self, ConnectionError> {
+        let (master, input, child) = Self::spawn(info).map_err(|e| {
+            error!("{}", e);
+            ConnectionError::OpenFailed
+        })?;
+        Ok(Self {
+            tx,
+            lines: FramedRead::with_capacity(master, TimedLinesCodec::new(), 1024),
+            input,
+            info: info.clone(),
+            child,
+            exited: false,
+            echoes: Echoes::default(),
+        })
+    }
+
+    async fn action(&self, _action: Self::Action) -> Result<()> {
+        Ok(())
+    }
+
+    async fn send(&mut self, buf: &str) -> Result<()> {
+        if self.info.send.suppress_echo {
+            self.echoes.sent(buf);
+        }
+        let mut data = buf.as_bytes().to_vec();
+        data.extend_from_slice(self.info.send.line_ending.as_bytes());
+        self.send_raw(&data).await
+    }
+
+    async fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        write_paced(&mut self.input, buf, &self.info.send)
+            .await
+            .map_err(|e| anyhow!("Failed to write to {}: {}", self.info.label, e))
+    }
+
+    async fn read(&mut self) {
+        match self.lines.try_next().await {
+            Ok(Some((line, _))) if self.info.send.suppress_echo && self.echoes.is_echo(&line) => {
+                trace!("{}: dropping echo {:?}", self.info.label, line);
+            }
+            Ok(Some((line, timing))) => {
+                if self.lines.decoder_mut().take_overflow() {
+                    let _ = self.tx.send(Event::Overflow(self.info.label.clone()));
+                }
+                let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+                    device: self.info.label.clone(),
+                    event: ConnectionEvent::NewLine(line),
+                    timing,
+                }));
+            }
+            _ => {
+                if !self.exited {
+                    if let Ok(Some(status)) = self.child.try_wait() {
+                        warn!("{} exited with {}", self.info.label, status);
+                        let _ = self.tx.send(Event::ConnectionClosed(self.info.label.clone()));
+                        self.exited = true;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
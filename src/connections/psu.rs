@@ -0,0 +1,236 @@
+use crate::config::{PsuConfig, PsuSettings};
+use crate::latency::Timing;
+use crate::{ConnectionEventData, Event};
+use anyhow::Result;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+trait Port: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Port for T {}
+
+/// Sends SCPI commands to a power supply. Instruments answer one query at a
+/// time, so the port is shared behind a lock by the controls and the poll
+/// loop. All I/O is blocking, like the other control handles.
+#[derive(Clone)]
+pub struct ScpiControl {
+    label: String,
+    channel: Option<u32>,
+    port: Arc<Mutex<BufReader<Box<dyn Port>>>>,
+}
+
+impl ScpiControl {
+    fn open(info: &PsuConfig) -> Result<Box<dyn Port>> {
+        let timeout = Duration::from_millis(info.timeout as u64);
+        match (&info.path, &info.host) {
+            (Some(path), None) => {
+                trace!("Opening {:?} at {} baud", path, info.baud);
+                let port = serialport::new(path.to_string_lossy(), info.baud)
+                    .timeout(timeout)
+                    .open()
+                    .map_err(|e| anyhow!("Failed to open {}: {}", path.display(), e))?;
+                Ok(Box::new(port))
+            }
+            (None, Some(host)) => {
+                trace!("Connecting to {}:{}", host, info.port);
+                let stream = TcpStream::connect((host.as_str(), info.port))
+                    .map_err(|e| anyhow!("Failed to connect to {}:{}: {}", host, info.port, e))?;
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            _ => bail!("{} needs either a path or a host", info.label),
+        }
+    }
+
+    fn write(&self, port: &mut BufReader<Box<dyn Port>>, command: &str) -> Result<()> {
+        trace!("{}: {}", self.label, command);
+        let port = port.get_mut();
+        port.write_all(command.as_bytes())?;
+        port.write_all(b"\n")?;
+        port.flush()?;
+        Ok(())
+    }
+
+    /// Send a command, or a query (ending in `?`) and return the response
+    pub fn send(&self, command: &str) -> Result<Option<String>> {
+        let mut port = self.port.lock().unwrap();
+        if let Some(channel) = self.channel {
+            self.write(&mut port, &format!("INST:NSEL {}", channel))?;
+        }
+        self.write(&mut port, command)
+            .map_err(|e| anyhow!("Failed to write to {}: {}", self.label, e))?;
+        if !command.trim_end().ends_with('?') {
+            return Ok(None);
+        }
+        let mut response = String::new();
+        port.read_line(&mut response)
+            .map_err(|e| anyhow!("{}: no response to {}: {}", self.label, command, e))?;
+        if response.is_empty() {
+            bail!("{} closed the connection", self.label);
+        }
+        Ok(Some(response.trim().to_string()))
+    }
+
+    pub fn query(&self, query: &str) -> Result<String> {
+        Ok(self.send(query)?.unwrap_or_default())
+    }
+
+    /// Apply output settings, then check the instrument accepted them
+    pub fn apply(&self, settings: &PsuSettings) -> Result<()> {
+        if let Some(voltage) = settings.voltage {
+            self.send(&format!("VOLT {:.3}", voltage))?;
+        }
+        if let Some(current) = settings.current {
+            self.send(&format!("CURR {:.3}", current))?;
+        }
+        if let Some(output) = settings.output {
+            self.send(if output { "OUTP ON" } else { "OUTP OFF" })?;
+        }
+        // e.g. `-222,"Data out of range"`, no error is 0
+        let error = self.query("SYST:ERR?")?;
+        match error.split_once(',') {
+            Some((code, _)) if code.trim_start_matches('+').parse::<i32>() == Ok(0) => Ok(()),
+            _ => bail!("{} rejected {:?}: {}", self.label, settings, error),
+        }
+    }
+}
+
+/// A bench power supply or battery simulator. Its protection state is polled
+/// and reported as the lines `overcurrent` and `overvoltage` when it trips, so
+/// transitions can react to a board drawing too much. Data sent to it is sent
+/// as SCPI commands, responses to queries come back as lines.
+pub struct Psu {
+    tx: UnboundedSender<Event>,
+    info: PsuConfig,
+    ctrl: ScpiControl,
+    interval: Interval,
+    /// Which protections had tripped at the last check
+    tripped: [bool; 2],
+    /// Whether the last check failed, so errors aren't repeated every interval
+    failing: bool,
+}
+
+impl Psu {
+    pub fn ctrl(&self) -> ScpiControl {
+        self.ctrl.clone()
+    }
+
+    fn line(&self, line: String) {
+        let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+            device: self.info.label.clone(),
+            event: ConnectionEvent::NewLine(line),
+            timing: Timing::now(),
+        }));
+    }
+
+    /// Check the protections and take measurements, off the runtime since the
+    /// port is blocking
+    async fn check(&self) -> Result<([bool; 2], Option<(String, String)>)> {
+        let ctrl = self.ctrl.clone();
+        let queries = [self.info.overcurrent.clone(), self.info.overvoltage.clone()];
+        let measure = self.info.measure;
+        tokio::task::spawn_blocking(move || {
+            let mut tripped = [false; 2];
+            for (tripped, query) in tripped.iter_mut().zip(queries.iter()) {
+                if !query.is_empty() {
+                    *tripped = ctrl.query(query)?.trim_start_matches('+').starts_with('1');
+                }
+            }
+            let measured = if measure {
+                Some((ctrl.query("MEAS:VOLT?")?, ctrl.query("MEAS:CURR?")?))
+            } else {
+                None
+            };
+            Ok((tripped, measured))
+        })
+        .await?
+    }
+}
+
+impl Connection for Psu {
+    type Info = PsuConfig;
+    type Action = PsuSettings;
+
+    async fn new(tx: UnboundedSender<Event>, info: &PsuConfig) -> Result<Self, ConnectionError> {
+        let port = ScpiControl::open(info).map_err(|e| {
+            error!("{}", e);
+            ConnectionError::OpenFailed
+        })?;
+        let ctrl = ScpiControl {
+            label: info.label.clone(),
+            channel: info.channel,
+            port: Arc::new(Mutex::new(BufReader::new(port))),
+        };
+        match ctrl.query("*IDN?") {
+            Ok(idn) => info!("{}: {}", info.label, idn),
+            Err(e) => {
+                error!("{}", e);
+                return Err(ConnectionError::OpenFailed);
+            }
+        }
+        let mut interval = interval(Duration::from_millis(info.interval as u64));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            tx,
+            info: info.clone(),
+            ctrl,
+            interval,
+            tripped: [false; 2],
+            failing: false,
+        })
+    }
+
+    async fn action(&self, action: Self::Action) -> Result<()> {
+        let ctrl = self.ctrl.clone();
+        tokio::task::spawn_blocking(move || ctrl.apply(&action)).await?
+    }
+
+    async fn send(&mut self, buf: &str) -> Result<()> {
+        let ctrl = self.ctrl.clone();
+        let command = buf.to_string();
+        if let Some(response) = tokio::task::spawn_blocking(move || ctrl.send(&command)).await?? {
+            self.line(response);
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self) {
+        self.interval.tick().await;
+        let (tripped, measured) = match self.check().await {
+            Ok(checked) => checked,
+            Err(e) => {
+                if !self.failing {
+                    let _ = self.tx.send(Event::Error {
+                        connection: self.info.label.clone(),
+                        message: e.to_string(),
+                    });
+                }
+                self.failing = true;
+                return;
+            }
+        };
+        self.failing = false;
+        for (i, name) in ["overcurrent", "overvoltage"].iter().enumerate() {
+            if tripped[i] && !self.tripped[i] {
+                warn!("{}: {} protection tripped", self.info.label, name);
+                self.line(name.to_string());
+            }
+        }
+        self.tripped = tripped;
+        if let Some((voltage, current)) = measured {
+            self.line(format!("measured {}V {}A", voltage, current));
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
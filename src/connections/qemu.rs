@@ -0,0 +1,193 @@
+use crate::{config::QemuConfig, ConnectionEventData, Event};
+use crate::latency::{RawBytes, TimedLinesCodec};
+use anyhow::Result;
+use futures::SinkExt;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+use super::{Connection, ConnectionError, ConnectionEvent};
+
+/// A QEMU virtual machine, its serial chardev is the console and QMP is used
+/// for power controls.
+pub struct Qemu {
+    tx: UnboundedSender<Event>,
+    lines: Framed<UnixStream, TimedLinesCodec>,
+    info: QemuConfig,
+    ctrl: QmpControl,
+    // Kept so QEMU is killed when the connection is dropped
+    _child: Option<Child>,
+}
+
+#[derive(Clone, Debug)]
+pub enum QemuAction {
+    Reset,
+    Powerdown,
+    Pause(bool),
+}
+
+/// Sends commands over QMP, a new QMP session is used for each command
+#[derive(Clone)]
+pub struct QmpControl {
+    path: PathBuf,
+}
+
+impl QmpControl {
+    fn read_reply(reader: &mut BufReader<StdUnixStream>) -> Result<Value> {
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                bail!("QMP connection closed");
+            }
+            let msg: Value = serde_json::from_str(&line)?;
+            if let Some(err) = msg.get("error") {
+                bail!("QMP error: {}", err);
+            }
+            // Skip async events
+            if msg.get("return").is_some() || msg.get("QMP").is_some() {
+                return Ok(msg);
+            }
+        }
+    }
+
+    pub fn execute(&self, command: &str) -> Result<Value> {
+        let stream = StdUnixStream::connect(&self.path)
+            .map_err(|e| anyhow!("Failed to connect to QMP socket {:?}: {}", self.path, e))?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        // Greeting, then capabilities negotiation
+        Self::read_reply(&mut reader)?;
+        writeln!(writer, "{}", json!({ "execute": "qmp_capabilities" }))?;
+        Self::read_reply(&mut reader)?;
+        writeln!(writer, "{}", json!({ "execute": command }))?;
+        Self::read_reply(&mut reader)
+    }
+
+    pub fn action(&self, action: QemuAction) -> Result<()> {
+        let command = match action {
+            QemuAction::Reset => "system_reset",
+            QemuAction::Powerdown => "system_powerdown",
+            QemuAction::Pause(true) => "stop",
+            QemuAction::Pause(false) => "cont",
+        };
+        self.execute(command)?;
+        Ok(())
+    }
+}
+
+async fn wait_for_socket(path: &Path) -> Result<UnixStream> {
+    let mut tries = 0;
+    loop {
+        match UnixStream::connect(path).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if tries < 50 => {
+                trace!("Waiting for {:?}: {}", path, e);
+                tries += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(e) => bail!("Failed to connect to {:?}: {}", path, e),
+        }
+    }
+}
+
+impl Qemu {
+    fn launch(info: &QemuConfig, command: &[String]) -> Result<Child> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty QEMU command"))?;
+        let _ = std::fs::remove_file(&info.serial);
+        let _ = std::fs::remove_file(&info.qmp);
+        debug!("Launching {} {:?}", program, args);
+        Command::new(program)
+            .args(args)
+            .arg("-serial")
+            .arg(format!("unix:{},server=on,wait=off", info.serial.display()))
+            .arg("-qmp")
+            .arg(format!("unix:{},server=on,wait=off", info.qmp.display()))
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Failed to launch QEMU: {}", e))
+    }
+
+    pub fn ctrl(&self) -> QmpControl {
+        self.ctrl.clone()
+    }
+}
+
+impl Connection for Qemu {
+    type Info = QemuConfig;
+    type Action = QemuAction;
+
+    async fn new(tx: UnboundedSender<Event>, info: &QemuConfig) -> Result<Self, ConnectionError> {
+        let child = match &info.command {
+            Some(command) => Some(Self::launch(info, command).map_err(|e| {
+                error!("{}", e);
+                ConnectionError::OpenFailed
+            })?),
+            None => None,
+        };
+        let stream = wait_for_socket(&info.serial).await.map_err(|e| {
+            error!("{}", e);
+            ConnectionError::OpenFailed
+        })?;
+        Ok(Self {
+            tx,
+            lines: Framed::with_capacity(stream, TimedLinesCodec::new(), 1024),
+            info: info.clone(),
+            ctrl: QmpControl {
+                path: info.qmp.clone(),
+            },
+            _child: child,
+        })
+    }
+
+    async fn action(&self, action: Self::Action) -> Result<()> {
+        trace!("QEMU: {:?}", action);
+        self.ctrl.action(action)
+    }
+
+    async fn send(&mut self, buf: &str) -> Result<()> {
+        self.lines
+            .send(buf)
+            .await
+            .map_err(|e| anyhow!("Failed to write to QEMU serial: {}", e))
+    }
+
+    async fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        self.lines
+            .send(RawBytes(buf))
+            .await
+            .map_err(|e| anyhow!("Failed to write to QEMU serial: {}", e))
+    }
+
+    async fn read(&mut self) {
+        match self.lines.try_next().await {
+            Ok(Some((line, timing))) => {
+                if self.lines.codec_mut().take_overflow() {
+                    let _ = self.tx.send(Event::Overflow(self.info.label.clone()));
+                }
+                let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+                    device: self.info.label.clone(),
+                    event: ConnectionEvent::NewLine(line),
+                    timing,
+                }));
+            }
+            // QEMU exited, don't spin
+            _ => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+
+    fn name(&self) -> &str {
+        &self.info.label
+    }
+}
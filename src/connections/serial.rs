@@ -1,41 +1,57 @@
 use crate::{config::SerialConfig, ConnectionEventData, Event};
+use crate::latency::{TimedLinesCodec, Timing};
 use anyhow::Result;
 use as_any::Downcast;
 use bytes::{BufMut, BytesMut};
-use futures::SinkExt;
-use realpath::realpath;
-use serialport::{SerialPort, TTYPort};
+use serialport::SerialPort;
 use std::{borrow::{Cow, BorrowMut}, path::PathBuf, time::Duration, sync::{Mutex, Arc}, ops::Deref};
 use std::any::Any;
+use std::io::Write;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, sync::mpsc::UnboundedSender
 };
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 use tokio_stream::{StreamExt, Timeout};
-use tokio_util::codec::{Decoder, Framed, LinesCodec};
+use tokio_util::codec::{Decoder, Framed, LinesCodecError};
 
-use super::{Connection, ConnectionError, ConnectionEvent};
+use super::{write_paced, Connection, ConnectionError, ConnectionEvent, Echoes, PROMPT_DELAY};
 
 pub struct Serial {
     tx: UnboundedSender<Event>,
-    lines: Framed<SerialStream, LinesCodec>,
+    lines: Framed<SerialStream, TimedLinesCodec>,
     //buf: BytesMut,
     info: SerialConfig,
     ctrl: SerialControl,
+    echoes: Echoes,
+    /// How much of the incomplete line in the buffer has been reported as a
+    /// prompt
+    prompted: usize,
 }
 
+/// How long the line is held in a break before a SysRq key
+const SYSRQ_BREAK: Duration = Duration::from_millis(250);
+
 #[derive(Clone)]
 pub struct SerialControl {
-    port: Arc<Mutex<TTYPort>>,
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
 }
 
 impl SerialControl {
+    /// Blocking, a SysRq holds the port for [SYSRQ_BREAK] so run that off the
+    /// runtime
     pub fn action(&self, action: SerialAction) -> Result<()> {
         let mut port = self.port.lock().unwrap();
         match action {
             SerialAction::Dtr(state) => port.write_data_terminal_ready(state)?,
             SerialAction::Rts(state) => port.write_request_to_send(state)?,
             SerialAction::Baud(baud) => port.set_baud_rate(baud)?,
+            SerialAction::Sysrq(key) => {
+                port.set_break()?;
+                std::thread::sleep(SYSRQ_BREAK);
+                port.clear_break()?;
+                port.write_all(&[key])?;
+                port.flush()?;
+            }
         }
         Ok(())
     }
@@ -46,32 +62,61 @@ pub enum SerialAction {
     Dtr(bool),
     Rts(bool),
     Baud(u32),
+    /// A break followed by a magic SysRq key
+    Sysrq(u8),
 }
 
 impl Serial {
+    /// Resolve symlinks like /dev/serial/by-id, on Windows the path is a port
+    /// name like COM3 and is used as is
+    fn resolve(path: &PathBuf) -> Result<PathBuf> {
+        #[cfg(unix)]
+        return Ok(std::fs::canonicalize(path)?);
+        #[cfg(not(unix))]
+        return Ok(path.clone());
+    }
+
     async fn open(path: &PathBuf, baud: u32) -> Result<SerialStream> {
-        let path = realpath(path)?;
+        let path = Self::resolve(path)?;
         trace!("Opening serial port {:?} at {} baud", path, baud);
-        tokio_serial::new(path.to_string_lossy(), baud)
+        #[allow(unused_mut)]
+        let mut port = tokio_serial::new(path.to_string_lossy(), baud)
             .open_native_async()
-            .map_err(|e| anyhow!("Failed to open serial port: {}", e))
-            .and_then(|mut port| {
-                port.set_exclusive(false)
-                    .map_err(|e| anyhow!("Failed to set serial port exclusive: {}", e))?;
-                Ok(port)
-            })
+            .map_err(|e| anyhow!("Failed to open serial port: {}", e))?;
+        // Let other tools open the port too, Windows never allows this
+        #[cfg(unix)]
+        port.set_exclusive(false)
+            .map_err(|e| anyhow!("Failed to set serial port exclusive: {}", e))?;
+        Ok(port)
     }
 
-    fn open_raw(path: &PathBuf, baud: u32) -> Result<TTYPort> {
-        serialport::new(path.to_string_lossy(), baud)
-            .open_native()
-            .map_err(|e| anyhow!("Failed to open serial port: {}", e))
+    /// A second handle to the port for control lines and baud rate, cloned
+    /// rather than reopened since Windows only lets a port be opened once
+    fn open_ctrl(port: &SerialStream) -> Result<Box<dyn SerialPort>> {
+        port.try_clone()
+            .map_err(|e| anyhow!("Failed to clone serial port: {}", e))
     }
 
     pub fn ctrl(&self) -> SerialControl {
         self.ctrl.clone()
     }
 
+    /// Report the incomplete line in the buffer as a prompt, unless it's
+    /// empty or has been already
+    fn prompt(&mut self) {
+        let buf = self.lines.read_buffer();
+        if buf.is_empty() || buf.len() == self.prompted {
+            return;
+        }
+        self.prompted = buf.len();
+        let prompt = String::from_utf8_lossy(buf).trim_end_matches('\r').to_string();
+        let _ = self.tx.send(Event::ConnectionEvent(ConnectionEventData {
+            device: self.info.label.clone(),
+            event: ConnectionEvent::Prompt(prompt),
+            timing: Timing::now(),
+        }));
+    }
+
     // pub async fn reopen(&mut self) -> Result<()> {
     //     self.lines.get_mut().deref() = Self::open(&self.info.path, self.info.baud)
     //         .await
@@ -88,13 +133,20 @@ impl Connection for Serial {
         let port = Self::open(&info.path, info.baud)
             .await
             .map_err(|_| ConnectionError::OpenFailed)?;
-        let ctrl = SerialControl { port: Arc::new(Mutex::new(Self::open_raw(&info.path, info.baud).unwrap())) };
-        let framed = Framed::with_capacity(port, LinesCodec::new(), 1024);
+        let ctrl = SerialControl {
+            port: Arc::new(Mutex::new(Self::open_ctrl(&port).map_err(|e| {
+                error!("{}", e);
+                ConnectionError::OpenFailed
+            })?)),
+        };
+        let framed = Framed::with_capacity(port, TimedLinesCodec::new(), 1024);
         Ok(Self {
             tx,
             info: info.clone(),
             lines: framed,
             ctrl,
+            echoes: Echoes::default(),
+            prompted: 0,
             //buf: BytesMut::with_capacity(256),
         })
     }
@@ -106,31 +158,67 @@ impl Connection for Serial {
     }
 
     async fn send(&mut self, buf: &str) -> Result<()> {
-        self.lines
-            .send(buf)
+        if self.info.send.suppress_echo {
+            self.echoes.sent(buf);
+        }
+        let mut data = buf.as_bytes().to_vec();
+        data.extend_from_slice(self.info.send.line_ending.as_bytes());
+        self.send_raw(&data).await
+    }
+
+    async fn send_raw(&mut self, buf: &[u8]) -> Result<()> {
+        // Nothing is left in the codec's write buffer, every send is flushed
+        write_paced(self.lines.get_mut(), buf, &self.info.send)
             .await
             .map_err(|e| anyhow!("Failed to write to serial port: {}", e))
     }
 
     async fn read(&mut self) {
         let run_until = tokio::time::Instant::now() + Duration::from_millis(100);
-        while let Ok(line) = self.lines.try_next().await {
-            match line {
-                Some(line) => {
-                    self.tx
-                        .send(Event::ConnectionEvent(ConnectionEventData {
-                            device: "device:axolotl".to_string(),
-                            event: ConnectionEvent::NewLine(line),
-                        }))
-                        .unwrap();
+        loop {
+            let next = match tokio::time::timeout(PROMPT_DELAY, self.lines.try_next()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    self.prompt();
+                    continue;
+                }
+            };
+            match next {
+                Ok(Some((line, timing))) => {
+                    self.prompted = 0;
+                    if self.lines.codec_mut().take_overflow() {
+                        let _ = self.tx.send(Event::Overflow(self.info.label.clone()));
+                    }
+                    if self.info.send.suppress_echo && self.echoes.is_echo(&line) {
+                        trace!("{}: dropping echo {:?}", self.info.label, line);
+                        continue;
+                    }
+                    let event = Event::ConnectionEvent(ConnectionEventData {
+                        device: self.info.label.clone(),
+                        event: ConnectionEvent::NewLine(line),
+                        timing,
+                    });
+                    // The device loop has gone away, we're shutting down
+                    if self.tx.send(event).is_err() {
+                        return;
+                    }
                     // Timeout and return so that actions can be handled
                     if run_until > tokio::time::Instant::now() {
                         break;
                     }
                 }
-                None => {
-                    // error!("Error reading from serial port");
-                    // break;
+                // The port went away, e.g. the adapter was unplugged
+                Ok(None) | Err(LinesCodecError::Io(_)) => {
+                    let _ = self.tx.send(Event::ConnectionClosed(self.info.label.clone()));
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    return;
+                }
+                Err(e) => {
+                    let _ = self.tx.send(Event::Error {
+                        connection: self.info.label.clone(),
+                        message: e.to_string(),
+                    });
+                    return;
                 }
             }
         }
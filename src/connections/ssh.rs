@@ -0,0 +1,66 @@
+use crate::config::SshConnection;
+use anyhow::Result;
+use std::process::{Command, Stdio};
+use tokio::sync::watch;
+
+/// Runs commands on the DUT (or a relay host like a Raspberry Pi driving a USB
+/// mux) with the ssh client, non-interactively so a missing key fails instead
+/// of prompting for a password.
+#[derive(Clone, Debug)]
+pub struct SshControl {
+    info: SshConnection,
+    /// Where to connect, None until a bootstrapped connection is up, see
+    /// [crate::bootstrap]
+    host: watch::Receiver<Option<String>>,
+}
+
+impl SshControl {
+    pub fn new(info: &SshConnection, host: watch::Receiver<Option<String>>) -> Self {
+        Self {
+            info: info.clone(),
+            host,
+        }
+    }
+
+    /// Whether the host is known, commands fail until it is
+    pub fn is_up(&self) -> bool {
+        self.host.borrow().is_some()
+    }
+
+    /// The ssh invocation that runs `command` on the host
+    pub fn command(&self, command: &str) -> Result<Command> {
+        let host = self
+            .host
+            .borrow()
+            .clone()
+            .ok_or_else(|| anyhow!("{} isn't up yet", self.info.label))?;
+        Ok(ssh_command(&self.info, &host, command))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.info.label
+    }
+}
+
+/// The ssh invocation that runs `command` on `host` with the options of a
+/// connection
+pub fn ssh_command(info: &SshConnection, host: &str, command: &str) -> Command {
+    let mut cmd = Command::new("ssh");
+    cmd.arg("-T")
+        .args(["-p", &info.port.to_string()])
+        .args(["-o", "BatchMode=yes"])
+        .args(["-o", &format!("ServerAliveInterval={}", info.alive_interval)])
+        .args(["-o", &format!("ServerAliveCountMax={}", info.alive_count_max)])
+        .args(["-o", &format!("ConnectTimeout={}", info.alive_interval * info.alive_count_max)]);
+    if let Some(identity) = &info.identity {
+        cmd.arg("-i").arg(identity);
+    }
+    let destination = match &info.user {
+        Some(user) => format!("{}@{}", user, host),
+        None => host.to_string(),
+    };
+    // A host starting with - would be an option otherwise
+    cmd.arg("--").arg(destination).arg(command);
+    cmd.stdin(Stdio::null());
+    cmd
+}
@@ -1 +1,400 @@
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
 
+use crate::config::{Control, ControlAction, ControlType, SysrqMethod, TransitionTrigger};
+#[cfg(target_os = "linux")]
+use crate::connections::parse_frame;
+#[cfg(unix)]
+use crate::connections::QemuAction;
+use crate::connections::{ContainerAction, ControlHandle, SerialAction};
+use crate::state::{is_in, State};
+use crate::vars::{self, Vars};
+use crate::{log_target, ConnectionInput};
+use anyhow::Result;
+use log::Level;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+
+/// How long writing a SysRq key over ssh may take
+const SYSRQ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Executes controls and trigger sequences for a device. Controls that are
+/// held are tracked so they're never left held: they're released by a later
+/// `release` step, when the trigger reaches its target state or times out,
+/// when the trigger is cancelled and when the device shuts down.
+pub struct Controls {
+    codename: String,
+    controls: Vec<Control>,
+    handles: Vec<(String, ControlHandle)>,
+    held: Mutex<Vec<String>>,
+    /// For the "send" steps of trigger sequences
+    input: UnboundedSender<ConnectionInput>,
+    /// Passed to the commands of command controls, see [Controls::vars]
+    variables: Vars,
+    context: watch::Receiver<Vars>,
+}
+
+/// Releases the controls a trigger held when it finishes, however it finishes
+struct HoldGuard<'a> {
+    controls: &'a Controls,
+    held: Vec<String>,
+}
+
+impl HoldGuard<'_> {
+    async fn hold(&mut self, name: &str) -> Result<()> {
+        self.controls.set(name, true).await?;
+        let mut registry = self.controls.held.lock().unwrap();
+        if !registry.iter().any(|c| c == name) {
+            registry.push(name.to_string());
+        }
+        if !self.held.iter().any(|c| c == name) {
+            self.held.push(name.to_string());
+        }
+        Ok(())
+    }
+
+    async fn release(&mut self, name: &str) -> Result<()> {
+        self.held.retain(|c| c != name);
+        self.controls.set(name, false).await
+    }
+
+    async fn release_all(&mut self) -> Result<()> {
+        let mut res = Ok(());
+        for name in std::mem::take(&mut self.held) {
+            // Keep going so one failure doesn't leave the rest held
+            if let Err(e) = self.controls.set(&name, false).await {
+                res = Err(e);
+            }
+        }
+        res
+    }
+}
+
+impl Drop for HoldGuard<'_> {
+    fn drop(&mut self) {
+        if self.held.is_empty() {
+            return;
+        }
+        warn!("Trigger stopped, releasing {}", self.held.join(", "));
+        for name in std::mem::take(&mut self.held) {
+            if let Err(e) = self.controls.set_blocking(&name, false) {
+                error!("Failed to release held control: {}", e);
+            }
+        }
+    }
+}
+
+impl Controls {
+    pub fn new(
+        codename: &str,
+        controls: Vec<Control>,
+        handles: Vec<(String, ControlHandle)>,
+        input: UnboundedSender<ConnectionInput>,
+        variables: Vars,
+        context: watch::Receiver<Vars>,
+    ) -> Self {
+        Self {
+            codename: codename.to_string(),
+            controls,
+            handles,
+            held: Mutex::new(vec![]),
+            input,
+            variables,
+            context,
+        }
+    }
+
+    /// The variables of the config overridden by those captured from the
+    /// console, as `FBUG_VAR_<name>` environment variables
+    fn vars(&self) -> impl Iterator<Item = (String, String)> {
+        let mut vars = self.variables.clone();
+        vars.extend(self.context.borrow().clone());
+        vars.into_iter().map(|(name, value)| (format!("FBUG_VAR_{}", name), value))
+    }
+
+    /// Release every held control, e.g. when the device is shutting down
+    pub fn release_all(&self) {
+        let held = std::mem::take(&mut *self.held.lock().unwrap());
+        for name in held {
+            debug!("Releasing held control {}", name);
+            if let Err(e) = self.set_blocking(&name, false) {
+                error!("Failed to release {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Whether a control exists and the connection it needs was opened,
+    /// command controls run on the host if theirs wasn't
+    pub fn available(&self, name: &str) -> bool {
+        let Some(control) = self.controls.iter().find(|c| c.name == name) else {
+            return false;
+        };
+        matches!(control.control_type, ControlType::Command(_))
+            || self.handles.iter().any(|(label, _)| *label == control.connection)
+    }
+
+    /// [Controls::set] for destructors, which can't wait for it. It's run to
+    /// completion on a thread of its own, so it works whether or not the
+    /// runtime is still running.
+    fn set_blocking(&self, name: &str, on: bool) -> Result<()> {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?
+                        .block_on(self.set(name, on))
+                })
+                .join()
+                .map_err(|_| anyhow!("Setting control {} panicked", name))?
+        })
+    }
+
+    /// Turn a control on (pressed) or off (released)
+    pub async fn set(&self, name: &str, on: bool) -> Result<()> {
+        if !on {
+            self.held.lock().unwrap().retain(|c| c != name);
+        }
+        let control = self
+            .controls
+            .iter()
+            .find(|c| c.name == name)
+            .ok_or_else(|| anyhow!("No such control {}", name))?;
+        trace!("Control {} -> {}", name, if on { "on" } else { "off" });
+        let handle = || {
+            self.handles
+                .iter()
+                .find(|(label, _)| *label == control.connection)
+                .map(|(_, h)| h)
+                .ok_or_else(|| anyhow!("Control {} needs connection {}", name, control.connection))
+        };
+        match &control.control_type {
+            ControlType::Button(button) => {
+                let handle = handle()?;
+                match (handle, button.action.as_str()) {
+                    (ControlHandle::Serial(s), "dtr") => s.action(SerialAction::Dtr(on)),
+                    (ControlHandle::Serial(s), "rts") => s.action(SerialAction::Rts(on)),
+                    // QEMU buttons only do something when pressed
+                    #[cfg(unix)]
+                    (ControlHandle::Qmp(q), "reset") if on => q.action(QemuAction::Reset),
+                    #[cfg(unix)]
+                    (ControlHandle::Qmp(q), "powerdown") if on => q.action(QemuAction::Powerdown),
+                    #[cfg(unix)]
+                    (ControlHandle::Qmp(_), "reset" | "powerdown") => Ok(()),
+                    #[cfg(unix)]
+                    (ControlHandle::Qmp(q), "pause") => q.action(QemuAction::Pause(on)),
+                    // Held like a power switch, pressing turns the container on
+                    (ControlHandle::Container(c), "power") => c.action(if on {
+                        ContainerAction::Start
+                    } else {
+                        ContainerAction::Stop
+                    }),
+                    (ControlHandle::Container(c), "restart") if on => c.action(ContainerAction::Restart),
+                    (ControlHandle::Container(_), "restart") => Ok(()),
+                    (ControlHandle::Container(c), "pause") => c.action(ContainerAction::Pause(on)),
+                    // The action is the frame to send when pressed
+                    #[cfg(target_os = "linux")]
+                    (ControlHandle::Can(c), frame) if on => c.send(&parse_frame(frame)?),
+                    #[cfg(target_os = "linux")]
+                    (ControlHandle::Can(_), _) => Ok(()),
+                    (_, action) => bail!("Unsupported button action {} on {}", action, control.connection),
+                }
+            }
+            ControlType::Command(command) => {
+                let timeout = Duration::from_millis(command.timeout as u64);
+                let command = if on { &command.command_on } else { &command.command_off };
+                match self.handles.iter().find(|(label, _)| *label == control.connection) {
+                    Some((_, ControlHandle::Ssh(ssh))) => {
+                        trace!("{}: running {:?} over {}", name, command, ssh.name());
+                        self.run_command(name, command, ssh.command(command)?, timeout).await
+                    }
+                    _ => {
+                        trace!("{}: running {:?}", name, command);
+                        let mut cmd = std::process::Command::new("sh");
+                        cmd.arg("-c").arg(command).stdin(Stdio::null());
+                        self.run_command(name, command, cmd, timeout).await
+                    }
+                }
+            }
+            ControlType::Psu(psu) => match handle()? {
+                // Blocking I/O, shared with the connection's poll loop
+                ControlHandle::Scpi(scpi) => {
+                    let scpi = scpi.clone();
+                    let settings = if on { psu.on.clone() } else { psu.off.clone() };
+                    tokio::task::spawn_blocking(move || scpi.apply(&settings)).await?
+                }
+                _ => bail!("Control {} needs a psu connection, {} isn't one", name, control.connection),
+            },
+            // Like a button, only pressing does something
+            ControlType::Sysrq(_) if !on => Ok(()),
+            ControlType::Sysrq(sysrq) => {
+                let key = sysrq.key()?;
+                debug!("{}: sysrq {} on {}", name, key as char, control.connection);
+                let command = format!("echo {} > /proc/sysrq-trigger", key as char);
+                let ssh = self.handles.iter().find_map(|(label, h)| match h {
+                    ControlHandle::Ssh(ssh) if *label == control.connection => Some(ssh),
+                    _ => None,
+                });
+                match (sysrq.method, ssh) {
+                    (_, Some(ssh)) => {
+                        self.run_command(name, &command, ssh.command(&command)?, SYSRQ_TIMEOUT)
+                            .await
+                    }
+                    (SysrqMethod::Shell, None) => self
+                        .input
+                        .send(ConnectionInput {
+                            connection: Some(control.connection.clone()),
+                            data: command.into(),
+                        })
+                        .map_err(|_| anyhow!("Connections stopped")),
+                    (SysrqMethod::Break, None) => match handle()? {
+                        // The break sleeps while holding the port
+                        ControlHandle::Serial(s) => {
+                            let s = s.clone();
+                            tokio::task::spawn_blocking(move || s.action(SerialAction::Sysrq(key))).await?
+                        }
+                        _ => bail!("Control {}: a break needs a serial connection, {} isn't one", name, control.connection),
+                    },
+                }
+            }
+        }
+    }
+
+    /// Run the command of a command control, logging its output to the
+    /// device's log target. It's killed if it doesn't finish in time, and
+    /// exiting unsuccessfully is an error so the trigger running it stops.
+    async fn run_command(&self, name: &str, command: &str, cmd: std::process::Command, timeout: Duration) -> Result<()> {
+        let mut child = tokio::process::Command::from(cmd)
+            .envs(self.vars())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow!("Control {}: failed to run {:?}: {}", name, command, e))?;
+        let target = log_target(&self.codename, None);
+        let stdout = forward(child.stdout.take(), &target, name, Level::Info);
+        let stderr = forward(child.stderr.take(), &target, name, Level::Warn);
+        let run = async {
+            let (status, _, stderr) = tokio::join!(child.wait(), stdout, stderr);
+            (status, stderr)
+        };
+        let (status, stderr) = tokio::time::timeout(timeout, run)
+            .await
+            .map_err(|_| anyhow!("Control {}: {:?} timed out after {:?}", name, command, timeout))?;
+        let status = status?;
+        if !status.success() {
+            bail!("Control {}: {:?} failed ({}): {}", name, command, status, stderr);
+        }
+        Ok(())
+    }
+
+    /// Run the sequence for a trigger. A `hold` step keeps the control held
+    /// through the following steps until a `release` step for it, and after
+    /// the sequence until the device enters the trigger's target state (or one
+    /// of its children, going by the parents in `states`). If
+    /// that doesn't happen within the trigger's hold timeout the controls are
+    /// released and an error returned.
+    ///
+    /// Variables in "send" steps are expanded from `vars` before anything is
+    /// done, so a missing variable doesn't leave the device half way through.
+    pub async fn run_trigger(
+        &self,
+        trigger: &TransitionTrigger,
+        vars: &Vars,
+        states: &[State],
+        mut state: watch::Receiver<Option<String>>,
+    ) -> Result<()> {
+        let sends = trigger
+            .sequence
+            .iter()
+            .map(|step| match (step.control.as_str(), &step.value) {
+                ("send", Some(value)) => vars::expand(value, vars).map(Some),
+                ("send", None) => bail!("Send step of trigger {} has no value", trigger.name),
+                _ => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| anyhow!("Trigger {}: {}", trigger.name, e))?;
+
+        info!("Running trigger {}", trigger.name);
+        let mut guard = HoldGuard {
+            controls: self,
+            held: vec![],
+        };
+        for (step, send) in trigger.sequence.iter().zip(sends) {
+            let duration = Duration::from_millis(step.duration.unwrap_or(0) as u64);
+            if step.control == "wait" {
+                tokio::time::sleep(duration).await;
+                continue;
+            }
+            if let Some(data) = send {
+                self.input
+                    .send(ConnectionInput {
+                        connection: step.connection.clone(),
+                        data: data.into(),
+                    })
+                    .map_err(|_| anyhow!("Connections stopped"))?;
+                tokio::time::sleep(duration).await;
+                continue;
+            }
+            match step.action {
+                // Held for the press, so it's released if the trigger is cancelled
+                ControlAction::Press => {
+                    guard.hold(&step.control).await?;
+                    tokio::time::sleep(duration).await;
+                    guard.release(&step.control).await?;
+                }
+                ControlAction::Release => {
+                    guard.release(&step.control).await?;
+                    tokio::time::sleep(duration).await;
+                }
+                ControlAction::Hold => {
+                    guard.hold(&step.control).await?;
+                    tokio::time::sleep(duration).await;
+                }
+            }
+        }
+
+        if guard.held.is_empty() {
+            return Ok(());
+        }
+
+        debug!("Holding {} until {}", guard.held.join(", "), trigger.to);
+        let hold_timeout = Duration::from_millis(trigger.hold_timeout as u64);
+        let reached = tokio::time::timeout(hold_timeout, async {
+            while !state.borrow().as_deref().is_some_and(|s| is_in(states, s, &trigger.to)) {
+                if state.changed().await.is_err() {
+                    bail!("Device stopped while holding controls");
+                }
+            }
+            Ok(())
+        })
+        .await;
+
+        guard.release_all().await?;
+
+        reached.map_err(|_| anyhow!("Timed out waiting for state {}, released held controls", trigger.to))?
+    }
+}
+
+/// Log the output of a command as it's produced, so a command that hangs
+/// still shows why. Returns the last line.
+async fn forward<R: AsyncRead + Unpin>(stream: Option<R>, target: &str, name: &str, level: Level) -> String {
+    let mut last = String::new();
+    let Some(stream) = stream else {
+        return last;
+    };
+    let mut lines = BufReader::new(stream).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        log::log!(target: target, level, "{}: {}", name, line);
+        last = line;
+    }
+    last
+}
+
+impl Drop for Controls {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
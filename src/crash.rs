@@ -0,0 +1,124 @@
+//! Collecting logs after a crash. When the device enters one of the crash
+//! states it's remembered until the device next reaches a state with a shell,
+//! then the collection commands (the kernel log, the journal of the previous
+//! boot, ...) are run and their output is stored in the `crashes` directory of
+//! the run, see [crate::artifacts].
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::config::{ConnectionInfo, CrashConfig};
+use crate::connections::SshControl;
+use crate::state::StateMachine;
+use crate::Event;
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The output of one collection command, or why it couldn't be run
+#[derive(Debug, Clone)]
+pub struct CrashOutput {
+    pub name: String,
+    pub output: Result<String, String>,
+}
+
+/// Everything collected after a crash
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    /// The crash state the device was in
+    pub state: String,
+    pub outputs: Vec<CrashOutput>,
+}
+
+pub struct Collector {
+    config: CrashConfig,
+    ssh: Option<SshControl>,
+    /// The crash state the device was last in, until it's collected
+    crashed: Option<String>,
+}
+
+impl Collector {
+    pub fn new(config: &CrashConfig, connections: &[ConnectionInfo], ssh: &[SshControl]) -> Result<Self> {
+        let ssh = match &config.connection {
+            Some(label) => match connections.iter().find(|c| c.label() == label.as_str()) {
+                Some(ConnectionInfo::Ssh(_)) => ssh.iter().find(|s| s.name() == label).cloned(),
+                Some(_) => bail!("Crash connection {} isn't an ssh connection", label),
+                None => bail!("Crash connection {} doesn't exist", label),
+            },
+            None => None,
+        };
+        Ok(Self {
+            config: config.clone(),
+            ssh,
+            crashed: None,
+        })
+    }
+
+    /// The device changed state, note a crash or start collecting once there's
+    /// a shell after one. The report is sent as [Event::Crash].
+    pub fn state_changed(&mut self, sm: &StateMachine, tx: &UnboundedSender<Event>) {
+        let Some(state) = sm.current_state() else {
+            return;
+        };
+        if self.config.states.iter().any(|s| sm.in_state(s)) {
+            self.crashed = Some(state.to_string());
+            return;
+        }
+        if !self.config.shell.iter().any(|s| sm.in_state(s)) {
+            return;
+        }
+        if let Some(crashed) = self.crashed.take() {
+            info!("Reached {} after crashing in {}, collecting logs", state, crashed);
+            self.collect(crashed, tx.clone());
+        }
+    }
+
+    fn command(&self, command: &str) -> Result<tokio::process::Command> {
+        let mut cmd = match &self.ssh {
+            Some(ssh) => tokio::process::Command::from(ssh.command(command)?),
+            None => {
+                let mut cmd = tokio::process::Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            }
+        };
+        cmd.stdin(Stdio::null()).stderr(Stdio::piped()).kill_on_drop(true);
+        Ok(cmd)
+    }
+
+    /// Run the collection commands one after the other in the background
+    fn collect(&self, state: String, tx: UnboundedSender<Event>) {
+        let commands: Vec<_> = self
+            .config
+            .commands
+            .iter()
+            .map(|c| (c.name.clone(), self.command(&c.command)))
+            .collect();
+        let timeout = Duration::from_millis(self.config.timeout as u64);
+        tokio::spawn(async move {
+            let mut outputs = vec![];
+            for (name, cmd) in commands {
+                let output = match cmd {
+                    Ok(mut cmd) => match tokio::time::timeout(timeout, cmd.output()).await {
+                        Ok(Ok(output)) if output.status.success() => {
+                            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+                        }
+                        Ok(Ok(output)) => Err(format!(
+                            "failed ({}): {}",
+                            output.status,
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        )),
+                        Ok(Err(e)) => Err(format!("failed to run: {}", e)),
+                        Err(_) => Err(format!("timed out after {:?}", timeout)),
+                    },
+                    // The ssh connection isn't up
+                    Err(e) => Err(format!("failed to run: {}", e)),
+                };
+                if let Err(e) = &output {
+                    warn!("Collecting {} after a crash {}", name, e);
+                }
+                outputs.push(CrashOutput { name, output });
+            }
+            let _ = tx.send(Event::Crash(CrashReport { state, outputs }));
+        });
+    }
+}
@@ -0,0 +1,346 @@
+//! Checking that a lab host is ready to run its devices, so cabling and
+//! permission problems turn up before a test run rather than halfway through
+//! one. Each connection is checked for what usually goes wrong with it: a
+//! serial port that's missing or can't be opened, an SSH host that can't be
+//! reached or whose host key isn't known, a USB port with nothing plugged in.
+//! The power control is checked through its connection, and the USB IDs the
+//! transitions look for are reported if they're visible. Nothing is sent to
+//! the devices.
+
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::config::{ConnectionInfo, ControlType, Device, SshConnection, TransitionCondition};
+use crate::state::usb_present;
+use serde::Serialize;
+use tokio::net::TcpStream;
+
+/// How long to wait when connecting to an SSH host or network instrument
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What was found, or what went wrong
+type Check = Result<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Ok,
+    /// Worth knowing, but it doesn't stop the device from running
+    Note,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    /// What was checked: a connection label, `power` or a USB ID
+    pub subject: String,
+    pub status: Status,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(subject: &str, check: Check) -> Self {
+        let (status, message) = match check {
+            Ok(message) => (Status::Ok, message),
+            Err(message) => (Status::Failed, message),
+        };
+        Self {
+            subject: subject.to_string(),
+            status,
+            message,
+        }
+    }
+
+    fn note(subject: &str, message: impl Into<String>) -> Self {
+        Self {
+            subject: subject.to_string(),
+            status: Status::Note,
+            message: message.into(),
+        }
+    }
+}
+
+/// How ready a device is to run on this host
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub codename: String,
+    /// Whether nothing failed
+    pub ready: bool,
+    pub findings: Vec<Finding>,
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.codename, if self.ready { "ready" } else { "NOT READY" })?;
+        for finding in self.findings.iter() {
+            let mark = match finding.status {
+                Status::Ok => "ok",
+                Status::Note => "--",
+                Status::Failed => "FAIL",
+            };
+            write!(f, "\n  [{:<4}] {}: {}", mark, finding.subject, finding.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check everything a device needs from the host
+pub async fn check(device: &Device) -> Report {
+    let mut findings = vec![];
+    for info in device.connections.iter() {
+        findings.push(check_connection(info).await);
+    }
+    if let Some(power) = &device.power {
+        findings.push(check_power(device, &power.control, &findings));
+    }
+    for spec in usb_ids(device) {
+        findings.push(if usb_present(&spec) {
+            Finding::new(&spec, Ok("visible".to_string()))
+        } else {
+            Finding::note(&spec, "not visible, fine unless the device is in a state that enumerates it")
+        });
+    }
+    Report {
+        codename: device.codename.clone(),
+        ready: !findings.iter().any(|f| f.status == Status::Failed),
+        findings,
+    }
+}
+
+async fn check_connection(info: &ConnectionInfo) -> Finding {
+    let label = info.label();
+    let check = match info {
+        ConnectionInfo::Serial(s) if s.hotplug => {
+            return Finding::note(label, "hotplugged, it's only there while the device is running")
+        }
+        ConnectionInfo::Serial(s) => serial(&s.path),
+        ConnectionInfo::Usb(u) => usb_port(&u.port),
+        ConnectionInfo::Ssh(s) if s.bootstrapped() => {
+            return Finding::note(label, "the host is only known once the device is running")
+        }
+        ConnectionInfo::Ssh(s) => ssh(s).await,
+        ConnectionInfo::Psu(p) => match (&p.path, &p.host) {
+            (Some(path), _) => serial(path),
+            (None, Some(host)) => reachable(host, p.port).await,
+            (None, None) => Err("needs either a path or a host".to_string()),
+        },
+        ConnectionInfo::Qemu(q) => match &q.command {
+            Some(command) => program(command.first().map_or("", String::as_str)),
+            None => exists(&q.serial).and_then(|_| exists(&q.qmp)),
+        },
+        ConnectionInfo::Process(p) => program(p.command.first().map_or("", String::as_str)),
+        ConnectionInfo::File(f) => exists(&f.path),
+        ConnectionInfo::Container(c) => program(&c.runtime),
+        ConnectionInfo::Can(c) => exists(&Path::new("/sys/class/net").join(&c.interface)),
+        ConnectionInfo::Capture(c) => match (&c.command, &c.device) {
+            (Some(command), _) => program(command.first().map_or("", String::as_str)),
+            (None, Some(device)) => readable(device),
+            (None, None) => Err("needs either a device or a command".to_string()),
+        },
+        ConnectionInfo::Bluetooth(_) => return Finding::note(label, "not checked"),
+    };
+    Finding::new(label, check)
+}
+
+/// The power control is reachable if its connection is, command controls run
+/// on the host need their program
+fn check_power(device: &Device, name: &str, findings: &[Finding]) -> Finding {
+    let Some(control) = device.controls.iter().find(|c| c.name == name) else {
+        return Finding::new("power", Err(format!("control {} doesn't exist", name)));
+    };
+    let over_ssh = device
+        .connections
+        .iter()
+        .any(|c| c.label() == control.connection && matches!(c, ConnectionInfo::Ssh(_)));
+    let check = match (&control.control_type, findings.iter().find(|f| f.subject == control.connection)) {
+        (ControlType::Command(command), _) if !over_ssh => {
+            program(command.command_on.split_whitespace().next().unwrap_or(""))
+        }
+        (_, Some(finding)) if finding.status == Status::Failed => {
+            Err(format!("{} is unreachable, {} failed", name, control.connection))
+        }
+        (_, Some(_)) => Ok(format!("{} through {}", name, control.connection)),
+        (_, None) => Err(format!("{} needs connection {}, which doesn't exist", name, control.connection)),
+    };
+    Finding::new("power", check)
+}
+
+/// The USB devices that the transitions look for
+fn usb_ids(device: &Device) -> BTreeSet<String> {
+    device
+        .transitions
+        .iter()
+        .flat_map(|t| {
+            let actions = t.actions.iter().filter(|a| a.is_usb()).map(|a| a.value.clone());
+            let conditions = t.conditions.iter().filter_map(|c| match c {
+                TransitionCondition::Usb(spec) => Some(spec.clone()),
+                _ => None,
+            });
+            actions.chain(conditions).collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn exists(path: &Path) -> Check {
+    match std::fs::metadata(path) {
+        Ok(_) => Ok(format!("{} exists", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(format!("{} doesn't exist", path.display())),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn readable(path: &Path) -> Check {
+    exists(path)?;
+    match std::fs::File::open(path) {
+        Ok(_) => Ok(format!("{} is readable", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(permission_denied(path)),
+        Err(e) => Err(format!("{} can't be opened: {}", path.display(), e)),
+    }
+}
+
+fn serial(path: &Path) -> Check {
+    exists(path).map_err(|e| format!("{}, is it plugged in?", e))?;
+    // Not with serialport, which would make the port exclusive while it's
+    // open, and without waiting for carrier or becoming our controlling
+    // terminal
+    #[cfg(unix)]
+    let res = {
+        use nix::fcntl::OFlag;
+        use std::os::unix::fs::OpenOptionsExt;
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags((OFlag::O_NONBLOCK | OFlag::O_NOCTTY).bits())
+            .open(path)
+            .map(|_| ())
+    };
+    // The baud rate doesn't matter for opening it
+    #[cfg(not(unix))]
+    let res = serialport::new(path.to_string_lossy(), 115200)
+        .open()
+        .map(|_| ())
+        .map_err(std::io::Error::from);
+    match res {
+        Ok(()) => Ok(format!("{} can be opened", path.display())),
+        #[cfg(unix)]
+        Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EBUSY as i32) => {
+            Ok(format!("{} is in use, by the daemon or another program", path.display()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(permission_denied(path)),
+        Err(e) => Err(format!("{} can't be opened: {}", path.display(), e)),
+    }
+}
+
+/// Explain which group a file needs, which is usually why it can't be opened
+fn permission_denied(path: &Path) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Some(group) = std::fs::metadata(path).ok().and_then(|m| group_name(m.gid())) {
+            return format!(
+                "permission denied opening {}, it belongs to group {}: add yourself to it and log in again",
+                path.display(),
+                group
+            );
+        }
+    }
+    format!("permission denied opening {}", path.display())
+}
+
+#[cfg(unix)]
+fn group_name(gid: u32) -> Option<String> {
+    std::fs::read_to_string("/etc/group").ok()?.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        (fields.nth(1)? == gid.to_string()).then(|| name.to_string())
+    })
+}
+
+fn usb_port(port: &str) -> Check {
+    let path = Path::new("/sys/bus/usb/devices").join(port);
+    if !path.exists() {
+        return Err(format!("nothing is plugged into USB port {}", port));
+    }
+    let product = std::fs::read_to_string(path.join("product")).unwrap_or_default();
+    match product.trim() {
+        "" => Ok(format!("USB port {} is in use", port)),
+        product => Ok(format!("{} on USB port {}", product, port)),
+    }
+}
+
+/// Find a program like the shell would
+fn program(name: &str) -> Check {
+    if name.is_empty() {
+        return Err("no command given".to_string());
+    }
+    if name.contains('/') {
+        return exists(Path::new(name));
+    }
+    std::env::var_os("PATH")
+        .iter()
+        .flat_map(std::env::split_paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .map(|path| format!("{} found", path.display()))
+        .ok_or_else(|| format!("{} isn't installed (not in $PATH)", name))
+}
+
+async fn reachable(host: &str, port: u16) -> Check {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(_)) => Ok(format!("{}:{} is reachable", host, port)),
+        Ok(Err(e)) => Err(format!("can't reach {}:{}: {}", host, port, e)),
+        Err(_) => Err(format!("can't reach {}:{}, timed out", host, port)),
+    }
+}
+
+/// The host and port ssh really connects to, hosts can be aliases from
+/// ~/.ssh/config
+async fn ssh_destination(info: &SshConnection) -> (String, u16) {
+    let output = tokio::process::Command::new("ssh")
+        .arg("-G")
+        .args(["-p", &info.port.to_string()])
+        .arg(&info.host)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+    let mut destination = (info.host.clone(), info.port);
+    let Ok(output) = output else {
+        return destination;
+    };
+    if !output.status.success() {
+        return destination;
+    }
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split_once(' ') {
+            Some(("hostname", host)) => destination.0 = host.to_string(),
+            Some(("port", port)) => destination.1 = port.parse().unwrap_or(info.port),
+            _ => {}
+        }
+    }
+    destination
+}
+
+async fn ssh(info: &SshConnection) -> Check {
+    let (host, port) = ssh_destination(info).await;
+    reachable(&host, port).await?;
+    // ssh runs in batch mode, so it won't ask about an unknown host key
+    let known = match port {
+        22 => host.clone(),
+        port => format!("[{}]:{}", host, port),
+    };
+    let status = tokio::process::Command::new("ssh-keygen")
+        .args(["-F", &known])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .map_err(|e| format!("can't run ssh-keygen: {}", e))?;
+    if !status.success() {
+        return Err(format!("the host key of {} isn't known, ssh to it once to accept it", known));
+    }
+    Ok(format!("{}:{} is reachable and its host key is known", host, port))
+}
@@ -0,0 +1,78 @@
+//! One-shot send and expect against a device console, the building block for
+//! shell based CI checks.
+
+use std::time::Duration;
+
+use crate::artifacts::RunDir;
+use crate::config::{ArtifactsConfig, Device};
+use crate::fleet::Access;
+use crate::{reservation, ConnectionEvent, ConnectionInput, RunningDevice};
+use anyhow::Result;
+use regex::Regex;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecOutcome {
+    /// The line that matched
+    Matched(String),
+    TimedOut,
+}
+
+/// Send `input` (if any) to the console and wait up to `timeout` for a line
+/// matching `expect`. Output is watched from before the input is sent so a
+/// fast response isn't missed.
+pub async fn exec(
+    device: Device,
+    input: Option<ConnectionInput>,
+    expect: &Regex,
+    timeout: Duration,
+    access: &Access,
+    artifacts: &ArtifactsConfig,
+) -> Result<ExecOutcome> {
+    reservation::check_access(&device.codename, &access.user, access.queue, timeout).await?;
+    let mut run_dir = RunDir::create(artifacts, &device, "exec")?;
+    let dev = RunningDevice::spawn(device);
+    if let Some(run_dir) = run_dir.as_mut() {
+        if let Err(e) = run_dir.record(&dev).await {
+            warn!("Not recording the run: {}", e);
+        }
+    }
+
+    let result = tokio::time::timeout(timeout, async {
+        let mut rx = dev.subscribe().await?;
+        if let Some(input) = input {
+            dev.send(input).await?;
+        }
+        loop {
+            match rx.recv().await {
+                Ok(ev) => {
+                    if let ConnectionEvent::NewLine(line) = ev.event {
+                        if expect.is_match(&line) {
+                            return Ok(ExecOutcome::Matched(line));
+                        }
+                    }
+                }
+                Err(RecvError::Lagged(n)) => warn!("Dropped {} lines", n),
+                Err(RecvError::Closed) => bail!("Device stopped"),
+            }
+        }
+    })
+    .await
+    .unwrap_or(Ok(ExecOutcome::TimedOut));
+
+    let state = dev.current_state();
+    let result = dev.shutdown().await.and(result);
+    if let Some(mut run_dir) = run_dir {
+        let error = match &result {
+            Ok(ExecOutcome::Matched(_)) => None,
+            Ok(ExecOutcome::TimedOut) => Some(format!("Timed out waiting for {}", expect)),
+            Err(e) => Some(e.to_string()),
+        };
+        run_dir.finish(state, error).await;
+        info!("Artifacts in {}", run_dir.path.display());
+        if let Some(url) = &run_dir.metadata.url {
+            info!("Artifacts uploaded to {}", url);
+        }
+    }
+    result
+}
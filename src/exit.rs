@@ -0,0 +1,84 @@
+//! Exit codes for automation commands (trigger, wait, exec), so CI can branch
+//! on why something failed instead of grepping stderr. Errors are classified
+//! where they happen by making them with a [Failure].
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+pub const SUCCESS: i32 = 0;
+/// Any failure that isn't one of the below
+pub const FAILURE: i32 = 1;
+pub const CONFIG: i32 = 2;
+pub const CONNECTION: i32 = 3;
+pub const RESERVED: i32 = 4;
+/// The same as timeout(1)
+pub const TIMEOUT: i32 = 124;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Failure {
+    /// The config couldn't be loaded or has errors
+    Config,
+    /// A connection couldn't be opened or was lost
+    Connection,
+    /// The device is reserved by somebody else
+    Reserved,
+    /// The device didn't reach the expected state (or output) in time
+    Timeout,
+}
+
+#[derive(Debug)]
+struct Classified {
+    failure: Failure,
+    message: String,
+}
+
+impl Display for Classified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Classified {}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Config => CONFIG,
+            Failure::Connection => CONNECTION,
+            Failure::Reserved => RESERVED,
+            Failure::Timeout => TIMEOUT,
+        }
+    }
+
+    /// An error of this kind
+    pub fn error(self, message: impl Display) -> anyhow::Error {
+        anyhow::Error::new(Classified {
+            failure: self,
+            message: message.to_string(),
+        })
+    }
+
+    /// Mark an error as this kind, unless it has already been classified
+    pub fn wrap(self, e: anyhow::Error) -> anyhow::Error {
+        match classify(&e) {
+            Some(_) => e,
+            None => self.error(e),
+        }
+    }
+}
+
+/// The kind of failure an error is, if known
+pub fn classify(e: &anyhow::Error) -> Option<Failure> {
+    e.chain()
+        .find_map(|e| e.downcast_ref::<Classified>())
+        .map(|c| c.failure)
+}
+
+/// The exit code for an error
+pub fn code(e: &anyhow::Error) -> i32 {
+    classify(e).map_or(FAILURE, Failure::code)
+}
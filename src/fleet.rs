@@ -0,0 +1,436 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use crate::artifacts::RunDir;
+use crate::config::{ArtifactsConfig, Device};
+use crate::exit::{self, Failure};
+use crate::reservation;
+use crate::vars::Vars;
+use crate::{RunningDevice, TriggerOptions};
+use anyhow::Result;
+use futures::future::join_all;
+use serde::ser::{Serialize, SerializeStruct};
+use serde::Deserialize;
+use strum_macros::Display;
+
+/// A tag expression. Terms separated by `,` must all match, alternatives are
+/// separated by `|`, a term starting with `!` negates it and a term ending in
+/// `*` matches by prefix. e.g. `soc:sdm845,!owner:caleb|soc:sm8150`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagFilter {
+    alternatives: Vec<Vec<(bool, String)>>,
+}
+
+impl std::str::FromStr for TagFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let alternatives = s
+            .split('|')
+            .map(|alt| {
+                alt.split(',')
+                    .map(|term| {
+                        let term = term.trim();
+                        let (negate, tag) = match term.strip_prefix('!') {
+                            Some(tag) => (true, tag.trim()),
+                            None => (false, term),
+                        };
+                        if tag.is_empty() {
+                            bail!("Empty tag in expression {:?}", s);
+                        }
+                        Ok((negate, tag.to_string()))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { alternatives })
+    }
+}
+
+impl TagFilter {
+    pub fn matches(&self, tags: &[String]) -> bool {
+        let has = |pattern: &str| match pattern.strip_suffix('*') {
+            Some(prefix) => tags.iter().any(|t| t.starts_with(prefix)),
+            None => tags.iter().any(|t| t == pattern),
+        };
+        self.alternatives
+            .iter()
+            .any(|terms| terms.iter().all(|(negate, tag)| has(tag) != *negate))
+    }
+}
+
+/// Which devices an operation applies to
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub all: bool,
+    pub group: Option<String>,
+    pub devices: Vec<String>,
+    /// Further restricts the selection to devices matching these tags
+    pub tags: Option<TagFilter>,
+}
+
+/// Anything that can be picked out by a `Selection`
+pub trait Selectable {
+    fn codename(&self) -> &str;
+    fn groups(&self) -> &[String];
+    fn tags(&self) -> &[String];
+}
+
+impl Selectable for Device {
+    fn codename(&self) -> &str {
+        &self.codename
+    }
+
+    fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    fn tags(&self) -> &[String] {
+        &self.tags
+    }
+}
+
+impl Selection {
+    pub fn select<T: Selectable>(&self, devices: Vec<T>) -> Result<Vec<T>> {
+        let devices: Vec<T> = match &self.tags {
+            Some(filter) => devices.into_iter().filter(|d| filter.matches(d.tags())).collect(),
+            None => devices,
+        };
+        let selected: Vec<T> = if self.all || (self.tags.is_some() && self.group.is_none() && self.devices.is_empty()) {
+            devices
+        } else if let Some(group) = &self.group {
+            devices.into_iter().filter(|d| d.groups().contains(group)).collect()
+        } else if !self.devices.is_empty() {
+            if let Some(missing) = self
+                .devices
+                .iter()
+                .find(|c| !devices.iter().any(|d| d.codename() == c.as_str()))
+            {
+                bail!("No such device {}", missing);
+            }
+            devices
+                .into_iter()
+                .filter(|d| self.devices.iter().any(|c| c == d.codename()))
+                .collect()
+        } else if devices.len() == 1 {
+            devices
+        } else {
+            bail!("Multiple devices configured, use --device, --group or --all");
+        };
+
+        if selected.is_empty() {
+            bail!("No devices selected");
+        }
+        Ok(selected)
+    }
+}
+
+/// What to do with a device's power control, see [crate::config::PowerConfig]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, serde::Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum PowerAction {
+    On,
+    Off,
+    /// Off, then on again after the cycle delay
+    Cycle,
+}
+
+impl std::str::FromStr for PowerAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "on" => PowerAction::On,
+            "off" => PowerAction::Off,
+            "cycle" => PowerAction::Cycle,
+            _ => bail!("Invalid power action {:?}, expected on, off or cycle", s),
+        })
+    }
+}
+
+/// An operation to perform on each selected device
+#[derive(Debug, Clone)]
+pub enum Operation {
+    /// Run a trigger with the given variables, optionally waiting for a state
+    /// afterwards. Forcing runs it even if it's interlocked in the current state.
+    Trigger {
+        name: String,
+        wait: Option<String>,
+        vars: Vars,
+        force: bool,
+    },
+    /// Wait for a state, the device's resting state if none is given
+    Wait { state: Option<String> },
+    /// Use the power control, optionally waiting for the state it leads to
+    Power { action: PowerAction, wait: bool },
+}
+
+impl Operation {
+    /// Describes the operation in run directory names
+    pub fn kind(&self) -> String {
+        match self {
+            Operation::Trigger { name, .. } => format!("trigger-{}", name),
+            Operation::Wait { .. } => "wait".to_string(),
+            Operation::Power { action, .. } => format!("power-{}", action),
+        }
+    }
+}
+
+pub struct DeviceResult {
+    pub codename: String,
+    pub result: Result<Option<String>>,
+    /// Where the artifacts of the run were uploaded to
+    pub url: Option<String>,
+}
+
+impl Serialize for DeviceResult {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("DeviceResult", 6)?;
+        s.serialize_field("codename", &self.codename)?;
+        s.serialize_field("ok", &self.result.is_ok())?;
+        s.serialize_field("state", &self.result.as_ref().ok().cloned().flatten())?;
+        s.serialize_field("error", &self.result.as_ref().err().map(|e| e.to_string()))?;
+        s.serialize_field("failure", &self.result.as_ref().err().and_then(exit::classify))?;
+        s.serialize_field("url", &self.url)?;
+        s.end()
+    }
+}
+
+impl Display for DeviceResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.result {
+            Ok(state) => write!(
+                f,
+                "{}: ok (state {})",
+                self.codename,
+                state.as_deref().unwrap_or("unknown")
+            ),
+            Err(e) => write!(f, "{}: FAILED: {}", self.codename, e),
+        }?;
+        match &self.url {
+            Some(url) => write!(f, ", artifacts at {}", url),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Who an operation is performed on behalf of, used to honour reservations
+#[derive(Debug, Clone)]
+pub struct Access {
+    pub user: String,
+    /// Wait for other users' reservations to be released instead of refusing
+    pub queue: bool,
+}
+
+async fn run_one(
+    device: Device,
+    op: Operation,
+    timeout: Duration,
+    access: Access,
+    artifacts: ArtifactsConfig,
+) -> DeviceResult {
+    let codename = device.codename.clone();
+    if let Err(e) = reservation::check_access(&codename, &access.user, access.queue, timeout).await {
+        return DeviceResult {
+            codename,
+            result: Err(e),
+            url: None,
+        };
+    }
+    let mut run_dir = RunDir::create(&artifacts, &device, &op.kind()).unwrap_or_else(|e| {
+        warn!("{}: not saving artifacts: {}", codename, e);
+        None
+    });
+    let resting = device.resting_state.clone();
+    let dev = RunningDevice::spawn(device);
+    if let Some(run_dir) = run_dir.as_mut() {
+        if let Err(e) = run_dir.record(&dev).await {
+            warn!("{}: not recording the run: {}", codename, e);
+        }
+    }
+
+    let result = tokio::time::timeout(timeout, async {
+        let target = match op {
+            Operation::Trigger { name, wait, vars, force } => {
+                if let Some(run_dir) = run_dir.as_mut() {
+                    run_dir.add_trigger(&name);
+                }
+                let opts = TriggerOptions {
+                    vars,
+                    user: Some(access.user.clone()),
+                    force,
+                };
+                dev.run_trigger_with(&name, opts).await?;
+                wait
+            }
+            Operation::Wait { state } => Some(
+                state
+                    .or(resting)
+                    .ok_or_else(|| anyhow!("No state given and no resting state configured"))?,
+            ),
+            Operation::Power { action, wait } => dev.power(action).await?.filter(|_| wait),
+        };
+        if let Some(target) = target {
+            dev.wait_for_state(&target).await?;
+        }
+        Ok::<(), anyhow::Error>(())
+    })
+    .await
+    .unwrap_or_else(|_| Err(Failure::Timeout.error(format!("Timed out after {}s", timeout.as_secs()))));
+
+    let state = dev.current_state();
+    let result = match dev.shutdown().await {
+        // The device loop exiting on its own means it failed
+        Err(e) => Err(e),
+        Ok(()) => result.map(|_| state.clone()),
+    };
+    let mut url = None;
+    if let Some(mut run_dir) = run_dir {
+        run_dir
+            .finish(state, result.as_ref().err().map(|e| e.to_string()))
+            .await;
+        info!("{}: artifacts in {}", codename, run_dir.path.display());
+        url = run_dir.metadata.url;
+    }
+
+    DeviceResult { codename, result, url }
+}
+
+/// Run an operation concurrently on all devices and collect the results
+pub async fn run(
+    devices: Vec<Device>,
+    op: Operation,
+    timeout: Duration,
+    access: Access,
+    artifacts: &ArtifactsConfig,
+) -> Vec<DeviceResult> {
+    join_all(
+        devices
+            .into_iter()
+            .map(|d| run_one(d, op.clone(), timeout, access.clone(), artifacts.clone())),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    fn filter(expr: &str) -> TagFilter {
+        expr.parse().unwrap()
+    }
+
+    #[test]
+    fn tag_filter_terms() {
+        let board = tags(&["soc:sdm845", "owner:caleb"]);
+        assert!(filter("soc:sdm845").matches(&board));
+        assert!(filter("soc:sdm845,owner:caleb").matches(&board));
+        assert!(!filter("soc:sdm845,owner:alice").matches(&board));
+        assert!(!filter("soc:sdm845,!owner:caleb").matches(&board));
+        assert!(filter("soc:sdm845,!owner:alice").matches(&board));
+    }
+
+    #[test]
+    fn tag_filter_prefix_and_alternatives() {
+        let board = tags(&["soc:sdm845"]);
+        assert!(filter("soc:*").matches(&board));
+        assert!(!filter("soc:sm*").matches(&board));
+        assert!(!filter("soc:sdm845,!owner:*").matches(&tags(&["soc:sdm845", "owner:caleb"])));
+        assert!(filter("soc:sm8150|soc:sdm845").matches(&board));
+        assert!(!filter("soc:sm8150|owner:caleb").matches(&board));
+        // Whole tags only, not substrings
+        assert!(!filter("soc:sdm").matches(&board));
+    }
+
+    #[test]
+    fn tag_filter_parse() {
+        assert_eq!(filter(" soc:sdm845 , ! owner:caleb "), filter("soc:sdm845,!owner:caleb"));
+        assert!("soc:sdm845,".parse::<TagFilter>().is_err());
+        assert!("soc:sdm845|".parse::<TagFilter>().is_err());
+        assert!("!".parse::<TagFilter>().is_err());
+    }
+
+    struct Board {
+        codename: String,
+        groups: Vec<String>,
+        tags: Vec<String>,
+    }
+
+    impl Selectable for Board {
+        fn codename(&self) -> &str {
+            &self.codename
+        }
+
+        fn groups(&self) -> &[String] {
+            &self.groups
+        }
+
+        fn tags(&self) -> &[String] {
+            &self.tags
+        }
+    }
+
+    fn boards() -> Vec<Board> {
+        [("sdm845", "phones", "soc:sdm845"), ("sm8150", "phones", "soc:sm8150"), ("rpi4", "sbcs", "soc:bcm2711")]
+            .iter()
+            .map(|(codename, group, tag)| Board {
+                codename: codename.to_string(),
+                groups: tags(&[group]),
+                tags: tags(&[tag]),
+            })
+            .collect()
+    }
+
+    fn selected(selection: Selection) -> Result<Vec<String>> {
+        Ok(selection.select(boards())?.into_iter().map(|b| b.codename).collect())
+    }
+
+    #[test]
+    fn selection() {
+        assert_eq!(selected(Selection { all: true, ..Default::default() }).unwrap().len(), 3);
+        let group = Selection {
+            group: Some("phones".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(selected(group).unwrap(), ["sdm845", "sm8150"]);
+        let device = Selection {
+            devices: tags(&["rpi4"]),
+            ..Default::default()
+        };
+        assert_eq!(selected(device).unwrap(), ["rpi4"]);
+        let missing = Selection {
+            devices: tags(&["nope"]),
+            ..Default::default()
+        };
+        assert!(selected(missing).is_err());
+        // Several devices and nothing selected
+        assert!(selected(Selection::default()).is_err());
+    }
+
+    #[test]
+    fn selection_by_tags() {
+        // Tags alone select every device that matches
+        let tagged = Selection {
+            tags: Some(filter("soc:sdm845|soc:bcm2711")),
+            ..Default::default()
+        };
+        assert_eq!(selected(tagged).unwrap(), ["sdm845", "rpi4"]);
+        // With a group they narrow it down
+        let narrowed = Selection {
+            group: Some("phones".to_string()),
+            tags: Some(filter("soc:sm8150")),
+            ..Default::default()
+        };
+        assert_eq!(selected(narrowed).unwrap(), ["sm8150"]);
+        let none = Selection {
+            tags: Some(filter("soc:nope")),
+            ..Default::default()
+        };
+        assert!(selected(none).is_err());
+    }
+}
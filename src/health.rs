@@ -0,0 +1,219 @@
+//! Keepalive probes for connections, so a console that has silently stopped
+//! working (a wedged USB serial adapter, an unreachable SSH host) is noticed
+//! instead of looking like a quiet device. A connection is degraded after a
+//! failed probe and hung after [ProbeConfig::failures] of them in a row.
+
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use crate::config::{ConnectionInfo, ProbeConfig};
+use crate::connections::{ConnectionInput, SshControl};
+use crate::Event;
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Extra time an SSH probe is given to report back, on top of its timeout
+const SSH_PROBE_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Health {
+    Healthy,
+    /// The last probe failed
+    Degraded,
+    /// Too many probes in a row failed
+    Hung,
+}
+
+/// The health of each probed connection, by label
+pub type HealthMap = BTreeMap<String, Health>;
+
+enum Target {
+    /// Send to the console and wait for a line in response
+    Console { send: String, expect: Option<Regex> },
+    /// Run `true` on the host
+    Ssh(SshControl),
+}
+
+struct Probe {
+    connection: String,
+    config: ProbeConfig,
+    target: Target,
+    /// When the connection last showed it was alive, or was last probed
+    alive: Instant,
+    /// When the outstanding probe fails if there's no response
+    deadline: Option<Instant>,
+    failures: u32,
+}
+
+impl Probe {
+    fn health(&self) -> Health {
+        match self.failures {
+            0 => Health::Healthy,
+            n if n < self.config.failures => Health::Degraded,
+            _ => Health::Hung,
+        }
+    }
+
+    /// Record the outcome of a probe, returns the new health if it changed
+    fn outcome(&mut self, ok: bool, now: Instant) -> Option<Health> {
+        let before = self.health();
+        self.deadline = None;
+        self.alive = now;
+        self.failures = if ok { 0 } else { self.failures + 1 };
+        let after = self.health();
+        (before != after).then_some(after)
+    }
+}
+
+/// The probes of a device's connections, driven by the device loop
+pub struct Probes {
+    probes: Vec<Probe>,
+    tx: UnboundedSender<Event>,
+    input: UnboundedSender<ConnectionInput>,
+}
+
+impl Probes {
+    pub fn new(
+        connections: &[ConnectionInfo],
+        ssh: &[SshControl],
+        tx: UnboundedSender<Event>,
+        input: UnboundedSender<ConnectionInput>,
+    ) -> Result<Self> {
+        let now = Instant::now();
+        let mut probes = vec![];
+        for info in connections {
+            let Some(config) = info.probe() else {
+                continue;
+            };
+            let target = match info {
+                ConnectionInfo::Ssh(info) => Target::Ssh(
+                    ssh.iter()
+                        .find(|s| s.name() == info.label)
+                        .cloned()
+                        .ok_or_else(|| anyhow!("No ssh connection {}", info.label))?,
+                ),
+                _ => Target::Console {
+                    send: config.send.clone(),
+                    expect: config
+                        .expect
+                        .as_deref()
+                        .map(Regex::new)
+                        .transpose()
+                        .map_err(|e| anyhow!("Invalid probe regex for {}: {}", info.label(), e))?,
+                },
+            };
+            probes.push(Probe {
+                connection: info.label().to_string(),
+                config: config.clone(),
+                target,
+                alive: now,
+                deadline: None,
+                failures: 0,
+            });
+        }
+        Ok(Self { probes, tx, input })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+
+    /// The health of every probed connection, they start out healthy
+    pub fn health(&self) -> HealthMap {
+        self.probes.iter().map(|p| (p.connection.clone(), p.health())).collect()
+    }
+
+    /// A line was received on `connection`, returns its new health if the
+    /// line answered a probe and that changed it
+    pub fn seen(&mut self, connection: &str, line: &str, now: Instant) -> Option<Health> {
+        let probe = self.probes.iter_mut().find(|p| p.connection == connection)?;
+        let Target::Console { expect, .. } = &probe.target else {
+            return None;
+        };
+        if probe.deadline.is_none() {
+            // Output is as good as a response, no need to probe for a while
+            probe.alive = now;
+            return None;
+        }
+        if expect.as_ref().is_some_and(|re| !re.is_match(line)) {
+            return None;
+        }
+        probe.outcome(true, now)
+    }
+
+    /// An SSH probe finished
+    pub fn result(&mut self, connection: &str, ok: bool, now: Instant) -> Option<Health> {
+        let probe = self.probes.iter_mut().find(|p| p.connection == connection)?;
+        // It already timed out
+        probe.deadline?;
+        probe.outcome(ok, now)
+    }
+
+    /// Time out outstanding probes and start any that are due, returns the
+    /// connections whose health changed
+    pub fn poll(&mut self, now: Instant) -> Vec<(String, Health)> {
+        let mut changed = vec![];
+        for probe in self.probes.iter_mut() {
+            if let Some(deadline) = probe.deadline {
+                if now >= deadline {
+                    debug!("{}: probe timed out", probe.connection);
+                    if let Some(health) = probe.outcome(false, now) {
+                        changed.push((probe.connection.clone(), health));
+                    }
+                }
+                continue;
+            }
+            if now.saturating_duration_since(probe.alive) < Duration::from_millis(probe.config.interval as u64) {
+                continue;
+            }
+            let timeout = Duration::from_millis(probe.config.timeout as u64);
+            trace!("{}: probing", probe.connection);
+            match &probe.target {
+                Target::Console { send, .. } => {
+                    let _ = self.input.send(ConnectionInput {
+                        connection: Some(probe.connection.clone()),
+                        data: send.clone().into(),
+                    });
+                    probe.deadline = Some(now + timeout);
+                }
+                // Bootstrapped connections aren't probed until they're up
+                Target::Ssh(ctrl) if !ctrl.is_up() => probe.alive = now,
+                Target::Ssh(ctrl) => {
+                    tokio::spawn(ssh_probe(ctrl.clone(), timeout, self.tx.clone()));
+                    probe.deadline = Some(now + timeout + SSH_PROBE_GRACE);
+                }
+            }
+        }
+        changed
+    }
+}
+
+async fn ssh_probe(ctrl: SshControl, timeout: Duration, tx: UnboundedSender<Event>) {
+    let Ok(cmd) = ctrl.command("true") else {
+        let _ = tx.send(Event::Probe {
+            connection: ctrl.name().to_string(),
+            ok: false,
+        });
+        return;
+    };
+    let mut cmd = tokio::process::Command::from(cmd);
+    cmd.stdout(Stdio::null()).stderr(Stdio::null()).kill_on_drop(true);
+    let ok = match tokio::time::timeout(timeout, cmd.status()).await {
+        Ok(Ok(status)) => status.success(),
+        Ok(Err(e)) => {
+            warn!("{}: failed to run ssh: {}", ctrl.name(), e);
+            false
+        }
+        Err(_) => false,
+    };
+    let _ = tx.send(Event::Probe {
+        connection: ctrl.name().to_string(),
+        ok,
+    });
+}
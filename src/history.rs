@@ -0,0 +1,233 @@
+//! Searching the console history of a device, i.e. the console logs of its
+//! run artifact directories (see [crate::artifacts]). Compressed logs are read
+//! transparently, and runs whose index shows they can't match are skipped.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::artifacts::{read_log, read_metadata, runs, CONSOLE_LOG, STATES_LOG};
+use crate::config::ArtifactsConfig;
+use anyhow::Result;
+use chrono::{DateTime, Duration, FixedOffset, Local};
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    pub pattern: Regex,
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+    /// Only search lines from this connection
+    pub connection: Option<String>,
+    /// Lines of context to include before and after each match
+    pub context: usize,
+}
+
+/// A line of a console log
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    pub timestamp: String,
+    pub connection: String,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Match {
+    pub codename: String,
+    /// The run directory the match is from
+    pub run: PathBuf,
+    /// The state of the device when the line was received
+    pub state: Option<String>,
+    pub before: Vec<LogLine>,
+    #[serde(flatten)]
+    pub line: LogLine,
+    pub after: Vec<LogLine>,
+}
+
+impl Display for LogLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.timestamp, self.connection, self.line)
+    }
+}
+
+impl Display for Match {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let run = self.run.file_name().unwrap_or_default().to_string_lossy();
+        for line in self.before.iter() {
+            writeln!(f, "{} {}- {}", self.codename, run, line)?;
+        }
+        write!(
+            f,
+            "{} {}: {} [{}]",
+            self.codename,
+            run,
+            self.line,
+            self.state.as_deref().unwrap_or("unknown")
+        )?;
+        for line in self.after.iter() {
+            write!(f, "\n{} {}- {}", self.codename, run, line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a time for the search range, either RFC 3339 or relative to now
+/// like `30m`, `2h` or `1d` (seconds, minutes, hours, days)
+pub fn parse_time(s: &str) -> Result<DateTime<FixedOffset>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t);
+    }
+    let unit = s.chars().last().ok_or_else(|| anyhow!("Empty time"))?;
+    // Unsigned, a negative time would be in the future
+    let n: u32 = s[..s.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| anyhow!("Invalid time {:?}, expected RFC 3339 or e.g. 2h", s))?;
+    let n = i64::from(n);
+    let ago = match unit {
+        's' => Duration::seconds(n),
+        'm' => Duration::minutes(n),
+        'h' => Duration::hours(n),
+        'd' => Duration::days(n),
+        _ => bail!("Invalid time unit in {:?}, expected s, m, h or d", s),
+    };
+    let t = Local::now()
+        .checked_sub_signed(ago)
+        .ok_or_else(|| anyhow!("Time {:?} is too long ago", s))?;
+    Ok(DateTime::<FixedOffset>::from(t))
+}
+
+/// Split a `<timestamp>\t<value>...` log line
+fn parse_ts(line: &str) -> Option<(DateTime<FixedOffset>, &str)> {
+    let (ts, rest) = line.split_once('\t')?;
+    Some((DateTime::parse_from_rfc3339(ts).ok()?, rest))
+}
+
+fn read_states(run: &Path) -> Vec<(DateTime<FixedOffset>, String)> {
+    read_log(run, STATES_LOG)
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| parse_ts(l).map(|(ts, state)| (ts, state.to_string())))
+        .collect()
+}
+
+/// Whether a run could have lines in the search range, going by its index
+fn in_range(run: &Path, opts: &SearchOptions) -> bool {
+    let Some(index) = read_metadata(run).ok().and_then(|m| m.console) else {
+        // No index yet, the run is still going or was interrupted
+        return true;
+    };
+    if opts.connection.as_ref().is_some_and(|c| !index.connections.contains(c)) {
+        return false;
+    }
+    let time = |t: Option<String>| t.and_then(|t| DateTime::parse_from_rfc3339(&t).ok());
+    match (time(index.first), time(index.last)) {
+        (Some(first), Some(last)) => {
+            !opts.since.is_some_and(|since| last < since) && !opts.until.is_some_and(|until| first > until)
+        }
+        // No lines at all
+        _ => index.lines > 0,
+    }
+}
+
+/// Search the console history of a device
+pub fn search(config: &ArtifactsConfig, codename: &str, opts: &SearchOptions) -> Result<Vec<Match>> {
+    let mut matches = vec![];
+    for run in runs(config, codename)?.into_iter().filter(|r| in_range(r, opts)) {
+        let Some(console) = read_log(&run, CONSOLE_LOG)? else {
+            continue;
+        };
+        let states = read_states(&run);
+        let mut before: VecDeque<LogLine> = VecDeque::with_capacity(opts.context);
+        // Matches still collecting lines of context after them
+        let mut pending: Vec<Match> = vec![];
+        for raw in console.lines() {
+            let Some((ts, rest)) = parse_ts(raw) else {
+                continue;
+            };
+            if opts.since.is_some_and(|since| ts < since) {
+                continue;
+            }
+            if opts.until.is_some_and(|until| ts > until) {
+                break;
+            }
+            let (connection, line) = rest.split_once('\t').unwrap_or(("", rest));
+            if opts.connection.as_deref().is_some_and(|c| c != connection) {
+                continue;
+            }
+            let line = LogLine {
+                timestamp: raw[..raw.len() - rest.len() - 1].to_string(),
+                connection: connection.to_string(),
+                line: line.to_string(),
+            };
+
+            for m in pending.iter_mut().filter(|m| m.after.len() < opts.context) {
+                m.after.push(line.clone());
+            }
+            let (done, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|m| m.after.len() >= opts.context);
+            matches.extend(done);
+            pending = waiting;
+
+            if opts.pattern.is_match(&line.line) {
+                pending.push(Match {
+                    codename: codename.to_string(),
+                    run: run.clone(),
+                    state: states
+                        .iter()
+                        .take_while(|(at, _)| *at <= ts)
+                        .last()
+                        .map(|(_, s)| s.clone()),
+                    before: before.iter().cloned().collect(),
+                    line: line.clone(),
+                    after: vec![],
+                });
+            }
+            if opts.context > 0 {
+                if before.len() == opts.context {
+                    before.pop_front();
+                }
+                before.push_back(line);
+            }
+        }
+        matches.extend(pending);
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absolute_time() {
+        let t = parse_time("2024-05-01T10:00:00+02:00").unwrap();
+        assert_eq!(t.to_rfc3339(), "2024-05-01T10:00:00+02:00");
+    }
+
+    #[test]
+    fn relative_time() {
+        let ago = |s: &str| {
+            // Parsed first so the time it's relative to isn't after now
+            let t = parse_time(s).unwrap();
+            Local::now().signed_duration_since(t)
+        };
+        for (s, expected) in [
+            ("30s", Duration::seconds(30)),
+            ("30m", Duration::minutes(30)),
+            ("2h", Duration::hours(2)),
+            ("1d", Duration::days(1)),
+        ] {
+            let diff = ago(s) - expected;
+            assert!(diff >= Duration::zero() && diff < Duration::seconds(5), "{}: off by {}", s, diff);
+        }
+    }
+
+    #[test]
+    fn invalid_time() {
+        for s in ["", "2", "h", "2w", "-2h", "2.5h", "4000000000d", "99999999d"] {
+            assert!(parse_time(s).is_err(), "{:?} parsed", s);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Host commands run when the device changes state, to glue existing shell
+//! tooling to state changes. Hooks are declared on states (`on-enter`,
+//! `on-exit`) and transitions (`hooks`) and run with `sh -c` in the background
+//! with the change described by their environment:
+//!
+//! * `FBUG_DEVICE`: the codename of the device
+//! * `FBUG_FROM`: the previous state, empty if it wasn't known
+//! * `FBUG_TO`: the new state
+//! * `FBUG_CONNECTION`, `FBUG_LINE`: the connection and line that caused the
+//!   transition, empty if it wasn't caused by a line
+//! * `FBUG_VAR_<name>`: the [variables](crate::vars) of the device, including
+//!   those captured from the console
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::vars::Vars;
+use crate::{log_target, DeviceEvent};
+use tokio::sync::broadcast::Sender;
+
+/// How long a hook may run before it's killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A change of state to run hooks for
+#[derive(Debug, Clone)]
+pub struct HookContext {
+    pub codename: String,
+    pub from: Option<String>,
+    pub to: String,
+    /// The connection and line that caused the transition
+    pub line: Option<(String, String)>,
+    /// Variables from the config and captured from the console
+    pub vars: Vars,
+}
+
+async fn run_hook(command: &str, ctx: &HookContext) -> Result<String, String> {
+    let (connection, line) = ctx.line.clone().unwrap_or_default();
+    let mut cmd = tokio::process::Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("FBUG_DEVICE", &ctx.codename)
+        .env("FBUG_FROM", ctx.from.as_deref().unwrap_or_default())
+        .env("FBUG_TO", &ctx.to)
+        .env("FBUG_CONNECTION", connection)
+        .env("FBUG_LINE", line)
+        .envs(ctx.vars.iter().map(|(name, value)| (format!("FBUG_VAR_{}", name), value)))
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    let output = match tokio::time::timeout(HOOK_TIMEOUT, cmd.output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => return Err(format!("failed to run: {}", e)),
+        Err(_) => return Err(format!("timed out after {:?}", HOOK_TIMEOUT)),
+    };
+    let target = log_target(&ctx.codename, None);
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines() {
+        info!(target: &target, "hook: {}", line);
+    }
+    for line in stderr.lines() {
+        warn!(target: &target, "hook: {}", line);
+    }
+    if !output.status.success() {
+        return Err(format!("failed ({}): {}", output.status, stderr.trim()));
+    }
+    Ok(stdout)
+}
+
+/// Run the hooks for a change of state one after the other in the background,
+/// publishing each one's output to `events_tx` as it finishes
+pub fn spawn(hooks: Vec<String>, ctx: HookContext, events_tx: &Sender<DeviceEvent>) {
+    if hooks.is_empty() {
+        return;
+    }
+    let events_tx = events_tx.clone();
+    tokio::spawn(async move {
+        for command in hooks {
+            debug!("{}: running hook {:?}", ctx.codename, command);
+            let result = run_hook(&command, &ctx).await;
+            if let Err(e) = &result {
+                error!("{}: hook {:?} {}", ctx.codename, command, e);
+            }
+            let _ = events_tx.send(DeviceEvent::Hook { command, result });
+        }
+    });
+}
@@ -0,0 +1,139 @@
+//! Working out which state the device is in when fbug starts, instead of
+//! starting out in no state and matching against every transition. A line is
+//! sent to the console (an empty one by default, which makes a shell or
+//! bootloader print its prompt again) and the output is matched against the
+//! `identify` regexes of each state, the first state with one that matches
+//! is entered. If nothing is printed at all before the timeout the device is
+//! in the `silent` state, if set. Probing stops as soon as the state is known
+//! some other way, e.g. a transition's action matched.
+
+use std::time::{Duration, Instant};
+
+use crate::config::{Device, IdentifyConfig};
+use crate::connections::ConnectionInput;
+use crate::state::StateMachine;
+use crate::thermal::THERMAL_SOURCE;
+use crate::CONNECTIONS_SOURCE;
+use anyhow::Result;
+use regex::Regex;
+use tokio::sync::mpsc::UnboundedSender;
+
+pub struct Identify {
+    config: IdentifyConfig,
+    /// The identifying regexes of each state, in config order
+    states: Vec<(String, Vec<Regex>)>,
+    input: UnboundedSender<ConnectionInput>,
+    /// When probing gives up, None once it's finished
+    deadline: Option<Instant>,
+    /// Whether anything was printed since probing started
+    heard: bool,
+}
+
+impl Identify {
+    pub fn new(config: &IdentifyConfig, device: &Device, input: UnboundedSender<ConnectionInput>) -> Result<Self> {
+        let states = device
+            .states
+            .iter()
+            .filter(|s| !s.identify.is_empty())
+            .map(|s| {
+                let regexes = s
+                    .identify
+                    .iter()
+                    .map(|re| Regex::new(re).map_err(|e| anyhow!("Invalid identify regex for {}: {}", s.name, e)))
+                    .collect::<Result<_>>()?;
+                Ok((s.name.clone(), regexes))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(silent) = &config.silent {
+            if !device.states.iter().any(|s| &s.name == silent) {
+                bail!("Silent state {} doesn't exist", silent);
+            }
+        }
+        if states.is_empty() && config.silent.is_none() {
+            bail!("Identifying the state needs states with identify regexes, or a silent state");
+        }
+        Ok(Self {
+            config: config.clone(),
+            states,
+            input,
+            deadline: None,
+            heard: false,
+        })
+    }
+
+    pub fn busy(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// Send the probe and start watching the output
+    pub fn start(&mut self, now: Instant) {
+        debug!("Probing for the initial state");
+        let _ = self.input.send(ConnectionInput {
+            connection: self.config.connection.clone(),
+            data: self.config.send.clone().into(),
+        });
+        self.deadline = Some(now + Duration::from_millis(self.config.timeout as u64));
+        self.heard = false;
+    }
+
+    /// Whether `connection` is the one being probed, any real connection if
+    /// none was named
+    fn watching(&self, connection: &str) -> bool {
+        match &self.config.connection {
+            Some(c) => c == connection,
+            None => connection != CONNECTIONS_SOURCE && connection != THERMAL_SOURCE,
+        }
+    }
+
+    /// A line (or a prompt that doesn't end the line) was received, returns
+    /// the state it identifies
+    pub fn seen(&mut self, connection: &str, line: &str, sm: &StateMachine) -> Option<String> {
+        if !self.busy() || !self.watching(connection) {
+            return None;
+        }
+        if sm.current_state().is_some() {
+            self.deadline = None;
+            return None;
+        }
+        // Most likely the echo of the probe
+        if line.trim().is_empty() {
+            return None;
+        }
+        self.heard = true;
+        let (state, _) = self
+            .states
+            .iter()
+            .find(|(_, regexes)| regexes.iter().any(|re| re.is_match(line)))?;
+        info!("Identified state {} from {:?}", state, line);
+        self.deadline = None;
+        Some(state.clone())
+    }
+
+    /// Give up once the timeout has passed, returns the silent state if
+    /// nothing was printed
+    pub fn poll(&mut self, sm: &StateMachine, now: Instant) -> Option<String> {
+        let deadline = self.deadline?;
+        if sm.current_state().is_some() {
+            self.deadline = None;
+            return None;
+        }
+        if now < deadline {
+            return None;
+        }
+        self.deadline = None;
+        if self.heard {
+            warn!("Couldn't identify the initial state from the output");
+            return None;
+        }
+        match &self.config.silent {
+            Some(silent) => {
+                info!("Nothing was printed, identified state {}", silent);
+                Some(silent.clone())
+            }
+            None => {
+                warn!("Nothing was printed, couldn't identify the initial state");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Quieting down devices nobody is using, so one small host can keep dozens
+//! of them. Once a device has sat in its resting state for a while with no
+//! console clients, no requests and no console output it goes idle: the
+//! connections listed in `close` (like a noisy secondary console) are closed,
+//! and health probes and temperature sampling are paused. A client attaching,
+//! a request, leaving the resting state or a console line (one matching
+//! `wake`, if set) wakes it up again and the connections are reopened.
+
+use std::time::{Duration, Instant};
+
+use crate::config::{ConnectionInfo, Device, IdleConfig};
+use crate::connections::ConnectionChange;
+use crate::state::StateMachine;
+use crate::CONNECTIONS_SOURCE;
+use anyhow::Result;
+use regex::Regex;
+use tokio::sync::mpsc::UnboundedSender;
+
+pub struct Idle {
+    after: Duration,
+    resting: String,
+    wake: Option<Regex>,
+    /// The connections to close while idle
+    close: Vec<ConnectionInfo>,
+    changes: UnboundedSender<ConnectionChange>,
+    /// When something last happened
+    active: Instant,
+    idle: bool,
+}
+
+impl Idle {
+    pub fn new(config: &IdleConfig, device: &Device, changes: UnboundedSender<ConnectionChange>) -> Result<Self> {
+        let resting = device
+            .resting_state
+            .clone()
+            .ok_or_else(|| anyhow!("Idle mode needs a resting state"))?;
+        let wake = config
+            .wake
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| anyhow!("Invalid idle wake regex: {}", e))?;
+        let close = config
+            .close
+            .iter()
+            .map(|label| {
+                device
+                    .connections
+                    .iter()
+                    .find(|c| c.label() == label.as_str())
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Idle connection {} doesn't exist", label))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            after: Duration::from_millis(config.after as u64),
+            resting,
+            wake,
+            close,
+            changes,
+            active: Instant::now(),
+            idle: false,
+        })
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// A request was made, returns true if it woke the device
+    pub fn activity(&mut self, now: Instant) -> bool {
+        self.active = now;
+        self.wake()
+    }
+
+    /// A line was received, returns true if it woke the device
+    pub fn seen(&mut self, connection: &str, line: &str, now: Instant) -> bool {
+        // Closing the connections is announced there
+        if connection == CONNECTIONS_SOURCE {
+            return false;
+        }
+        if self.idle && self.wake.as_ref().is_some_and(|re| !re.is_match(line)) {
+            return false;
+        }
+        self.activity(now)
+    }
+
+    /// Go idle once the device has been left alone for long enough, or wake
+    /// up if it isn't any more. Returns true if it went idle or woke up.
+    pub fn poll(&mut self, clients: usize, sm: &StateMachine, now: Instant) -> bool {
+        if clients > 0 || !sm.in_state(&self.resting) {
+            return self.activity(now);
+        }
+        if self.idle || now.saturating_duration_since(self.active) < self.after {
+            return false;
+        }
+        self.idle = true;
+        for info in self.close.iter() {
+            let _ = self.changes.send(ConnectionChange::Remove(info.label().to_string()));
+        }
+        true
+    }
+
+    fn wake(&mut self) -> bool {
+        if !self.idle {
+            return false;
+        }
+        self.idle = false;
+        for info in self.close.iter() {
+            let _ = self.changes.send(ConnectionChange::Add(info.clone()));
+        }
+        true
+    }
+}
@@ -0,0 +1,338 @@
+//! labgrid compatibility. fbug exports consoles as raw TCP ports and controls
+//! through a REST endpoint compatible with labgrid's `rest` power model, and
+//! generates an exporter config so the stock `labgrid-exporter` can announce
+//! them to the coordinator as `NetworkSerialPort` and `NetworkPowerPort`
+//! resources.
+//!
+//! Power requests are checked against the agent's tokens and the devices'
+//! reservations like remote clients are. Raw consoles can't authenticate, so
+//! they're only exported on a loopback address when the agent has tokens,
+//! and labgrid reaches them through its SSH proxy.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth;
+use crate::config::{AgentConfig, ConnectionInfo, Device, LabgridConfig, Permission, TokenConfig};
+use crate::exit::{self, Failure};
+use crate::{reservation, ConnectionEvent, ConnectionInput, RunningDevice};
+use anyhow::Result;
+use serde_yaml::{Mapping, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+
+/// A console exported on a TCP port
+struct ExportedConsole {
+    codename: String,
+    connection: String,
+    baud: u32,
+    port: u16,
+}
+
+fn consoles(devices: &[Device], config: &LabgridConfig) -> Vec<ExportedConsole> {
+    devices
+        .iter()
+        .flat_map(|d| {
+            d.connections.iter().filter_map(move |c| match c {
+                ConnectionInfo::Serial(s) => Some((d.codename.clone(), s.label.clone(), s.baud)),
+                _ => None,
+            })
+        })
+        .enumerate()
+        .map(|(i, (codename, connection, baud))| ExportedConsole {
+            codename,
+            connection,
+            baud,
+            port: config.serial_base_port + i as u16,
+        })
+        .collect()
+}
+
+/// Who reservations are checked against when labgrid doesn't say, reserve
+/// a device as this user to hand it over to labgrid
+pub const LABGRID_USER: &str = "labgrid";
+
+fn loopback(config: &LabgridConfig) -> bool {
+    match config.listen.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback(),
+        Err(_) => config.listen == "localhost",
+    }
+}
+
+fn advertised_host(config: &LabgridConfig) -> String {
+    config.host.clone().unwrap_or_else(|| {
+        // Only reachable from this host, labgrid clients have to go through
+        // its SSH proxy
+        if loopback(config) {
+            return "localhost".to_string();
+        }
+        std::fs::read_to_string("/etc/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or_else(|_| "localhost".to_string())
+    })
+}
+
+fn resource(cls: &str, params: Vec<(&str, Value)>) -> Value {
+    let mut map = Mapping::new();
+    map.insert("cls".into(), cls.into());
+    for (k, v) in params {
+        map.insert(k.into(), v);
+    }
+    Value::Mapping(map)
+}
+
+/// Generate a labgrid exporter config with a resource group per device
+pub fn exporter_config(devices: &[Device], config: &LabgridConfig) -> Result<String> {
+    let host = advertised_host(config);
+    let consoles = consoles(devices, config);
+    let query = config.token.as_ref().map_or(String::new(), |t| format!("?token={}", t));
+    let mut groups = Mapping::new();
+    for device in devices {
+        let mut group = Mapping::new();
+        for console in consoles.iter().filter(|c| c.codename == device.codename) {
+            group.insert(
+                console.connection.clone().into(),
+                resource(
+                    "NetworkSerialPort",
+                    vec![
+                        ("host", host.clone().into()),
+                        ("port", (console.port as u64).into()),
+                        ("speed", (console.baud as u64).into()),
+                        ("protocol", "raw".into()),
+                    ],
+                ),
+            );
+        }
+        for control in device.controls.iter() {
+            group.insert(
+                control.name.clone().into(),
+                resource(
+                    "NetworkPowerPort",
+                    vec![
+                        ("model", "rest".into()),
+                        (
+                            "host",
+                            format!(
+                                "http://{}:{}/power/{}/{}/{{value}}{}",
+                                host, config.http_port, device.codename, control.name, query
+                            )
+                            .into(),
+                        ),
+                        ("index", 0u64.into()),
+                    ],
+                ),
+            );
+        }
+        groups.insert(device.codename.clone().into(), Value::Mapping(group));
+    }
+    Ok(serde_yaml::to_string(&groups)?)
+}
+
+async fn serve_console(dev: Arc<RunningDevice>, connection: String, listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!("labgrid console {} connected from {}", connection, peer);
+        let dev = dev.clone();
+        let connection = connection.clone();
+        tokio::spawn(async move {
+            if let Err(e) = bridge_console(&dev, &connection, stream).await {
+                warn!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Bridge a TCP client to a console. The pipeline is line based so this isn't
+/// fully raw, but it's enough for labgrid's console drivers.
+async fn bridge_console<S: AsyncRead + AsyncWrite + Unpin>(
+    dev: &RunningDevice,
+    connection: &str,
+    stream: S,
+) -> Result<()> {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut lines = BufReader::new(r).lines();
+    let mut rx = dev.subscribe().await?;
+    // Whether the client was told its input is refused
+    let mut refused = false;
+    loop {
+        tokio::select! {
+            ev = rx.recv() => match ev {
+                Ok(ev) if ev.device == connection => if let ConnectionEvent::NewLine(line) = ev.event {
+                    w.write_all(format!("{}\r\n", line).as_bytes()).await?;
+                },
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => warn!("labgrid console {} dropped {} lines", connection, n),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            line = lines.next_line() => match line? {
+                Some(data) => match reservation::check_access(&dev.device.codename, LABGRID_USER, false, Duration::ZERO).await {
+                    Ok(()) => dev.send(ConnectionInput {
+                        connection: Some(connection.to_string()),
+                        data: data.into(),
+                    }).await?,
+                    // Once is enough, the client stays connected
+                    Err(e) if !refused => {
+                        refused = true;
+                        w.write_all(format!("fbug: {}, input isn't sent\r\n", e).as_bytes()).await?;
+                    }
+                    Err(_) => {}
+                },
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Who a power request is from. It authenticates like remote clients do, but
+/// labgrid can't send headers so the token is a query parameter. Without
+/// tokens the user can be given as one too.
+fn authorize(tokens: &[TokenConfig], query: &str, perm: Permission) -> Result<String> {
+    let param = |name: &str| {
+        query
+            .split('&')
+            .find_map(|p| p.strip_prefix(name)?.strip_prefix('='))
+    };
+    if tokens.is_empty() {
+        return Ok(param("user").unwrap_or(LABGRID_USER).to_string());
+    }
+    let token = param("token")
+        .and_then(|t| auth::authenticate(tokens, t))
+        .ok_or_else(|| anyhow!("Invalid token"))?;
+    if !token.permissions.contains(&perm) {
+        bail!("Permission denied, {} permission needed", perm);
+    }
+    Ok(token.name.clone())
+}
+
+/// Handle a request from labgrid's `rest` power model: `PUT
+/// /power/<device>/<control>/<0|1>` sets a control, `GET
+/// /power/<device>/<control>` returns the last value set.
+async fn handle_power<S: AsyncRead + AsyncWrite + Unpin>(
+    devices: &[Arc<RunningDevice>],
+    tokens: &[TokenConfig],
+    states: &std::sync::Mutex<Vec<(String, bool)>>,
+    stream: S,
+) -> Result<()> {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut lines = BufReader::new(r).lines();
+    let request = lines.next_line().await?.unwrap_or_default();
+    // Skip the headers, requests from labgrid don't have a body
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let perm = match method {
+        "PUT" => Permission::Control,
+        _ => Permission::Read,
+    };
+
+    let user = match authorize(tokens, query, perm) {
+        Ok(user) => user,
+        Err(e) => return respond(&mut w, "403 Forbidden", &e.to_string()).await,
+    };
+
+    let result: Result<String> = async {
+        match (method, path.as_slice()) {
+            ("PUT", ["power", device, control, value]) => {
+                let on = match *value {
+                    "1" => true,
+                    "0" => false,
+                    v => bail!("Invalid value {}", v),
+                };
+                let dev = devices
+                    .iter()
+                    .find(|d| d.device.codename == *device)
+                    .ok_or_else(|| anyhow!("No such device {}", device))?;
+                reservation::check_access(device, &user, false, Duration::ZERO).await?;
+                dev.set_control(control, on).await?;
+                let key = format!("{}/{}", device, control);
+                let mut states = states.lock().unwrap();
+                states.retain(|(k, _)| *k != key);
+                states.push((key, on));
+                Ok(String::new())
+            }
+            ("GET", ["power", device, control]) => {
+                let key = format!("{}/{}", device, control);
+                let states = states.lock().unwrap();
+                let on = states.iter().find(|(k, _)| *k == key).map(|(_, v)| *v).unwrap_or(false);
+                Ok(if on { "1" } else { "0" }.to_string())
+            }
+            _ => bail!("Not found"),
+        }
+    }
+    .await;
+
+    let (status, body) = match result {
+        Ok(body) => ("200 OK", body),
+        Err(e) if exit::classify(&e) == Some(Failure::Reserved) => ("409 Conflict", e.to_string()),
+        Err(e) => ("400 Bad Request", e.to_string()),
+    };
+    respond(&mut w, status, &body).await
+}
+
+async fn respond<W: AsyncWrite + Unpin>(w: &mut W, status: &str, body: &str) -> Result<()> {
+    w.write_all(
+        format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Run the devices and export their consoles and controls for labgrid
+pub async fn serve(devices: Vec<Device>, config: &LabgridConfig, agent: &AgentConfig) -> Result<()> {
+    if !agent.tokens.is_empty() && !loopback(config) {
+        bail!("labgrid consoles can't authenticate, listen on a loopback address when the agent has tokens");
+    }
+    let consoles = consoles(&devices, config);
+    let running: Vec<Arc<RunningDevice>> = devices
+        .into_iter()
+        .map(|d| Arc::new(RunningDevice::spawn(d)))
+        .collect();
+
+    for console in consoles {
+        let dev = running
+            .iter()
+            .find(|d| d.device.codename == console.codename)
+            .unwrap()
+            .clone();
+        let listener = TcpListener::bind((config.listen.as_str(), console.port)).await?;
+        info!(
+            "Exporting {} {} on port {}",
+            console.codename, console.connection, console.port
+        );
+        tokio::spawn(serve_console(dev, console.connection, listener));
+    }
+
+    let listener = TcpListener::bind((config.listen.as_str(), config.http_port)).await?;
+    info!("Exporting controls on port {}", config.http_port);
+    let running = Arc::new(running);
+    let tokens = Arc::new(agent.tokens.clone());
+    let states = Arc::new(std::sync::Mutex::new(vec![]));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let running = running.clone();
+        let states = states.clone();
+        let tokens = tokens.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_power(&running, &tokens, &states, stream).await {
+                warn!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
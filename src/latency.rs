@@ -0,0 +1,239 @@
+//! Latency instrumentation for the console pipeline. Each line is timestamped
+//! when it's received from the connection, when it's decoded into a line,
+//! when the event loop dispatches it and when the state machine has matched
+//! it, so problems like missing a 2 second autoboot window can be tracked
+//! down to a stage.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use strum_macros::Display;
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+/// How many samples are kept per stage for the percentiles
+const WINDOW: usize = 4096;
+/// Lines longer than this are split, so output without line endings (a
+/// binary blob, a stuck progress bar) can't grow the buffer forever
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// When a line was read from its connection
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    /// The bytes that completed the line were read
+    pub received: Instant,
+    /// The line was split out of the stream
+    pub decoded: Instant,
+}
+
+impl Timing {
+    /// For connections that don't distinguish receiving from decoding
+    pub fn now() -> Self {
+        let now = Instant::now();
+        Self {
+            received: now,
+            decoded: now,
+        }
+    }
+}
+
+/// A [LinesCodec] that also records when each line was received, and splits
+/// lines longer than [MAX_LINE_LENGTH]
+#[derive(Debug)]
+pub struct TimedLinesCodec {
+    inner: LinesCodec,
+    received: Instant,
+    /// Bytes left in the buffer after the last decode, more means a read happened
+    buffered: usize,
+    /// The last line was split, see [TimedLinesCodec::take_overflow]
+    overflow: bool,
+}
+
+impl TimedLinesCodec {
+    pub fn new() -> Self {
+        Self {
+            inner: LinesCodec::new(),
+            received: Instant::now(),
+            buffered: 0,
+            overflow: false,
+        }
+    }
+
+    /// Whether the last line was too long and had to be split, connections
+    /// report it as [crate::Event::Overflow]
+    pub fn take_overflow(&mut self) -> bool {
+        std::mem::take(&mut self.overflow)
+    }
+
+    fn split(&mut self, buf: &mut BytesMut, line: Option<String>) -> Option<String> {
+        if line.is_some() || buf.len() < MAX_LINE_LENGTH {
+            return line;
+        }
+        self.overflow = true;
+        // It remembers how far it has searched for a line ending
+        self.inner = LinesCodec::new();
+        Some(String::from_utf8_lossy(&buf.split_to(MAX_LINE_LENGTH)).into_owned())
+    }
+
+    fn stamp(&mut self, buf: &BytesMut, line: Option<String>) -> Option<(String, Timing)> {
+        self.buffered = buf.len();
+        line.map(|line| {
+            (
+                line,
+                Timing {
+                    received: self.received,
+                    decoded: Instant::now(),
+                },
+            )
+        })
+    }
+}
+
+impl Default for TimedLinesCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for TimedLinesCodec {
+    type Item = (String, Timing);
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if buf.len() > self.buffered {
+            self.received = Instant::now();
+        }
+        let line = self.inner.decode(buf)?;
+        let line = self.split(buf, line);
+        Ok(self.stamp(buf, line))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if buf.len() > self.buffered {
+            self.received = Instant::now();
+        }
+        let line = self.inner.decode_eof(buf)?;
+        Ok(self.stamp(buf, line))
+    }
+}
+
+impl<T: AsRef<str>> Encoder<T> for TimedLinesCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: T, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        self.inner.encode(line, buf)
+    }
+}
+
+/// Bytes to send as they are, without a line ending
+pub struct RawBytes<'a>(pub &'a [u8]);
+
+impl Encoder<RawBytes<'_>> for TimedLinesCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, raw: RawBytes<'_>, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        buf.extend_from_slice(raw.0);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Stage {
+    /// Received to decoded
+    Decode,
+    /// Decoded to picked up by the event loop
+    Dispatch,
+    /// Picked up to matched by the state machine
+    Match,
+    /// Received to matched
+    Total,
+}
+
+const STAGES: [Stage; 4] = [Stage::Decode, Stage::Dispatch, Stage::Match, Stage::Total];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Latency percentiles for each stage over the most recent lines
+#[derive(Debug, Clone, Default)]
+pub struct LatencySummary {
+    pub samples: usize,
+    pub stages: Vec<(Stage, Percentiles)>,
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} lines", self.samples)?;
+        for (stage, p) in self.stages.iter() {
+            write!(
+                f,
+                "\n  {:<8} p50 {:?} p95 {:?} p99 {:?} max {:?}",
+                stage, p.p50, p.p95, p.p99, p.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects per-stage latencies for the lines of a device
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    samples: [VecDeque<Duration>; 4],
+    /// Lines recorded since the last summary was logged
+    pub since_report: usize,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, timing: &Timing, dispatched: Instant, matched: Instant) {
+        let durations = [
+            timing.decoded.saturating_duration_since(timing.received),
+            dispatched.saturating_duration_since(timing.decoded),
+            matched.saturating_duration_since(dispatched),
+            matched.saturating_duration_since(timing.received),
+        ];
+        for (samples, d) in self.samples.iter_mut().zip(durations) {
+            if samples.len() == WINDOW {
+                samples.pop_front();
+            }
+            samples.push_back(d);
+        }
+        self.since_report += 1;
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let stages = STAGES
+            .iter()
+            .zip(self.samples.iter())
+            .map(|(stage, samples)| {
+                let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+                sorted.sort();
+                let at = |p: f64| {
+                    sorted
+                        .get(((sorted.len().saturating_sub(1)) as f64 * p).round() as usize)
+                        .copied()
+                        .unwrap_or_default()
+                };
+                (
+                    *stage,
+                    Percentiles {
+                        p50: at(0.5),
+                        p95: at(0.95),
+                        p99: at(0.99),
+                        max: at(1.0),
+                    },
+                )
+            })
+            .collect();
+        LatencySummary {
+            samples: self.samples[0].len(),
+            stages,
+        }
+    }
+}
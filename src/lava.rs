@@ -0,0 +1,100 @@
+//! A shim so a LAVA dispatcher can use fbug as the power and console backend
+//! for a device. LAVA runs a command for each power operation and another to
+//! get a console, these are mapped onto fbug triggers.
+
+use crate::config::Device;
+use crate::session::{Direction, Recorder};
+use crate::{ConnectionEvent, ConnectionInput, RunningDevice};
+use anyhow::Result;
+use strum_macros::Display;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::broadcast::error::RecvError;
+
+/// The operations LAVA performs through its device dictionary commands
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "kebab-case")]
+pub enum LavaAction {
+    PowerOn,
+    PowerOff,
+    HardReset,
+}
+
+impl LavaAction {
+    /// The device dictionary variable LAVA uses for this action
+    fn variable(&self) -> &'static str {
+        match self {
+            LavaAction::PowerOn => "power_on_command",
+            LavaAction::PowerOff => "power_off_command",
+            LavaAction::HardReset => "hard_reset_command",
+        }
+    }
+
+    /// The trigger configured for this action on a device
+    pub fn trigger(&self, device: &Device) -> Result<String> {
+        let lava = device
+            .lava
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} has no lava section in its config", device.codename))?;
+        let trigger = match self {
+            LavaAction::PowerOn => &lava.power_on,
+            LavaAction::PowerOff => &lava.power_off,
+            LavaAction::HardReset => &lava.hard_reset,
+        };
+        trigger
+            .clone()
+            .ok_or_else(|| anyhow!("{} has no trigger configured for {}", device.codename, self))
+    }
+}
+
+/// Generate the device dictionary snippet for a device, `invocation` is the
+/// fbug command line (without subcommand) that selects it.
+pub fn device_dict(device: &Device, invocation: &str) -> String {
+    let mut dict = format!(
+        "{{% set connection_command = '{} lava console' %}}\n",
+        invocation
+    );
+    for action in [LavaAction::PowerOn, LavaAction::PowerOff, LavaAction::HardReset] {
+        if action.trigger(device).is_ok() {
+            dict += &format!(
+                "{{% set {} = '{} lava {}' %}}\n",
+                action.variable(),
+                invocation,
+                action
+            );
+        }
+    }
+    dict
+}
+
+/// Attach stdin/stdout to the console of a device, this is what LAVA talks to
+pub async fn console(device: Device, mut recorder: Option<Recorder>) -> Result<()> {
+    let dev = RunningDevice::spawn(device);
+    let mut rx = dev.subscribe().await?;
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+    let res = loop {
+        tokio::select! {
+            ev = rx.recv() => match ev {
+                Ok(ev) => if let ConnectionEvent::NewLine(line) = ev.event {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record(Direction::Out, Some(&ev.device), &line);
+                    }
+                    println!("{}", line);
+                },
+                Err(RecvError::Lagged(n)) => warn!("Console dropped {} lines", n),
+                Err(RecvError::Closed) => break Ok(()),
+            },
+            line = stdin.next_line() => match line {
+                Ok(Some(data)) => {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record(Direction::In, None, &data);
+                    }
+                    dev.send(ConnectionInput { connection: None, data: data.into() }).await?
+                }
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e.into()),
+            },
+        }
+    };
+    dev.shutdown().await?;
+    res
+}
@@ -6,64 +6,746 @@ extern crate anyhow;
 #[macro_use]
 extern crate log;
 
+pub mod android;
+pub mod artifacts;
+pub mod auth;
+pub mod bandwidth;
+pub mod bench;
+pub mod bootstrap;
+pub mod check;
 pub mod config;
 pub mod connections;
+pub mod exec;
+pub mod exit;
 pub mod state;
 pub mod controls;
+pub mod crash;
+pub mod doctor;
+pub mod fleet;
+pub mod health;
+pub mod history;
+pub mod hooks;
+pub mod identify;
+pub mod idle;
+pub mod labgrid;
+pub mod latency;
+pub mod lava;
+pub mod login;
+pub mod printk;
+pub mod remote;
+pub mod responder;
+pub mod reservation;
+pub mod scenario;
+pub mod session;
+#[cfg(unix)]
+pub mod systemd;
+pub mod thermal;
+pub mod timestamps;
+pub mod trace;
+pub mod upload;
+pub mod vars;
 
-use config::{Device, Property};
-pub use connections::ConnectionEvent;
+use config::{ConnectionInfo, Device, GlobalProperties, Property, PropertyFailure, TimestampMode};
+pub use connections::{ConnectionEvent, ConnectionInput, InputData};
 
 use anyhow::Result;
-use connections::{Connections, Connection, SerialAction, Connectable};
+use bandwidth::{Bandwidth, BandwidthMap};
+use bootstrap::Bootstraps;
+use connections::{Connections, ConnectionChange, SerialAction};
 use futures::channel::mpsc::unbounded;
+use controls::Controls;
+use crash::{Collector, CrashReport};
+use exit::Failure;
+use fleet::PowerAction;
+use health::{Health, HealthMap, Probes};
+use hooks::HookContext;
+use identify::Identify;
+use idle::Idle;
+use latency::{LatencyStats, LatencySummary, Timing, MAX_LINE_LENGTH};
+use login::Logins;
+use responder::Responders;
 use state::StateMachine;
-use tokio::sync::{mpsc::unbounded_channel, watch, broadcast::{channel, Sender, Receiver}};
+use printk::KernelClock;
+use thermal::{Monitor, Reading, THERMAL_SOURCE};
+use timestamps::{LineTimestamps, ToggleSignal};
+use trace::MatchTrace;
+use vars::Vars;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, oneshot, watch, broadcast::{self, channel, Sender, Receiver}};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// How often the console pipeline latency is logged
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// How often USB and GPIO transition conditions are checked
+const CONDITION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often connection probes are started and timed out, see [health]
+const PROBE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often the steps of logins are taken, see [login]
+const LOGIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often bootstrapped SSH connections are checked, see [bootstrap]
+const BOOTSTRAP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often whether the device should go idle is checked, see [idle]
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often probing for the initial state is timed out, see [identify]
+const IDENTIFY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often console throughput is worked out, see [bandwidth]
+const BANDWIDTH_INTERVAL: Duration = Duration::from_secs(1);
+/// The source of the lines announcing connections coming and going
+pub const CONNECTIONS_SOURCE: &str = "CONNECTIONS";
+/// How many events a subscriber can fall behind by, see [RunningDevice::events]
+pub const EVENT_BUFFER: usize = 1024;
 
 #[derive(Clone, Debug)]
 pub struct ConnectionEventData {
     pub device: String,
     pub event: ConnectionEvent,
+    pub timing: Timing,
 }
 
 #[derive(Clone, Debug)]
 pub enum Event {
     //ApplyProperties(Vec<Property>),
     ConnectionEvent(ConnectionEventData),
+    /// A connection hit an error it can recover from
+    Error { connection: String, message: String },
+    /// A connection is gone for good (e.g. the adapter was unplugged), the
+    /// device stops unless it's hotplugged
+    ConnectionClosed(String),
+    /// A hotplugged or added connection was opened
+    ConnectionUp(String),
+    /// A connection was removed, or a hotplugged one went away
+    ConnectionDown(String),
+    /// A line was too long and had to be split, see [bandwidth]
+    Overflow(String),
+    /// A probe that runs outside the connection (e.g. over ssh) finished
+    Probe { connection: String, ok: bool },
+    /// Whether a bootstrapped SSH connection answered at `host`, see
+    /// [bootstrap]
+    Reachable { connection: String, host: String, ok: bool },
+    /// A temperature sample, see [thermal]
+    Thermal(Vec<Reading>),
+    /// Logs were collected after a crash, see [crash]
+    Crash(CrashReport),
+    /// A property of the new state was applied, or couldn't be
+    Property {
+        property: Property,
+        result: Result<(), String>,
+    },
+    /// Injected by a program embedding fbug, see [RunningDevice::emit]
+    Custom(CustomEvent),
+}
+
+/// An event from outside fbug, like a result from a custom measurement rig,
+/// injected by a program embedding it with [RunningDevice::emit]. Subscribers
+/// get it as [DeviceEvent::Custom], and the state machine sees it as the line
+/// `<name> <payload>` from `source` so transitions can match on it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomEvent {
+    pub source: String,
+    pub name: String,
+    /// Whatever the program wants to attach, `Null` for nothing
+    pub payload: serde_json::Value,
+}
+
+impl CustomEvent {
+    /// The line the state machine matches on, strings are included as they
+    /// are and other payloads as JSON
+    pub fn line(&self) -> String {
+        match &self.payload {
+            serde_json::Value::Null => self.name.clone(),
+            serde_json::Value::String(s) => format!("{} {}", self.name, s),
+            payload => format!("{} {}", self.name, payload),
+        }
+    }
+}
+
+/// What happens on a running device, for programs embedding fbug to react to.
+/// See [RunningDevice::events].
+#[derive(Clone, Debug)]
+pub enum DeviceEvent {
+    /// A line from a connection
+    Line {
+        connection: String,
+        line: String,
+        timing: Timing,
+    },
+    /// The device entered a state, `from` is `None` for the first one
+    Transition { from: Option<String>, to: String },
+    /// The health of a probed connection changed, i.e. it stopped or started
+    /// responding again
+    ConnectionHealth { connection: String, health: Health },
+    /// A connection hit an error it can recover from
+    ConnectionError { connection: String, message: String },
+    /// A connection is gone for good, the device stops
+    ConnectionClosed(String),
+    /// A hotplugged or added connection was opened
+    ConnectionUp(String),
+    /// A connection was removed, or a hotplugged one went away
+    ConnectionDown(String),
+    /// A trigger finished running, with the error if it failed
+    TriggerFinished { name: String, result: Result<(), String> },
+    /// A property of the new state was applied, with the error if it failed
+    Property {
+        property: Property,
+        result: Result<(), String>,
+    },
+    /// Logs were collected after a crash
+    CrashReport(CrashReport),
+    /// A hook finished running, with its output or why it failed
+    Hook {
+        command: String,
+        result: Result<String, String>,
+    },
+    /// The device went idle or woke up, see [idle]
+    Idle(bool),
+    /// An event injected with [RunningDevice::emit]
+    Custom(CustomEvent),
+}
+
+/// How a trigger is run
+#[derive(Debug, Clone, Default)]
+pub struct TriggerOptions {
+    /// Override variables from the config and console
+    pub vars: Vars,
+    /// Who is running it, for the `unreserved` precondition (default: the
+    /// current user)
+    pub user: Option<String>,
+    /// Run it even if it's interlocked in the current state
+    pub force: bool,
+}
+
+/// Requests that can be made to a running device
+#[derive(Debug)]
+pub enum Command {
+    /// Run the named trigger, replies once the sequence has completed
+    Trigger(String, TriggerOptions, oneshot::Sender<Result<()>>),
+    /// Send input to a connection
+    Send(ConnectionInput, oneshot::Sender<Result<()>>),
+    /// Subscribe to console output
+    Subscribe(oneshot::Sender<broadcast::Receiver<ConnectionEventData>>),
+    /// Subscribe to temperature samples
+    SubscribeThermal(oneshot::Sender<broadcast::Receiver<Vec<Reading>>>),
+    /// Turn a control on or off
+    SetControl(String, bool, oneshot::Sender<Result<()>>),
+    /// Open another connection, or wait for it to appear if it's hotplugged
+    AddConnection(ConnectionInfo, oneshot::Sender<Result<()>>),
+    /// Close a connection
+    RemoveConnection(String, oneshot::Sender<Result<()>>),
+    /// Get the latency percentiles of the console pipeline
+    Latency(oneshot::Sender<LatencySummary>),
+    /// Set how console lines are timestamped, or cycle to the next mode.
+    /// Replies with the new mode.
+    Timestamps(Option<TimestampMode>, oneshot::Sender<TimestampMode>),
+    /// Inject an event from outside fbug
+    Emit(CustomEvent),
+}
+
+impl Device {
+    /// Run the device in the background, see [RunningDevice]
+    pub fn start(self) -> RunningDevice {
+        RunningDevice::spawn(self)
+    }
+}
+
+/// A device running in the background, the handle for everything that can be
+/// done with it: triggers, sending input, subscribing to its console and
+/// [events](RunningDevice::events), and stopping it.
+pub struct RunningDevice {
+    pub device: Device,
+    commands: UnboundedSender<Command>,
+    state: watch::Receiver<Option<String>>,
+    health: watch::Receiver<HealthMap>,
+    context: watch::Receiver<Vars>,
+    bandwidth: watch::Receiver<BandwidthMap>,
+    events: Sender<DeviceEvent>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl RunningDevice {
+    pub fn spawn(device: Device) -> Self {
+        let (commands, crx) = unbounded_channel::<Command>();
+        let (stx, state) = watch::channel::<Option<String>>(None);
+        let (htx, health) = watch::channel(HealthMap::new());
+        let (ctx, context) = watch::channel(Vars::new());
+        let (btx, bandwidth) = watch::channel(BandwidthMap::new());
+        let (events, _) = channel::<DeviceEvent>(EVENT_BUFFER);
+        let task = tokio::spawn(device_loop(device.clone(), crx, stx, htx, ctx, btx, events.clone()));
+        Self {
+            device,
+            commands,
+            state,
+            health,
+            context,
+            bandwidth,
+            events,
+            task,
+        }
+    }
+
+    /// Subscribe to everything that happens on the device from now on. A
+    /// receiver that falls more than [EVENT_BUFFER] events behind misses the
+    /// oldest ones, see [broadcast::error::RecvError::Lagged].
+    pub fn events(&self) -> Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn current_state(&self) -> Option<String> {
+        self.state.borrow().clone()
+    }
+
+    /// A receiver that's notified whenever the state changes
+    pub fn watch_state(&self) -> watch::Receiver<Option<String>> {
+        self.state.clone()
+    }
+
+    /// Variables captured from the console so far, see [vars]
+    pub fn context(&self) -> Vars {
+        self.context.borrow().clone()
+    }
+
+    /// A receiver that's notified whenever a variable is captured
+    pub fn watch_context(&self) -> watch::Receiver<Vars> {
+        self.context.clone()
+    }
+
+    /// The health of the connections that have probes
+    pub fn health(&self) -> HealthMap {
+        self.health.borrow().clone()
+    }
+
+    /// How much each connection has printed, see [bandwidth]
+    pub fn bandwidth(&self) -> BandwidthMap {
+        self.bandwidth.borrow().clone()
+    }
+
+    /// Whether a connection that should restart the agent when it hangs has
+    /// hung, see [config::ProbeConfig::watchdog]
+    pub fn wedged(&self) -> bool {
+        let health = self.health.borrow();
+        self.device.connections.iter().any(|c| {
+            c.probe().is_some_and(|p| p.watchdog) && health.get(c.label()) == Some(&Health::Hung)
+        })
+    }
+
+    async fn request<T>(&self, cmd: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(cmd(reply))
+            .map_err(|_| anyhow!("Device {} stopped", self.device.codename))?;
+        rx.await
+            .map_err(|_| anyhow!("Device {} stopped", self.device.codename))
+    }
+
+    /// Run a trigger, `vars` override variables from the config and console
+    pub async fn run_trigger(&self, name: &str, vars: Vars) -> Result<()> {
+        self.run_trigger_with(
+            name,
+            TriggerOptions {
+                vars,
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Run a trigger, it's refused if its preconditions don't hold or it's
+    /// interlocked in the current state and not forced
+    pub async fn run_trigger_with(&self, name: &str, opts: TriggerOptions) -> Result<()> {
+        self.request(|reply| Command::Trigger(name.to_string(), opts, reply))
+            .await?
+    }
+
+    pub async fn send(&self, input: ConnectionInput) -> Result<()> {
+        self.request(|reply| Command::Send(input, reply)).await?
+    }
+
+    pub async fn set_control(&self, name: &str, on: bool) -> Result<()> {
+        self.request(|reply| Command::SetControl(name.to_string(), on, reply))
+            .await?
+    }
+
+    /// Inject an event, e.g. from a measurement rig, into the device's event
+    /// loop. Its source can't be one of the device's connections.
+    pub fn emit(&self, event: CustomEvent) -> Result<()> {
+        if self.device.connections.iter().any(|c| c.label() == event.source)
+            || [CONNECTIONS_SOURCE, THERMAL_SOURCE].contains(&event.source.as_str())
+        {
+            bail!("{} is taken, custom events need a source of their own", event.source);
+        }
+        self.commands
+            .send(Command::Emit(event))
+            .map_err(|_| anyhow!("Device {} stopped", self.device.codename))
+    }
+
+    /// Open a connection that isn't in the config, like one that only exists
+    /// after the device has booted. It's announced with the line
+    /// `connection <label> up` from the `CONNECTIONS` source once it's open.
+    pub async fn add_connection(&self, info: ConnectionInfo) -> Result<()> {
+        self.request(|reply| Command::AddConnection(info, reply)).await?
+    }
+
+    /// Close a connection, it's announced with `connection <label> down`
+    pub async fn remove_connection(&self, label: &str) -> Result<()> {
+        self.request(|reply| Command::RemoveConnection(label.to_string(), reply))
+            .await?
+    }
+
+    /// Use the device's power control, returns the state it should end up in
+    pub async fn power(&self, action: PowerAction) -> Result<Option<String>> {
+        let power = self
+            .device
+            .power
+            .as_ref()
+            .ok_or_else(|| anyhow!("{} has no power control configured", self.device.codename))?;
+        info!("{}: power {}", self.device.codename, action);
+        match action {
+            PowerAction::On => self.set_control(&power.control, true).await?,
+            PowerAction::Off => self.set_control(&power.control, false).await?,
+            PowerAction::Cycle => {
+                self.set_control(&power.control, false).await?;
+                tokio::time::sleep(Duration::from_millis(power.cycle_delay as u64)).await;
+                self.set_control(&power.control, true).await?;
+            }
+        }
+        Ok(match action {
+            PowerAction::Off => power.off_state.clone(),
+            PowerAction::On | PowerAction::Cycle => power.on_state.clone(),
+        })
+    }
+
+    pub async fn subscribe(&self) -> Result<broadcast::Receiver<ConnectionEventData>> {
+        self.request(Command::Subscribe).await
+    }
+
+    pub async fn subscribe_thermal(&self) -> Result<broadcast::Receiver<Vec<Reading>>> {
+        self.request(Command::SubscribeThermal).await
+    }
+
+    pub async fn latency(&self) -> Result<LatencySummary> {
+        self.request(Command::Latency).await
+    }
+
+    /// Set how console lines are timestamped, `None` cycles to the next mode
+    pub async fn timestamps(&self, mode: Option<TimestampMode>) -> Result<TimestampMode> {
+        self.request(|reply| Command::Timestamps(mode, reply)).await
+    }
+
+    /// Wait for the device to enter `target`, or one of its children
+    pub async fn wait_for_state(&self, target: &str) -> Result<()> {
+        let mut state = self.state.clone();
+        while !state
+            .borrow()
+            .as_deref()
+            .is_some_and(|s| state::is_in(&self.device.states, s, target))
+        {
+            state
+                .changed()
+                .await
+                .map_err(|_| anyhow!("Device stopped while waiting for state {}", target))?;
+        }
+        Ok(())
+    }
+
+    /// Stop the device and its triggers without waiting for it, e.g. when
+    /// it's shared
+    pub fn abort(&self) {
+        self.task.abort();
+    }
+
+    /// Wait for the device to stop, it only stops on its own if it failed
+    pub async fn wait(self) -> Result<()> {
+        let codename = self.device.codename.clone();
+        let task = self.task;
+        // Keep the handle's channels open while waiting
+        let _commands = self.commands;
+        task.await.map_err(|e| anyhow!("Device {} panicked: {}", codename, e))?
+    }
+
+    /// Stop the device along with any triggers it's running, which release
+    /// the controls they held. Returns the error if it had already failed.
+    pub async fn shutdown(self) -> Result<()> {
+        self.task.abort();
+        match self.task.await {
+            Ok(res) => res,
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// The log target for a device, or one of its connections. Log levels can be
+/// set per target, see [config::LogConfig].
+pub fn log_target(codename: &str, connection: Option<&str>) -> String {
+    match connection {
+        Some(connection) => format!("device:{}:{}", codename, connection),
+        None => format!("device:{}", codename),
+    }
 }
 
-async fn conn_event(ev: ConnectionEventData, sm: &mut StateMachine, ptx: &Sender<Vec<Property>>) {
-    let log_target = format!("device:{}", ev.device);
+async fn conn_event(
+    ev: ConnectionEventData,
+    codename: &str,
+    stamp: &str,
+    sm: &mut StateMachine,
+    ptx: &Sender<Vec<Property>>,
+    trace: Option<&mut MatchTrace>,
+) {
+    let log_target = log_target(codename, Some(&ev.device));
     match ev.event {
         ConnectionEvent::NewLine(line) => {
-            if let Some(props) = sm.process_line(&line) {
+            let props = match trace {
+                Some(trace) => {
+                    let (props, line_trace) = sm.trace_line(&ev.device, &line);
+                    trace.record(&line_trace);
+                    props
+                }
+                None => sm.process_line(&ev.device, &line),
+            };
+            if let Some(props) = props {
                 let _ = ptx.send(props).map_err(|e| error!("{}", e));
             }
-            log::info!(target: &log_target, "{}", line);
+            log::info!(target: &log_target, "{}{}", stamp, line);
         }
         ConnectionEvent::Bytes(bytes) => {
             log::trace!(target: &log_target, "{:?}", bytes);
         }
+        ConnectionEvent::Prompt(prompt) => {
+            log::trace!(target: &log_target, "prompt {:?}", prompt);
+        }
     }
 }
 
-async fn process_event(ev: Event, sm: &mut StateMachine, ptx: &Sender<Vec<Property>>) {
+/// Handle an event from the connections, console lines are logged prefixed
+/// with `stamp` and their matching recorded to `trace`
+async fn process_event(
+    ev: Event,
+    codename: &str,
+    stamp: &str,
+    sm: &mut StateMachine,
+    ptx: &Sender<Vec<Property>>,
+    trace: Option<&mut MatchTrace>,
+) -> Result<()> {
     match ev {
-        Event::ConnectionEvent(ev) => conn_event(ev, sm, ptx).await,
+        Event::ConnectionEvent(ev) => conn_event(ev, codename, stamp, sm, ptx, trace).await,
+        Event::Error { connection, message } => {
+            log::error!(target: &log_target(codename, Some(&connection)), "{}", message)
+        }
+        Event::ConnectionClosed(connection) => {
+            return Err(Failure::Connection.error(format!("{}: connection {} closed", codename, connection)))
+        }
+        // Handled by the device loop
+        Event::Probe { .. }
+        | Event::Reachable { .. }
+        | Event::Thermal(_)
+        | Event::Crash(_)
+        | Event::Property { .. }
+        | Event::ConnectionUp(_)
+        | Event::ConnectionDown(_)
+        | Event::Overflow(_)
+        | Event::Custom(_) => {}
     };
+    Ok(())
 }
 
+/// Get a trigger ready to run in the background, it's an error if it isn't
+/// valid from the current state. `given` variables override those from the
+/// config and console.
+fn prepare_trigger(
+    name: &str,
+    given: Vars,
+    sm: &StateMachine,
+    controls: &Arc<Controls>,
+    variables: &Vars,
+    state_tx: &watch::Sender<Option<String>>,
+) -> Result<impl std::future::Future<Output = Result<()>> + Send + 'static> {
+    let trigger = sm.find_trigger(name).cloned().ok_or_else(|| {
+        anyhow!(
+            "No trigger {} valid from state {}",
+            name,
+            sm.current_state().unwrap_or("unknown")
+        )
+    })?;
+    let controls = controls.clone();
+    let state_rx = state_tx.subscribe();
+    let states = sm.states().to_vec();
+    let mut vars = variables.clone();
+    vars.extend(sm.context().clone());
+    vars.extend(given);
+    Ok(async move { controls.run_trigger(&trigger, &vars, &states, state_rx).await })
+}
+
+/// Refuse to run a trigger whose preconditions don't hold, or that's
+/// interlocked in the current state and not forced
+fn check_trigger(
+    name: &str,
+    opts: &TriggerOptions,
+    sm: &StateMachine,
+    device: &Device,
+    controls: &Controls,
+    health: &HealthMap,
+) -> Result<()> {
+    // prepare_trigger reports triggers that can't run from here
+    let Some(trigger) = sm.find_trigger(name) else {
+        return Ok(());
+    };
+    let requires = &trigger.requires;
+    if requires.power && !device.power.as_ref().is_some_and(|p| controls.available(&p.control)) {
+        bail!("Refusing to run {}: it needs power control, which isn't available", name);
+    }
+    if requires.unreserved {
+        let user = opts.user.clone().unwrap_or_else(reservation::current_user);
+        if let Some(r) = reservation::current(&device.codename)?.filter(|r| r.owner != user) {
+            bail!("Refusing to run {}: {} is reserved by {}", name, device.codename, r.owner);
+        }
+    }
+    for connection in requires.healthy.iter() {
+        match health.get(connection) {
+            Some(Health::Healthy) => {}
+            Some(h) => bail!("Refusing to run {}: it needs {} to be healthy, but it's {}", name, connection, h),
+            None => bail!("Refusing to run {}: it needs {} to be healthy, but it isn't probed", name, connection),
+        }
+    }
+    if let Some(state) = trigger.interlock.iter().find(|s| sm.in_state(s)) {
+        if !opts.force {
+            bail!("Refusing to run {} in state {}, it's interlocked there (force it to run anyway)", name, state);
+        }
+        warn!("{}: forcing {} in interlocked state {}", device.codename, name, state);
+    }
+    Ok(())
+}
+
+/// Run a device until it fails
 pub async fn main_loop(device: Device) -> Result<()> {
+    device.start().wait().await
+}
+
+/// Run a trigger in the background, publishing the result to `events_tx`
+/// before passing it on. It's run in `tasks` so it stops along with the
+/// device, releasing whatever it held.
+fn spawn_trigger(
+    name: String,
+    run: impl std::future::Future<Output = Result<()>> + Send + 'static,
+    events_tx: &Sender<DeviceEvent>,
+    tasks: &mut JoinSet<()>,
+    done: impl FnOnce(Result<()>) + Send + 'static,
+) {
+    let events_tx = events_tx.clone();
+    tasks.spawn(async move {
+        let res = run.await;
+        let _ = events_tx.send(DeviceEvent::TriggerFinished {
+            name,
+            result: res.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+        });
+        done(res);
+    });
+}
+
+/// Run a device, handling requests from `commands` and publishing the current
+/// state to `state_tx`, the health of its connections to `health_tx` and the
+/// variables captured from the console to `context_tx` whenever they change.
+/// Everything else that happens is published to `events_tx`.
+pub async fn device_loop(
+    device: Device,
+    mut commands: UnboundedReceiver<Command>,
+    state_tx: watch::Sender<Option<String>>,
+    health_tx: watch::Sender<HealthMap>,
+    context_tx: watch::Sender<Vars>,
+    bandwidth_tx: watch::Sender<BandwidthMap>,
+    events_tx: Sender<DeviceEvent>,
+) -> Result<()> {
     let (tx, mut rx) = unbounded_channel::<Event>();
     let (ptx, prx) = channel::<Vec<Property>>(8);
-    let mut connections = Connections::new(tx.clone(), prx, &device.connections).await?;
-    if let Some(Connectable::Serial(s)) = connections.get(connections::ConnectionType::Serial) {
-        s.action(SerialAction::Dtr(false)).await?;
-        s.action(SerialAction::Rts(false)).await?;
-        debug!("DTR/RTS lowered");
+    let (console_tx, _) = channel::<ConnectionEventData>(256);
+    let (thermal_tx, _) = channel::<Vec<Reading>>(16);
+
+    let mut sm =
+        StateMachine::new(device.states.clone(), device.transitions.clone()).map_err(|e| Failure::Config.wrap(e))?;
+    let diags = check::analyse(&sm, &device.controls);
+    for diag in diags.iter() {
+        match diag.severity {
+            check::Severity::Warning => log::warn!("{}", diag.message),
+            check::Severity::Error => log::error!("{}", diag.message),
+        }
+    }
+    if diags.iter().any(|d| d.severity == check::Severity::Error) {
+        return Err(Failure::Config.error(format!(
+            "Config for {} has errors, refusing to start",
+            device.codename
+        )));
+    }
+
+    let mut connections = Connections::new(tx.clone(), prx, &device.connections)
+        .await
+        .map_err(|e| Failure::Connection.wrap(e))?;
+    for (label, serial) in connections.serials() {
+        serial.action(SerialAction::Dtr(false))?;
+        serial.action(SerialAction::Rts(false))?;
+        debug!("{}: DTR/RTS lowered", label);
+    }
+    let input = connections.input();
+    let sender = connections.sender();
+    let changes = connections.changes();
+    // Connections that may go away without the device stopping
+    let mut hotplug: Vec<String> = device
+        .connections
+        .iter()
+        .filter(|c| c.hotplug())
+        .map(|c| c.label().to_string())
+        .collect();
+    let controls = Arc::new(Controls::new(
+        &device.codename,
+        device.controls.clone(),
+        connections.control_handles(),
+        input.clone(),
+        device.variables.clone(),
+        context_tx.subscribe(),
+    ));
+
+    let mut probes = Probes::new(&device.connections, connections.ssh(), tx.clone(), input.clone())
+        .map_err(|e| Failure::Config.wrap(e))?;
+    health_tx.send_replace(probes.health());
+    let mut logins = Logins::new(&device, input.clone(), changes.clone()).map_err(|e| Failure::Config.wrap(e))?;
+    let mut responders = Responders::new(&device, input.clone()).map_err(|e| Failure::Config.wrap(e))?;
+    let mut identify = device
+        .identify
+        .as_ref()
+        .map(|i| Identify::new(i, &device, input.clone()))
+        .transpose()
+        .map_err(|e| Failure::Config.wrap(e))?;
+    if let Some(identify) = identify.as_mut() {
+        identify.start(Instant::now());
     }
+    logins.state_changed(&sm, Instant::now());
+    let mut bootstraps = Bootstraps::new(connections.take_bootstraps(), device.variables.clone(), tx.clone());
+    bootstraps.update(&sm, Instant::now());
+    let mut thermal = device
+        .thermal
+        .as_ref()
+        .map(|t| Monitor::new(t, &device.connections, connections.ssh()))
+        .transpose()
+        .map_err(|e| Failure::Config.wrap(e))?;
+    let mut crash = device
+        .crash
+        .as_ref()
+        .map(|c| Collector::new(c, &device.connections, connections.ssh()))
+        .transpose()
+        .map_err(|e| Failure::Config.wrap(e))?;
+    let mut idle = device
+        .idle
+        .as_ref()
+        .map(|i| Idle::new(i, &device, changes.clone()))
+        .transpose()
+        .map_err(|e| Failure::Config.wrap(e))?;
+    let mut bandwidth = Bandwidth::new(&device.connections);
+    bandwidth_tx.send_replace(bandwidth.counters());
+    let mut trace = device
+        .trace_matches
+        .as_deref()
+        .map(MatchTrace::create)
+        .transpose()
+        .map_err(|e| Failure::Config.wrap(e))?;
 
-    let mut sm = StateMachine::new(device.states.clone(), device.transitions.clone())?;
     let triggers = sm.list_triggers();
 
     for trigger in triggers {
@@ -72,15 +754,437 @@ pub async fn main_loop(device: Device) -> Result<()> {
 
     let conn_thread = connections.poll();
 
-    let event_thread = tokio::spawn(async move {
+    let codename = device.codename.clone();
+    let variables = device.variables.clone();
+    let mut latency = LatencyStats::default();
+    let mut latency_report = tokio::time::interval(LATENCY_REPORT_INTERVAL);
+    let mut condition_poll = tokio::time::interval(CONDITION_POLL_INTERVAL);
+    let mut probe_poll = tokio::time::interval(PROBE_POLL_INTERVAL);
+    let mut login_poll = tokio::time::interval(LOGIN_POLL_INTERVAL);
+    let mut bootstrap_poll = tokio::time::interval(BOOTSTRAP_POLL_INTERVAL);
+    let mut idle_poll = tokio::time::interval(IDLE_POLL_INTERVAL);
+    let mut bandwidth_poll = tokio::time::interval(BANDWIDTH_INTERVAL);
+    let mut identify_poll = tokio::time::interval(IDENTIFY_POLL_INTERVAL);
+    // Never ticks without a monitor, see below
+    let mut thermal_poll = tokio::time::interval(thermal.as_ref().map_or(LATENCY_REPORT_INTERVAL, |t| t.interval()));
+    let poll_conditions = sm.has_polled_conditions();
+    let mut stamps = LineTimestamps::new(&device.log.timestamps);
+    let mut stamp_mode = device.log.timestamps.mode;
+    let mut toggle_stamps = ToggleSignal::new();
+    let mut kernel_clocks: HashMap<String, KernelClock> = HashMap::new();
+    // When the current state was entered, for reporting how long each takes
+    let mut entered: Option<(String, Instant)> = None;
+    // The state before the current one, to go back to if its properties
+    // can't be applied
+    let mut previous: Option<String> = None;
+    // Triggers and controls running in the background, they're aborted when
+    // the device stops
+    let mut tasks = JoinSet::new();
+    let event_thread = async move {
         loop {
-            let event = rx.recv().await.unwrap();
-            //log::trace!("{:?}", &event);
-            process_event(event, &mut sm, &ptx).await;
+            // When the line being handled was printed, see [printk]
+            let mut printed = None;
+            // The line being handled and its connection, for hooks
+            let mut matched = None;
+            let mut health_changes = vec![];
+            // Whether the device went idle or woke up
+            let mut idle_changed = false;
+            let idling = idle.as_ref().is_some_and(|i| i.is_idle());
+            tokio::select! {
+                event = rx.recv() => {
+                    // Can't happen while we hold tx, but don't panic if it does
+                    let Some(event) = event else {
+                        return Err::<(), anyhow::Error>(Failure::Connection.error(format!("Connections of {} stopped", codename)));
+                    };
+                    let dispatched = Instant::now();
+                    //log::trace!("{:?}", &event);
+                    if let Event::ConnectionClosed(connection) = &event {
+                        if hotplug.contains(connection) {
+                            let _ = changes.send(ConnectionChange::Lost(connection.clone()));
+                            continue;
+                        }
+                    }
+                    let timing = match &event {
+                        Event::ConnectionEvent(data) => {
+                            let _ = console_tx.send(data.clone());
+                            if let ConnectionEvent::NewLine(line) = &data.event {
+                                let _ = events_tx.send(DeviceEvent::Line {
+                                    connection: data.device.clone(),
+                                    line: line.clone(),
+                                    timing: data.timing,
+                                });
+                            }
+                            Some(data.timing)
+                        }
+                        Event::Error { connection, message } => {
+                            let _ = events_tx.send(DeviceEvent::ConnectionError {
+                                connection: connection.clone(),
+                                message: message.clone(),
+                            });
+                            None
+                        }
+                        Event::ConnectionClosed(connection) => {
+                            let _ = events_tx.send(DeviceEvent::ConnectionClosed(connection.clone()));
+                            None
+                        }
+                        Event::ConnectionUp(connection) | Event::ConnectionDown(connection) => {
+                            let up = matches!(event, Event::ConnectionUp(_));
+                            info!("{}: {} is {}", codename, connection, if up { "up" } else { "down" });
+                            if up {
+                                logins.up(connection, &sm, dispatched);
+                                bandwidth.up(connection);
+                            } else {
+                                logins.down(connection);
+                            }
+                            let _ = events_tx.send(if up {
+                                DeviceEvent::ConnectionUp(connection.clone())
+                            } else {
+                                DeviceEvent::ConnectionDown(connection.clone())
+                            });
+                            // Let transitions react to it
+                            let _ = tx.send(Event::ConnectionEvent(ConnectionEventData {
+                                device: CONNECTIONS_SOURCE.to_string(),
+                                event: ConnectionEvent::NewLine(format!(
+                                    "connection {} {}",
+                                    connection,
+                                    if up { "up" } else { "down" }
+                                )),
+                                timing: Timing::now(),
+                            }));
+                            None
+                        }
+                        _ => None,
+                    };
+                    let stamp = match &event {
+                        Event::ConnectionEvent(ConnectionEventData { device, event: ConnectionEvent::NewLine(line), timing }) => {
+                            health_changes.extend(probes.seen(device, line, dispatched).map(|h| (device.clone(), h)));
+                            bandwidth.line(device, line);
+                            responders.seen(device, line, false, &sm, dispatched);
+                            if let Some(state) = identify.as_mut().and_then(|i| i.seen(device, line, &sm)) {
+                                if let Some(props) = sm.identified(&state) {
+                                    let _ = ptx.send(props).map_err(|e| error!("{}", e));
+                                }
+                            }
+                            if let Some(idle) = idle.as_mut() {
+                                idle_changed |= idle.seen(device, line, dispatched);
+                            }
+                            if logins.seen(device, line) {
+                                let _ = tx.send(Event::ConnectionEvent(ConnectionEventData {
+                                    device: CONNECTIONS_SOURCE.to_string(),
+                                    event: ConnectionEvent::NewLine(format!("connection {} logged-in", device)),
+                                    timing: Timing::now(),
+                                }));
+                            }
+                            let at = kernel_clocks.entry(device.clone()).or_default().align(line, timing.received);
+                            printed = Some(at);
+                            matched = Some((device.clone(), line.clone()));
+                            stamps.stamp(at).format(stamp_mode)
+                        }
+                        Event::ConnectionEvent(ConnectionEventData { device, event: ConnectionEvent::Prompt(prompt), .. }) => {
+                            responders.seen(device, prompt, true, &sm, dispatched);
+                            // Shells and bootloaders don't end the line after their prompt
+                            if let Some(state) = identify.as_mut().and_then(|i| i.seen(device, prompt, &sm)) {
+                                if let Some(props) = sm.identified(&state) {
+                                    let _ = ptx.send(props).map_err(|e| error!("{}", e));
+                                }
+                            }
+                            String::new()
+                        }
+                        Event::Probe { connection, ok } => {
+                            health_changes.extend(probes.result(connection, *ok, dispatched).map(|h| (connection.clone(), h)));
+                            String::new()
+                        }
+                        Event::Reachable { connection, host, ok } => {
+                            bootstraps.result(connection, host, *ok, dispatched);
+                            String::new()
+                        }
+                        Event::Custom(custom) => {
+                            let _ = events_tx.send(DeviceEvent::Custom(custom.clone()));
+                            let _ = tx.send(Event::ConnectionEvent(ConnectionEventData {
+                                device: custom.source.clone(),
+                                event: ConnectionEvent::NewLine(custom.line()),
+                                timing: Timing::now(),
+                            }));
+                            String::new()
+                        }
+                        Event::Overflow(connection) => {
+                            bandwidth.overflow(connection);
+                            warn!(
+                                target: &log_target(&codename, Some(connection)),
+                                "Line longer than {} bytes, splitting it. Is the baud rate right?",
+                                MAX_LINE_LENGTH
+                            );
+                            String::new()
+                        }
+                        Event::Thermal(readings) => {
+                            trace!(target: &log_target(&codename, Some(THERMAL_SOURCE)), "{:?}", readings);
+                            let _ = thermal_tx.send(readings.clone());
+                            let crossings = thermal.as_mut().map(|t| t.update(readings)).unwrap_or_default();
+                            for crossing in crossings {
+                                let _ = tx.send(Event::ConnectionEvent(ConnectionEventData {
+                                    device: THERMAL_SOURCE.to_string(),
+                                    event: ConnectionEvent::NewLine(crossing.to_string()),
+                                    timing: Timing::now(),
+                                }));
+                                let Some(trigger) = crossing.threshold.trigger.as_deref().filter(|_| crossing.exceeded) else {
+                                    continue;
+                                };
+                                warn!("{}: {}, running trigger {}", codename, crossing, trigger);
+                                let opts = TriggerOptions::default();
+                                match check_trigger(trigger, &opts, &sm, &device, &controls, &health_tx.borrow())
+                                    .and_then(|_| prepare_trigger(trigger, opts.vars, &sm, &controls, &variables, &state_tx))
+                                {
+                                    Ok(run) => {
+                                        let codename = codename.clone();
+                                        spawn_trigger(trigger.to_string(), run, &events_tx, &mut tasks, move |res| {
+                                            if let Err(e) = res {
+                                                error!("{}: thermal trigger failed: {}", codename, e);
+                                            }
+                                        });
+                                    }
+                                    Err(e) => error!("{}: {}", codename, e),
+                                }
+                            }
+                            String::new()
+                        }
+                        Event::Crash(report) => {
+                            info!(
+                                "{}: collected {} of {} crash logs",
+                                codename,
+                                report.outputs.iter().filter(|o| o.output.is_ok()).count(),
+                                report.outputs.len()
+                            );
+                            let _ = events_tx.send(DeviceEvent::CrashReport(report.clone()));
+                            String::new()
+                        }
+                        Event::Property { property, result } => {
+                            let _ = events_tx.send(DeviceEvent::Property {
+                                property: property.clone(),
+                                result: result.clone(),
+                            });
+                            match result {
+                                Ok(()) => {
+                                    let GlobalProperties::Baud(baud) = property.name;
+                                    bandwidth.baud(property.connection.as_deref(), baud);
+                                    debug!("{}: applied {:?}", codename, property.name)
+                                }
+                                Err(e) if property.on_failure == PropertyFailure::Revert
+                                    && sm.properties().contains(property) =>
+                                {
+                                    match previous.as_deref() {
+                                        Some(state) if sm.revert(state) => {
+                                            error!("{}: {}, going back to {}", codename, e, state)
+                                        }
+                                        _ => error!("{}: {}, no state to go back to", codename, e),
+                                    }
+                                }
+                                Err(e) => error!("{}: {}", codename, e),
+                            }
+                            String::new()
+                        }
+                        _ => String::new(),
+                    };
+                    process_event(event, &codename, &stamp, &mut sm, &ptx, trace.as_mut()).await?;
+                    if let Some(timing) = timing {
+                        latency.record(&timing, dispatched, Instant::now());
+                    }
+                }
+                Some(res) = tasks.join_next() => {
+                    if let Err(e) = res {
+                        error!("{}: background task failed: {}", codename, e);
+                    }
+                }
+                Some(cmd) = commands.recv() => {
+                    let busy = matches!(
+                        cmd,
+                        Command::Trigger(..) | Command::Send(..) | Command::SetControl(..) | Command::Subscribe(_)
+                    );
+                    if let Some(idle) = idle.as_mut().filter(|_| busy) {
+                        idle_changed |= idle.activity(Instant::now());
+                    }
+                    match cmd {
+                        Command::Trigger(name, opts, reply) => {
+                            match check_trigger(&name, &opts, &sm, &device, &controls, &health_tx.borrow())
+                                .and_then(|_| prepare_trigger(&name, opts.vars, &sm, &controls, &variables, &state_tx))
+                            {
+                                Ok(run) => {
+                                    spawn_trigger(name, run, &events_tx, &mut tasks, move |res| {
+                                        let _ = reply.send(res);
+                                    });
+                                }
+                                Err(e) => {
+                                    let _ = reply.send(Err(e));
+                                }
+                            }
+                        }
+                        Command::Send(data, reply) => {
+                            // The connections report whether it was sent
+                            if let Err(e) = sender.send((data, reply)) {
+                                let (_, reply) = e.0;
+                                let _ = reply.send(Err(anyhow!("Connections stopped")));
+                            }
+                        }
+                        Command::Subscribe(reply) => {
+                            let _ = reply.send(console_tx.subscribe());
+                        }
+                        Command::SubscribeThermal(reply) => {
+                            let _ = reply.send(thermal_tx.subscribe());
+                        }
+                        Command::SetControl(name, on, reply) => {
+                            // Command controls can take a while, don't hold up the console
+                            let controls = controls.clone();
+                            tasks.spawn(async move {
+                                let _ = reply.send(controls.set(&name, on).await);
+                            });
+                        }
+                        Command::AddConnection(info, reply) => {
+                            let label = info.label().to_string();
+                            let res = changes
+                                .send(ConnectionChange::Add(info))
+                                .map_err(|_| anyhow!("Connections stopped"));
+                            if res.is_ok() && !hotplug.contains(&label) {
+                                hotplug.push(label);
+                            }
+                            let _ = reply.send(res);
+                        }
+                        Command::RemoveConnection(label, reply) => {
+                            let _ = reply.send(
+                                changes
+                                    .send(ConnectionChange::Remove(label))
+                                    .map_err(|_| anyhow!("Connections stopped")),
+                            );
+                        }
+                        Command::Latency(reply) => {
+                            let _ = reply.send(latency.summary());
+                        }
+                        Command::Timestamps(mode, reply) => {
+                            stamp_mode = mode.unwrap_or(stamp_mode.next());
+                            let _ = reply.send(stamp_mode);
+                        }
+                        Command::Emit(event) => {
+                            let _ = tx.send(Event::Custom(event));
+                        }
+                    }
+                }
+                _ = toggle_stamps.recv() => {
+                    stamp_mode = stamp_mode.next();
+                    info!("{}: console timestamps {}", codename, stamp_mode);
+                }
+                _ = condition_poll.tick(), if poll_conditions => {
+                    if let Some(props) = sm.poll_conditions() {
+                        let _ = ptx.send(props).map_err(|e| error!("{}", e));
+                    }
+                }
+                _ = thermal_poll.tick(), if thermal.is_some() && !idling => {
+                    if let Some(monitor) = thermal.as_ref().filter(|t| t.active(&sm)) {
+                        monitor.sample(tx.clone());
+                    }
+                }
+                _ = probe_poll.tick(), if !probes.is_empty() && !idling => {
+                    health_changes = probes.poll(Instant::now());
+                }
+                _ = login_poll.tick(), if logins.busy() => {
+                    logins.poll(Instant::now());
+                }
+                _ = bootstrap_poll.tick(), if bootstraps.busy() => {
+                    bootstraps.poll(Instant::now());
+                }
+                _ = idle_poll.tick(), if idle.is_some() => {
+                    if let Some(idle) = idle.as_mut() {
+                        // Clients are console subscribers: attached consoles, recorded runs...
+                        idle_changed = idle.poll(console_tx.receiver_count(), &sm, Instant::now());
+                    }
+                }
+                _ = identify_poll.tick(), if identify.as_ref().is_some_and(|i| i.busy()) => {
+                    if let Some(state) = identify.as_mut().and_then(|i| i.poll(&sm, Instant::now())) {
+                        if let Some(props) = sm.identified(&state) {
+                            let _ = ptx.send(props).map_err(|e| error!("{}", e));
+                        }
+                    }
+                }
+                _ = bandwidth_poll.tick() => {
+                    bandwidth_tx.send_replace(bandwidth.tick(&codename, Instant::now()));
+                }
+                _ = latency_report.tick() => {
+                    if latency.since_report > 0 {
+                        debug!("{}: pipeline latency over the last {}", codename, latency.summary());
+                        latency.since_report = 0;
+                    }
+                }
+            }
+            for (connection, health) in health_changes {
+                let target = log_target(&codename, Some(&connection));
+                match health {
+                    Health::Healthy => info!(target: &target, "{} is responding again", connection),
+                    _ => warn!(target: &target, "{} is {}, probes aren't being answered", connection, health),
+                }
+                let _ = events_tx.send(DeviceEvent::ConnectionHealth {
+                    connection: connection.clone(),
+                    health,
+                });
+                health_tx.send_modify(|map| {
+                    map.insert(connection, health);
+                });
+            }
+            if idle_changed {
+                let idle = idle.as_ref().is_some_and(|i| i.is_idle());
+                info!("{}: {}", codename, if idle { "idle, quieting down" } else { "waking up" });
+                let _ = events_tx.send(DeviceEvent::Idle(idle));
+            }
+            let mut context_changed = false;
+            if *context_tx.borrow() != *sm.context() {
+                context_tx.send_replace(sm.context().clone());
+                context_changed = true;
+            }
+            let state = sm.current_state().map(|s| s.to_string());
+            if context_changed || *state_tx.borrow() != state {
+                bootstraps.update(&sm, Instant::now());
+            }
+            if *state_tx.borrow() != state {
+                if let Some(state) = &state {
+                    let at = printed.unwrap_or_else(Instant::now);
+                    stamps.entered(state, at);
+                    if let Some((previous, since)) = entered.replace((state.clone(), at)) {
+                        info!(
+                            "{}: {} after {:.3}s in {}",
+                            codename,
+                            state,
+                            at.saturating_duration_since(since).as_secs_f64(),
+                            previous
+                        );
+                    }
+                }
+                if let Some(to) = &state {
+                    let _ = events_tx.send(DeviceEvent::Transition {
+                        from: state_tx.borrow().clone(),
+                        to: to.clone(),
+                    });
+                }
+                previous = state_tx.send_replace(state.clone());
+                if let Some(to) = state {
+                    let mut vars = variables.clone();
+                    vars.extend(sm.context().clone());
+                    let ctx = HookContext {
+                        codename: codename.clone(),
+                        from: previous.clone(),
+                        to,
+                        line: matched,
+                        vars,
+                    };
+                    hooks::spawn(sm.hooks(previous.as_deref()), ctx, &events_tx);
+                }
+                logins.state_changed(&sm, Instant::now());
+                if let Some(crash) = crash.as_mut() {
+                    crash.state_changed(&sm, &tx);
+                }
+            }
         }
-    });
-
-    let _ = tokio::join!(conn_thread, event_thread);
+    };
 
-    Ok(())
-}
\ No newline at end of file
+    // Neither side finishes unless something went wrong, the connections are
+    // dropped along with whichever one is still running
+    tokio::select! {
+        res = conn_thread => res.map_err(|e| Failure::Connection.wrap(e)),
+        res = event_thread => res,
+    }
+}
@@ -0,0 +1,238 @@
+//! Logging in to consoles that run a getty, like the USB gadget serial port a
+//! device brings up once it has booted. A login is attempted when the
+//! connection comes up (or the device enters one of the login's states), and
+//! once the shell answers the line `connection <label> logged-in` is emitted
+//! from the `CONNECTIONS` source and the console can be preferred for input.
+
+use std::time::{Duration, Instant};
+
+use crate::config::{Device, LoginConfig};
+use crate::connections::{ConnectionChange, ConnectionInput};
+use crate::state::StateMachine;
+use anyhow::Result;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// A command whose output can't be confused with its echo, only a shell
+/// prints [LOGGED_IN]
+const CHECK_COMMAND: &str = "echo FBUG_LOGIN_$((6*7))";
+const LOGGED_IN: &str = "FBUG_LOGIN_42";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    /// Not logging in, the connection is down or not in a login state
+    Idle,
+    /// Check whether a shell is already running
+    Check,
+    /// Finish whatever the check started at a login prompt (it's taken as the
+    /// username) with an empty password, and wait for the prompt to return
+    Reset,
+    Username,
+    Password,
+    /// Run the check command again after logging in
+    Verify,
+    /// Waiting for the check command's output
+    Waiting,
+    LoggedIn,
+}
+
+struct Login {
+    connection: String,
+    config: LoginConfig,
+    username: String,
+    password: Option<String>,
+    step: Step,
+    /// When to take the next step
+    next: Option<Instant>,
+    attempts: u32,
+    /// Whether the connection is open
+    up: bool,
+}
+
+impl Login {
+    fn delay(&self) -> Duration {
+        Duration::from_millis(self.config.delay as u64)
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.step = Step::Check;
+        self.next = Some(now + self.delay());
+        self.attempts = 0;
+    }
+
+    fn stop(&mut self) {
+        self.step = Step::Idle;
+        self.next = None;
+    }
+}
+
+/// The logins of a device's consoles, driven by the device loop
+pub struct Logins {
+    logins: Vec<Login>,
+    input: UnboundedSender<ConnectionInput>,
+    changes: UnboundedSender<ConnectionChange>,
+}
+
+impl Logins {
+    pub fn new(
+        device: &Device,
+        input: UnboundedSender<ConnectionInput>,
+        changes: UnboundedSender<ConnectionChange>,
+    ) -> Result<Self> {
+        let mut logins = vec![];
+        for info in device.connections.iter() {
+            let Some(config) = info.login() else {
+                continue;
+            };
+            let username = config
+                .username
+                .clone()
+                .or_else(|| device.username.clone())
+                .ok_or_else(|| anyhow!("No username to log in to {} with", info.label()))?;
+            logins.push(Login {
+                connection: info.label().to_string(),
+                config: config.clone(),
+                username,
+                password: config.password.clone().or_else(|| device.password.clone()),
+                step: Step::Idle,
+                next: None,
+                attempts: 0,
+                // Hotplugged connections announce themselves
+                up: !info.hotplug(),
+            });
+        }
+        Ok(Self { logins, input, changes })
+    }
+
+    /// Whether any login has a step due
+    pub fn busy(&self) -> bool {
+        self.logins.iter().any(|l| l.next.is_some())
+    }
+
+    fn wanted(login: &Login, sm: &StateMachine) -> bool {
+        login.up && (login.config.states.is_empty() || login.config.states.iter().any(|s| sm.in_state(s)))
+    }
+
+    /// A connection was opened
+    pub fn up(&mut self, connection: &str, sm: &StateMachine, now: Instant) {
+        for login in self.logins.iter_mut().filter(|l| l.connection == connection) {
+            login.up = true;
+            if Self::wanted(login, sm) {
+                login.start(now);
+            }
+        }
+    }
+
+    /// A connection went away, it's logged out
+    pub fn down(&mut self, connection: &str) {
+        for login in self.logins.iter_mut().filter(|l| l.connection == connection) {
+            if login.step == Step::LoggedIn && login.config.prefer {
+                let _ = self.changes.send(ConnectionChange::Prefer(None));
+            }
+            login.up = false;
+            login.stop();
+        }
+    }
+
+    /// The device changed state, start logging in to consoles that should be
+    /// and forget about the ones that shouldn't any more
+    pub fn state_changed(&mut self, sm: &StateMachine, now: Instant) {
+        for login in self.logins.iter_mut() {
+            match (Self::wanted(login, sm), login.step) {
+                (true, Step::Idle) => login.start(now),
+                (false, Step::Idle) => {}
+                (false, step) => {
+                    if step == Step::LoggedIn && login.config.prefer {
+                        let _ = self.changes.send(ConnectionChange::Prefer(None));
+                    }
+                    login.stop();
+                }
+                (true, _) => {}
+            }
+        }
+    }
+
+    /// A line was received on `connection`, returns true if it shows the
+    /// login succeeded
+    pub fn seen(&mut self, connection: &str, line: &str) -> bool {
+        let Some(login) = self
+            .logins
+            .iter_mut()
+            .find(|l| l.connection == connection && !matches!(l.step, Step::Idle | Step::LoggedIn))
+        else {
+            return false;
+        };
+        if line.trim() != LOGGED_IN {
+            return false;
+        }
+        info!("Logged in to {} as {}", login.connection, login.username);
+        login.step = Step::LoggedIn;
+        login.next = None;
+        if login.config.prefer {
+            let _ = self.changes.send(ConnectionChange::Prefer(Some(login.connection.clone())));
+        }
+        true
+    }
+
+    /// Take the steps that are due
+    pub fn poll(&mut self, now: Instant) {
+        for login in self.logins.iter_mut() {
+            if login.next.map_or(true, |next| now < next) {
+                continue;
+            }
+            let send = |data: &str| {
+                let _ = self.input.send(ConnectionInput {
+                    connection: Some(login.connection.clone()),
+                    data: data.to_string().into(),
+                });
+            };
+            let delay = login.delay();
+            let timeout = Duration::from_millis(login.config.timeout as u64);
+            match login.step {
+                Step::Check => {
+                    send(CHECK_COMMAND);
+                    login.step = Step::Reset;
+                }
+                Step::Reset => {
+                    send("");
+                    login.step = Step::Username;
+                    // Failed logins are delayed before the prompt comes back
+                    login.next = Some(now + timeout);
+                    continue;
+                }
+                Step::Username => {
+                    debug!("Logging in to {} as {}", login.connection, login.username);
+                    send(&login.username);
+                    login.step = match login.password {
+                        Some(_) => Step::Password,
+                        None => Step::Verify,
+                    };
+                }
+                Step::Password => {
+                    send(login.password.as_deref().unwrap_or_default());
+                    login.step = Step::Verify;
+                }
+                Step::Verify => {
+                    send(CHECK_COMMAND);
+                    login.step = Step::Waiting;
+                    login.next = Some(now + timeout);
+                    continue;
+                }
+                Step::Waiting => {
+                    login.attempts += 1;
+                    if login.attempts >= login.config.attempts {
+                        warn!(
+                            "Failed to log in to {} after {} attempts",
+                            login.connection, login.attempts
+                        );
+                        login.stop();
+                        continue;
+                    }
+                    debug!("No shell on {} yet, trying to log in again", login.connection);
+                    login.step = Step::Reset;
+                }
+                Step::Idle | Step::LoggedIn => {}
+            }
+            login.next = Some(now + delay);
+        }
+    }
+}
@@ -1,53 +1,1056 @@
-use anyhow::Result;
-use clap::Parser;
-use env_logger::fmt::Formatter;
-use fbug::main_loop;
-use fbug::{config::load_config, connections::Connections, state::StateMachine, Event};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, FixedOffset};
+use clap::{Parser, Subcommand};
+use env_logger::fmt::{Color, Formatter};
+use fbug::bench::{self, BenchOptions};
+use fbug::check::{check_device, Diagnostic, Severity};
+use fbug::doctor;
+use fbug::exec::{self, ExecOutcome};
+use fbug::exit::{self, Failure};
+use fbug::labgrid;
+use fbug::lava::{self, LavaAction};
+use fbug::history::{self, SearchOptions};
+use fbug::health::Health;
+use fbug::fleet::{self, Access, DeviceResult, Operation, PowerAction, Selection, TagFilter};
+use fbug::remote::{self, RemoteClient, RemoteDevice};
+use fbug::reservation::{self, ReservationGuard};
+use fbug::scenario;
+use fbug::session::{self, Recorder, Replay};
+use fbug::trace;
+use fbug::vars::{self, Vars};
+use fbug::{log_target, RunningDevice};
+use log::{debug, LevelFilter};
+use fbug::config::{load_host_config, Device, DisplayConfig, HostConfig, LogLayout, TimestampMode};
+use fbug::{config::load_configs, connections::{self, Connections}, state::StateMachine, ConnectionInput, Event, InputData};
+use futures::future::{join_all, try_join_all};
 use log::Record;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::json;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     // TODO: Have main conf + multiple per device configs
+    /// Device config file, or a directory of them. Can be given multiple times
     #[arg(short, long, default_value = "XDG_CONFIG_HOME/fbug/config.yaml")]
-    pub config_path: PathBuf,
+    pub config_path: Vec<PathBuf>,
+    /// Host config, used for settings shared by all devices
+    #[arg(long, default_value = "XDG_CONFIG_HOME/fbug/fbug.yaml")]
+    pub host_config: PathBuf,
+    /// Operate on the device with this codename. Can be given multiple times
+    #[arg(short, long = "device")]
+    pub devices: Vec<String>,
+    /// Operate on all devices in this group
+    #[arg(short, long, conflicts_with = "devices")]
+    pub group: Option<String>,
+    /// Only operate on devices whose tags match this expression, e.g.
+    /// "soc:sdm845,!owner:caleb|soc:sm8150"
+    #[arg(short, long)]
+    pub tags: Option<TagFilter>,
+    /// Operate on all configured devices
+    #[arg(short, long, conflicts_with_all = ["devices", "group"])]
+    pub all: bool,
+    /// Operate on devices exported by a remote agent (host[:port]) instead of
+    /// local configs
+    #[arg(short, long)]
+    pub remote: Option<String>,
+    /// Open the devices in this process even if the daemon is running them
+    #[arg(short = 'F', long, conflicts_with = "remote")]
+    pub foreground: bool,
+    /// The user to act as when checking reservations, defaults to $USER
+    #[arg(short, long)]
+    pub user: Option<String>,
+    /// Wait for devices reserved by other users to be released instead of refusing
+    #[arg(short, long)]
+    pub queue: bool,
+    /// Log more, can be given twice for trace
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+    /// Set the log level, globally ("debug") or for a target like RUST_LOG
+    /// ("device:sdm845:MODEM=trace"). Can be given multiple times, overrides
+    /// the device configs
+    #[arg(short = 'L', long = "log-level")]
+    pub log_levels: Vec<String>,
+    /// Prefix console lines with timestamps: off, elapsed (since the last
+    /// transition), delta (since the previous line) or both. Overrides the
+    /// device configs, send SIGUSR1 to cycle through them while running
+    #[arg(short = 'T', long)]
+    pub timestamps: Option<TimestampMode>,
+    /// How the logs of several devices are told apart: plain, color (each
+    /// device in its own color) or split (two devices side by side).
+    /// Overrides the host config
+    #[arg(long)]
+    pub layout: Option<LogLayout>,
+    /// Print machine readable JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Record the console session, what's typed and what's received, to this
+    /// file so it can be replayed with `fbug replay`
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// Record how every console line was matched against the transitions
+    /// to this file (one per device if several are selected), to find out
+    /// why a transition doesn't fire
+    #[arg(long, conflicts_with = "remote")]
+    pub trace_matches: Option<PathBuf>,
+    /// Only watch: the agent or daemon refuses input and controls from this
+    /// client, so there's no risk of typing into someone else's session
+    #[arg(long, conflicts_with = "foreground")]
+    pub read_only: bool,
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Connect to the device and run the debugger (default)
+    Run,
+    /// Load and statically analyse the config without touching any hardware
+    Check,
+    /// Check that this host is ready to run the selected devices: ports
+    /// present and openable, power control reachable, SSH host keys known
+    /// and USB devices visible
+    Doctor,
+    /// List the selected devices
+    List,
+    /// Commands for using fbug as a LAVA dispatcher's device backend
+    Lava {
+        #[command(subcommand)]
+        command: LavaCommand,
+    },
+    /// Export the selected devices (all by default) as labgrid resources
+    Labgrid {
+        /// Print the labgrid-exporter config for the exported resources and exit
+        #[arg(short, long)]
+        print_config: bool,
+    },
+    /// Serve the selected devices (all by default) to remote clients
+    Agent {
+        /// Address to listen on, or unix:<path> for a Unix socket. Ignored if
+        /// started by systemd socket activation
+        #[arg(short, long, default_value = "0.0.0.0:7420")]
+        listen: String,
+    },
+    /// Run the selected devices (all by default) and serve them on a local
+    /// socket, other commands for those devices then attach to the daemon
+    /// instead of opening the devices themselves
+    Daemon,
+    /// Run a trigger on each selected device
+    Trigger {
+        name: String,
+        /// Wait for the device to reach this state after the trigger completes
+        #[arg(short, long)]
+        wait: Option<String>,
+        /// Give up after this many seconds
+        #[arg(short, long, default_value_t = 60)]
+        timeout: u64,
+        /// Set a variable used by the trigger sequence, as name=value. Can be
+        /// given multiple times
+        #[arg(short = 'V', long = "var", value_parser = vars::parse_var)]
+        vars: Vec<(String, String)>,
+        /// Run the trigger even if it's interlocked in the current state
+        #[arg(long)]
+        force: bool,
+    },
+    /// Power each selected device on or off with its power control
+    Power {
+        action: PowerAction,
+        /// Don't wait for the device to reach its on or off state
+        #[arg(short, long)]
+        no_wait: bool,
+        /// Give up after this many seconds
+        #[arg(short, long, default_value_t = 60)]
+        timeout: u64,
+    },
+    /// Send a line, control character or bytes to a connection of the devices
+    /// run by the daemon or a remote agent, without attaching to the console
+    Send {
+        /// The line to send
+        #[arg(required_unless_present_any = ["ctrl", "hex"], conflicts_with_all = ["ctrl", "hex"])]
+        text: Option<String>,
+        /// Send the text as is, without a line ending
+        #[arg(short, long, requires = "text")]
+        raw: bool,
+        /// Send a control character, e.g. c or ^C
+        #[arg(long, value_parser = connections::parse_ctrl, conflicts_with = "hex")]
+        ctrl: Option<u8>,
+        /// Send bytes given in hex, e.g. 1b5b41
+        #[arg(short = 'x', long)]
+        hex: Option<String>,
+        /// The connection to send to, defaults to the first one
+        #[arg(short = 'C', long)]
+        connection: Option<String>,
+    },
+    /// Reserve the selected devices so other users can't control them
+    Reserve {
+        /// Release the reservation automatically after this many seconds
+        #[arg(short = 'f', long = "for")]
+        duration: Option<u64>,
+        /// A note explaining why the devices are reserved
+        #[arg(short, long)]
+        note: Option<String>,
+    },
+    /// Release reservations on the selected devices
+    Release {
+        /// Release reservations held by other users
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Show who has reserved the selected devices
+    Reservations,
+    /// Search the console history of the selected devices (the console logs
+    /// of their run artifacts)
+    Grep {
+        /// Regex to search for
+        pattern: Regex,
+        /// Only search lines received after this time, RFC 3339 or relative
+        /// like 30m, 2h or 1d
+        #[arg(short = 'S', long, value_parser = history::parse_time)]
+        since: Option<DateTime<FixedOffset>>,
+        /// Only search lines received before this time
+        #[arg(short = 'U', long, value_parser = history::parse_time)]
+        until: Option<DateTime<FixedOffset>>,
+        /// Only search lines from this connection
+        #[arg(short = 'C', long)]
+        connection: Option<String>,
+        /// Lines of context to print around each match
+        #[arg(short = 'n', long, default_value_t = 0)]
+        context: usize,
+    },
+    /// Send input to the console and wait for a line matching a regex. Exits 0
+    /// if it matched, 124 on timeout and 1 on any other error
+    Exec {
+        /// Input to send, if not given only wait for the expected output
+        input: Option<String>,
+        /// Regex to wait for
+        #[arg(short, long)]
+        expect: Regex,
+        /// The connection to send to, defaults to the first one
+        #[arg(short = 'C', long)]
+        connection: Option<String>,
+        /// Give up after this many seconds
+        #[arg(short, long, default_value_t = 30)]
+        timeout: u64,
+    },
+    /// Run scenario files one after the other on each selected device, the
+    /// devices concurrently, and print a report. Exits like exec
+    Scenario {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Give up on a scenario after this many seconds
+        #[arg(short, long, default_value_t = 3600)]
+        timeout: u64,
+    },
+    /// Measure console throughput and latency, the console must echo back what
+    /// it's sent (e.g. a loopback adapter)
+    Bench {
+        /// Number of lines to send
+        #[arg(short = 'n', long, default_value_t = 1000)]
+        count: usize,
+        /// Length of each line in bytes
+        #[arg(short, long, default_value_t = 64)]
+        size: usize,
+        /// Lines per second to send, 0 sends as fast as possible
+        #[arg(short, long, default_value_t = 0)]
+        rate: u32,
+        /// The connection to benchmark, defaults to the first one
+        #[arg(short = 'C', long)]
+        connection: Option<String>,
+        /// Seconds to wait for lines still in flight after the last one is sent
+        #[arg(long, default_value_t = 2)]
+        drain: u64,
+    },
+    /// Send what was typed in a recorded console session (see --record) to
+    /// the device again, with its original timing
+    Replay {
+        /// The recording
+        path: PathBuf,
+        /// Replay this many times faster
+        #[arg(short, long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Wait for each selected device to reach a state
+    Wait {
+        /// The state to wait for, defaults to the device's resting state
+        state: Option<String>,
+        /// Give up after this many seconds
+        #[arg(short, long, default_value_t = 60)]
+        timeout: u64,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LavaCommand {
+    PowerOn,
+    PowerOff,
+    HardReset,
+    /// Attach stdin/stdout to the device console
+    Console,
+    /// Print the device dictionary commands for the selected devices
+    DeviceDict,
+}
+
+impl LavaCommand {
+    fn action(&self) -> Option<LavaAction> {
+        match self {
+            LavaCommand::PowerOn => Some(LavaAction::PowerOn),
+            LavaCommand::PowerOff => Some(LavaAction::PowerOff),
+            LavaCommand::HardReset => Some(LavaAction::HardReset),
+            _ => None,
+        }
+    }
+}
+
+/// The command line LAVA should use to select a device
+fn lava_invocation(args: &Args, codename: &str) -> Result<String> {
+    let mut cmd = vec![std::env::current_exe()?.to_string_lossy().to_string()];
+    for path in args.config_path.iter() {
+        let path = path.canonicalize().unwrap_or_else(|_| path.clone());
+        cmd.extend(["-c".to_string(), path.to_string_lossy().to_string()]);
+    }
+    cmd.extend(["-d".to_string(), codename.to_string()]);
+    Ok(cmd.join(" "))
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    setup_logging();
-    let args = Args::parse();
-    let device = load_config(&args.config_path).unwrap();
+async fn main() {
+    if let Err(e) = run(Args::parse()).await {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(exit::code(&e));
+    }
+}
+
+async fn run(mut args: Args) -> Result<()> {
+    let is_agent = matches!(
+        args.command,
+        Some(Commands::Agent { .. }) | Some(Commands::Labgrid { .. }) | Some(Commands::Daemon)
+    );
+    let selection = Selection {
+        all: args.all || (is_agent && args.group.is_none() && args.devices.is_empty()),
+        group: args.group.clone(),
+        devices: args.devices.clone(),
+        tags: args.tags.clone(),
+    };
+    let access = Access {
+        user: args.user.clone().unwrap_or_else(reservation::current_user),
+        queue: args.queue,
+    };
+    let mut host = load_host_config(&args.host_config).map_err(|e| Failure::Config.wrap(e))?;
+    host.client.read_only |= args.read_only;
+    if let Some(dir) = &host.reservations.dir {
+        reservation::set_dir(dir.clone());
+    }
+    if let (Some(Commands::Lava { command: LavaCommand::DeviceDict }), Some(_)) = (&args.command, &args.remote) {
+        bail!("Generate the device dictionary on the agent host");
+    }
+    if let Some(addr) = &args.remote {
+        setup_logging(&args, &[], &host.display);
+        let command = args.command.take().unwrap_or(Commands::Run);
+        let stamps = args.timestamps.unwrap_or_default();
+        let console = ConsoleOptions {
+            stamps,
+            record: args.record.as_deref(),
+        };
+        return remote_main(addr, &host, command, &selection, &access, console, args.json).await;
+    }
+    let mut devices = selection.select(load_configs(&args.config_path).map_err(|e| Failure::Config.wrap(e))?)?;
+    check_log_levels(&devices).map_err(|e| Failure::Config.wrap(e))?;
+    setup_logging(&args, &devices, &host.display);
+    if let Some(mode) = args.timestamps {
+        for device in devices.iter_mut() {
+            device.log.timestamps.mode = mode;
+        }
+    }
+    if let Some(path) = &args.trace_matches {
+        let several = devices.len() > 1;
+        for device in devices.iter_mut() {
+            device.trace_matches = Some(if several {
+                trace::device_path(path, &device.codename)
+            } else {
+                path.clone()
+            });
+        }
+    }
+
+    if !args.foreground {
+        let command = args.command.get_or_insert(Commands::Run);
+        if opens_devices(command) {
+            if let Some(addr) = daemon_addr(&host, &devices).await? {
+                debug!("Attaching to the daemon at {}", addr);
+                if !attachable(command) {
+                    bail!("The daemon is running these devices, stop it or use --foreground");
+                }
+                if args.trace_matches.is_some() {
+                    bail!("The daemon is running these devices, use --foreground to trace matches");
+                }
+                let selection = Selection {
+                    all: false,
+                    group: None,
+                    devices: devices.iter().map(|d| d.codename.clone()).collect(),
+                    tags: None,
+                };
+                let command = match args.command.take().unwrap() {
+                    // The daemon doesn't know about LAVA, run the mapped trigger
+                    Commands::Lava { command } if command.action().is_some() => {
+                        if devices.len() != 1 {
+                            bail!("Select a single device for LAVA");
+                        }
+                        Commands::Trigger {
+                            name: command.action().unwrap().trigger(&devices[0])?,
+                            wait: None,
+                            timeout: 60,
+                            vars: vec![],
+                            force: false,
+                        }
+                    }
+                    command => command,
+                };
+                let stamps = devices[0].log.timestamps.mode;
+                let console = ConsoleOptions {
+                    stamps,
+                    record: args.record.as_deref(),
+                };
+                return remote_main(&addr, &host, command, &selection, &access, console, args.json).await;
+            }
+        }
+    }
+
+    if args.read_only && args.command.as_ref().map_or(true, opens_devices) {
+        bail!("Read-only clients can only watch devices run by the daemon or an agent");
+    }
+
+    let (op, timeout) = match args.command.take().unwrap_or(Commands::Run) {
+        Commands::Run => {
+            // Hold a reservation while attached so nobody power cycles the device under us
+            let _guards = devices
+                .iter()
+                .map(|d| ReservationGuard::new(&d.codename, &access.user, Some("attached".to_string())))
+                .collect::<Result<Vec<_>>>()?;
+            // Healthy devices never stop, so fail as soon as any one does
+            try_join_all(devices.into_iter().map(|d| d.start().wait())).await?;
+            return Ok(());
+        }
+        Commands::Reserve { duration, note } => {
+            for device in devices.iter() {
+                let r = reservation::reserve(
+                    &device.codename,
+                    &access.user,
+                    duration.map(Duration::from_secs),
+                    None,
+                    note.clone(),
+                )?;
+                println!("{}: {}", device.codename, r);
+            }
+            return Ok(());
+        }
+        Commands::Release { force } => {
+            for device in devices.iter() {
+                reservation::release(&device.codename, &access.user, force)?;
+                println!("{}: released", device.codename);
+            }
+            return Ok(());
+        }
+        Commands::Reservations => {
+            let mut reservations = vec![];
+            for device in devices.iter() {
+                let current = reservation::current(&device.codename)?;
+                if !args.json {
+                    match &current {
+                        Some(r) => println!("{}: {}", device.codename, r),
+                        None => println!("{}: free", device.codename),
+                    }
+                }
+                reservations.push(json!({ "codename": device.codename, "reservation": current }));
+            }
+            if args.json {
+                print_json(&reservations)?;
+            }
+            return Ok(());
+        }
+        Commands::Grep {
+            pattern,
+            since,
+            until,
+            connection,
+            context,
+        } => {
+            let opts = SearchOptions {
+                pattern,
+                since,
+                until,
+                connection,
+                context,
+            };
+            let mut matches = vec![];
+            for device in devices.iter() {
+                matches.extend(history::search(&host.artifacts, &device.codename, &opts)?);
+            }
+            if args.json {
+                print_json(&matches)?;
+            } else {
+                for (i, m) in matches.iter().enumerate() {
+                    if context > 0 && i > 0 {
+                        println!("--");
+                    }
+                    println!("{}", m);
+                }
+            }
+            if matches.is_empty() {
+                std::process::exit(exit::FAILURE);
+            }
+            return Ok(());
+        }
+        Commands::List => {
+            if args.json {
+                return print_json(&devices.iter().map(RemoteDevice::from).collect::<Vec<_>>());
+            }
+            for device in devices.iter() {
+                println!("{}: {}", device.codename, device.name);
+            }
+            return Ok(());
+        }
+        Commands::Lava { command: LavaCommand::DeviceDict } => {
+            for device in devices.iter() {
+                println!("{{# {} #}}", device.codename);
+                print!("{}", lava::device_dict(device, &lava_invocation(&args, &device.codename)?));
+            }
+            return Ok(());
+        }
+        Commands::Lava { command: LavaCommand::Console } => {
+            if devices.len() != 1 {
+                bail!("Select a single device to attach to");
+            }
+            let recorder = args.record.as_deref().map(Recorder::create).transpose()?;
+            return lava::console(devices.into_iter().next().unwrap(), recorder).await;
+        }
+        Commands::Lava { command } => {
+            let action = command.action().unwrap();
+            if devices.len() != 1 {
+                bail!("Select a single device for LAVA");
+            }
+            let name = action.trigger(&devices[0])?;
+            (
+                Operation::Trigger {
+                    name,
+                    wait: None,
+                    vars: Vars::new(),
+                    force: false,
+                },
+                60,
+            )
+        }
+        Commands::Labgrid { print_config: true } => {
+            print!("{}", labgrid::exporter_config(&devices, &host.labgrid)?);
+            return Ok(());
+        }
+        Commands::Labgrid { print_config: false } => return labgrid::serve(devices, &host.labgrid, &host.agent).await,
+        Commands::Agent { listen } => return remote::serve(devices, &listen, &host.agent, &host.artifacts).await,
+        Commands::Daemon => {
+            let socket = host.daemon.socket();
+            if let Some(dir) = socket.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let listen = format!("{}{}", remote::UNIX_PREFIX, socket.display());
+            return remote::serve(devices, &listen, &host.agent, &host.artifacts).await;
+        }
+        Commands::Check => {
+            let mut ok = true;
+            let mut reports = vec![];
+            for device in devices.iter() {
+                let diags = check(device, args.json)?;
+                ok &= !diags.iter().any(|d| d.severity == Severity::Error);
+                reports.push(json!({ "codename": device.codename, "diagnostics": diags }));
+            }
+            if args.json {
+                print_json(&reports)?;
+            }
+            if !ok {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Commands::Doctor => {
+            let reports = join_all(devices.iter().map(doctor::check)).await;
+            if args.json {
+                print_json(&reports)?;
+            } else {
+                for report in reports.iter() {
+                    println!("{}", report);
+                }
+            }
+            if reports.iter().any(|r| !r.ready) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Commands::Exec { input, expect, connection, timeout } => {
+            if devices.len() != 1 {
+                bail!("Select a single device to exec on");
+            }
+            let input = input.map(|data| ConnectionInput { connection, data: data.into() });
+            let device = devices.into_iter().next().unwrap();
+            match exec::exec(device, input, &expect, Duration::from_secs(timeout), &access, &host.artifacts).await {
+                Ok(ExecOutcome::Matched(line)) => println!("{}", line),
+                Ok(ExecOutcome::TimedOut) => {
+                    eprintln!("Timed out after {}s waiting for {}", timeout, expect);
+                    std::process::exit(exit::TIMEOUT);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(exit::code(&e));
+                }
+            }
+            return Ok(());
+        }
+        Commands::Scenario { paths, timeout } => {
+            let scenarios = paths.iter().map(|p| scenario::load(p)).collect::<Result<Vec<_>>>()?;
+            let report = scenario::run(devices, &scenarios, Duration::from_secs(timeout), &access, &host.artifacts).await;
+            if args.json {
+                print_json(&report)?;
+            } else {
+                println!("{}", report);
+            }
+            exit_on_failure(report.errors());
+            return Ok(());
+        }
+        Commands::Bench { count, size, rate, connection, drain } => {
+            if devices.len() != 1 {
+                bail!("Select a single device to benchmark");
+            }
+            let device = devices.into_iter().next().unwrap();
+            let _guard = ReservationGuard::new(&device.codename, &access.user, Some("bench".to_string()))?;
+            let dev = RunningDevice::spawn(device);
+            let opts = BenchOptions {
+                count,
+                size,
+                rate,
+                drain: Duration::from_secs(drain),
+                connection,
+            };
+            let report = bench::bench(&dev, &opts).await;
+            let latency = dev.latency().await;
+            let bandwidth = dev.bandwidth();
+            dev.shutdown().await?;
+            println!("{}", report?);
+            println!("pipeline:   {}", latency?);
+            for (connection, throughput) in bandwidth {
+                println!("{:<12}{}", format!("{}:", connection), throughput);
+            }
+            return Ok(());
+        }
+        Commands::Replay { path, speed } => {
+            if devices.len() != 1 {
+                bail!("Select a single device to replay to");
+            }
+            let mut replay = Replay::new(session::load(&path)?, speed)?;
+            let device = devices.into_iter().next().unwrap();
+            let _guard = ReservationGuard::new(&device.codename, &access.user, Some("replay".to_string()))?;
+            // Its output is logged like any other console output
+            let dev = RunningDevice::spawn(device);
+            let res = async {
+                while let Some(input) = replay.next().await {
+                    dev.send(input).await?;
+                }
+                Ok::<(), anyhow::Error>(())
+            }
+            .await;
+            dev.shutdown().await?;
+            return res;
+        }
+        Commands::Trigger {
+            name,
+            wait,
+            timeout,
+            vars,
+            force,
+        } => (
+            Operation::Trigger {
+                name,
+                wait,
+                vars: vars.into_iter().collect(),
+                force,
+            },
+            timeout,
+        ),
+        Commands::Wait { state, timeout } => (Operation::Wait { state }, timeout),
+        Commands::Power {
+            action,
+            no_wait,
+            timeout,
+        } => (Operation::Power { action, wait: !no_wait }, timeout),
+        Commands::Send { .. } => bail!("Nothing is running the selected devices, start `fbug daemon` or use --remote"),
+    };
+
+    let results = fleet::run(devices, op, Duration::from_secs(timeout), access, &host.artifacts).await;
+    print_results(&results, args.json)
+}
+
+/// What `fbug send` sends, clap makes sure exactly one of them is given
+fn input_data(text: Option<String>, raw: bool, ctrl: Option<u8>, hex: Option<String>) -> Result<InputData> {
+    Ok(match (text, ctrl, hex) {
+        (Some(text), _, _) if raw => InputData::Raw(text.into_bytes()),
+        (Some(text), _, _) => InputData::Line(text),
+        (None, Some(ctrl), _) => InputData::Raw(vec![ctrl]),
+        (None, None, Some(hex)) => InputData::Raw(connections::parse_hex(&hex)?),
+        (None, None, None) => bail!("Nothing to send"),
+    })
+}
+
+/// Whether a command opens the connections of devices, and would conflict
+/// with the daemon running them
+fn opens_devices(command: &Commands) -> bool {
+    !matches!(
+        command,
+        Commands::Check
+            | Commands::Doctor
+            | Commands::List
+            | Commands::Reserve { .. }
+            | Commands::Release { .. }
+            | Commands::Reservations
+            | Commands::Grep { .. }
+            | Commands::Daemon
+            | Commands::Lava {
+                command: LavaCommand::DeviceDict
+            }
+    )
+}
+
+/// Whether a command can be run through the daemon
+fn attachable(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Run
+            | Commands::Trigger { .. }
+            | Commands::Wait { .. }
+            | Commands::Power { .. }
+            | Commands::Send { .. }
+            | Commands::Replay { .. }
+            | Commands::Lava { .. }
+    )
+}
+
+/// The address of the daemon if it's running the selected devices. If it's
+/// only running some of them there's no way to avoid conflicting with it.
+async fn daemon_addr(host: &HostConfig, devices: &[Device]) -> Result<Option<String>> {
+    let socket = host.daemon.socket();
+    if !socket.exists() {
+        return Ok(None);
+    }
+    let addr = format!("{}{}", remote::UNIX_PREFIX, socket.display());
+    let running = match RemoteClient::connect(&addr, &host.client).await {
+        Ok(mut client) => client.list().await?,
+        Err(e) => {
+            // Left behind by a daemon that didn't exit cleanly
+            debug!("Daemon isn't running: {}", e);
+            return Ok(None);
+        }
+    };
+    let (attached, local): (Vec<&Device>, Vec<&Device>) = devices
+        .iter()
+        .partition(|d| running.iter().any(|r| r.codename == d.codename));
+    match (attached.is_empty(), local.is_empty()) {
+        (true, _) => Ok(None),
+        (false, true) => Ok(Some(addr)),
+        (false, false) => bail!(
+            "The daemon is running {} but not {}, select them separately or use --foreground",
+            attached.iter().map(|d| d.codename.as_str()).collect::<Vec<_>>().join(", "),
+            local.iter().map(|d| d.codename.as_str()).collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+/// How an attached console is shown and recorded
+struct ConsoleOptions<'a> {
+    stamps: TimestampMode,
+    record: Option<&'a Path>,
+}
 
-    main_loop(device).await
+impl ConsoleOptions<'_> {
+    fn recorder(&self) -> Result<Option<Recorder>> {
+        self.record.map(Recorder::create).transpose()
+    }
 }
 
-fn setup_logging() {
-    #[cfg(debug_assertions)]
-    ::std::env::set_var("RUST_LOG", "trace");
-    #[cfg(not(debug_assertions))]
-    ::std::env::set_var("RUST_LOG", "info");
+/// Handle commands against devices exported by a remote agent
+async fn remote_main(
+    addr: &str,
+    host: &HostConfig,
+    command: Commands,
+    selection: &Selection,
+    access: &Access,
+    console: ConsoleOptions<'_>,
+    json: bool,
+) -> Result<()> {
+    let devices = selection.select(RemoteClient::connect(addr, &host.client).await?.list().await?)?;
+    let (op, timeout) = match command {
+        Commands::Run => {
+            if devices.len() != 1 {
+                bail!("Select a single device to attach to");
+            }
+            return RemoteClient::connect(addr, &host.client)
+                .await?
+                .console(&devices[0].codename, &access.user, console.stamps, console.recorder()?)
+                .await;
+        }
+        Commands::List => {
+            if json {
+                return print_json(&devices);
+            }
+            for device in devices.iter() {
+                let problems = device
+                    .health
+                    .iter()
+                    .filter(|(_, h)| **h != Health::Healthy)
+                    .map(|(c, h)| format!(" {} {}", c, h))
+                    .chain(
+                        device
+                            .bandwidth
+                            .iter()
+                            .filter(|(_, t)| t.saturated)
+                            .map(|(c, _)| format!(" {} saturated", c)),
+                    )
+                    .collect::<String>();
+                println!(
+                    "{}: {} (state {}) triggers: {}{}",
+                    device.codename,
+                    device.name,
+                    device.state.as_deref().unwrap_or("unknown"),
+                    device.triggers.join(", "),
+                    problems
+                );
+            }
+            return Ok(());
+        }
+        Commands::Trigger {
+            name,
+            wait,
+            timeout,
+            vars,
+            force,
+        } => (
+            Operation::Trigger {
+                name,
+                wait,
+                vars: vars.into_iter().collect(),
+                force,
+            },
+            timeout,
+        ),
+        Commands::Wait { state, timeout } => (Operation::Wait { state }, timeout),
+        Commands::Power {
+            action,
+            no_wait,
+            timeout,
+        } => (Operation::Power { action, wait: !no_wait }, timeout),
+        Commands::Send {
+            text,
+            raw,
+            ctrl,
+            hex,
+            connection,
+        } => {
+            let data = input_data(text, raw, ctrl, hex)?;
+            let mut client = RemoteClient::connect(addr, &host.client).await?;
+            for device in devices.iter() {
+                client
+                    .send(&device.codename, connection.clone(), data.clone(), &access.user)
+                    .await
+                    .map_err(|e| anyhow!("{}: {}", device.codename, e))?;
+            }
+            return Ok(());
+        }
+        Commands::Replay { path, speed } => {
+            if devices.len() != 1 {
+                bail!("Select a single device to replay to");
+            }
+            let mut replay = Replay::new(session::load(&path)?, speed)?;
+            let mut client = RemoteClient::connect(addr, &host.client).await?;
+            while let Some(input) = replay.next().await {
+                client
+                    .send(&devices[0].codename, input.connection, input.data, &access.user)
+                    .await?;
+            }
+            return Ok(());
+        }
+        Commands::Lava { command: LavaCommand::Console } => {
+            if devices.len() != 1 {
+                bail!("Select a single device to attach to");
+            }
+            return RemoteClient::connect(addr, &host.client)
+                .await?
+                .console(&devices[0].codename, &access.user, TimestampMode::Off, console.recorder()?)
+                .await;
+        }
+        cmd => bail!("{:?} isn't supported with --remote", cmd),
+    };
+
+    let results = remote::run(addr, &host.client, devices, op, Duration::from_secs(timeout), &access.user).await;
+    print_results(&results, json)
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Print the result of an operation on each device. If any failed then exit
+/// with the code for their failure if they all failed the same way, otherwise
+/// the generic failure code.
+fn print_results(results: &[DeviceResult], json: bool) -> Result<()> {
+    if json {
+        print_json(results)?;
+    } else {
+        for res in results.iter() {
+            println!("{}", res);
+        }
+    }
+    exit_on_failure(results.iter().filter_map(|r| r.result.as_ref().err()));
+    Ok(())
+}
 
-    env_logger::Builder::from_default_env()
-        .format(|buf, record| {
+/// Exit with the code for the failures if they were all the same kind,
+/// otherwise the generic failure code. Returns if there weren't any.
+fn exit_on_failure<'a>(errors: impl Iterator<Item = &'a anyhow::Error>) {
+    let mut codes = errors.map(exit::code);
+    if let Some(code) = codes.next() {
+        std::process::exit(if codes.all(|c| c == code) { code } else { exit::FAILURE });
+    }
+}
+
+/// Check a device and print its diagnostics unless they're wanted as JSON
+fn check(device: &fbug::config::Device, json: bool) -> Result<Vec<Diagnostic>> {
+    let diags = check_device(device)?;
+    if json {
+        return Ok(diags);
+    }
+    for diag in diags.iter() {
+        println!("{}: {}", device.codename, diag);
+    }
+    let errors = diags.iter().filter(|d| d.severity == Severity::Error).count();
+    println!(
+        "{}: {} errors, {} warnings",
+        device.codename,
+        errors,
+        diags.len() - errors
+    );
+    Ok(diags)
+}
+
+/// Colors that tell devices' log lines apart, in the order the devices are
+/// first seen
+const DEVICE_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Yellow,
+    Color::Green,
+    Color::Blue,
+    Color::Red,
+];
+
+/// Split `text` into lines of at most `width` characters
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    for line in text.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        lines.extend(chars.chunks(width.max(1)).map(|c| c.iter().collect::<String>()));
+    }
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines
+}
+
+/// Log levels are set by target prefix, so the level of a device or
+/// connection would also apply to any other whose name it's a prefix of. The
+/// level of a device applying to its own connections is intended.
+fn check_log_levels(devices: &[Device]) -> Result<()> {
+    for device in devices {
+        if device.log.level.is_some() {
+            let other = devices
+                .iter()
+                .find(|d| d.codename != device.codename && d.codename.starts_with(&device.codename));
+            if let Some(other) = other {
+                bail!(
+                    "The log level of {} would also apply to {}, rename one of them",
+                    device.codename,
+                    other.codename
+                );
+            }
+        }
+        for connection in device.log.connections.keys() {
+            let other = device
+                .connections
+                .iter()
+                .map(|c| c.label())
+                .find(|l| *l != connection.as_str() && l.starts_with(connection.as_str()));
+            if let Some(other) = other {
+                bail!(
+                    "{}: the log level of {} would also apply to {}, rename one of them",
+                    device.codename,
+                    connection,
+                    other
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set up logging, later sources take precedence: the default of info (or
+/// more with -v), RUST_LOG, the log levels of each device and then -L
+fn setup_logging(args: &Args, devices: &[Device], display: &DisplayConfig) {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(match args.verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    for device in devices {
+        if let Some(level) = device.log.level {
+            builder.filter_module(&log_target(&device.codename, None), level);
+        }
+        for (connection, level) in device.log.connections.iter() {
+            builder.filter_module(&log_target(&device.codename, Some(connection)), *level);
+        }
+    }
+    for filters in args.log_levels.iter() {
+        builder.parse_filters(filters);
+    }
+
+    let layout = args.layout.unwrap_or(display.layout);
+    let width = display.column_width;
+    // Devices that log through the remote agent are only known once seen
+    let seen = Mutex::new(devices.iter().map(|d| d.codename.clone()).collect::<Vec<_>>());
+    builder
+        .format(move |buf, record| {
             let style = buf.default_level_style(record.level());
             let mut local_file_style = buf.style();
             let p = PathBuf::from(record.file().unwrap_or(""));
 
             let p = if p.is_absolute() {
-                local_file_style.set_color(env_logger::fmt::Color::Cyan);
+                local_file_style.set_color(Color::Cyan);
                 p.file_name()
                     .unwrap_or_default()
                     .to_string_lossy()
             } else {
-                local_file_style.set_color(env_logger::fmt::Color::Green);
+                local_file_style.set_color(Color::Green);
                 p.to_string_lossy()
             };
 
-            let p = if record.target().starts_with("device:") {
-                format!("{}", record.target().split(":").last().unwrap())
+            let device = record.target().strip_prefix("device:");
+            let p = if let Some(target) = device {
+                target.to_string()
             } else {
                 format!("{} at {}:{}:",
                         record
@@ -57,14 +1060,52 @@ fn setup_logging() {
                     record.line().unwrap_or(0))
             };
 
-            write!(
-                buf,
-                "[{:<5}] {} │ {} {}\n",
-                style.value(record.level()),
-                chrono::Local::now().format("%T%.3f"),
-                local_file_style.value(p),
-                record.args()
-            )
+            // Which device the line is from, in the order they're seen
+            let index = device.filter(|_| layout != LogLayout::Plain).map(|target| {
+                let codename = target.split(':').next().unwrap_or(target);
+                let mut seen = seen.lock().unwrap();
+                seen.iter().position(|c| c == codename).unwrap_or_else(|| {
+                    seen.push(codename.to_string());
+                    seen.len() - 1
+                })
+            });
+            if let Some(index) = index {
+                local_file_style.set_color(DEVICE_COLORS[index % DEVICE_COLORS.len()].clone());
+            }
+
+            match index {
+                Some(index @ 0..=1) if layout == LogLayout::Split => {
+                    let text = format!("{} {}", p, record.args());
+                    for (n, line) in wrap(&text, width).iter().enumerate() {
+                        if n == 0 {
+                            write!(
+                                buf,
+                                "[{:<5}] {} │ ",
+                                style.value(record.level()),
+                                chrono::Local::now().format("%T%.3f")
+                            )?;
+                        } else {
+                            write!(buf, "{:20} │ ", "")?;
+                        }
+                        let (left, right) = if index == 0 { (line.as_str(), "") } else { ("", line.as_str()) };
+                        writeln!(
+                            buf,
+                            "{} │ {}",
+                            local_file_style.value(format!("{:<width$}", left)),
+                            local_file_style.value(right)
+                        )?;
+                    }
+                    Ok(())
+                }
+                _ => writeln!(
+                    buf,
+                    "[{:<5}] {} │ {} {}",
+                    style.value(record.level()),
+                    chrono::Local::now().format("%T%.3f"),
+                    local_file_style.value(p),
+                    record.args()
+                ),
+            }
         })
         .init();
 }
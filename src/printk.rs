@@ -0,0 +1,128 @@
+//! Kernel printk timestamps (`[   12.345678] ...`). Lines sit in UART FIFOs
+//! and buffers before fbug gets them, so the time a line was received can be
+//! late by a varying amount. When lines carry printk timestamps the kernel's
+//! clock is used instead, aligned to the host's clock so it can be compared
+//! with everything else.
+
+use std::time::{Duration, Instant};
+
+/// Parse the printk timestamp at the start of a line, which may be preceded
+/// by a `<N>` log level
+pub fn parse_printk(line: &str) -> Option<Duration> {
+    let rest = match line.strip_prefix('<') {
+        Some(rest) => rest.split_once('>')?.1,
+        None => line,
+    };
+    let (ts, _) = rest.strip_prefix('[')?.split_once(']')?;
+    let (secs, frac) = ts.trim_start().split_once('.')?;
+    if frac.is_empty() || frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let secs: u64 = secs.parse().ok()?;
+    let nanos: u32 = format!("{:0<9}", frac).parse().ok()?;
+    Some(Duration::new(secs, nanos))
+}
+
+/// Maps kernel timestamps to host time for one console. Every line is
+/// delayed by at least some amount, so the line with the smallest difference
+/// between when it was received and its kernel timestamp gives the best
+/// estimate of the offset between the clocks.
+#[derive(Debug, Default)]
+pub struct KernelClock {
+    /// The least delayed line seen: when it was received and its timestamp
+    reference: Option<(Instant, Duration)>,
+    last: Option<Duration>,
+}
+
+impl KernelClock {
+    /// Record a line with a kernel timestamp received at `received`
+    pub fn observe(&mut self, received: Instant, ts: Duration) {
+        if self.last.is_some_and(|last| ts < last) {
+            debug!("Kernel clock went backwards, the device rebooted");
+            self.reference = None;
+        }
+        self.last = Some(ts);
+        match self.reference {
+            // At least as delayed as the reference
+            Some((ref_received, ref_ts)) if received + ref_ts >= ref_received + ts => {}
+            _ => self.reference = Some((received, ts)),
+        }
+    }
+
+    /// The host time corresponding to a kernel timestamp
+    pub fn to_host(&self, ts: Duration) -> Option<Instant> {
+        let (ref_received, ref_ts) = self.reference?;
+        if ts >= ref_ts {
+            Some(ref_received + (ts - ref_ts))
+        } else {
+            ref_received.checked_sub(ref_ts - ts)
+        }
+    }
+
+    /// When a line was really printed: its kernel timestamp in host time if
+    /// it has one, otherwise when it was received
+    pub fn align(&mut self, line: &str, received: Instant) -> Instant {
+        let Some(ts) = parse_printk(line) else {
+            return received;
+        };
+        self.observe(received, ts);
+        self.to_host(ts).unwrap_or(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn printk_timestamps() {
+        assert_eq!(parse_printk("[   12.345678] Booting Linux"), Some(Duration::new(12, 345678000)));
+        assert_eq!(parse_printk("<6>[    0.000000] Booting Linux"), Some(Duration::ZERO));
+        assert_eq!(parse_printk("[12.5]"), Some(ms(12500)));
+        assert_eq!(parse_printk("[ 1.123456789] ns"), Some(Duration::new(1, 123456789)));
+        for line in [
+            "Booting Linux",
+            "",
+            " [ 1.000000] leading space",
+            "[ 1.1234567890] too precise",
+            "[12] no fraction",
+            "[12.] empty fraction",
+            "[ab.cdef] not a number",
+            "[ 1.-5] negative",
+            "[ 1.000000 unterminated",
+            "<6 [ 1.000000] unterminated level",
+        ] {
+            assert_eq!(parse_printk(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn kernel_clock_uses_least_delayed_line() {
+        let t0 = Instant::now();
+        let mut clock = KernelClock::default();
+        assert_eq!(clock.to_host(ms(1000)), None);
+        assert_eq!(clock.align("no timestamp", t0), t0);
+
+        // Received 500ms after it was printed, as far as we know
+        assert_eq!(clock.align("[    1.000000] a", t0 + ms(500)), t0 + ms(500));
+        // Less delayed, so this is the better estimate
+        assert_eq!(clock.align("[    2.000000] b", t0 + ms(1200)), t0 + ms(1200));
+        assert_eq!(clock.to_host(ms(1000)), Some(t0 + ms(200)));
+        // More delayed, placed by the earlier line
+        assert_eq!(clock.align("[    3.000000] c", t0 + ms(2500)), t0 + ms(2200));
+        assert_eq!(clock.to_host(ms(0)), t0.checked_sub(ms(800)));
+    }
+
+    #[test]
+    fn kernel_clock_resets_on_reboot() {
+        let t0 = Instant::now();
+        let mut clock = KernelClock::default();
+        clock.align("[   50.000000] before", t0 + ms(100));
+        assert_eq!(clock.align("[    0.500000] after", t0 + ms(10000)), t0 + ms(10000));
+        assert_eq!(clock.to_host(ms(1500)), Some(t0 + ms(11000)));
+    }
+}
@@ -0,0 +1,939 @@
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth;
+use crate::artifacts::RunDir;
+use crate::config::{AgentConfig, ArtifactsConfig, ClientConfig, Device, Permission, TimestampMode, TokenConfig};
+use crate::exit::{self, Failure};
+use crate::fleet::{DeviceResult, Operation, PowerAction, Selectable};
+use crate::bandwidth::BandwidthMap;
+use crate::health::HealthMap;
+#[cfg(unix)]
+use crate::systemd;
+use crate::printk::KernelClock;
+use crate::session::{Direction, Recorder};
+use crate::timestamps::{LineTimestamps, Stamp, ToggleSignal};
+use crate::vars::Vars;
+use crate::{reservation, ConnectionEvent, ConnectionInput, InputData, RunningDevice, TriggerOptions};
+use anyhow::Result;
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::TlsAcceptor;
+use tokio::sync::broadcast::error::RecvError;
+
+pub const DEFAULT_PORT: u16 = 7420;
+
+/// Requests from a client to an agent, sent as one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Request {
+    /// Authenticate with a token, required first if the agent has tokens configured
+    Auth { token: String },
+    /// Only watch from now on: for the rest of the connection input and
+    /// controls are refused, whatever the token allows
+    Observe,
+    List,
+    Trigger {
+        device: String,
+        name: String,
+        user: String,
+        #[serde(default)]
+        vars: Vars,
+        /// Run it even if it's interlocked in the current state
+        #[serde(default)]
+        force: bool,
+    },
+    Wait {
+        device: String,
+        state: Option<String>,
+        timeout: u64,
+    },
+    /// Use the power control of a device, the response has the state it
+    /// should end up in
+    Power {
+        device: String,
+        action: PowerAction,
+        user: String,
+    },
+    /// Send to a connection of a device without attaching to its console
+    Send {
+        device: String,
+        connection: Option<String>,
+        data: InputData,
+        user: String,
+    },
+    /// Attach to the console of a device, after this the agent streams lines
+    /// and the client may only send `Input`, which is sent as `user`
+    Console { device: String, user: String },
+    Input {
+        connection: Option<String>,
+        data: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Devices { devices: Vec<RemoteDevice> },
+    Ok {
+        state: Option<String>,
+        /// Where the artifacts of the run were uploaded to, for triggers and
+        /// power, see [crate::artifacts]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<String>,
+    },
+    Error {
+        message: String,
+        /// Why it failed, if known, so clients can exit with the same code
+        #[serde(default)]
+        failure: Option<Failure>,
+    },
+    /// Something to tell the user that doesn't end the console, like input
+    /// being refused
+    Notice { message: String },
+    Line {
+        line: String,
+        /// The connection it was received on
+        #[serde(default)]
+        connection: Option<String>,
+        /// Seconds since the timestamp reference point and since the previous
+        /// line, see [crate::timestamps]
+        #[serde(default)]
+        elapsed: f64,
+        #[serde(default)]
+        delta: f64,
+    },
+}
+
+impl Response {
+    fn error(e: &anyhow::Error) -> Self {
+        Response::Error {
+            message: e.to_string(),
+            failure: exit::classify(e),
+        }
+    }
+}
+
+/// A device exported by an agent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteDevice {
+    pub codename: String,
+    pub name: String,
+    pub groups: Vec<String>,
+    pub tags: Vec<String>,
+    pub resting_state: Option<String>,
+    pub state: Option<String>,
+    pub triggers: Vec<String>,
+    /// The health of the connections that have probes
+    #[serde(default, skip_serializing_if = "HealthMap::is_empty")]
+    pub health: HealthMap,
+    /// Variables captured from the console
+    #[serde(default, skip_serializing_if = "Vars::is_empty")]
+    pub context: Vars,
+    /// How much each connection has printed
+    #[serde(default, skip_serializing_if = "BandwidthMap::is_empty")]
+    pub bandwidth: BandwidthMap,
+}
+
+impl Selectable for RemoteDevice {
+    fn codename(&self) -> &str {
+        &self.codename
+    }
+
+    fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    fn tags(&self) -> &[String] {
+        &self.tags
+    }
+}
+
+impl From<&Device> for RemoteDevice {
+    /// A device that isn't running, so its state is unknown
+    fn from(d: &Device) -> Self {
+        Self {
+            codename: d.codename.clone(),
+            name: d.name.clone(),
+            groups: d.groups.clone(),
+            tags: d.tags.clone(),
+            resting_state: d.resting_state.clone(),
+            state: None,
+            triggers: d
+                .transitions
+                .iter()
+                .flat_map(|t| t.triggers.iter().map(|t| t.name.clone()))
+                .collect(),
+            health: HealthMap::new(),
+            context: Vars::new(),
+            bandwidth: BandwidthMap::new(),
+        }
+    }
+}
+
+impl From<&RunningDevice> for RemoteDevice {
+    fn from(dev: &RunningDevice) -> Self {
+        Self {
+            state: dev.current_state(),
+            health: dev.health(),
+            context: dev.context(),
+            bandwidth: dev.bandwidth(),
+            ..Self::from(&dev.device)
+        }
+    }
+}
+
+async fn write_msg<W: AsyncWrite + Unpin, T: Serialize>(w: &mut W, msg: &T) -> Result<()> {
+    let mut buf = serde_json::to_vec(msg)?;
+    buf.push(b'\n');
+    w.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Serves the devices on this host to remote clients
+pub struct Agent {
+    devices: Vec<RunningDevice>,
+    tokens: Vec<TokenConfig>,
+    /// Where the runs of triggers and power requests are recorded
+    artifacts: ArtifactsConfig,
+}
+
+/// What a client connection may do
+struct Session {
+    perms: Vec<Permission>,
+    /// The client asked to only watch, see [Request::Observe]
+    read_only: bool,
+    /// The name of the token the client authenticated with
+    name: Option<String>,
+}
+
+impl Session {
+    fn require(&self, perm: Permission) -> Result<()> {
+        if self.read_only && perm != Permission::Read {
+            bail!("Permission denied, this client is read-only");
+        }
+        if !self.perms.contains(&perm) {
+            bail!("Permission denied, {} permission needed", perm);
+        }
+        Ok(())
+    }
+
+    /// Who reservations are checked against: the name of the client's token,
+    /// the user it says it is is only trusted if there are no tokens
+    fn user(&self, claimed: String) -> Result<String> {
+        match &self.name {
+            Some(name) if *name != claimed => {
+                bail!("Permission denied, authenticated as {} but acting as {}", name, claimed)
+            }
+            Some(name) => Ok(name.clone()),
+            None => Ok(claimed),
+        }
+    }
+}
+
+impl Agent {
+    pub fn new(devices: Vec<Device>, config: &AgentConfig, artifacts: &ArtifactsConfig) -> Self {
+        Self {
+            devices: devices.into_iter().map(RunningDevice::spawn).collect(),
+            tokens: config.tokens.clone(),
+            artifacts: artifacts.clone(),
+        }
+    }
+
+    /// Stop all devices, releasing any controls they hold
+    pub fn stop(&self) {
+        for dev in self.devices.iter() {
+            debug!("Stopping {}", dev.device.codename);
+            dev.abort();
+        }
+    }
+
+    /// Whether the service watchdog should be pinged, it isn't while a device
+    /// has a wedged connection so that systemd restarts the agent
+    pub fn healthy(&self) -> bool {
+        !self.devices.iter().any(RunningDevice::wedged)
+    }
+
+    /// Start recording an operation on a device, not being able to is only
+    /// worth a warning
+    async fn start_run(&self, dev: &RunningDevice, kind: &str) -> Option<RunDir> {
+        let codename = &dev.device.codename;
+        let mut run_dir = RunDir::create(&self.artifacts, &dev.device, kind).unwrap_or_else(|e| {
+            warn!("{}: not saving artifacts: {}", codename, e);
+            None
+        })?;
+        if let Err(e) = run_dir.record(dev).await {
+            warn!("{}: not recording the run: {}", codename, e);
+        }
+        Some(run_dir)
+    }
+
+    fn find(&self, codename: &str) -> Result<&RunningDevice> {
+        self.devices
+            .iter()
+            .find(|d| d.device.codename == codename)
+            .ok_or_else(|| anyhow!("No such device {}", codename))
+    }
+
+    async fn respond(&self, req: Request, session: &mut Session) -> Result<Response> {
+        Ok(match req {
+            Request::Auth { token } => {
+                let token = auth::authenticate(&self.tokens, &token)
+                    .ok_or_else(|| anyhow!("Invalid token"))?;
+                debug!("Client authenticated as {}", token.name);
+                session.perms = token.permissions.clone();
+                session.name = Some(token.name.clone());
+                Response::Ok { state: None, url: None }
+            }
+            Request::Observe => {
+                debug!("Client is read-only");
+                session.read_only = true;
+                Response::Ok { state: None, url: None }
+            }
+            Request::List => {
+                session.require(Permission::Read)?;
+                Response::Devices {
+                    devices: self.devices.iter().map(RemoteDevice::from).collect(),
+                }
+            }
+            Request::Trigger { device, name, user, vars, force } => {
+                session.require(Permission::Control)?;
+                let user = session.user(user)?;
+                let dev = self.find(&device)?;
+                reservation::check_access(&device, &user, false, Duration::ZERO).await?;
+                let opts = TriggerOptions {
+                    vars,
+                    user: Some(user),
+                    force,
+                };
+                let mut run_dir = self.start_run(dev, &format!("trigger-{}", name)).await;
+                if let Some(run_dir) = run_dir.as_mut() {
+                    run_dir.add_trigger(&name);
+                }
+                let result = dev.run_trigger_with(&name, opts).await;
+                let url = finish_run(run_dir, dev, &result).await;
+                result?;
+                Response::Ok {
+                    state: dev.current_state(),
+                    url,
+                }
+            }
+            Request::Power { device, action, user } => {
+                session.require(Permission::Control)?;
+                let user = session.user(user)?;
+                let dev = self.find(&device)?;
+                reservation::check_access(&device, &user, false, Duration::ZERO).await?;
+                let run_dir = self.start_run(dev, &format!("power-{}", action)).await;
+                let result = dev.power(action).await;
+                let url = finish_run(run_dir, dev, &result).await;
+                Response::Ok { state: result?, url }
+            }
+            Request::Wait {
+                device,
+                state,
+                timeout,
+            } => {
+                session.require(Permission::Read)?;
+                let dev = self.find(&device)?;
+                let target = state
+                    .or_else(|| dev.device.resting_state.clone())
+                    .ok_or_else(|| anyhow!("No state given and no resting state configured"))?;
+                tokio::time::timeout(Duration::from_secs(timeout), dev.wait_for_state(&target))
+                    .await
+                    .map_err(|_| Failure::Timeout.error(format!("Timed out after {}s", timeout)))??;
+                Response::Ok {
+                    state: dev.current_state(),
+                    url: None,
+                }
+            }
+            Request::Send {
+                device,
+                connection,
+                data,
+                user,
+            } => {
+                session.require(Permission::Control)?;
+                let user = session.user(user)?;
+                let dev = self.find(&device)?;
+                reservation::check_access(&device, &user, false, Duration::ZERO).await?;
+                if let Some(label) = &connection {
+                    if !dev.device.connections.iter().any(|c| c.label() == label.as_str()) {
+                        bail!("{} has no connection {}", device, label);
+                    }
+                }
+                dev.send(ConnectionInput { connection, data }).await?;
+                Response::Ok {
+                    state: dev.current_state(),
+                    url: None,
+                }
+            }
+            Request::Console { .. } | Request::Input { .. } => bail!("Unexpected request"),
+        })
+    }
+
+    /// Whether console input from `user` may be sent, which can change while
+    /// they're attached as the device is reserved and released
+    async fn may_send(&self, device: &str, session: &Session, user: &str) -> Result<()> {
+        session.require(Permission::Write)?;
+        reservation::check_access(device, user, false, Duration::ZERO).await
+    }
+
+    async fn console<R, W>(
+        &self,
+        device: &str,
+        lines: &mut Lines<R>,
+        w: &mut W,
+        session: &Session,
+        user: &str,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        session.require(Permission::Read)?;
+        let dev = self.find(device)?;
+        let mut rx = dev.subscribe().await?;
+        let mut state = dev.watch_state();
+        let mut stamps = LineTimestamps::new(&dev.device.log.timestamps);
+        let mut kernel_clocks: HashMap<String, KernelClock> = HashMap::new();
+        // Whether the client was told its input is refused
+        let mut refused = false;
+        write_msg(w, &Response::Ok { state: dev.current_state(), url: None }).await?;
+        loop {
+            tokio::select! {
+                ev = rx.recv() => match ev {
+                    Ok(ev) => if let ConnectionEvent::NewLine(line) = ev.event {
+                        let printed = kernel_clocks.entry(ev.device.clone()).or_default().align(&line, ev.timing.received);
+                        let stamp = stamps.stamp(printed);
+                        write_msg(w, &Response::Line {
+                            line,
+                            connection: Some(ev.device),
+                            elapsed: stamp.elapsed.as_secs_f64(),
+                            delta: stamp.delta.as_secs_f64(),
+                        }).await?;
+                    },
+                    Err(RecvError::Lagged(n)) => warn!("Remote console for {} dropped {} lines", device, n),
+                    Err(RecvError::Closed) => bail!("Device {} stopped", device),
+                },
+                Ok(()) = state.changed() => {
+                    if let Some(state) = state.borrow().as_deref() {
+                        stamps.entered(state, Instant::now());
+                    }
+                },
+                line = lines.next_line() => match line? {
+                    Some(line) => match serde_json::from_str::<Request>(&line) {
+                        Ok(Request::Input { connection, data }) => match self.may_send(device, session, user).await {
+                            Ok(()) => {
+                                if let Err(e) = dev.send(ConnectionInput { connection, data: data.into() }).await {
+                                    write_msg(w, &Response::error(&e)).await?;
+                                }
+                            }
+                            // Once is enough, the client stays attached
+                            Err(e) if !refused => {
+                                refused = true;
+                                let message = format!("{}, input isn't sent", e);
+                                write_msg(w, &Response::Notice { message }).await?;
+                            }
+                            Err(_) => {}
+                        },
+                        _ => write_msg(w, &Response::error(&anyhow!("Expected input"))).await?,
+                    },
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Handle a single client connection
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<()> {
+        let (r, mut w) = tokio::io::split(stream);
+        let mut lines = BufReader::new(r).lines();
+        let mut session = Session {
+            perms: auth::default_permissions(&self.tokens),
+            read_only: false,
+            name: None,
+        };
+        while let Some(line) = lines.next_line().await? {
+            let resp = match serde_json::from_str::<Request>(&line) {
+                Ok(Request::Console { device, user }) => match session.user(user) {
+                    Ok(user) => match self.console(&device, &mut lines, &mut w, &session, &user).await {
+                        Err(e) => Err(e),
+                        Ok(()) => return Ok(()),
+                    },
+                    Err(e) => Err(e),
+                },
+                Ok(req) => self.respond(req, &mut session).await,
+                Err(e) => Err(anyhow!("Invalid request: {}", e)),
+            };
+            let resp = resp.unwrap_or_else(|e| Response::error(&e));
+            write_msg(&mut w, &resp).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Prefix of a listen or connect address for a Unix socket, e.g. `unix:/run/fbug.sock`
+pub const UNIX_PREFIX: &str = "unix:";
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn bind(listen: &str) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = listen.strip_prefix(UNIX_PREFIX) {
+            // Left over if the agent didn't exit cleanly
+            if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(Listener::Unix(UnixListener::bind(path)?));
+        }
+        Ok(Listener::Tcp(TcpListener::bind(listen).await?))
+    }
+
+    /// The sockets passed by systemd socket activation, if any
+    #[cfg(unix)]
+    fn activated() -> Result<Vec<Self>> {
+        systemd::listen_fds()?
+            .into_iter()
+            .map(|socket| {
+                Ok(match socket {
+                    systemd::ActivatedSocket::Tcp(l) => {
+                        l.set_nonblocking(true)?;
+                        Listener::Tcp(TcpListener::from_std(l)?)
+                    }
+                    systemd::ActivatedSocket::Unix(l) => {
+                        l.set_nonblocking(true)?;
+                        Listener::Unix(UnixListener::from_std(l)?)
+                    }
+                })
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    fn activated() -> Result<Vec<Self>> {
+        Ok(vec![])
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map_or("tcp".to_string(), |a| a.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(l) => l
+                .local_addr()
+                .ok()
+                .and_then(|a| a.as_pathname().map(|p| format!("{}{}", UNIX_PREFIX, p.display())))
+                .unwrap_or_else(|| "unix socket".to_string()),
+        }
+    }
+}
+
+fn spawn_client<S>(agent: Arc<Agent>, acceptor: Option<TlsAcceptor>, stream: S, peer: String)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    debug!("Client connected from {}", peer);
+    tokio::spawn(async move {
+        let res = match acceptor {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => agent.handle(stream).await,
+                Err(e) => Err(anyhow!("TLS handshake failed: {}", e)),
+            },
+            None => agent.handle(stream).await,
+        };
+        if let Err(e) = res {
+            warn!("{}: {}", peer, e);
+        }
+    });
+}
+
+async fn accept_loop(listener: Listener, agent: Arc<Agent>, acceptor: Option<TlsAcceptor>) -> Result<()> {
+    loop {
+        match &listener {
+            Listener::Tcp(l) => {
+                let (stream, peer) = l.accept().await?;
+                spawn_client(agent.clone(), acceptor.clone(), stream, peer.to_string());
+            }
+            // Local clients are already authenticated by the permissions of
+            // the socket, so there's no TLS
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                let (stream, _) = l.accept().await?;
+                spawn_client(agent.clone(), None, stream, "unix socket".to_string());
+            }
+        }
+    }
+}
+
+/// Resolves when the agent is asked to stop, by systemd or ^C
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = term.recv() => info!("Received SIGTERM, stopping"),
+            res = tokio::signal::ctrl_c() => { res?; info!("Interrupted, stopping") }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        info!("Interrupted, stopping");
+    }
+    Ok(())
+}
+
+/// Run devices and serve them over TCP (with TLS if configured) or a Unix
+/// socket, or the sockets passed by systemd socket activation. Runs until
+/// stopped by SIGTERM or ^C, when devices are stopped cleanly so held
+/// controls are released.
+pub async fn serve(devices: Vec<Device>, listen: &str, config: &AgentConfig, artifacts: &ArtifactsConfig) -> Result<()> {
+    let acceptor = config.tls.as_ref().map(auth::acceptor).transpose()?;
+    let mut listeners = Listener::activated()?;
+    let activated = !listeners.is_empty();
+    if activated {
+        info!("Using {} socket(s) from systemd, ignoring --listen", listeners.len());
+    } else {
+        listeners.push(Listener::bind(listen).await?);
+    }
+    if listeners.iter().any(|l| matches!(l, Listener::Tcp(_))) && (acceptor.is_none() || config.tokens.is_empty()) {
+        warn!("Agent is running without TLS or tokens, anyone who can connect can control the devices");
+    }
+    let addrs = listeners.iter().map(Listener::describe).collect::<Vec<_>>().join(", ");
+    info!("Agent listening on {}", addrs);
+
+    let agent = Arc::new(Agent::new(devices, config, artifacts));
+    let mut tasks = tokio::task::JoinSet::new();
+    for listener in listeners {
+        tasks.spawn(accept_loop(listener, agent.clone(), acceptor.clone()));
+    }
+    #[cfg(unix)]
+    {
+        systemd::ready(&format!("Serving {} devices on {}", agent.devices.len(), addrs));
+        let watched = agent.clone();
+        tokio::spawn(systemd::watchdog(move || watched.healthy()));
+    }
+
+    let res = tokio::select! {
+        res = shutdown_signal() => res,
+        Some(res) = tasks.join_next() => res.map_err(|e| anyhow!(e)).and_then(|r| r),
+    };
+    #[cfg(unix)]
+    systemd::stopping();
+    tasks.shutdown().await;
+    agent.stop();
+    #[cfg(unix)]
+    if let (false, Some(path)) = (activated, listen.strip_prefix(UNIX_PREFIX)) {
+        let _ = std::fs::remove_file(path);
+    }
+    res
+}
+
+type BoxedRead = Box<dyn AsyncRead + Send + Unpin>;
+type BoxedWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
+async fn recv_msg(lines: &mut Lines<BufReader<BoxedRead>>) -> Result<Response> {
+    let line = lines
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("Agent closed the connection"))?;
+    match serde_json::from_str(&line)? {
+        Response::Error {
+            message,
+            failure: Some(failure),
+        } => Err(failure.error(message)),
+        Response::Error { message, .. } => Err(anyhow!(message)),
+        resp => Ok(resp),
+    }
+}
+
+/// A connection to a remote agent
+pub struct RemoteClient {
+    lines: Lines<BufReader<BoxedRead>>,
+    writer: BoxedWrite,
+    /// Only watching, see [Request::Observe]
+    read_only: bool,
+}
+
+impl RemoteClient {
+    pub async fn connect(addr: &str, config: &ClientConfig) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            let stream = UnixStream::connect(path)
+                .await
+                .map_err(|e| anyhow!("Failed to connect to agent {}: {}", addr, e))?;
+            let (r, w) = stream.into_split();
+            return Self::new(Box::new(r), Box::new(w), config).await;
+        }
+        let (host, addr) = match addr.rsplit_once(':') {
+            Some((host, _)) => (host.to_string(), addr.to_string()),
+            None => (addr.to_string(), format!("{}:{}", addr, DEFAULT_PORT)),
+        };
+        let stream = TcpStream::connect(&addr)
+            .await
+            .map_err(|e| anyhow!("Failed to connect to agent {}: {}", addr, e))?;
+        let (r, w): (BoxedRead, BoxedWrite) = match auth::connector(config)? {
+            Some(connector) => {
+                let stream = connector
+                    .connect(auth::server_name(&host)?, stream)
+                    .await
+                    .map_err(|e| anyhow!("TLS handshake with {} failed: {}", addr, e))?;
+                let (r, w) = tokio::io::split(stream);
+                (Box::new(r), Box::new(w))
+            }
+            None => {
+                let (r, w) = stream.into_split();
+                (Box::new(r), Box::new(w))
+            }
+        };
+        Self::new(r, w, config).await
+    }
+
+    async fn new(r: BoxedRead, w: BoxedWrite, config: &ClientConfig) -> Result<Self> {
+        let mut client = Self {
+            lines: BufReader::new(r).lines(),
+            writer: w,
+            read_only: config.read_only,
+        };
+        if let Some(token) = &config.token {
+            client
+                .request(&Request::Auth {
+                    token: token.clone(),
+                })
+                .await?;
+        }
+        if config.read_only {
+            client.expect_ok(&Request::Observe).await?;
+        }
+        Ok(client)
+    }
+
+    async fn request(&mut self, req: &Request) -> Result<Response> {
+        write_msg(&mut self.writer, req).await?;
+        recv_msg(&mut self.lines).await
+    }
+
+    pub async fn list(&mut self) -> Result<Vec<RemoteDevice>> {
+        match self.request(&Request::List).await? {
+            Response::Devices { devices } => Ok(devices),
+            resp => bail!("Unexpected response {:?}", resp),
+        }
+    }
+
+    async fn expect_ok(&mut self, req: &Request) -> Result<Option<String>> {
+        Ok(self.expect_run(req).await?.0)
+    }
+
+    /// Like [RemoteClient::expect_ok], along with where the agent uploaded
+    /// the artifacts of the run
+    async fn expect_run(&mut self, req: &Request) -> Result<(Option<String>, Option<String>)> {
+        match self.request(req).await? {
+            Response::Ok { state, url } => Ok((state, url)),
+            resp => bail!("Unexpected response {:?}", resp),
+        }
+    }
+
+    /// Run a trigger, returns the state the device is in and where the run
+    /// was uploaded to
+    pub async fn trigger(
+        &mut self,
+        device: &str,
+        name: &str,
+        user: &str,
+        vars: Vars,
+        force: bool,
+    ) -> Result<(Option<String>, Option<String>)> {
+        self.expect_run(&Request::Trigger {
+            device: device.to_string(),
+            name: name.to_string(),
+            user: user.to_string(),
+            vars,
+            force,
+        })
+        .await
+    }
+
+    /// Use the power control of a device, returns the state it should end up
+    /// in and where the run was uploaded to
+    pub async fn power(
+        &mut self,
+        device: &str,
+        action: PowerAction,
+        user: &str,
+    ) -> Result<(Option<String>, Option<String>)> {
+        self.expect_run(&Request::Power {
+            device: device.to_string(),
+            action,
+            user: user.to_string(),
+        })
+        .await
+    }
+
+    pub async fn send(
+        &mut self,
+        device: &str,
+        connection: Option<String>,
+        data: InputData,
+        user: &str,
+    ) -> Result<Option<String>> {
+        self.expect_ok(&Request::Send {
+            device: device.to_string(),
+            connection,
+            data,
+            user: user.to_string(),
+        })
+        .await
+    }
+
+    pub async fn wait(&mut self, device: &str, state: Option<String>, timeout: Duration) -> Result<Option<String>> {
+        self.expect_ok(&Request::Wait {
+            device: device.to_string(),
+            state,
+            timeout: timeout.as_secs(),
+        })
+        .await
+    }
+
+    /// Attach to a remote console, printing its output (timestamped according
+    /// to `stamps`, cycled with SIGUSR1) and forwarding stdin unless the client
+    /// is read-only. Both are recorded by `recorder` if given.
+    pub async fn console(
+        mut self,
+        device: &str,
+        user: &str,
+        mut stamps: TimestampMode,
+        mut recorder: Option<Recorder>,
+    ) -> Result<()> {
+        self.expect_ok(&Request::Console {
+            device: device.to_string(),
+            user: user.to_string(),
+        })
+        .await?;
+        let Self {
+            mut lines,
+            mut writer,
+            read_only,
+        } = self;
+        if read_only {
+            eprintln!("Watching {} read-only, ^C to detach", device);
+        }
+        let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+        let mut toggle_stamps = ToggleSignal::new();
+        loop {
+            tokio::select! {
+                resp = recv_msg(&mut lines) => match resp {
+                    Ok(Response::Line { line, connection, elapsed, delta }) => {
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.record(Direction::Out, connection.as_deref(), &line);
+                        }
+                        let stamp = Stamp {
+                            elapsed: Duration::from_secs_f64(elapsed),
+                            delta: Duration::from_secs_f64(delta),
+                        };
+                        println!("{}{}", stamp.format(stamps), line);
+                    }
+                    Ok(Response::Notice { message }) => eprintln!("{}", message),
+                    Ok(_) => {}
+                    Err(e) => return Err(e),
+                },
+                _ = toggle_stamps.recv() => {
+                    stamps = stamps.next();
+                    eprintln!("Timestamps: {}", stamps);
+                },
+                line = stdin.next_line(), if !read_only => match line? {
+                    Some(data) => {
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.record(Direction::In, None, &data);
+                        }
+                        write_msg(&mut writer, &Request::Input { connection: None, data }).await?
+                    }
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+}
+
+/// Finish recording an operation, returns where the run was uploaded to
+async fn finish_run<T>(run_dir: Option<RunDir>, dev: &RunningDevice, result: &Result<T>) -> Option<String> {
+    let mut run_dir = run_dir?;
+    run_dir
+        .finish(dev.current_state(), result.as_ref().err().map(|e| e.to_string()))
+        .await;
+    info!("{}: artifacts in {}", dev.device.codename, run_dir.path.display());
+    run_dir.metadata.url
+}
+
+async fn run_one(
+    addr: String,
+    config: ClientConfig,
+    codename: String,
+    op: Operation,
+    timeout: Duration,
+    user: String,
+) -> DeviceResult {
+    // The agent records triggers and power, not waits
+    let mut url = None;
+    let result = async {
+        let mut client = RemoteClient::connect(&addr, &config).await?;
+        match op {
+            Operation::Trigger { name, wait, vars, force } => {
+                let (state, run) = client.trigger(&codename, &name, &user, vars, force).await?;
+                url = run;
+                match wait {
+                    Some(wait) => client.wait(&codename, Some(wait), timeout).await,
+                    None => Ok(state),
+                }
+            }
+            Operation::Wait { state } => client.wait(&codename, state, timeout).await,
+            Operation::Power { action, wait } => {
+                let (state, run) = client.power(&codename, action, &user).await?;
+                url = run;
+                match state {
+                    Some(state) if wait => client.wait(&codename, Some(state), timeout).await,
+                    state => Ok(state),
+                }
+            }
+        }
+    }
+    .await;
+    DeviceResult {
+        codename,
+        result,
+        url,
+    }
+}
+
+/// Run an operation on devices exported by an agent
+pub async fn run(
+    addr: &str,
+    config: &ClientConfig,
+    devices: Vec<RemoteDevice>,
+    op: Operation,
+    timeout: Duration,
+    user: &str,
+) -> Vec<DeviceResult> {
+    join_all(devices.into_iter().map(|d| {
+        run_one(
+            addr.to_string(),
+            config.clone(),
+            d.codename,
+            op.clone(),
+            timeout,
+            user.to_string(),
+        )
+    }))
+    .await
+}
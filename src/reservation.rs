@@ -0,0 +1,413 @@
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::exit::Failure;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// A claim on a device by a user, other users are refused control of the
+/// device while it's held.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub owner: String,
+    /// Unix timestamp the reservation was made
+    pub since: u64,
+    /// Unix timestamp after which the reservation is no longer valid
+    pub expires: Option<u64>,
+    /// The process holding the reservation, it's released if the process exits
+    pub pid: Option<u32>,
+    pub note: Option<String>,
+}
+
+impl Display for Reservation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reserved by {}", self.owner)?;
+        if let Some(expires) = self.expires {
+            write!(f, " for another {}s", expires.saturating_sub(now()))?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, " (pid {})", pid)?;
+        }
+        if let Some(note) = &self.note {
+            write!(f, ": {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl Reservation {
+    fn is_stale(&self) -> bool {
+        if self.expires.map(|e| e <= now()).unwrap_or(false) {
+            return true;
+        }
+        match self.pid {
+            Some(pid) => !process_alive(pid),
+            None => false,
+        }
+    }
+}
+
+/// Whether a process is still running
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    use nix::errno::Errno;
+    use nix::sys::signal::kill;
+    use nix::unistd::Pid;
+    // Signal 0 only checks the process exists, EPERM means it does but isn't ours
+    !matches!(kill(Pid::from_raw(pid as i32), None), Err(Errno::ESRCH))
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // No cheap way to tell, rely on the expiry instead
+    true
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The user operations are performed as
+pub fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Reservations have to be seen by every user of the host, so they're kept in
+/// a shared directory rather than a per-user one
+const DEFAULT_DIR: &str = "/var/lib/fbug/reservations";
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keep reservations in `dir` instead of the default, it has to be set before
+/// any reservation is looked at
+pub fn set_dir(dir: PathBuf) {
+    let _ = DIR.set(dir);
+}
+
+fn root_dir() -> PathBuf {
+    DIR.get().cloned().unwrap_or_else(|| PathBuf::from(DEFAULT_DIR))
+}
+
+fn device_dir(codename: &str) -> PathBuf {
+    root_dir().join(codename)
+}
+
+/// Each owner has a file of their own, so nobody has to write to (or remove)
+/// a file somebody else created
+fn reservation_path(codename: &str, owner: &str) -> PathBuf {
+    let name: String = owner
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02x}", b),
+        })
+        .collect();
+    device_dir(codename).join(format!("{}.yaml", name))
+}
+
+/// Create a directory everybody can add files to, but only remove their own
+/// from (mode 1777, like /tmp)
+fn create_shared_dir(path: &Path) -> std::io::Result<()> {
+    match std::fs::create_dir(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    // The mode given to mkdir is masked by the umask, so set it afterwards
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o1777))?;
+    }
+    Ok(())
+}
+
+fn create_dirs(codename: &str) -> Result<()> {
+    let root = root_dir();
+    let created = match root.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+    .and_then(|_| create_shared_dir(&root))
+    .and_then(|_| create_shared_dir(&device_dir(codename)));
+    created.map_err(|e| {
+        anyhow!(
+            "Failed to create {}: {}, create it with mode 1777 or set reservations.dir in the host config",
+            device_dir(codename).display(),
+            e
+        )
+    })
+}
+
+/// The reservations of a device that are still valid, oldest first. Stale ones
+/// are removed if they're ours to remove, and ignored otherwise.
+fn live(codename: &str) -> Result<Vec<(PathBuf, Reservation)>> {
+    let entries = match std::fs::read_dir(device_dir(codename)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut reservations = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().map_or(true, |e| e != "yaml") {
+            continue;
+        }
+        let reservation: Reservation = match std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|c| Ok(serde_yaml::from_str(&c)?))
+        {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Ignoring reservation {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if reservation.is_stale() {
+            debug!("Removing stale reservation of {} by {}", codename, reservation.owner);
+            if let Err(e) = std::fs::remove_file(&path) {
+                debug!("Leaving {} for its owner: {}", path.display(), e);
+            }
+            continue;
+        }
+        reservations.push((path, reservation));
+    }
+    reservations.sort_by_key(|(_, r)| r.since);
+    Ok(reservations)
+}
+
+/// Get the active reservation for a device, stale reservations are removed
+pub fn current(codename: &str) -> Result<Option<Reservation>> {
+    Ok(live(codename)?.into_iter().next().map(|(_, r)| r))
+}
+
+/// Reserve a device for `owner`. Re-reserving a device you already hold
+/// replaces the reservation.
+pub fn reserve(
+    codename: &str,
+    owner: &str,
+    duration: Option<Duration>,
+    pid: Option<u32>,
+    note: Option<String>,
+) -> Result<Reservation> {
+    if let Some((_, existing)) = live(codename)?.into_iter().find(|(_, r)| r.owner != owner) {
+        return Err(Failure::Reserved.error(format!("{} is {}", codename, existing)));
+    }
+
+    let reservation = Reservation {
+        owner: owner.to_string(),
+        since: now(),
+        expires: duration.map(|d| now() + d.as_secs()),
+        pid,
+        note,
+    };
+    create_dirs(codename)?;
+    let path = reservation_path(codename, owner);
+    // Write it in one go so nobody reads half a reservation
+    let partial = path.with_extension("partial");
+    let mut file = std::fs::File::create(&partial)
+        .map_err(|e| anyhow!("Failed to reserve {}: {}", codename, e))?;
+    file.write_all(serde_yaml::to_string(&reservation)?.as_bytes())?;
+    std::fs::rename(&partial, &path)?;
+
+    // Don't race another user reserving the device at the same time, if they
+    // did then both back off
+    if live(codename)?.iter().any(|(_, r)| r.owner != owner) {
+        let _ = std::fs::remove_file(&path);
+        return Err(Failure::Reserved.error(format!("{} was reserved by somebody else just now", codename)));
+    }
+    Ok(reservation)
+}
+
+/// Release a reservation, only the owner can release it unless `force` is set.
+/// Forcing only works on reservations the filesystem lets us remove, i.e.
+/// those of the same Unix user or as root.
+pub fn release(codename: &str, owner: &str, force: bool) -> Result<()> {
+    let reservations = live(codename)?;
+    if !force {
+        if let Some((_, r)) = reservations.iter().find(|(_, r)| r.owner != owner) {
+            bail!("{} is {}, use --force to release it anyway", codename, r);
+        }
+    }
+    for (path, r) in reservations {
+        std::fs::remove_file(&path)
+            .map_err(|e| anyhow!("Failed to release the reservation of {} by {}: {}", codename, r.owner, e))?;
+    }
+    Ok(())
+}
+
+/// Check that `user` may control the device. If `queue` is set then wait
+/// (up to `timeout`) for another user's reservation to be released.
+pub async fn check_access(codename: &str, user: &str, queue: bool, timeout: Duration) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        match current(codename)? {
+            Some(r) if r.owner != user => {
+                if !queue {
+                    return Err(Failure::Reserved.error(format!("{} is {}", codename, r)));
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(Failure::Reserved.error(format!(
+                        "Timed out waiting for {} to be released, it is {}",
+                        codename, r
+                    )));
+                }
+                debug!("{} is {}, waiting", codename, r);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Holds a reservation for as long as it's alive. If the owner has already
+/// reserved the device, e.g. with `fbug reserve`, that reservation is left as
+/// it is and kept after the guard is dropped.
+pub struct ReservationGuard {
+    codename: String,
+    owner: String,
+    /// Whether the guard made the reservation, so it's the one to release it
+    created: bool,
+}
+
+impl ReservationGuard {
+    pub fn new(codename: &str, owner: &str, note: Option<String>) -> Result<Self> {
+        let created = match current(codename)? {
+            Some(r) if r.owner == owner => {
+                debug!("{} is already reserved by {}, keeping it", codename, owner);
+                false
+            }
+            _ => {
+                reserve(codename, owner, None, Some(std::process::id()), note)?;
+                true
+            }
+        };
+        Ok(Self {
+            codename: codename.to_string(),
+            owner: owner.to_string(),
+            created,
+        })
+    }
+}
+
+impl Drop for ReservationGuard {
+    fn drop(&mut self) {
+        if !self.created {
+            return;
+        }
+        if let Err(e) = release(&self.codename, &self.owner, false) {
+            warn!("Failed to release reservation of {}: {}", self.codename, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit;
+
+    fn setup() {
+        // Every test shares the directory, so each uses devices of its own
+        static TEMP: OnceLock<tempfile::TempDir> = OnceLock::new();
+        set_dir(TEMP.get_or_init(|| tempfile::tempdir().unwrap()).path().to_path_buf());
+    }
+
+    fn exited_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn reserve_and_release() {
+        setup();
+        assert_eq!(current("reserve").unwrap(), None);
+        let r = reserve("reserve", "alice", None, None, Some("debugging".to_string())).unwrap();
+        assert_eq!(current("reserve").unwrap(), Some(r));
+
+        let e = reserve("reserve", "bob", None, None, None).unwrap_err();
+        assert_eq!(exit::classify(&e), Some(Failure::Reserved));
+        // Re-reserving replaces the reservation
+        let r = reserve("reserve", "alice", Some(Duration::from_secs(60)), None, None).unwrap();
+        assert_eq!(current("reserve").unwrap(), Some(r));
+
+        assert!(release("reserve", "bob", false).is_err());
+        release("reserve", "alice", false).unwrap();
+        assert_eq!(current("reserve").unwrap(), None);
+        reserve("reserve", "bob", None, None, None).unwrap();
+        release("reserve", "alice", true).unwrap();
+        assert_eq!(current("reserve").unwrap(), None);
+    }
+
+    #[test]
+    fn owners_with_odd_names() {
+        setup();
+        reserve("odd-owner", "../alice", None, None, None).unwrap();
+        assert_eq!(current("odd-owner").unwrap().unwrap().owner, "../alice");
+        assert!(reservation_path("odd-owner", "../alice").starts_with(device_dir("odd-owner")));
+        assert_ne!(reservation_path("odd-owner", "a b"), reservation_path("odd-owner", "a_b"));
+    }
+
+    #[test]
+    fn expired_reservations_are_stale() {
+        setup();
+        reserve("expired", "alice", Some(Duration::ZERO), None, None).unwrap();
+        assert_eq!(current("expired").unwrap(), None);
+        reserve("expired", "bob", None, None, None).unwrap();
+        assert_eq!(current("expired").unwrap().unwrap().owner, "bob");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reservations_of_exited_processes_are_stale() {
+        setup();
+        reserve("exited", "alice", None, Some(exited_pid()), None).unwrap();
+        assert_eq!(current("exited").unwrap(), None);
+
+        reserve("running", "alice", None, Some(std::process::id()), None).unwrap();
+        assert_eq!(current("running").unwrap().unwrap().pid, Some(std::process::id()));
+    }
+
+    #[test]
+    fn guard_keeps_an_existing_reservation() {
+        setup();
+        reserve("guarded", "alice", None, None, Some("mine".to_string())).unwrap();
+        drop(ReservationGuard::new("guarded", "alice", None).unwrap());
+        assert_eq!(current("guarded").unwrap().unwrap().note.as_deref(), Some("mine"));
+
+        assert!(ReservationGuard::new("guarded", "bob", None).is_err());
+        release("guarded", "alice", false).unwrap();
+        let guard = ReservationGuard::new("guarded", "bob", None).unwrap();
+        assert_eq!(current("guarded").unwrap().unwrap().owner, "bob");
+        drop(guard);
+        assert_eq!(current("guarded").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn check_access_refuses_other_users() {
+        setup();
+        check_access("access", "bob", false, Duration::ZERO).await.unwrap();
+        reserve("access", "alice", None, None, None).unwrap();
+        check_access("access", "alice", false, Duration::ZERO).await.unwrap();
+        let e = check_access("access", "bob", false, Duration::ZERO).await.unwrap_err();
+        assert_eq!(exit::classify(&e), Some(Failure::Reserved));
+        let e = check_access("access", "bob", true, Duration::ZERO).await.unwrap_err();
+        assert_eq!(exit::classify(&e), Some(Failure::Reserved));
+    }
+
+    #[tokio::test]
+    async fn check_access_queues_until_released() {
+        setup();
+        reserve("queue", "alice", Some(Duration::from_secs(1)), None, None).unwrap();
+        check_access("queue", "bob", true, Duration::from_secs(10)).await.unwrap();
+        assert_eq!(current("queue").unwrap(), None);
+    }
+}
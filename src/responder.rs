@@ -0,0 +1,147 @@
+//! Answering small interactive hurdles on the console without attaching to
+//! it, like `Press any key to continue` or fsck asking whether to fix
+//! something. A responder's `prompt` is matched against console lines and
+//! against incomplete lines that have been waiting for a while (see
+//! [ConnectionEvent::Prompt](crate::ConnectionEvent::Prompt)), since most
+//! prompts don't end the line. Each answer is logged, and a responder answers
+//! at most `limit` times within `period`, so a prompt that keeps coming back
+//! isn't answered forever.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::{Device, ResponderConfig};
+use crate::connections::{ConnectionInput, InputData};
+use crate::state::StateMachine;
+use crate::thermal::THERMAL_SOURCE;
+use crate::vars::{self, Vars};
+use crate::CONNECTIONS_SOURCE;
+use anyhow::Result;
+use regex::Regex;
+use tokio::sync::mpsc::UnboundedSender;
+
+struct Responder {
+    config: ResponderConfig,
+    prompt: Regex,
+    /// When it answered within the last period
+    answers: VecDeque<Instant>,
+    /// Whether hitting the limit has been logged
+    limited: bool,
+}
+
+impl Responder {
+    fn name(&self) -> &str {
+        self.config.name.as_deref().unwrap_or(&self.config.prompt)
+    }
+
+    /// Whether it's allowed to answer again, forgetting answers older than
+    /// the period
+    fn allowed(&mut self, now: Instant) -> bool {
+        let period = Duration::from_millis(self.config.period as u64);
+        while self
+            .answers
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= period)
+        {
+            self.answers.pop_front();
+        }
+        if self.answers.len() < self.config.limit as usize {
+            self.limited = false;
+            return true;
+        }
+        if !self.limited {
+            warn!(
+                "Responder {} answered {} times in {}ms, not answering again for now",
+                self.name(),
+                self.answers.len(),
+                self.config.period
+            );
+            self.limited = true;
+        }
+        false
+    }
+}
+
+/// The responders of a device, driven by the device loop
+pub struct Responders {
+    responders: Vec<Responder>,
+    variables: Vars,
+    input: UnboundedSender<ConnectionInput>,
+    /// Connections whose prompt was answered before it ended the line, the
+    /// line it ends up as isn't answered again
+    answered: Vec<String>,
+}
+
+impl Responders {
+    pub fn new(device: &Device, input: UnboundedSender<ConnectionInput>) -> Result<Self> {
+        let responders = device
+            .responders
+            .iter()
+            .map(|config| {
+                let prompt = Regex::new(&config.prompt)
+                    .map_err(|e| anyhow!("Invalid responder prompt {:?}: {}", config.prompt, e))?;
+                Ok(Responder {
+                    config: config.clone(),
+                    prompt,
+                    answers: VecDeque::new(),
+                    limited: false,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            responders,
+            variables: device.variables.clone(),
+            input,
+            answered: vec![],
+        })
+    }
+
+    /// A line (or an incomplete one if `prompt`) was received on
+    /// `connection`, answer it if a responder matches
+    pub fn seen(&mut self, connection: &str, line: &str, prompt: bool, sm: &StateMachine, now: Instant) {
+        // There's nothing to answer
+        if connection == CONNECTIONS_SOURCE || connection == THERMAL_SOURCE {
+            return;
+        }
+        if let Some(i) = self.answered.iter().position(|c| c == connection) {
+            // Anything more before the line ends is the echo of the answer
+            if !prompt {
+                self.answered.remove(i);
+            }
+            return;
+        }
+        let Some(responder) = self.responders.iter_mut().find(|r| {
+            r.config.connection.as_deref().map_or(true, |c| c == connection)
+                && (r.config.states.is_empty() || r.config.states.iter().any(|s| sm.in_state(s)))
+                && r.prompt.is_match(line)
+        }) else {
+            return;
+        };
+        if !responder.allowed(now) {
+            return;
+        }
+        let mut vars = self.variables.clone();
+        vars.extend(sm.context().clone());
+        let answer = match vars::expand(&responder.config.send, &vars) {
+            Ok(answer) => answer,
+            Err(e) => {
+                error!("Responder {}: {}", responder.name(), e);
+                return;
+            }
+        };
+        info!("{}: answering {:?} with {:?} ({})", connection, line, answer, responder.name());
+        responder.answers.push_back(now);
+        if prompt {
+            self.answered.push(connection.to_string());
+        }
+        let data = if responder.config.raw {
+            InputData::Raw(answer.into_bytes())
+        } else {
+            InputData::Line(answer)
+        };
+        let _ = self.input.send(ConnectionInput {
+            connection: Some(connection.to_string()),
+            data,
+        });
+    }
+}
@@ -0,0 +1,937 @@
+//! Scenarios: a list of steps (triggers, waits, power, console input and
+//! output, fastboot, adb and edl) run one after the other against a device,
+//! loaded from a YAML file.
+//! Steps and whole scenarios can be retried with recovery steps run between
+//! attempts, so a flaky board doesn't throw away the rest of a test run. A
+//! suite of scenarios runs on each selected device concurrently, with the
+//! results merged into one [Report].
+
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::android::{self, RebootTarget, Tool};
+use crate::artifacts::RunDir;
+use crate::config::{ArtifactsConfig, Device};
+use crate::exit::{self, Failure};
+use crate::fleet::{Access, PowerAction};
+use crate::reservation::{self, ReservationGuard};
+use crate::vars::Vars;
+use crate::{log_target, ConnectionEvent, ConnectionInput, InputData, RunningDevice, TriggerOptions};
+use anyhow::{Context, Result};
+use futures::future::join_all;
+use regex::Regex;
+use serde::ser::{Serialize, SerializeStruct};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+fn _default_step_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"))]
+pub struct Scenario {
+    /// Defaults to the name of the file it's loaded from
+    #[serde(default)]
+    pub name: String,
+    /// How many more times to run all the steps if one of them fails
+    #[serde(default)]
+    pub retries: u32,
+    /// Steps to run before each retry of the scenario, e.g. a power cycle
+    #[serde(default)]
+    pub recovery: Vec<Step>,
+    pub steps: Vec<Step>,
+}
+
+/// What a step does
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Run a trigger, optionally waiting for a state afterwards
+    Trigger {
+        name: String,
+        wait: Option<String>,
+        vars: Vars,
+        force: bool,
+    },
+    /// Wait for the device to enter a state, or one of its children
+    Wait(String),
+    /// Use the power control and wait for the state it leads to, or the given
+    /// one instead
+    Power { action: PowerAction, wait: Option<String> },
+    /// Send input to the console and/or wait for a line matching a regex
+    Exec {
+        input: Option<ConnectionInput>,
+        expect: Option<Regex>,
+    },
+    /// Run fastboot, adb or edl, optionally checking that a line of its
+    /// output matches a regex
+    Tool {
+        tool: Tool,
+        args: Vec<String>,
+        expect: Option<Regex>,
+    },
+    /// Flash an image to a partition with fastboot
+    Flash { partition: String, image: PathBuf },
+    /// Reboot with fastboot or adb, optionally waiting for a state afterwards
+    Reboot { to: RebootTarget, wait: Option<String> },
+    /// Wait for the device to show up to fastboot or adb, or in EDL mode
+    WaitFor(Tool),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Trigger { name, wait, .. } => {
+                write!(f, "trigger {}", name)?;
+                if let Some(wait) = wait {
+                    write!(f, " and wait for {}", wait)?;
+                }
+                Ok(())
+            }
+            Action::Wait(state) => write!(f, "wait for {}", state),
+            Action::Power { action, wait } => {
+                write!(f, "power {}", action)?;
+                if let Some(wait) = wait {
+                    write!(f, " and wait for {}", wait)?;
+                }
+                Ok(())
+            }
+            Action::Exec { input, expect } => {
+                match input.as_ref().map(|i| &i.data) {
+                    Some(InputData::Line(line)) => write!(f, "send {:?}", line)?,
+                    Some(InputData::Raw(bytes)) => write!(f, "send {:?}", bytes)?,
+                    None => (),
+                }
+                match (input, expect) {
+                    (Some(_), Some(expect)) => write!(f, " and expect /{}/", expect),
+                    (None, Some(expect)) => write!(f, "expect /{}/", expect),
+                    _ => Ok(()),
+                }
+            }
+            Action::Tool { tool, args, expect } => {
+                write!(f, "{} {}", tool, args.join(" "))?;
+                if let Some(expect) = expect {
+                    write!(f, " and expect /{}/", expect)?;
+                }
+                Ok(())
+            }
+            Action::Flash { partition, image } => write!(f, "flash {} with {}", partition, image.display()),
+            Action::Reboot { to, wait } => {
+                write!(f, "reboot to {}", to)?;
+                if let Some(wait) = wait {
+                    write!(f, " and wait for {}", wait)?;
+                }
+                Ok(())
+            }
+            Action::WaitFor(tool) => write!(f, "wait for {}", tool),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "StepConfig")]
+pub struct Step {
+    pub action: Action,
+    /// How long an attempt at the step may take
+    pub timeout: Duration,
+    /// How many more times to try the step if it fails
+    pub retries: u32,
+    /// Steps to run before each retry of this step
+    pub recovery: Vec<Step>,
+    /// Record the failure and carry on with the next step instead of
+    /// failing the scenario there and then
+    pub continue_on_failure: bool,
+}
+
+/// A step as it's written in the scenario file, at most one of `trigger`,
+/// `power`, `send`, `fastboot`, `adb`, `edl`, `flash`, `reboot` or `wait-for`
+/// says what it does, otherwise it's an `expect` or `wait` on its own
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "kebab-case"), deny_unknown_fields)]
+struct StepConfig {
+    trigger: Option<String>,
+    #[serde(default)]
+    vars: Vars,
+    #[serde(default)]
+    force: bool,
+    power: Option<PowerAction>,
+    send: Option<String>,
+    connection: Option<String>,
+    expect: Option<String>,
+    wait: Option<String>,
+    fastboot: Option<Vec<String>>,
+    adb: Option<Vec<String>>,
+    edl: Option<Vec<String>>,
+    flash: Option<String>,
+    image: Option<PathBuf>,
+    reboot: Option<RebootTarget>,
+    wait_for: Option<Tool>,
+    /// Seconds
+    #[serde(default = "_default_step_timeout")]
+    timeout: u64,
+    #[serde(default)]
+    retries: u32,
+    #[serde(default)]
+    recovery: Vec<Step>,
+    #[serde(default)]
+    continue_on_failure: bool,
+}
+
+impl TryFrom<StepConfig> for Step {
+    type Error = anyhow::Error;
+
+    fn try_from(c: StepConfig) -> Result<Self> {
+        let kinds: Vec<&str> = [
+            ("trigger", c.trigger.is_some()),
+            ("power", c.power.is_some()),
+            ("send", c.send.is_some()),
+            ("fastboot", c.fastboot.is_some()),
+            ("adb", c.adb.is_some()),
+            ("edl", c.edl.is_some()),
+            ("flash", c.flash.is_some()),
+            ("reboot", c.reboot.is_some()),
+            ("wait-for", c.wait_for.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(kind, set)| set.then_some(kind))
+        .collect();
+        if kinds.len() > 1 {
+            bail!("A step can only do one thing, this one has {}", kinds.join(", "));
+        }
+        let kind = kinds.first().copied();
+        if c.wait.is_some() && !matches!(kind, None | Some("trigger" | "power" | "reboot")) {
+            bail!("wait can't be combined with {}, use a step of its own", kind.unwrap());
+        }
+        if c.expect.is_some() && !matches!(kind, None | Some("send" | "fastboot" | "adb" | "edl")) {
+            bail!("expect can't be combined with {}", kind.unwrap());
+        }
+        if kind != Some("trigger") && (!c.vars.is_empty() || c.force) {
+            bail!("vars and force only apply to trigger steps");
+        }
+        if kind != Some("send") && c.connection.is_some() {
+            bail!("connection only applies to send steps");
+        }
+        if kind != Some("flash") && c.image.is_some() {
+            bail!("image only applies to flash steps");
+        }
+        let expect = match c.expect {
+            Some(expect) => Some(Regex::new(&expect).with_context(|| format!("Invalid regex {:?}", expect))?),
+            None => None,
+        };
+        let tool = |tool, args| Action::Tool {
+            tool,
+            args,
+            expect: expect.clone(),
+        };
+        let action = if let Some(name) = c.trigger {
+            Action::Trigger {
+                name,
+                wait: c.wait,
+                vars: c.vars,
+                force: c.force,
+            }
+        } else if let Some(action) = c.power {
+            Action::Power { action, wait: c.wait }
+        } else if let Some(data) = c.send {
+            Action::Exec {
+                input: Some(ConnectionInput {
+                    connection: c.connection,
+                    data: data.into(),
+                }),
+                expect,
+            }
+        } else if let Some(args) = c.fastboot {
+            tool(Tool::Fastboot, args)
+        } else if let Some(args) = c.adb {
+            tool(Tool::Adb, args)
+        } else if let Some(args) = c.edl {
+            tool(Tool::Edl, args)
+        } else if let Some(partition) = c.flash {
+            Action::Flash {
+                partition,
+                image: c.image.ok_or_else(|| anyhow!("flash needs the image to flash"))?,
+            }
+        } else if let Some(to) = c.reboot {
+            Action::Reboot { to, wait: c.wait }
+        } else if let Some(tool) = c.wait_for {
+            Action::WaitFor(tool)
+        } else if expect.is_some() {
+            Action::Exec { input: None, expect }
+        } else {
+            Action::Wait(c.wait.ok_or_else(|| {
+                anyhow!("A step needs one of trigger, power, send, expect, fastboot, adb, edl, flash, reboot, wait-for or wait")
+            })?)
+        };
+        check_recovery(&c.recovery)?;
+        Ok(Step {
+            action,
+            timeout: Duration::from_secs(c.timeout),
+            retries: c.retries,
+            recovery: c.recovery,
+            continue_on_failure: c.continue_on_failure,
+        })
+    }
+}
+
+/// Recovery steps are run once each, whatever happens
+fn check_recovery(steps: &[Step]) -> Result<()> {
+    if steps
+        .iter()
+        .any(|s| s.retries > 0 || !s.recovery.is_empty() || s.continue_on_failure)
+    {
+        bail!("Recovery steps can't have retries, recovery or continue-on-failure of their own");
+    }
+    Ok(())
+}
+
+pub fn parse(s: &str) -> Result<Scenario> {
+    let scenario: Scenario = serde_yaml::from_str(s)?;
+    check_recovery(&scenario.recovery)?;
+    if scenario.steps.is_empty() {
+        bail!("Scenario has no steps");
+    }
+    Ok(scenario)
+}
+
+pub fn load(path: &Path) -> Result<Scenario> {
+    let s = std::fs::read_to_string(path).with_context(|| format!("Couldn't read {}", path.display()))?;
+    let mut scenario = parse(&s)
+        .with_context(|| format!("Invalid scenario {}", path.display()))
+        .map_err(|e| Failure::Config.wrap(e))?;
+    if scenario.name.is_empty() {
+        scenario.name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    Ok(scenario)
+}
+
+fn serialize_secs<S: serde::Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(d.as_secs_f64())
+}
+
+/// The outcome of a step in the last attempt at a scenario
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StepResult {
+    pub step: String,
+    pub attempts: u32,
+    #[serde(rename = "seconds", serialize_with = "serialize_secs")]
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+impl Display for StepResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            None => write!(f, "ok: {} ({:.1}s", self.step, self.duration.as_secs_f64()),
+            Some(_) => write!(f, "FAILED: {} ({:.1}s", self.step, self.duration.as_secs_f64()),
+        }?;
+        if self.attempts > 1 {
+            write!(f, ", {} attempts", self.attempts)?;
+        }
+        write!(f, ")")?;
+        match &self.error {
+            Some(e) => write!(f, ": {}", e),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct ScenarioResult {
+    pub codename: String,
+    pub scenario: String,
+    pub attempts: u32,
+    pub duration: Duration,
+    /// The steps of the last attempt, up to the one that failed it
+    pub steps: Vec<StepResult>,
+    pub result: Result<()>,
+    /// Where the artifacts of the run were uploaded to
+    pub url: Option<String>,
+}
+
+impl Serialize for ScenarioResult {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ScenarioResult", 9)?;
+        s.serialize_field("codename", &self.codename)?;
+        s.serialize_field("scenario", &self.scenario)?;
+        s.serialize_field("ok", &self.result.is_ok())?;
+        s.serialize_field("attempts", &self.attempts)?;
+        s.serialize_field("seconds", &self.duration.as_secs_f64())?;
+        s.serialize_field("steps", &self.steps)?;
+        s.serialize_field("error", &self.result.as_ref().err().map(|e| e.to_string()))?;
+        s.serialize_field("failure", &self.result.as_ref().err().and_then(exit::classify))?;
+        s.serialize_field("url", &self.url)?;
+        s.end()
+    }
+}
+
+impl Display for ScenarioResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.duration.as_secs_f64();
+        match &self.result {
+            Ok(()) => write!(f, "{}: {} passed in {:.1}s", self.codename, self.scenario, secs),
+            Err(_) => write!(f, "{}: {} FAILED in {:.1}s", self.codename, self.scenario, secs),
+        }?;
+        if self.attempts > 1 {
+            write!(f, " ({} attempts)", self.attempts)?;
+        }
+        if let Err(e) = &self.result {
+            write!(f, ": {}", e)?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, ", artifacts at {}", url)?;
+        }
+        for step in self.steps.iter() {
+            write!(f, "\n  {}", step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Make an attempt, and up to `retries` more while `failed` says it failed,
+/// running `recover` before each retry. Returns the number of attempts made
+/// and the result of the last one.
+async fn retry<T, A, AF, R, RF>(
+    what: &str,
+    retries: u32,
+    failed: impl Fn(&T) -> bool,
+    mut attempt: A,
+    mut recover: R,
+) -> (u32, T)
+where
+    A: FnMut() -> AF,
+    AF: Future<Output = T>,
+    R: FnMut() -> RF,
+    RF: Future<Output = ()>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let res = attempt().await;
+        if !failed(&res) || attempts > retries {
+            return (attempts, res);
+        }
+        warn!("{} failed, retrying ({} of {})", what, attempts, retries);
+        recover().await;
+    }
+}
+
+async fn run_action(dev: &RunningDevice, action: &Action, timeout: Duration, user: &str) -> Result<()> {
+    let android = dev.device.android.clone().unwrap_or_default();
+    let target = log_target(&dev.device.codename, None);
+    tokio::time::timeout(timeout, async {
+        match action {
+            Action::Trigger { name, wait, vars, force } => {
+                let opts = TriggerOptions {
+                    vars: vars.clone(),
+                    user: Some(user.to_string()),
+                    force: *force,
+                };
+                dev.run_trigger_with(name, opts).await?;
+                if let Some(wait) = wait {
+                    dev.wait_for_state(wait).await?;
+                }
+            }
+            Action::Wait(state) => dev.wait_for_state(state).await?,
+            Action::Power { action, wait } => {
+                let target = dev.power(*action).await?;
+                if let Some(target) = wait.clone().or(target) {
+                    dev.wait_for_state(&target).await?;
+                }
+            }
+            Action::Exec { input, expect } => {
+                let mut rx = dev.subscribe().await?;
+                if let Some(input) = input {
+                    dev.send(input.clone()).await?;
+                }
+                let Some(expect) = expect else {
+                    return Ok(());
+                };
+                loop {
+                    match rx.recv().await {
+                        Ok(ev) => {
+                            if let ConnectionEvent::NewLine(line) = ev.event {
+                                if expect.is_match(&line) {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(RecvError::Lagged(n)) => warn!("Dropped {} lines", n),
+                        Err(RecvError::Closed) => bail!("Device stopped"),
+                    }
+                }
+            }
+            Action::Tool { tool, args, expect } => {
+                let output = android::run(*tool, &android, args, &target).await?;
+                if let Some(expect) = expect {
+                    if !output.lines().any(|line| expect.is_match(line)) {
+                        bail!("No output of {} matched /{}/", tool, expect);
+                    }
+                }
+            }
+            Action::Flash { partition, image } => {
+                let args = ["flash".to_string(), partition.clone(), image.display().to_string()];
+                android::run(Tool::Fastboot, &android, &args, &target).await?;
+            }
+            Action::Reboot { to, wait } => {
+                android::reboot(&android, *to, &target).await?;
+                if let Some(wait) = wait {
+                    dev.wait_for_state(wait).await?;
+                }
+            }
+            Action::WaitFor(tool) => android::wait_for(*tool, &android).await,
+        }
+        Ok(())
+    })
+    .await
+    .unwrap_or_else(|_| Err(Failure::Timeout.error(format!("Timed out after {}s", timeout.as_secs()))))
+}
+
+/// Run recovery steps, their failures are logged but otherwise ignored as the
+/// retry will tell whether they helped
+async fn recover(dev: &RunningDevice, steps: &[Step], user: &str) {
+    for step in steps.iter() {
+        info!("{}: recovering: {}", dev.device.codename, step.action);
+        if let Err(e) = run_action(dev, &step.action, step.timeout, user).await {
+            warn!("{}: recovery step {} failed: {}", dev.device.codename, step.action, e);
+        }
+    }
+}
+
+/// Run the steps once, stopping at the first failure unless the step is
+/// allowed to fail
+async fn run_steps(dev: &RunningDevice, steps: &[Step], user: &str) -> (Vec<StepResult>, Result<()>) {
+    let codename = &dev.device.codename;
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for step in steps.iter() {
+        info!("{}: {}", codename, step.action);
+        let start = Instant::now();
+        let what = format!("{}: {}", codename, step.action);
+        let (attempts, res) = retry(
+            &what,
+            step.retries,
+            |res: &Result<()>| res.is_err(),
+            move || run_action(dev, &step.action, step.timeout, user),
+            move || recover(dev, &step.recovery, user),
+        )
+        .await;
+        results.push(StepResult {
+            step: step.action.to_string(),
+            attempts,
+            duration: start.elapsed(),
+            error: res.as_ref().err().map(|e| e.to_string()),
+        });
+        if let Err(e) = res {
+            if step.continue_on_failure {
+                warn!("{}: {} failed, continuing: {}", codename, step.action, e);
+                failed += 1;
+                continue;
+            }
+            error!("{}: {} failed: {}", codename, step.action, e);
+            return (results, Err(e.context(format!("{} failed", step.action))));
+        }
+    }
+    match failed {
+        0 => (results, Ok(())),
+        1 => (results, Err(anyhow!("1 step failed"))),
+        n => (results, Err(anyhow!("{} steps failed", n))),
+    }
+}
+
+/// Run a scenario on a device, retrying it as a whole if a step fails
+pub async fn run_one(
+    device: Device,
+    scenario: &Scenario,
+    timeout: Duration,
+    access: &Access,
+    artifacts: &ArtifactsConfig,
+) -> ScenarioResult {
+    let start = Instant::now();
+    let mut result = ScenarioResult {
+        codename: device.codename.clone(),
+        scenario: scenario.name.clone(),
+        attempts: 0,
+        duration: Duration::ZERO,
+        steps: Vec::new(),
+        result: Ok(()),
+        url: None,
+    };
+    let codename = device.codename.clone();
+    let guard = match reservation::check_access(&codename, &access.user, access.queue, timeout).await {
+        Ok(()) => ReservationGuard::new(&codename, &access.user, Some(format!("scenario {}", scenario.name))),
+        Err(e) => Err(e),
+    };
+    let _guard = match guard {
+        Ok(guard) => guard,
+        Err(e) => {
+            result.result = Err(e);
+            return result;
+        }
+    };
+    let mut run_dir = RunDir::create(artifacts, &device, &format!("scenario-{}", scenario.name)).unwrap_or_else(|e| {
+        warn!("{}: not saving artifacts: {}", codename, e);
+        None
+    });
+    let dev = RunningDevice::spawn(device);
+    if let Some(run_dir) = run_dir.as_mut() {
+        if let Err(e) = run_dir.record(&dev).await {
+            warn!("{}: not recording the run: {}", codename, e);
+        }
+    }
+
+    let what = format!("{}: scenario {}", codename, scenario.name);
+    let user = access.user.as_str();
+    let dev_ref = &dev;
+    let run = retry(
+        &what,
+        scenario.retries,
+        |(_, res): &(Vec<StepResult>, Result<()>)| res.is_err(),
+        move || run_steps(dev_ref, &scenario.steps, user),
+        move || recover(dev_ref, &scenario.recovery, user),
+    );
+    let (attempts, (steps, res)) = match tokio::time::timeout(timeout, run).await {
+        Ok(run) => run,
+        Err(_) => (
+            1,
+            (
+                Vec::new(),
+                Err(Failure::Timeout.error(format!("Timed out after {}s", timeout.as_secs()))),
+            ),
+        ),
+    };
+
+    let state = dev.current_state();
+    result.result = match dev.shutdown().await {
+        // The device loop exiting on its own means it failed
+        Err(e) => Err(e),
+        Ok(()) => res,
+    };
+    result.attempts = attempts;
+    result.steps = steps;
+    if let Some(mut run_dir) = run_dir {
+        run_dir
+            .finish(state, result.result.as_ref().err().map(|e| e.to_string()))
+            .await;
+        info!("{}: artifacts in {}", codename, run_dir.path.display());
+        result.url = run_dir.metadata.url;
+    }
+    result.duration = start.elapsed();
+    result
+}
+
+/// Run the scenarios of a suite one after the other on a device. A failed
+/// scenario doesn't stop the rest.
+async fn run_suite(
+    device: Device,
+    scenarios: &[Scenario],
+    timeout: Duration,
+    access: &Access,
+    artifacts: &ArtifactsConfig,
+) -> Vec<ScenarioResult> {
+    let mut results = Vec::new();
+    for scenario in scenarios.iter() {
+        results.push(run_one(device.clone(), scenario, timeout, access, artifacts).await);
+    }
+    results
+}
+
+/// Run a suite of scenarios on all devices concurrently, `timeout` applies to
+/// each scenario
+pub async fn run(
+    devices: Vec<Device>,
+    scenarios: &[Scenario],
+    timeout: Duration,
+    access: &Access,
+    artifacts: &ArtifactsConfig,
+) -> Report {
+    let start = Instant::now();
+    let results = join_all(
+        devices
+            .into_iter()
+            .map(|d| run_suite(d, scenarios, timeout, access, artifacts)),
+    )
+    .await;
+    Report {
+        results: results.into_iter().flatten().collect(),
+        duration: start.elapsed(),
+    }
+}
+
+/// The results of running a suite on several devices
+pub struct Report {
+    /// Grouped by device, in the order the scenarios ran
+    pub results: Vec<ScenarioResult>,
+    pub duration: Duration,
+}
+
+impl Report {
+    pub fn errors(&self) -> impl Iterator<Item = &anyhow::Error> {
+        self.results.iter().filter_map(|r| r.result.as_ref().err())
+    }
+}
+
+impl Serialize for Report {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Report", 4)?;
+        s.serialize_field("passed", &self.results.iter().filter(|r| r.result.is_ok()).count())?;
+        s.serialize_field("failed", &self.errors().count())?;
+        s.serialize_field("seconds", &self.duration.as_secs_f64())?;
+        s.serialize_field("results", &self.results)?;
+        s.end()
+    }
+}
+
+/// Each result with its steps, then a table of devices against scenarios
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for result in self.results.iter() {
+            writeln!(f, "{}", result)?;
+        }
+        let mut devices: Vec<&str> = Vec::new();
+        let mut scenarios: Vec<&str> = Vec::new();
+        for result in self.results.iter() {
+            if !devices.contains(&result.codename.as_str()) {
+                devices.push(&result.codename);
+            }
+            if !scenarios.contains(&result.scenario.as_str()) {
+                scenarios.push(&result.scenario);
+            }
+        }
+        let cell = |device: &str, scenario: &str| {
+            match self.results.iter().find(|r| r.codename == device && r.scenario == scenario) {
+                Some(r) if r.result.is_ok() => format!("ok {:.1}s", r.duration.as_secs_f64()),
+                Some(r) => format!("FAILED {:.1}s", r.duration.as_secs_f64()),
+                None => "-".to_string(),
+            }
+        };
+        let first = devices.iter().map(|d| d.len()).max().unwrap_or(0);
+        let widths: Vec<usize> = scenarios
+            .iter()
+            .map(|s| devices.iter().map(|d| cell(d, s).len()).chain([s.len()]).max().unwrap_or(0))
+            .collect();
+        write!(f, "\n{:first$}", "")?;
+        for (scenario, width) in scenarios.iter().zip(widths.iter()) {
+            write!(f, "  {:width$}", scenario)?;
+        }
+        for device in devices.iter() {
+            write!(f, "\n{:first$}", device)?;
+            for (scenario, width) in scenarios.iter().zip(widths.iter()) {
+                write!(f, "  {:width$}", cell(device, scenario))?;
+            }
+        }
+        let failed = self.errors().count();
+        write!(
+            f,
+            "\n{} of {} passed in {:.1}s",
+            self.results.len() - failed,
+            self.results.len(),
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn parse_steps() {
+        let scenario = parse(
+            r#"
+name: smoke
+retries: 1
+recovery:
+  - power: cycle
+steps:
+  - trigger: bootloader
+    wait: fastboot
+    vars:
+      slot: a
+  - wait: linux
+    timeout: 300
+    retries: 2
+    recovery:
+      - trigger: reset
+  - send: uname -r
+    connection: UART
+    expect: '^6\.'
+  - expect: login
+    continue-on-failure: true
+"#,
+        )
+        .unwrap();
+        assert_eq!(scenario.name, "smoke");
+        assert_eq!(scenario.retries, 1);
+        assert!(matches!(scenario.recovery[0].action, Action::Power { action: PowerAction::Cycle, wait: None }));
+        let steps: Vec<String> = scenario.steps.iter().map(|s| s.action.to_string()).collect();
+        assert_eq!(
+            steps,
+            [
+                "trigger bootloader and wait for fastboot",
+                "wait for linux",
+                "send \"uname -r\" and expect /^6\\./",
+                "expect /login/",
+            ]
+        );
+        assert_eq!(scenario.steps[0].timeout, Duration::from_secs(60));
+        assert_eq!(scenario.steps[1].timeout, Duration::from_secs(300));
+        assert_eq!(scenario.steps[1].retries, 2);
+        assert_eq!(scenario.steps[1].recovery.len(), 1);
+        assert!(scenario.steps[3].continue_on_failure);
+        match &scenario.steps[2].action {
+            Action::Exec { input: Some(input), .. } => assert_eq!(input.connection.as_deref(), Some("UART")),
+            action => panic!("Unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn parse_android_steps() {
+        let scenario = parse(
+            r#"
+steps:
+  - reboot: bootloader
+    wait: fastboot
+  - wait-for: fastboot
+  - flash: boot
+    image: out/boot.img
+  - fastboot: [getvar, current-slot]
+    expect: 'current-slot: a'
+  - reboot: system
+  - wait-for: adb
+    timeout: 120
+  - adb: [shell, uname -r]
+    expect: '^6\.'
+  - edl: [printgpt]
+"#,
+        )
+        .unwrap();
+        let steps: Vec<String> = scenario.steps.iter().map(|s| s.action.to_string()).collect();
+        assert_eq!(
+            steps,
+            [
+                "reboot to bootloader and wait for fastboot",
+                "wait for fastboot",
+                "flash boot with out/boot.img",
+                "fastboot getvar current-slot and expect /current-slot: a/",
+                "reboot to system",
+                "wait for adb",
+                "adb shell uname -r and expect /^6\\./",
+                "edl printgpt",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_invalid_steps() {
+        for steps in [
+            "- trigger: boot\n  power: on",
+            "- send: reboot\n  wait: off",
+            "- power: cycle\n  vars: {a: b}",
+            "- wait: linux\n  connection: UART",
+            "- timeout: 10",
+            "- expect: '('",
+            "- wait: linux\n  retires: 2",
+            "- wait: linux\n  recovery:\n    - power: cycle\n      retries: 1",
+            "- flash: boot",
+            "- wait: linux\n  image: boot.img",
+            "- fastboot: [devices]\n  adb: [devices]",
+            "- wait-for: adb\n  wait: linux",
+            "- reboot: edl\n  expect: Sahara",
+            "- wait-for: ssh",
+        ] {
+            assert!(parse(&format!("steps:\n{}", steps)).is_err(), "{}", steps);
+        }
+        assert!(parse("steps: []").is_err());
+        assert!(parse("recovery:\n  - power: cycle\n    continue-on-failure: true\nsteps:\n  - wait: linux").is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let attempts = Cell::new(0);
+        let recoveries = Cell::new(0);
+        let (n, res) = retry(
+            "test",
+            3,
+            |res: &Result<(), ()>| res.is_err(),
+            || {
+                attempts.set(attempts.get() + 1);
+                let ok = attempts.get() == 2;
+                async move { if ok { Ok(()) } else { Err(()) } }
+            },
+            || {
+                recoveries.set(recoveries.get() + 1);
+                async {}
+            },
+        )
+        .await;
+        assert_eq!((n, res), (2, Ok(())));
+        assert_eq!(recoveries.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_give_up() {
+        let recoveries = Cell::new(0);
+        let (n, res) = retry(
+            "test",
+            2,
+            |res: &Result<(), ()>| res.is_err(),
+            || async { Err(()) },
+            || {
+                recoveries.set(recoveries.get() + 1);
+                async {}
+            },
+        )
+        .await;
+        assert_eq!((n, res), (3, Err(())));
+        // No recovery after the last attempt
+        assert_eq!(recoveries.get(), 2);
+
+        let (n, _) = retry("test", 0, |res: &Result<(), ()>| res.is_err(), || async { Err(()) }, || async {}).await;
+        assert_eq!(n, 1);
+    }
+
+    fn result(codename: &str, scenario: &str, secs: u64, ok: bool) -> ScenarioResult {
+        ScenarioResult {
+            codename: codename.to_string(),
+            scenario: scenario.to_string(),
+            attempts: 1,
+            duration: Duration::from_secs(secs),
+            steps: Vec::new(),
+            result: if ok { Ok(()) } else { Err(anyhow!("wait for linux failed")) },
+            url: None,
+        }
+    }
+
+    #[test]
+    fn report() {
+        let report = Report {
+            results: vec![
+                result("sdm845", "smoke", 42, true),
+                result("sdm845", "reflash", 120, false),
+                result("sm8150", "smoke", 40, true),
+            ],
+            duration: Duration::from_secs(162),
+        };
+        let s = report.to_string();
+        let summary: Vec<&str> = s.lines().skip(4).collect();
+        assert_eq!(
+            summary,
+            [
+                "        smoke     reflash      ",
+                "sdm845  ok 42.0s  FAILED 120.0s",
+                "sm8150  ok 40.0s  -            ",
+                "2 of 3 passed in 162.0s",
+            ]
+        );
+        assert_eq!(report.errors().count(), 1);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["passed"], 2);
+        assert_eq!(json["failed"], 1);
+        assert_eq!(json["results"][1]["error"], "wait for linux failed");
+    }
+}
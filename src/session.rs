@@ -0,0 +1,152 @@
+//! Recording interactive console sessions, both what was typed and what was
+//! received, so a manual session (like bringing up a new board) can be turned
+//! into a repeatable script and replayed with `fbug replay`. A recording has a
+//! line for everything sent or received:
+//!
+//! ```text
+//! <seconds since the start>\t<in|out>\t<connection>\t<line>
+//! ```
+//!
+//! The connection is `-` for input that didn't name one, it went to the
+//! preferred or first connection.
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::ConnectionInput;
+use anyhow::Result;
+use strum_macros::Display;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Direction {
+    /// Typed by the user
+    In,
+    /// Received from the device
+    Out,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Since the recording started
+    pub at: Duration,
+    pub direction: Direction,
+    pub connection: Option<String>,
+    pub line: String,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3}\t{}\t{}\t{}",
+            self.at.as_secs_f64(),
+            self.direction,
+            self.connection.as_deref().unwrap_or("-"),
+            self.line
+        )
+    }
+}
+
+impl FromStr for Entry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.splitn(4, '\t');
+        let (Some(at), Some(direction), Some(connection), Some(line)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            bail!("Expected 4 tab separated fields");
+        };
+        let at: f64 = at.parse().map_err(|_| anyhow!("Invalid time {:?}", at))?;
+        Ok(Self {
+            at: Duration::try_from_secs_f64(at).map_err(|_| anyhow!("Invalid time {:?}", at))?,
+            direction: match direction {
+                "in" => Direction::In,
+                "out" => Direction::Out,
+                _ => bail!("Invalid direction {:?}, expected in or out", direction),
+            },
+            connection: Some(connection).filter(|c| *c != "-").map(String::from),
+            line: line.to_string(),
+        })
+    }
+}
+
+/// Writes a recording as the session goes, so it survives the session being
+/// killed
+pub struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).map_err(|e| anyhow!("Failed to create {}: {}", path.display(), e))?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            start: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, direction: Direction, connection: Option<&str>, line: &str) {
+        let entry = Entry {
+            at: self.start.elapsed(),
+            direction,
+            connection: connection.map(String::from),
+            line: line.to_string(),
+        };
+        if let Err(e) = writeln!(self.file, "{}", entry).and_then(|_| self.file.flush()) {
+            warn!("Failed to record the session: {}", e);
+        }
+    }
+}
+
+/// Read a recording made by [Recorder]
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let recording =
+        std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+    recording
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| line.parse().map_err(|e| anyhow!("{}:{}: {}", path.display(), i + 1, e)))
+        .collect()
+}
+
+/// The input side of a recording, handed out at the times it was typed
+pub struct Replay {
+    inputs: std::vec::IntoIter<Entry>,
+    start: tokio::time::Instant,
+    speed: f64,
+}
+
+impl Replay {
+    /// `speed` scales the timing, 2 replays twice as fast
+    pub fn new(entries: Vec<Entry>, speed: f64) -> Result<Self> {
+        if !(speed > 0.0 && speed.is_finite()) {
+            bail!("Invalid replay speed {}", speed);
+        }
+        let inputs: Vec<Entry> = entries.into_iter().filter(|e| e.direction == Direction::In).collect();
+        Ok(Self {
+            inputs: inputs.into_iter(),
+            start: tokio::time::Instant::now(),
+            speed,
+        })
+    }
+
+    /// Wait until the next input is due and return it, None once everything
+    /// has been sent
+    pub async fn next(&mut self) -> Option<ConnectionInput> {
+        let entry = self.inputs.next()?;
+        tokio::time::sleep_until(self.start + entry.at.div_f64(self.speed)).await;
+        debug!("Replaying {:?}", entry.line);
+        Some(ConnectionInput {
+            connection: entry.connection,
+            data: entry.line.into(),
+        })
+    }
+}
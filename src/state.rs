@@ -1,9 +1,15 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::path::Path;
 use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant};
 
 use crate::Event;
-use crate::config::{Property, TransitionAction, TransitionTrigger, TransitionTriggerSequence};
+use crate::vars::{self, Vars};
+use crate::config::{
+    GpioLevel, Property, TransitionAction, TransitionCondition, TransitionTrigger, TransitionTriggerSequence,
+};
 use anyhow::Result;
 use regex::Regex;
 use rs_graph::linkedlistgraph::*;
@@ -11,18 +17,29 @@ use rs_graph::traits::*;
 use rs_graph::LinkedListGraph;
 use rs_graph::{Buildable, Builder};
 use rs_graph_derive::Graph;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use titlecase::titlecase;
 
 #[derive(Clone, Default, Debug)]
 pub struct EdgeData {
     pub to: String,
+    /// States this transition is valid from, empty means any state
+    pub from: Vec<String>,
     /// Actions that cause this transition detected by the state machine and actually
     /// cause the state machine to change
     pub actions: Vec<TransitionAction>,
     /// triggers that can be used to cause this transition e.g. a button press should
     /// result in the associated action occuring
     pub triggers: Vec<TransitionTrigger>,
+    /// Conditions which must all be met within `window` to cause this transition
+    pub conditions: Vec<TransitionCondition>,
+    pub window: Duration,
+    /// Matches within this long of the previous match are ignored
+    pub debounce: Option<Duration>,
+    /// Minimum time between two occurrences of this transition
+    pub cooldown: Option<Duration>,
+    /// Host commands to run when this transition occurs, see [crate::hooks]
+    pub hooks: Vec<String>,
     ids: Vec<Edge<usize>>,
 }
 
@@ -35,16 +52,46 @@ pub struct Transition {
     pub actions: Vec<TransitionAction>,
     #[serde(default)]
     pub triggers: Vec<TransitionTrigger>,
+    #[serde(default)]
+    pub conditions: Vec<TransitionCondition>,
+    /// Time in ms that all the conditions have to be met within
+    #[serde(default = "_default_condition_window")]
+    pub window: u32,
+    /// Time in ms after a match during which further matches are ignored
+    pub debounce: Option<u32>,
+    /// Time in ms after this transition occurs before it can occur again
+    pub cooldown: Option<u32>,
+    /// Host commands to run when this transition occurs
+    #[serde(default)]
+    pub hooks: Vec<String>,
     #[serde(skip)]
     ids: Vec<Edge<usize>>,
 }
 
+fn _default_condition_window() -> u32 {
+    5000
+}
+
 #[derive(Deserialize, Default, Clone, Debug)]
 #[serde(rename_all(deserialize = "kebab-case"))]
 pub struct State {
     pub name: String,
+    /// The parent state, transitions from the parent and its properties apply
+    /// to all of its children
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub properties: Vec<Property>,
+    /// Host commands to run when entering and leaving this state, unlike
+    /// properties they aren't inherited
     #[serde(default)]
-    properties: Vec<Property>,
+    pub on_enter: Vec<String>,
+    #[serde(default)]
+    pub on_exit: Vec<String>,
+    /// Regexes that tell this state apart when probing the device at
+    /// startup, see [crate::identify]
+    #[serde(default)]
+    pub identify: Vec<String>,
     #[serde(skip)]
     node: Option<Node<usize>>,
 }
@@ -72,21 +119,169 @@ impl From<Transition> for EdgeData {
     fn from(t: Transition) -> Self {
         Self {
             to: t.to,
+            from: t.from,
             actions: t.actions,
             triggers: t.triggers,
+            conditions: t.conditions,
+            window: Duration::from_millis(t.window as u64),
+            debounce: t.debounce.map(|d| Duration::from_millis(d as u64)),
+            cooldown: t.cooldown.map(|d| Duration::from_millis(d as u64)),
+            hooks: t.hooks,
             ids: t.ids,
         }
     }
 }
 
+/// The source of actions that match USB devices being enumerated or removed,
+/// their value is a `vid:pid` or udev properties (see [parse_usb_match])
+pub const USB_SOURCE: &str = "USB";
+/// The events of USB actions
+pub const USB_ADD: &str = "add";
+pub const USB_REMOVE: &str = "remove";
+
+impl TransitionAction {
+    /// Whether this action matches USB devices rather than console lines
+    pub fn is_usb(&self) -> bool {
+        self.source.eq_ignore_ascii_case(USB_SOURCE)
+    }
+
+    /// Values starting with a `^` are treated as a regex, otherwise a substring match
+    pub fn matches(&self, line: &str) -> bool {
+        if self.value.starts_with("^") {
+            match Regex::new(&self.value[1..]) {
+                Ok(re) => re.is_match(line),
+                Err(e) => {
+                    log::error!("Invalid regex {:?}: {}", self.value, e);
+                    false
+                }
+            }
+        } else {
+            line.contains(self.value.as_str())
+        }
+    }
+}
+
+impl TransitionCondition {
+    /// Check a condition that reflects the current state of the hardware,
+    /// returns None for conditions that are met by events (console lines)
+    pub fn poll(&self) -> Option<bool> {
+        match self {
+            TransitionCondition::Line(_) => None,
+            TransitionCondition::Usb(id) => Some(usb_present(id)),
+            TransitionCondition::Gpio { pin, level } => Some(gpio_level(*pin) == Some(*level)),
+        }
+    }
+}
+
+impl Display for TransitionCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransitionCondition::Line(action) => write!(f, "line {:?}", action.value),
+            TransitionCondition::Usb(id) => write!(f, "usb {}", id),
+            TransitionCondition::Gpio { pin, level } => write!(f, "gpio {} {}", pin, level),
+        }
+    }
+}
+
+/// Parse a `vid:pid` USB ID
+pub fn parse_usb_id(id: &str) -> Option<(u16, u16)> {
+    let (vid, pid) = id.split_once(':')?;
+    Some((u16::from_str_radix(vid, 16).ok()?, u16::from_str_radix(pid, 16).ok()?))
+}
+
+/// Which USB devices a USB action or condition refers to
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsbMatch {
+    Id(u16, u16),
+    /// udev properties which must all match, e.g. `ID_SERIAL_SHORT=1234`
+    Properties(Vec<(String, String)>),
+}
+
+/// Parse a `vid:pid` USB ID, or a comma separated list of udev properties
+/// like `ID_VENDOR_ID=05c6,ID_MODEL=QUSB__BULK`
+pub fn parse_usb_match(s: &str) -> Option<UsbMatch> {
+    if let Some((vid, pid)) = parse_usb_id(s) {
+        return Some(UsbMatch::Id(vid, pid));
+    }
+    s.split(',')
+        .map(|prop| {
+            let (key, value) = prop.trim().split_once('=')?;
+            (!key.is_empty()).then(|| (key.to_string(), value.to_string()))
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(UsbMatch::Properties)
+}
+
+impl UsbMatch {
+    fn matches(&self, device: &Path) -> bool {
+        match self {
+            UsbMatch::Id(vid, pid) => {
+                let read_id = |name: &str| {
+                    std::fs::read_to_string(device.join(name))
+                        .ok()
+                        .and_then(|s| u16::from_str_radix(s.trim(), 16).ok())
+                };
+                read_id("idVendor") == Some(*vid) && read_id("idProduct") == Some(*pid)
+            }
+            UsbMatch::Properties(props) => {
+                let found = usb_properties(device);
+                props.iter().all(|(k, v)| found.get(k) == Some(v))
+            }
+        }
+    }
+}
+
+/// The properties of a USB device, from the kernel (its uevent) and udev (its
+/// database entry)
+fn usb_properties(device: &Path) -> BTreeMap<String, String> {
+    let parse = |contents: &str, prefix: &str| -> Vec<(String, String)> {
+        contents
+            .lines()
+            .filter_map(|l| l.strip_prefix(prefix)?.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    let uevent = std::fs::read_to_string(device.join("uevent")).unwrap_or_default();
+    let mut props: BTreeMap<String, String> = parse(&uevent, "").into_iter().collect();
+    if let (Some(major), Some(minor)) = (props.get("MAJOR"), props.get("MINOR")) {
+        let db = std::fs::read_to_string(format!("/run/udev/data/c{}:{}", major, minor)).unwrap_or_default();
+        props.extend(parse(&db, "E:"));
+    }
+    props
+}
+
+/// Whether a USB device matching `spec` (see [parse_usb_match]) is enumerated
+pub fn usb_present(spec: &str) -> bool {
+    let Some(m) = parse_usb_match(spec) else {
+        return false;
+    };
+    let Ok(entries) = std::fs::read_dir("/sys/bus/usb/devices") else {
+        return false;
+    };
+    entries.flatten().any(|e| m.matches(&e.path()))
+}
+
+/// The level of an exported sysfs GPIO
+fn gpio_level(pin: u32) -> Option<GpioLevel> {
+    match std::fs::read_to_string(format!("/sys/class/gpio/gpio{}/value", pin)).ok()?.trim() {
+        "0" => Some(GpioLevel::Low),
+        "1" => Some(GpioLevel::High),
+        _ => None,
+    }
+}
+
 impl Display for TransitionTriggerSequence {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "\t* {} {} ",
-            self.action,
-            titlecase(&self.control.replace("_", " "))
-        )?;
+        if let Some(value) = self.value.as_ref().filter(|_| self.control == "send") {
+            write!(f, "\t* Send {:?} ", value)?;
+        } else {
+            write!(
+                f,
+                "\t* {} {} ",
+                self.action,
+                titlecase(&self.control.replace("_", " "))
+            )?;
+        }
         if let Some(duration) = self.duration {
             write!(f, "for {}ms", duration)?;
         }
@@ -124,13 +319,69 @@ impl Display for TransitionTrigger {
     }
 }
 
+/// What became of a line action when a line was seen, see
+/// [StateMachine::trace_line]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verdict {
+    /// It matched and its transition was taken
+    Taken,
+    /// It matched, but an action with a higher priority (or earlier in the
+    /// config) won instead
+    Outranked,
+    /// It won, but within the debounce or cooldown of its transition, so the
+    /// line was dropped
+    Debounced,
+    /// The line doesn't match its value
+    NoMatch,
+    /// The line is from another connection
+    WrongSource,
+    /// Its transition isn't valid from the current state
+    WrongState,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActionTrace {
+    pub to: String,
+    pub from: Vec<String>,
+    pub source: String,
+    pub value: String,
+    pub verdict: Verdict,
+}
+
+/// How a line was matched against every line action
+#[derive(Debug, Clone, Serialize)]
+pub struct LineTrace {
+    pub source: String,
+    pub line: String,
+    /// The state the line was seen in
+    pub state: Option<String>,
+    pub actions: Vec<ActionTrace>,
+    /// The state it caused a transition to
+    pub transition: Option<String>,
+}
+
 pub struct StateMachine {
     states: StateGraph,
     current_state: Option<Node<usize>>,
+    /// When each condition of each transition was last met, tracks partial
+    /// matches of composite transitions. Cleared on every state change.
+    conditions_met: Vec<Vec<Option<Instant>>>,
+    /// Named capture groups from the actions that caused transitions
+    context: Vars,
+    /// When each transition was last matched and last occurred, for debouncing
+    last_matched: Vec<Option<Instant>>,
+    last_occurred: Vec<Option<Instant>>,
+    /// Whether the USB device of each USB action was present when last polled
+    usb_seen: BTreeMap<String, bool>,
+    /// The transition taken to the current state, if it was taken since the
+    /// last call to [StateMachine::hooks]
+    taken: Option<usize>,
 }
 
 impl StateMachine {
     pub fn new(mut states: Vec<State>, mut transitions: Vec<Transition>) -> Result<Self> {
+        expand_hierarchy(&mut states, &mut transitions)?;
         let mut g: LinkedListGraphBuilder<usize, State, EdgeData> = LinkedListGraph::new_builder();
 
         for trans in transitions.iter_mut() {
@@ -178,9 +429,100 @@ impl StateMachine {
 
         //log::info!("State graph: {:#?}", sg);
 
+        let conditions_met = sg.edges.iter().map(|e| vec![None; e.conditions.len()]).collect();
+        let edges = sg.edges.len();
+
         Ok(Self {
             states: sg,
             current_state: None,
+            conditions_met,
+            context: Vars::new(),
+            last_matched: vec![None; edges],
+            last_occurred: vec![None; edges],
+            usb_seen: BTreeMap::new(),
+            taken: None,
+        })
+    }
+
+    pub fn states(&self) -> &[State] {
+        &self.states.states
+    }
+
+    pub fn transitions(&self) -> &[EdgeData] {
+        &self.states.edges
+    }
+
+    pub fn current_state(&self) -> Option<&str> {
+        let node = self.current_state?;
+        self.states
+            .states
+            .iter()
+            .find(|s| s.node == Some(node))
+            .map(|s| s.name.as_str())
+    }
+
+    /// The properties of the current state, including inherited ones
+    pub fn properties(&self) -> &[Property] {
+        let Some(node) = self.current_state else {
+            return &[];
+        };
+        self.states
+            .states
+            .iter()
+            .find(|s| s.node == Some(node))
+            .map_or(&[], |s| s.properties.as_slice())
+    }
+
+    /// Go back to a state without taking a transition, e.g. because the
+    /// hardware couldn't be set up for the new one. Returns false if there's
+    /// no such state.
+    pub fn revert(&mut self, name: &str) -> bool {
+        self.taken = None;
+        self.enter(name).is_some()
+    }
+
+    /// Start out in a state found by probing the device, see
+    /// [crate::identify]. Returns its properties, or None if there's no such
+    /// state.
+    pub fn identified(&mut self, name: &str) -> Option<Vec<Property>> {
+        self.taken = None;
+        self.enter(name)
+    }
+
+    /// The hooks to run after changing state from `from`, in order: leaving
+    /// the old state, the transition taken and entering the new state
+    pub fn hooks(&mut self, from: Option<&str>) -> Vec<String> {
+        let state = |name: Option<&str>| self.states.states.iter().find(|s| Some(s.name.as_str()) == name);
+        let mut hooks = vec![];
+        if let Some(from) = state(from) {
+            hooks.extend(from.on_exit.iter().cloned());
+        }
+        if let Some(i) = self.taken {
+            hooks.extend(self.states.edges[i].hooks.iter().cloned());
+        }
+        if let Some(to) = state(self.current_state()) {
+            hooks.extend(to.on_enter.iter().cloned());
+        }
+        self.taken = None;
+        hooks
+    }
+
+    /// Whether the current state is `name` or one of its children
+    pub fn in_state(&self, name: &str) -> bool {
+        self.current_state()
+            .is_some_and(|current| is_in(&self.states.states, current, name))
+    }
+
+    /// Find a trigger by name which is valid from the current state, if the
+    /// current state is unknown then all triggers are valid.
+    pub fn find_trigger(&self, name: &str) -> Option<&TransitionTrigger> {
+        let current = self.current_state();
+        self.list_triggers().find(|t| {
+            t.name == name
+                && match current {
+                    Some(current) => t.from.is_empty() || t.from.iter().any(|f| f == current),
+                    None => true,
+                }
         })
     }
 
@@ -191,6 +533,34 @@ impl StateMachine {
             .flat_map(|e| e.triggers.iter().filter(|t| t.sequence.len() != 0))
     }
 
+    /// Variables captured from the console so far
+    pub fn context(&self) -> &Vars {
+        &self.context
+    }
+
+    /// Indices of the transitions that are valid from the current state
+    fn valid_transitions(&self) -> Vec<usize> {
+        let valid_edges: Vec<Edge<usize>> = match self.current_state {
+            Some(s) => self
+                .states
+                .incident_edges(s)
+                .map(|e| e.0)
+                .filter(|e| e.is_incoming())
+                .collect(),
+            None => self.states.edges().collect(),
+        };
+        (0..self.states.edges.len())
+            .filter(|i| self.states.edges[*i].ids.iter().any(|id| valid_edges.contains(id)))
+            .collect()
+    }
+
+    /// Whether any transition has conditions or actions that need to be polled
+    pub fn has_polled_conditions(&self) -> bool {
+        self.states.edges.iter().any(|e| {
+            e.conditions.iter().any(|c| c.poll().is_some()) || e.actions.iter().any(TransitionAction::is_usb)
+        })
+    }
+
     pub fn list_actions(&self) -> Vec<(&EdgeData, &TransitionAction)> {
         let valid_actions: Vec<Edge<usize>> = match self.current_state {
             Some(s) => self
@@ -210,39 +580,269 @@ impl StateMachine {
             }).collect()
     }
 
-    pub fn process_line(&mut self, line: &str) -> Option<Vec<Property>> {
-        let new_state = self.list_actions().iter_mut().find_map(|(t, a)| {
-            if a.value.starts_with("^") {
-                let re = Regex::new(&a.value[1..]).unwrap();
-                if re.is_match(line) {
-                    self.states.states.iter().find(|s| s.name == t.to)
+    /// Match a line from `source` against the actions valid from the current
+    /// state, returns the properties of the new state if it caused a
+    /// transition
+    pub fn process_line(&mut self, source: &str, line: &str) -> Option<Vec<Property>> {
+        self.match_line(source, line, None)
+    }
+
+    /// Like [StateMachine::process_line], also returning what became of every
+    /// line action and why
+    pub fn trace_line(&mut self, source: &str, line: &str) -> (Option<Vec<Property>>, LineTrace) {
+        let mut trace = LineTrace {
+            source: source.to_string(),
+            line: line.to_string(),
+            state: self.current_state().map(str::to_string),
+            actions: vec![],
+            transition: None,
+        };
+        let props = self.match_line(source, line, Some(&mut trace));
+        if props.is_some() {
+            trace.transition = self.current_state().map(str::to_string);
+        }
+        (props, trace)
+    }
+
+    fn match_line(&mut self, source: &str, line: &str, trace: Option<&mut LineTrace>) -> Option<Vec<Property>> {
+        let now = Instant::now();
+        let mut matches: Vec<(usize, TransitionAction)> = self
+            .list_actions()
+            .into_iter()
+            .filter(|(_, a)| !a.is_usb() && a.source == source && a.matches(line))
+            .map(|(t, a)| (self.edge_index(t), a.clone()))
+            .collect();
+        // Stable sort, so actions with equal priority keep config order
+        matches.sort_by_key(|(_, a)| std::cmp::Reverse(a.priority));
+        // Only the winner is debounced: if it's suppressed the line is
+        // dropped, rather than taking a transition it outranked
+        let suppressed = matches.first().is_some_and(|(i, _)| self.debounced(*i, now));
+        if let Some(trace) = trace {
+            trace.actions = self.verdicts(source, &matches, suppressed);
+        }
+        if suppressed {
+            return None;
+        }
+        let to = |i: usize| &self.states.edges[i].to;
+        if matches.len() > 1 {
+            // Only ambiguous if the priorities don't decide it, otherwise
+            // they're doing what they were set for
+            let level = match matches[0].1.priority == matches[1].1.priority {
+                true => log::Level::Warn,
+                false => log::Level::Debug,
+            };
+            log::log!(
+                level,
+                "Line {:?} matches {} actions, using transition to {} (priority {}) over {}",
+                line,
+                matches.len(),
+                to(matches[0].0),
+                matches[0].1.priority,
+                matches[1..]
+                    .iter()
+                    .map(|(i, a)| format!("{} (priority {})", to(*i), a.priority))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if let Some((_, action)) = matches.first() {
+            if let Some(Ok(re)) = action.value.strip_prefix("^").map(Regex::new) {
+                vars::capture(&re, line, &mut self.context);
+            }
+        }
+
+        match matches.first() {
+            Some((i, _)) => self.occur(*i, now),
+            None => {
+                let now = Instant::now();
+                self.update_conditions(|c| match c {
+                    TransitionCondition::Line(action) => {
+                        (action.source == source && action.matches(line)).then_some(now)
+                    }
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    /// Explain every line action's outcome, `matched` are the actions that
+    /// matched the line, best first, and `suppressed` whether the best was
+    /// debounced
+    fn verdicts(&self, source: &str, matched: &[(usize, TransitionAction)], suppressed: bool) -> Vec<ActionTrace> {
+        let valid = self.valid_transitions();
+        let mut verdicts = vec![];
+        for (i, edge) in self.states.edges.iter().enumerate() {
+            for action in edge.actions.iter().filter(|a| !a.is_usb()) {
+                let is = |(j, a): &(usize, TransitionAction)| *j == i && a == action;
+                let verdict = if matched.first().is_some_and(is) {
+                    match suppressed {
+                        true => Verdict::Debounced,
+                        false => Verdict::Taken,
+                    }
+                } else if matched.iter().any(is) {
+                    Verdict::Outranked
+                } else if !valid.contains(&i) {
+                    Verdict::WrongState
+                } else if action.source != source {
+                    Verdict::WrongSource
                 } else {
-                    None
-                }
-            } else if line.matches(a.value.as_str()).count() > 0 {
-                self.states.states.iter().find(|s| s.name == t.to)
-            } else {
-                None
+                    Verdict::NoMatch
+                };
+                verdicts.push(ActionTrace {
+                    to: edge.to.clone(),
+                    from: edge.from.clone(),
+                    source: action.source.clone(),
+                    value: action.value.clone(),
+                    verdict,
+                });
+            }
+        }
+        verdicts
+    }
+
+    /// Check the conditions which reflect the state of the hardware (USB
+    /// devices, GPIOs) and USB actions, call this periodically if
+    /// [has_polled_conditions] is true
+    pub fn poll_conditions(&mut self) -> Option<Vec<Property>> {
+        let now = Instant::now();
+        if let Some(props) = self.poll_usb(now) {
+            return Some(props);
+        }
+        self.update_conditions(|c| c.poll().unwrap_or(false).then_some(now))
+    }
+
+    /// Take the transition of the first valid USB action whose device has
+    /// been enumerated (`add`) or removed (`remove`) since the last poll.
+    /// Devices already present when fbug starts count as being enumerated.
+    fn poll_usb(&mut self, now: Instant) -> Option<Vec<Property>> {
+        // Every USB action is tracked, not just the valid ones, so that only
+        // changes are reported rather than whatever is plugged in
+        let specs: BTreeSet<String> = self
+            .states
+            .edges
+            .iter()
+            .flat_map(|e| e.actions.iter().filter(|a| a.is_usb()).map(|a| a.value.clone()))
+            .collect();
+        let mut changed = BTreeMap::new();
+        for spec in specs {
+            let present = usb_present(&spec);
+            if self.usb_seen.insert(spec.clone(), present).unwrap_or(false) != present {
+                log::debug!("USB {} {}", spec, if present { "enumerated" } else { "removed" });
+                changed.insert(spec, present);
+            }
+        }
+        if changed.is_empty() {
+            return None;
+        }
+        let mut matches: Vec<(usize, TransitionAction)> = self
+            .list_actions()
+            .into_iter()
+            .filter(|(_, a)| a.is_usb() && changed.get(&a.value) == Some(&(a.event == USB_ADD)))
+            .map(|(t, a)| (self.edge_index(t), a.clone()))
+            .collect();
+        matches.sort_by_key(|(_, a)| std::cmp::Reverse(a.priority));
+        let (i, _) = matches.first()?;
+        if self.debounced(*i, now) {
+            return None;
+        }
+        self.occur(*i, now)
+    }
+
+    /// Record the conditions met (`met` returns when) for the transitions valid
+    /// from the current state, and take the first transition whose conditions
+    /// have all been met within its window.
+    fn update_conditions(&mut self, met: impl Fn(&TransitionCondition) -> Option<Instant>) -> Option<Vec<Property>> {
+        let now = Instant::now();
+        let mut complete = None;
+        for i in self.valid_transitions() {
+            let edge = &self.states.edges[i];
+            if edge.conditions.is_empty() {
+                continue;
             }
-        });
-
-        match new_state {
-            Some(state) => {
-                if let Some(state) = self.state_transition(state) {
-                    let props = state.properties.clone();
-                    self.current_state = state.node;
-                    return Some(props)
+            let progress = &mut self.conditions_met[i];
+            let before = progress.iter().filter(|m| m.is_some()).count();
+            for (cond, last) in edge.conditions.iter().zip(progress.iter_mut()) {
+                if let Some(at) = met(cond) {
+                    *last = Some(at);
+                }
+                // Too long ago to count towards the window any more
+                if last.is_some_and(|at| now.duration_since(at) > edge.window) {
+                    *last = None;
                 }
-                None
             }
-            None => {
-                None
+            let count = progress.iter().filter(|m| m.is_some()).count();
+            if count != before {
+                log::debug!(
+                    "Transition to {}: {}/{} conditions met (waiting for {})",
+                    edge.to,
+                    count,
+                    progress.len(),
+                    edge.conditions
+                        .iter()
+                        .zip(progress.iter())
+                        .filter(|(_, m)| m.is_none())
+                        .map(|(c, _)| c.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
             }
+            if complete.is_none() && count == progress.len() {
+                complete = Some(i);
+            }
+        }
+        let i = complete?;
+        if self.debounced(i, now) {
+            // Start over, otherwise conditions that stay met would keep matching
+            self.conditions_met[i].iter_mut().for_each(|m| *m = None);
+            return None;
         }
+        self.occur(i, now)
+    }
+
+    fn edge_index(&self, edge: &EdgeData) -> usize {
+        self.states
+            .edges
+            .iter()
+            .position(|e| std::ptr::eq(e, edge))
+            .expect("edge belongs to this state machine")
+    }
+
+    /// Record a match of a transition, returns true if it should be ignored
+    /// because of its debounce or cooldown
+    fn debounced(&mut self, i: usize, now: Instant) -> bool {
+        let edge = &self.states.edges[i];
+        let within = |last: Option<Instant>, period: Option<Duration>| match (last, period) {
+            (Some(last), Some(period)) => now.duration_since(last) < period,
+            _ => false,
+        };
+        let last_matched = self.last_matched[i].replace(now);
+        let ignore = within(last_matched, edge.debounce) || within(self.last_occurred[i], edge.cooldown);
+        if ignore {
+            log::debug!("Ignoring repeated match of transition to {}", edge.to);
+        }
+        ignore
+    }
+
+    /// Take transition `i`, returns the properties of the new state
+    fn occur(&mut self, i: usize, now: Instant) -> Option<Vec<Property>> {
+        self.last_occurred[i] = Some(now);
+        self.taken = Some(i);
+        let to = self.states.edges[i].to.clone();
+        self.enter(&to)
+    }
+
+    /// Move to a new state, returns its properties
+    fn enter(&mut self, name: &str) -> Option<Vec<Property>> {
+        let state = self.states.states.iter().find(|s| s.name == name)?;
+        let state = self.state_transition(state)?;
+        let props = state.properties.clone();
+        self.current_state = state.node;
+        self.conditions_met.iter_mut().flatten().for_each(|m| *m = None);
+        Some(props)
     }
 
     fn state_transition<'a>(&'a self, state: &'a State) -> Option<&State> {
-        warn!(target: "device:axolotl", "New state {}", state.name);
+        debug!("New state {}", state.name);
         if state.node.is_none() {
             log::warn!("State {} has no node", state.name);
             return None;
@@ -251,6 +851,86 @@ impl StateMachine {
     }
 }
 
+/// Returns the chain of parents for a state, starting with the direct parent
+fn ancestors<'a>(states: &'a [State], name: &str) -> Result<Vec<&'a State>> {
+    let mut chain: Vec<&State> = vec![];
+    let mut current = states.iter().find(|s| s.name == name);
+    while let Some(parent) = current.and_then(|s| s.parent.as_ref()) {
+        let parent = states
+            .iter()
+            .find(|s| s.name == *parent)
+            .ok_or_else(|| anyhow!("Parent state {} of {} not found", parent, name))?;
+        if parent.name == name || chain.iter().any(|s| s.name == parent.name) {
+            bail!("State {} has a cyclic parent chain", name);
+        }
+        chain.push(parent);
+        current = Some(parent);
+    }
+    Ok(chain)
+}
+
+/// Whether `state` is `name` or one of its children, for when there's only the
+/// name of the current state rather than a [StateMachine]
+pub fn is_in(states: &[State], state: &str, name: &str) -> bool {
+    state == name || ancestors(states, state).is_ok_and(|a| a.iter().any(|s| s.name == name))
+}
+
+/// Returns the names of all states which are (transitively) children of `name`
+fn descendants(states: &[State], name: &str) -> Vec<String> {
+    states
+        .iter()
+        .filter(|s| {
+            ancestors(states, &s.name)
+                .map(|a| a.iter().any(|p| p.name == name))
+                .unwrap_or(false)
+        })
+        .map(|s| s.name.clone())
+        .collect()
+}
+
+/// Flatten the state hierarchy, children inherit the properties of their parents
+/// (closest parent wins) and transitions or triggers from a parent state are valid
+/// from any of its children.
+pub fn expand_hierarchy(states: &mut [State], transitions: &mut [Transition]) -> Result<()> {
+    let mut inherited: Vec<Vec<Property>> = vec![];
+    for state in states.iter() {
+        let mut props: Vec<Property> = vec![];
+        for parent in ancestors(states, &state.name)?.iter().rev().chain(std::iter::once(&state)) {
+            for prop in parent.properties.iter() {
+                props.retain(|p| {
+                    std::mem::discriminant(&p.name) != std::mem::discriminant(&prop.name)
+                        || p.connection != prop.connection
+                });
+                props.push(prop.clone());
+            }
+        }
+        inherited.push(props);
+    }
+    for (state, props) in states.iter_mut().zip(inherited) {
+        state.properties = props;
+    }
+
+    let states: &[State] = states;
+    let expand = |from: &mut Vec<String>| {
+        let mut expanded: Vec<String> = vec![];
+        for f in from.iter() {
+            for name in std::iter::once(f.clone()).chain(descendants(states, f)) {
+                if !expanded.contains(&name) {
+                    expanded.push(name);
+                }
+            }
+        }
+        *from = expanded;
+    };
+
+    for trans in transitions.iter_mut() {
+        expand(&mut trans.from);
+        trans.triggers.iter_mut().for_each(|t| expand(&mut t.from));
+    }
+
+    Ok(())
+}
+
 fn get_states_for_transition(states: &Vec<State>, ts: &Transition) -> Result<(usize, Vec<usize>)> {
     let from = ts
         .from
@@ -280,3 +960,176 @@ fn get_states_for_transition(states: &Vec<State>, ts: &Transition) -> Result<(us
 
     Ok((to, from))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GlobalProperties;
+
+    fn machine(transitions: &str) -> StateMachine {
+        let states = ["booting", "linux", "crashed"]
+            .iter()
+            .map(|name| State {
+                name: name.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        StateMachine::new(states, serde_yaml::from_str(transitions).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn debounced_winner_drops_line() {
+        let mut sm = machine(
+            r#"
+- to: booting
+  from: [linux]
+  debounce: 60000
+  actions: [{ source: UART, event: input, value: U-Boot, priority: 1 }]
+- to: crashed
+  from: [linux]
+  actions: [{ source: UART, event: input, value: U-Boot }]
+- to: linux
+  from: [booting]
+  actions: [{ source: UART, event: input, value: "login:" }]
+"#,
+        );
+        sm.identified("linux");
+        assert!(sm.process_line("UART", "U-Boot 2023.04").is_some());
+        assert_eq!(sm.current_state(), Some("booting"));
+        assert!(sm.process_line("UART", "login:").is_some());
+        // The banner again, within the debounce of the transition it would take
+        assert!(sm.process_line("UART", "U-Boot 2023.04").is_none());
+        assert_eq!(sm.current_state(), Some("linux"));
+    }
+
+    #[test]
+    fn outranked_match_isnt_debounced() {
+        let mut sm = machine(
+            r#"
+- to: booting
+  from: [linux]
+  actions: [{ source: UART, event: input, value: U-Boot, priority: 1 }]
+- to: crashed
+  from: [linux, booting]
+  debounce: 60000
+  actions: [{ source: UART, event: input, value: U-Boot }]
+"#,
+        );
+        sm.identified("linux");
+        assert!(sm.process_line("UART", "U-Boot 2023.04").is_some());
+        assert_eq!(sm.current_state(), Some("booting"));
+        assert!(sm.process_line("UART", "U-Boot 2023.04").is_some());
+        assert_eq!(sm.current_state(), Some("crashed"));
+    }
+
+    const HIERARCHY: &str = r#"
+- name: "off"
+- name: "on"
+  properties: [{ baud: 115200 }, { baud: 9600, connection: MODEM }]
+- name: linux
+  parent: "on"
+  properties: [{ baud: 1500000 }]
+- name: shell
+  parent: linux
+- name: fastboot
+  parent: "on"
+"#;
+
+    fn states(yaml: &str) -> Vec<State> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn names(states: &[&State]) -> Vec<String> {
+        states.iter().map(|s| s.name.clone()).collect()
+    }
+
+    #[test]
+    fn ancestors_and_descendants() {
+        let states = states(HIERARCHY);
+        assert_eq!(names(&ancestors(&states, "shell").unwrap()), ["linux", "on"]);
+        assert_eq!(names(&ancestors(&states, "fastboot").unwrap()), ["on"]);
+        assert!(ancestors(&states, "on").unwrap().is_empty());
+        assert!(ancestors(&states, "nonexistent").unwrap().is_empty());
+        assert_eq!(descendants(&states, "on"), ["linux", "shell", "fastboot"]);
+        assert_eq!(descendants(&states, "linux"), ["shell"]);
+        assert!(descendants(&states, "shell").is_empty());
+    }
+
+    #[test]
+    fn cyclic_and_missing_parents() {
+        let mut cyclic = states("- { name: a, parent: b }\n- { name: b, parent: a }\n- { name: c, parent: a }");
+        assert!(ancestors(&cyclic, "a").is_err());
+        // Not part of the cycle but leads into it
+        assert!(ancestors(&cyclic, "c").is_err());
+        assert!(expand_hierarchy(&mut cyclic, &mut []).is_err());
+        assert!(ancestors(&states("- { name: a, parent: a }"), "a").is_err());
+        assert!(ancestors(&states("- { name: a, parent: b }"), "a").is_err());
+    }
+
+    #[test]
+    fn properties_are_inherited() {
+        let mut states = states(HIERARCHY);
+        expand_hierarchy(&mut states, &mut []).unwrap();
+        let props = |name: &str| -> Vec<(GlobalProperties, Option<String>)> {
+            let state = states.iter().find(|s| s.name == name).unwrap();
+            state.properties.iter().map(|p| (p.name, p.connection.clone())).collect()
+        };
+        // The closest state's property wins, the one for another connection
+        // is kept
+        assert_eq!(
+            props("shell"),
+            [
+                (GlobalProperties::Baud(9600), Some("MODEM".to_string())),
+                (GlobalProperties::Baud(1500000), None),
+            ]
+        );
+        assert_eq!(props("linux"), props("shell"));
+        assert_eq!(
+            props("fastboot"),
+            [
+                (GlobalProperties::Baud(115200), None),
+                (GlobalProperties::Baud(9600), Some("MODEM".to_string())),
+            ]
+        );
+        assert!(props("off").is_empty());
+    }
+
+    #[test]
+    fn parent_transitions_apply_to_children() {
+        let mut states = states(HIERARCHY);
+        let mut transitions: Vec<Transition> = serde_yaml::from_str(
+            r#"
+- to: "off"
+  from: ["on"]
+  triggers: [{ name: power-off, from: [linux] }]
+- to: linux
+  from: [shell, linux, fastboot]
+"#,
+        )
+        .unwrap();
+        expand_hierarchy(&mut states, &mut transitions).unwrap();
+        assert_eq!(transitions[0].from, ["on", "linux", "shell", "fastboot"]);
+        assert_eq!(transitions[0].triggers[0].from, ["linux", "shell"]);
+        // Children that are listed as well aren't repeated
+        assert_eq!(transitions[1].from, ["shell", "linux", "fastboot"]);
+    }
+
+    #[test]
+    fn in_state_includes_parents() {
+        let transitions = r#"
+- to: shell
+  from: ["off"]
+  actions: [{ source: UART, event: input, value: "login:" }]
+"#;
+        let mut sm = StateMachine::new(states(HIERARCHY), serde_yaml::from_str(transitions).unwrap()).unwrap();
+        assert!(!sm.in_state("shell"));
+        sm.identified("shell");
+        for name in ["shell", "linux", "on"] {
+            assert!(sm.in_state(name), "{}", name);
+        }
+        assert!(!sm.in_state("fastboot"));
+        assert!(!sm.in_state("off"));
+        assert!(is_in(sm.states(), "fastboot", "on"));
+        assert!(!is_in(sm.states(), "on", "fastboot"));
+    }
+}
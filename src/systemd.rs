@@ -0,0 +1,118 @@
+//! Integration with systemd when running as a service: readiness and watchdog
+//! notifications (sd_notify) and socket activation. Everything here is a no-op
+//! when not started by systemd.
+
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use anyhow::Result;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+use nix::unistd::getpid;
+
+/// The first file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Send a notification to the service manager, e.g. `READY=1`
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let bytes = path.as_bytes();
+    // A leading @ is a socket in the abstract namespace
+    if let Some(name) = bytes.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+        #[cfg(not(target_os = "linux"))]
+        bail!("Abstract notify socket {:?} isn't supported", name);
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+fn notify_or_warn(state: &str) {
+    if let Err(e) = notify(state) {
+        warn!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+pub fn ready(status: &str) {
+    notify_or_warn(&format!("READY=1\nSTATUS={}", status));
+}
+
+pub fn stopping() {
+    notify_or_warn("STOPPING=1");
+}
+
+/// How often to ping the watchdog, if it's enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<i32>().ok()? != getpid().as_raw() {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // Ping at twice the rate required so a slow tick isn't fatal
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Ping the watchdog for as long as the runtime keeps running tasks and
+/// `healthy` holds, so a wedged agent gets restarted
+pub async fn watchdog(healthy: impl Fn() -> bool) {
+    let Some(interval) = watchdog_interval() else {
+        return std::future::pending().await;
+    };
+    debug!("Pinging the systemd watchdog every {:?}", interval);
+    let mut interval = tokio::time::interval(interval);
+    let mut pinging = true;
+    loop {
+        interval.tick().await;
+        let ok = healthy();
+        if ok {
+            notify_or_warn("WATCHDOG=1");
+        } else if pinging {
+            warn!("A connection has hung, no longer pinging the systemd watchdog");
+        }
+        pinging = ok;
+    }
+}
+
+/// A socket passed by socket activation
+pub enum ActivatedSocket {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// Take the sockets passed by socket activation, if any
+pub fn listen_fds() -> Result<Vec<ActivatedSocket>> {
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|p| p.parse::<i32>().ok());
+    if pid != Some(getpid().as_raw()) {
+        return Ok(vec![]);
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")?.parse()?;
+    // Don't pass them on to children
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+            let family = getsockname::<SockaddrStorage>(fd)?.family();
+            // Safe, systemd hands these over to us and nothing else uses them
+            let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(match family {
+                Some(AddressFamily::Unix) => ActivatedSocket::Unix(owned.into()),
+                Some(AddressFamily::Inet | AddressFamily::Inet6) => ActivatedSocket::Tcp(owned.into()),
+                family => bail!("Unsupported activated socket family {:?}", family),
+            })
+        })
+        .collect()
+}
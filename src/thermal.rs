@@ -0,0 +1,262 @@
+//! Temperature monitoring for soak tests. While the device is in one of the
+//! configured states its thermal zones (or an external sensor) are sampled
+//! periodically, and crossing a threshold emits a line from the `THERMAL`
+//! source so transitions can react to it, optionally running a trigger too.
+//! Samples are recorded in the run artifacts along with the state.
+
+use std::fmt::Display;
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::config::{ConnectionInfo, ThermalConfig, ThermalThreshold};
+use crate::connections::SshControl;
+use crate::state::StateMachine;
+use crate::Event;
+use anyhow::Result;
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// The source of the lines emitted when thresholds are crossed
+pub const THERMAL_SOURCE: &str = "THERMAL";
+
+/// Prints the type and temperature of each thermal zone
+const ZONES_COMMAND: &str =
+    "for z in /sys/class/thermal/thermal_zone*; do echo \"$(cat $z/type) $(cat $z/temp)\"; done";
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Reading {
+    pub zone: String,
+    pub celsius: f32,
+}
+
+/// Parse `<zone> <temperature>` lines, anything else is skipped. Values of
+/// 1000 or more are millidegrees, like sysfs reports.
+pub fn parse_readings(output: &str) -> Vec<Reading> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (zone, value) = line.trim().rsplit_once(char::is_whitespace)?;
+            let value: f32 = value.parse().ok()?;
+            Some(Reading {
+                zone: zone.trim().to_string(),
+                celsius: if value.abs() >= 1000.0 { value / 1000.0 } else { value },
+            })
+        })
+        .collect()
+}
+
+/// A threshold was exceeded, or cleared again
+#[derive(Debug, Clone)]
+pub struct Crossing {
+    pub threshold: ThermalThreshold,
+    pub reading: Reading,
+    pub exceeded: bool,
+}
+
+impl Display for Crossing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "thermal {} {}: {} at {:.1}C",
+            self.threshold.name,
+            if self.exceeded { "exceeded" } else { "cleared" },
+            self.reading.zone,
+            self.reading.celsius
+        )
+    }
+}
+
+pub struct Monitor {
+    config: ThermalConfig,
+    ssh: Option<SshControl>,
+    /// Whether each threshold is currently exceeded
+    exceeded: Vec<bool>,
+}
+
+impl Monitor {
+    pub fn new(config: &ThermalConfig, connections: &[ConnectionInfo], ssh: &[SshControl]) -> Result<Self> {
+        let ssh = match &config.connection {
+            Some(label) => match connections.iter().find(|c| c.label() == label.as_str()) {
+                Some(ConnectionInfo::Ssh(_)) => ssh.iter().find(|s| s.name() == label).cloned(),
+                Some(_) => bail!("Thermal connection {} isn't an ssh connection", label),
+                None => bail!("Thermal connection {} doesn't exist", label),
+            },
+            None => None,
+        };
+        Ok(Self {
+            config: config.clone(),
+            ssh,
+            exceeded: vec![false; config.thresholds.len()],
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.config.interval as u64)
+    }
+
+    /// Whether the device is in a state to sample in
+    pub fn active(&self, sm: &StateMachine) -> bool {
+        self.config.states.iter().any(|s| sm.in_state(s))
+    }
+
+    /// Take a sample in the background, the readings are sent as
+    /// [Event::Thermal]
+    pub fn sample(&self, tx: UnboundedSender<Event>) {
+        let command = self.config.command.as_deref().unwrap_or(ZONES_COMMAND);
+        let mut cmd = match self.ssh.as_ref().map(|ssh| ssh.command(command)) {
+            Some(Ok(cmd)) => tokio::process::Command::from(cmd),
+            // A bootstrapped connection that isn't up yet
+            Some(Err(e)) => {
+                trace!("Not sampling temperatures: {}", e);
+                return;
+            }
+            None => {
+                let mut cmd = tokio::process::Command::new("sh");
+                cmd.arg("-c").arg(command);
+                cmd
+            }
+        };
+        cmd.stdin(Stdio::null()).stderr(Stdio::piped()).kill_on_drop(true);
+        let zones = self.config.zones.clone();
+        // Give up before the next sample is due
+        let timeout = self.interval();
+        tokio::spawn(async move {
+            let res = match tokio::time::timeout(timeout, cmd.output()).await {
+                Ok(Ok(output)) if output.status.success() => Ok(output.stdout),
+                Ok(Ok(output)) => Err(format!(
+                    "sampling failed ({}): {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+                Ok(Err(e)) => Err(format!("failed to run sampling command: {}", e)),
+                Err(_) => Err(format!("sampling timed out after {:?}", timeout)),
+            };
+            let _ = tx.send(match res {
+                Ok(stdout) => {
+                    let mut readings = parse_readings(&String::from_utf8_lossy(&stdout));
+                    if !zones.is_empty() {
+                        readings.retain(|r| zones.contains(&r.zone));
+                    }
+                    Event::Thermal(readings)
+                }
+                Err(message) => Event::Error {
+                    connection: THERMAL_SOURCE.to_string(),
+                    message,
+                },
+            });
+        });
+    }
+
+    /// Check a sample against the thresholds
+    pub fn update(&mut self, readings: &[Reading]) -> Vec<Crossing> {
+        let mut crossings = vec![];
+        for (threshold, exceeded) in self.config.thresholds.iter().zip(self.exceeded.iter_mut()) {
+            let Some(hottest) = readings
+                .iter()
+                .filter(|r| threshold.zone.as_ref().map_or(true, |z| *z == r.zone))
+                .max_by(|a, b| a.celsius.total_cmp(&b.celsius))
+            else {
+                continue;
+            };
+            let crossed = if *exceeded {
+                hottest.celsius < threshold.celsius - self.config.hysteresis
+            } else {
+                hottest.celsius >= threshold.celsius
+            };
+            if crossed {
+                *exceeded = !*exceeded;
+                crossings.push(Crossing {
+                    threshold: threshold.clone(),
+                    reading: hottest.clone(),
+                    exceeded: *exceeded,
+                });
+            }
+        }
+        crossings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(zone: &str, celsius: f32) -> Reading {
+        Reading {
+            zone: zone.to_string(),
+            celsius,
+        }
+    }
+
+    #[test]
+    fn readings() {
+        let output = "cpu0-thermal 45000\nbattery 31\nskin therm 41.5\n\
+                      cat: /sys/class/thermal/thermal_zone9/temp: No such file\n\
+                      \n45000\nsub-zero -5000\n";
+        assert_eq!(
+            parse_readings(output),
+            [
+                reading("cpu0-thermal", 45.0),
+                reading("battery", 31.0),
+                reading("skin therm", 41.5),
+                reading("sub-zero", -5.0),
+            ]
+        );
+    }
+
+    fn monitor() -> Monitor {
+        let config: ThermalConfig = serde_yaml::from_str(
+            r#"
+states: [linux]
+hysteresis: 5
+thresholds:
+  - name: hot
+    celsius: 80
+  - name: gpu-hot
+    celsius: 70
+    zone: gpu
+"#,
+        )
+        .unwrap();
+        Monitor::new(&config, &[], &[]).unwrap()
+    }
+
+    /// The thresholds crossed by a sample, and which way
+    fn crossed(monitor: &mut Monitor, readings: &[Reading]) -> Vec<(String, bool)> {
+        monitor
+            .update(readings)
+            .into_iter()
+            .map(|c| (c.threshold.name, c.exceeded))
+            .collect()
+    }
+
+    #[test]
+    fn thresholds_have_hysteresis() {
+        let mut monitor = monitor();
+        assert!(crossed(&mut monitor, &[reading("cpu", 79.9)]).is_empty());
+        assert_eq!(crossed(&mut monitor, &[reading("cpu", 80.0)]), [("hot".to_string(), true)]);
+        assert!(crossed(&mut monitor, &[reading("cpu", 90.0)]).is_empty());
+        // Not cool enough to clear it yet
+        assert!(crossed(&mut monitor, &[reading("cpu", 76.0)]).is_empty());
+        assert!(crossed(&mut monitor, &[reading("cpu", 75.0)]).is_empty());
+        assert_eq!(crossed(&mut monitor, &[reading("cpu", 74.9)]), [("hot".to_string(), false)]);
+        assert!(crossed(&mut monitor, &[reading("cpu", 79.0)]).is_empty());
+        assert_eq!(crossed(&mut monitor, &[reading("cpu", 81.0)]), [("hot".to_string(), true)]);
+    }
+
+    #[test]
+    fn thresholds_watch_their_zone() {
+        let mut monitor = monitor();
+        // The hottest zone counts for thresholds without one
+        assert_eq!(
+            crossed(&mut monitor, &[reading("cpu", 60.0), reading("gpu", 82.0)]),
+            [("hot".to_string(), true), ("gpu-hot".to_string(), true)]
+        );
+        assert_eq!(
+            crossed(&mut monitor, &[reading("cpu", 85.0), reading("gpu", 60.0)]),
+            [("gpu-hot".to_string(), false)]
+        );
+        // A sample without the zone leaves its threshold as it was
+        assert_eq!(crossed(&mut monitor, &[reading("cpu", 70.0)]), [("hot".to_string(), false)]);
+        assert!(crossed(&mut monitor, &[]).is_empty());
+    }
+}
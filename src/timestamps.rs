@@ -0,0 +1,99 @@
+//! grabserial style timestamps for console lines: the time since a reference
+//! point (the last transition, or entering a given state like the first boot
+//! stage) and the time since the previous line, so slow stages of a boot stand
+//! out while watching it. The times are always tracked, the mode only decides
+//! what's shown and can be cycled at runtime with SIGUSR1.
+
+use std::time::{Duration, Instant};
+
+use crate::config::{TimestampConfig, TimestampMode};
+
+/// The timing of a console line
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    pub elapsed: Duration,
+    pub delta: Duration,
+}
+
+impl Stamp {
+    /// The prefix for a line, empty when timestamps are off
+    pub fn format(&self, mode: TimestampMode) -> String {
+        let elapsed = self.elapsed.as_secs_f64();
+        let delta = self.delta.as_secs_f64();
+        match mode {
+            TimestampMode::Off => String::new(),
+            TimestampMode::Elapsed => format!("[{:>11.6}] ", elapsed),
+            TimestampMode::Delta => format!("[+{:>9.6}] ", delta),
+            TimestampMode::Both => format!("[{:>11.6} +{:>9.6}] ", elapsed, delta),
+        }
+    }
+}
+
+pub struct LineTimestamps {
+    since: Option<String>,
+    start: Instant,
+    last: Option<Instant>,
+}
+
+impl LineTimestamps {
+    pub fn new(config: &TimestampConfig) -> Self {
+        Self {
+            since: config.since.clone(),
+            start: Instant::now(),
+            last: None,
+        }
+    }
+
+    /// Restart the elapsed time if `state` is the reference point
+    pub fn entered(&mut self, state: &str, at: Instant) {
+        if self.since.as_deref().map_or(true, |since| since == state) {
+            self.start = at;
+        }
+    }
+
+    /// Timestamp a line received at `at`
+    pub fn stamp(&mut self, at: Instant) -> Stamp {
+        let delta = self.last.map_or(Duration::ZERO, |last| at.saturating_duration_since(last));
+        self.last = Some(at);
+        Stamp {
+            elapsed: at.saturating_duration_since(self.start),
+            delta,
+        }
+    }
+}
+
+/// Resolves each time the timestamp mode should be cycled (SIGUSR1)
+pub struct ToggleSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl ToggleSignal {
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let signal = signal(SignalKind::user_defined1())
+                .map_err(|e| warn!("Can't toggle timestamps with SIGUSR1: {}", e))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+impl Default for ToggleSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,59 @@
+//! Recording how every console line was matched against the transitions'
+//! actions, for working out why a transition never fires. Each line is
+//! written to the trace file as a JSON object with the state it was seen in,
+//! every line action with its [Verdict](crate::state::Verdict) (taken,
+//! outranked, debounced, no match, wrong source or wrong state) and the state
+//! it led to, if any.
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::state::LineTrace;
+use anyhow::Result;
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    #[serde(flatten)]
+    trace: &'a LineTrace,
+}
+
+pub struct MatchTrace {
+    file: LineWriter<File>,
+}
+
+impl MatchTrace {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).map_err(|e| anyhow!("Can't create trace {}: {}", path.display(), e))?;
+        info!("Tracing matches to {}", path.display());
+        Ok(Self {
+            file: LineWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, trace: &LineTrace) {
+        let record = Record {
+            time: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            trace,
+        };
+        let res = serde_json::to_string(&record)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(writeln!(self.file, "{}", json)?));
+        if let Err(e) = res {
+            error!("Failed to write match trace: {}", e);
+        }
+    }
+}
+
+/// The trace file of one of several devices, `trace.jsonl` becomes
+/// `trace.<codename>.jsonl`
+pub fn device_path(path: &Path, codename: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, codename, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, codename),
+    };
+    path.with_file_name(name)
+}
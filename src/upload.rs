@@ -0,0 +1,208 @@
+//! Uploading finished runs, so CI jobs on other machines can link to their
+//! logs and crash dumps. Runs are uploaded to `<url>/<codename>/<run>` with
+//! the tools that are usually installed anyway, curl for WebDAV and plain HTTP
+//! and the aws cli for S3. The URL a run can be fetched from is recorded in
+//! its metadata before it's uploaded, and cleared again if the upload fails.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use crate::config::{UploadConfig, UploadKind};
+use anyhow::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Where a run goes relative to the base URL, `<codename>/<run>`
+fn remote_name(run: &Path) -> Result<String> {
+    let name = run.file_name().map(|n| n.to_string_lossy());
+    let codename = run.parent().and_then(|p| p.file_name()).map(|n| n.to_string_lossy());
+    match (codename, name) {
+        (Some(codename), Some(name)) => Ok(format!("{}/{}", codename, name)),
+        _ => bail!("{} isn't a run directory", run.display()),
+    }
+}
+
+/// Where a run can be fetched from once it has been uploaded
+pub fn url(config: &UploadConfig, run: &Path) -> Result<String> {
+    let base = config.public_url.as_deref().unwrap_or(&config.url).trim_end_matches('/');
+    let name = remote_name(run)?;
+    Ok(match config.kind {
+        UploadKind::Http => format!("{}/{}.tar.gz", base, name),
+        UploadKind::S3 | UploadKind::Webdav => format!("{}/{}/", base, name),
+    })
+}
+
+/// Run a command to completion, writing `input` to its stdin
+async fn execute(mut cmd: Command, input: Option<&str>) -> Result<()> {
+    cmd.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let program = cmd.as_std().get_program().to_string_lossy().into_owned();
+    let mut child = cmd.spawn().map_err(|e| anyhow!("Failed to run {}: {}", program, e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed ({}): {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Quote a value for a curl config file
+fn quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The credentials and headers of `config` as a curl config. They're passed
+/// on curl's stdin, as arguments every user could read them with ps.
+fn curl_config(config: &UploadConfig) -> String {
+    let mut lines = vec![];
+    if let Some(user) = &config.user {
+        lines.push(format!("user = {}\n", quote(user)));
+    }
+    for header in config.headers.iter() {
+        lines.push(format!("header = {}\n", quote(header)));
+    }
+    lines.concat()
+}
+
+/// Run curl with the arguments `args` adds, see [curl_config]
+async fn curl(config: &UploadConfig, args: impl FnOnce(&mut Command)) -> Result<()> {
+    let mut cmd = Command::new("curl");
+    cmd.args(["-fsS", "-K", "-"]);
+    args(&mut cmd);
+    execute(cmd, Some(&curl_config(config))).await
+}
+
+/// The subdirectories of `dir` (parents first) and its files, relative to it
+fn walk(dir: &Path, relative: &Path, dirs: &mut Vec<PathBuf>, files: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).map_err(|e| anyhow!("Failed to read {}: {}", dir.display(), e))?;
+    for entry in entries.flatten() {
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            dirs.push(path.clone());
+            walk(&entry.path(), &path, dirs, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+async fn webdav(config: &UploadConfig, run: &Path, target: &str) -> Result<()> {
+    let mut dirs = vec![];
+    let mut files = vec![];
+    walk(run, Path::new(""), &mut dirs, &mut files)?;
+    // The device's collection may already exist, which MKCOL refuses. Any
+    // other failure shows up when the files are put.
+    let codename = target.rsplit_once('/').map_or(target, |(codename, _)| codename);
+    let collections = [codename.to_string(), target.to_string()]
+        .into_iter()
+        .chain(dirs.iter().map(|d| format!("{}/{}", target, d.to_string_lossy())));
+    for collection in collections {
+        let _ = curl(config, |cmd| {
+            cmd.args(["-X", "MKCOL"]).arg(format!("{}/", collection));
+        })
+        .await;
+    }
+    for file in files {
+        curl(config, |cmd| {
+            cmd.arg("-T")
+                .arg(run.join(&file))
+                .arg(format!("{}/{}", target, file.to_string_lossy()));
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+async fn http(config: &UploadConfig, run: &Path, target: &str) -> Result<()> {
+    let (Some(parent), Some(name)) = (run.parent(), run.file_name()) else {
+        bail!("{} isn't a run directory", run.display());
+    };
+    // Removed when it's dropped
+    let archive = tempfile::Builder::new()
+        .prefix("fbug-upload-")
+        .suffix(".tar.gz")
+        .tempfile()
+        .map_err(|e| anyhow!("Failed to create the archive of {}: {}", run.display(), e))?;
+    let mut tar = Command::new("tar");
+    tar.arg("-czf").arg(archive.path()).arg("-C").arg(parent).arg(name);
+    execute(tar, None).await?;
+    curl(config, |cmd| {
+        cmd.arg("-T").arg(archive.path()).arg(format!("{}.tar.gz", target));
+    })
+    .await
+}
+
+async fn s3(run: &Path, target: &str) -> Result<()> {
+    let mut cmd = Command::new("aws");
+    cmd.args(["s3", "cp", "--recursive", "--only-show-errors"])
+        .arg(run)
+        .arg(format!("{}/", target));
+    execute(cmd, None).await
+}
+
+/// Upload a finished run
+pub async fn upload(config: &UploadConfig, run: &Path) -> Result<()> {
+    let target = format!("{}/{}", config.url.trim_end_matches('/'), remote_name(run)?);
+    let timeout = Duration::from_millis(config.timeout as u64);
+    debug!("Uploading {} to {}", run.display(), target);
+    let upload = async {
+        match config.kind {
+            UploadKind::S3 => s3(run, &target).await,
+            UploadKind::Webdav => webdav(config, run, &target).await,
+            UploadKind::Http => http(config, run, &target).await,
+        }
+    };
+    tokio::time::timeout(timeout, upload)
+        .await
+        .map_err(|_| anyhow!("Upload timed out after {:?}", timeout))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_quoted_for_curl() {
+        let config = UploadConfig {
+            kind: UploadKind::Webdav,
+            url: "https://dav.example.com/fbug".to_string(),
+            public_url: None,
+            user: Some("fbug:s3\"cr\\et".to_string()),
+            headers: vec!["Authorization: Bearer abc".to_string()],
+            timeout: 1000,
+        };
+        assert_eq!(
+            curl_config(&config),
+            "user = \"fbug:s3\\\"cr\\\\et\"\nheader = \"Authorization: Bearer abc\"\n"
+        );
+        assert_eq!(quote("a\nb"), "\"a\\nb\"");
+    }
+}
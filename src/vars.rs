@@ -0,0 +1,113 @@
+//! Variables for trigger sequences, e.g. `${image_path}`. They come from the
+//! device config, from named capture groups of the actions that caused
+//! transitions, and from whoever runs the trigger, in increasing order of
+//! precedence.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use regex::Regex;
+
+pub type Vars = BTreeMap<String, String>;
+
+/// Replace `${name}` with the value of `name`, `$$` is a literal `$`. It's an
+/// error to reference a variable that isn't set.
+pub fn expand(template: &str, vars: &Vars) -> Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            let end = r
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable in {:?}", template))?;
+            let name = &r[..end];
+            let value = vars
+                .get(name)
+                .ok_or_else(|| anyhow!("Variable {} isn't set", name))?;
+            out.push_str(value);
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Parse a `name=value` pair, as given on the command line
+pub fn parse_var(s: &str) -> Result<(String, String)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected name=value, got {:?}", s))?;
+    if name.is_empty() {
+        bail!("Variable name can't be empty in {:?}", s);
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Store the named capture groups of `re` matching `line` into `vars`
+pub fn capture(re: &Regex, line: &str, vars: &mut Vars) {
+    let Some(caps) = re.captures(line) else {
+        return;
+    };
+    for name in re.capture_names().flatten() {
+        if let Some(m) = caps.name(name) {
+            debug!("Captured {}={:?}", name, m.as_str());
+            vars.insert(name.to_string(), m.as_str().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        [("image", "boot.img"), ("slot", "a")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn expand_vars() {
+        let vars = vars();
+        assert_eq!(expand("flash boot_${slot} ${image}", &vars).unwrap(), "flash boot_a boot.img");
+        assert_eq!(expand("${slot}${slot}", &vars).unwrap(), "aa");
+        assert_eq!(expand("no variables", &vars).unwrap(), "no variables");
+        assert_eq!(expand("", &vars).unwrap(), "");
+    }
+
+    #[test]
+    fn expand_dollars() {
+        let vars = vars();
+        assert_eq!(expand("$${slot}", &vars).unwrap(), "${slot}");
+        assert_eq!(expand("$$${slot}", &vars).unwrap(), "$a");
+        assert_eq!(expand("$$$${slot}", &vars).unwrap(), "$${slot}");
+        assert_eq!(expand("costs $5", &vars).unwrap(), "costs $5");
+        assert_eq!(expand("trailing $", &vars).unwrap(), "trailing $");
+    }
+
+    #[test]
+    fn expand_errors() {
+        let vars = vars();
+        assert!(expand("${slot", &vars).is_err());
+        assert!(expand("${", &vars).is_err());
+        assert!(expand("${missing}", &vars).is_err());
+        assert!(expand("${}", &vars).is_err());
+    }
+
+    #[test]
+    fn parse_vars() {
+        assert_eq!(parse_var("slot=a").unwrap(), ("slot".to_string(), "a".to_string()));
+        assert_eq!(parse_var("cmdline=a=b").unwrap(), ("cmdline".to_string(), "a=b".to_string()));
+        assert_eq!(parse_var("empty=").unwrap(), ("empty".to_string(), String::new()));
+        assert!(parse_var("slot").is_err());
+        assert!(parse_var("=a").is_err());
+    }
+}